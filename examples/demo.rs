@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use vibradb::{Row, VibraConfig, VibraDB};
+
+#[tokio::main]
+async fn main() {
+    // Set up configuration
+    let mut config = match VibraConfig::init() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed to read config file: {}", e);
+            return;
+        }
+    };
+
+    // Use the master key in vibra.key unless one is configured, creating it on the first run.
+    // Keep it out of version control, and safe: the database can't be read without it.
+    if config.key_file.is_none() {
+        let key_file = PathBuf::from("vibra.key");
+        if !key_file.exists() {
+            VibraDB::generate_key_file(&key_file).unwrap();
+        }
+        config.key_file = Some(key_file);
+    }
+
+    // Initialize VibraDB with custom configurations
+    let vibra_db = VibraDB::new(config).unwrap();
+
+    // Example usage
+    vibra_db.create_table("users", None).await.unwrap();
+
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
+        ],
+    };
+
+    vibra_db.insert_row("users", row).await.unwrap();
+
+    if let Some(value) = vibra_db.get_row("users", "user1").await.unwrap() {
+        println!("Retrieved: {:?}", value);
+    } else {
+        println!("Failed to retrieve row");
+    }
+
+    let updated_row = Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe Updated".into()),
+            ("email".to_string(), "john.doe.updated@example.com".into()),
+        ],
+    };
+
+    vibra_db.update_row("users", updated_row).await.unwrap();
+
+    if let Some(value) = vibra_db.get_row("users", "user1").await.unwrap() {
+        println!("Retrieved: {:?}", value);
+    } else {
+        println!("Failed to retrieve row");
+    }
+
+    // Delete DB
+    vibra_db.delete_db().await.unwrap();
+}
@@ -1,31 +1,278 @@
-use log::info;
-use serde::Deserialize;
+use crate::db::{per_row_overhead, MasterKey};
+use crate::error::{ConfigError, VibraError};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use toml;
+use zeroize::Zeroizing;
 
-#[derive(Deserialize)]
+const MAX_CACHE_SIZE: usize = 1 << 24; // Anything larger is almost certainly a typo
+const PATH_ENV_VAR: &str = "VIBRA_DB_PATH"; // Overrides the path from Vibra.toml
+const MASTER_KEY_ENV_VAR: &str = "VIBRA_MASTER_KEY"; // Overrides the key_file from Vibra.toml
+pub(crate) const MAX_ENCRYPTION_LAYERS: usize = 64; // Each layer costs CPU time on every read/write
+const OVERHEAD_WARNING_BYTES: usize = 1024; // Warn when encryption adds more than this to each row
+// Why `key_source = "keyring"` is rejected by builds without the keyring feature
+const KEYRING_DISABLED: &str = "key_source = \"keyring\" needs vibradb built with the keyring feature";
+const STRICT_KEY: &str = "strict"; // Set to false to only warn about unknown keys
+// Every key Vibra.toml may set. Keep in sync with the deserialized fields of VibraConfig.
+const KNOWN_KEYS: &[&str] = &[
+    "path",
+    "cache_size",
+    "cache_bytes",
+    "cache_shards",
+    "cache_plaintext",
+    "encryption_layers",
+    "cipher",
+    "log_operations",
+    "encrypt_names",
+    "upgrade_on_read",
+    "strict_nonces",
+    "compliance_mode",
+    "audit_log",
+    "write_gitignore",
+    "key_file",
+    "key_source",
+    "keyring_service",
+    "keyring_account",
+    TABLES_KEY,
+    STRICT_KEY,
+];
+const TABLES_KEY: &str = "tables"; // Per-table settings, as `[tables.<name>]` sections
+// Every key a `[tables.<name>]` section may set. Keep in sync with the fields of TableConfig.
+const KNOWN_TABLE_KEYS: &[&str] = &["encrypted", "sensitive", "deterministic", "cache_plaintext"];
+
+#[derive(Deserialize, Default)]
 pub struct VibraConfig {
-    pub path: Option<String>,
+    pub path: Option<PathBuf>,
     pub cache_size: Option<usize>,
+    // Optional memory budget for the cache, in bytes of cached keys and row data. When set
+    // alongside cache_size, whichever limit is reached first causes eviction.
+    pub cache_bytes: Option<usize>,
+    // Number of independently locked shards the cache is split into, so concurrent lookups of
+    // different rows don't contend. cache_size and cache_bytes are divided between them.
+    pub cache_shards: Option<usize>,
+    // Whether the cache holds rows decrypted. With false it holds their encrypted records
+    // instead, so no plaintext stays in memory between reads, and every hit is decrypted again.
+    // Unset means true; tables can override it.
+    pub cache_plaintext: Option<bool>,
     pub encryption_layers: Option<usize>,
+    // The cipher every encryption layer of new records uses. Each record names its own, so
+    // changing it leaves existing records readable.
+    pub cipher: Option<CipherSuite>,
+    // Log every row operation at debug/trace level. Off by default: the messages name tables and
+    // rows, and logging every get and insert floods production logs.
+    #[serde(default)]
+    pub log_operations: bool,
+    // Store table names and row ids pseudonymized, so the sled keys don't reveal them. It takes
+    // effect when the database is created; a database keeps the setting it was created with.
+    #[serde(default)]
+    pub encrypt_names: bool,
+    // Re-encrypt rows get_row reads that were written with other settings than the current ones,
+    // e.g. fewer layers or another cipher, so rows in use move to them without a full rekey
+    #[serde(default)]
+    pub upgrade_on_read: bool,
+    // Flush the nonce sequence's high-water mark to disk before using the nonces it reserves, so
+    // not even a crash can make one repeat, at the cost of a flush every 65536 nonces
+    #[serde(default)]
+    pub strict_nonces: bool,
+    // Append an entry for every insert, update and delete of a row, and every truncated or
+    // deleted table, to a hash-chained audit log that `VibraDB::verify_audit_log` checks
+    #[serde(default)]
+    pub audit_log: bool,
+    // Restrict the crypto to what `ComplianceMode::Strict` allows, refusing anything else
+    pub compliance_mode: Option<ComplianceMode>,
+    // Write a `.gitignore` into the database directory so it isn't committed by accident. Unset,
+    // an existing `.gitignore` is left alone; `true` overwrites it, `false` never writes one.
+    pub write_gitignore: Option<bool>,
+    // A file holding the master key as 64 hex digits or as base64, e.g. one written by
+    // `VibraDB::generate_key_file`. VIBRA_MASTER_KEY and `master_key` take precedence over it.
+    pub key_file: Option<PathBuf>,
+    // Where the master key comes from when neither `master_key` nor VIBRA_MASTER_KEY is set:
+    // `key_file`, or with the `keyring` feature, the OS keychain entry named below
+    pub key_source: Option<MasterKeySource>,
+    // The keychain entry holding the master key with `key_source = "keyring"`. The service must
+    // be set, usually to the app's name; the account is "master-key" unless set.
+    pub keyring_service: Option<String>,
+    pub keyring_account: Option<String>,
+    // Settings for particular tables, by table name, from `[tables.<name>]` sections
+    #[serde(default)]
+    pub tables: HashMap<String, TableConfig>,
+    // The key that wraps every table's data key. It can't be set from Vibra.toml;
+    // set it here, in VIBRA_MASTER_KEY or in `key_file`.
+    #[serde(skip)]
+    pub master_key: Option<MasterKey>,
+    // Seeds the key/nonce RNG so ciphertext is reproducible. Only for tests; it can't be set
+    // from Vibra.toml, and production always uses the OS RNG.
+    #[serde(skip)]
+    pub seed: Option<u64>,
+}
+
+/// Settings for one table, from a `[tables.<name>]` section of `Vibra.toml`.
+///
+/// They take effect when `create_table` creates the table; a table keeps the settings it was
+/// created with.
+///
+/// # Fields
+///
+/// * `encrypted` - Whether the table's rows and schema are encrypted. Unset means `true`. With
+///   `false`, rows are stored as their JSON behind a plaintext record header, which saves the
+///   layers of encryption on every read and write for data that isn't sensitive.
+/// * `sensitive` - Columns to encrypt, leaving the rest of each row in the clear. Unset, rows
+///   are encrypted whole. Listed columns are sealed together in a record stored inside the row,
+///   which also authenticates the clear columns, so those can be read and scanned without
+///   decrypting anything. Every column not listed is stored in the clear, including ones a
+///   schemaless table's rows add later. Unlike `encrypted`, changes apply to every row written
+///   from then on; `VibraDB::migrate_sensitive_columns` rewrites the rows already stored. Can't be
+///   set for an unencrypted table.
+/// * `deterministic` - Columns to encrypt deterministically with AES-256-GCM-SIV, under a key of
+///   their own, so equal values have equal ciphertext within the column and rows can be matched
+///   by value without decrypting them. That reveals which rows share a value, so list only columns
+///   where that is acceptable. They are stored in the row next to its clear columns, and when
+///   `sensitive` is unset, every other column is sealed as if it were listed there. Can't be set
+///   for an unencrypted table, or name a sensitive column.
+/// * `cache_plaintext` - Overrides the database's `cache_plaintext` for the table's rows. Unlike
+///   the settings above, it applies from when the database is opened, to tables old and new.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Deserialize)]
+pub struct TableConfig {
+    pub encrypted: Option<bool>,
+    pub sensitive: Option<Vec<String>>,
+    pub deterministic: Option<Vec<String>>,
+    pub cache_plaintext: Option<bool>,
+}
+
+/// The authenticated cipher used for each encryption layer.
+///
+/// Set in `Vibra.toml` as `cipher = "aes256gcm"` or `cipher = "chacha20poly1305"`. Both take
+/// 256-bit keys and 96-bit nonces and add a 16-byte tag per layer, so the choice doesn't change
+/// the size of records. ChaCha20-Poly1305 is faster on CPUs without AES instructions.
+///
+/// # Variants
+///
+/// * `Aes256Gcm` - AES-256 in Galois/Counter Mode. The default.
+/// * `ChaCha20Poly1305` - ChaCha20 with a Poly1305 authenticator (RFC 8439).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherSuite::Aes256Gcm => write!(f, "aes256gcm"),
+            CipherSuite::ChaCha20Poly1305 => write!(f, "chacha20poly1305"),
+        }
+    }
+}
+
+/// Where `VibraDB::new` looks for the master key when it isn't set in code or in
+/// `VIBRA_MASTER_KEY`.
+///
+/// Set in `Vibra.toml` as `key_source = "file"` or `key_source = "keyring"`.
+///
+/// # Variants
+///
+/// * `File` - The file named by `key_file`. The default.
+/// * `Keyring` - The OS keychain: the Keychain on macOS, the Credential Manager on Windows and
+///   the Secret Service on Linux. The key is kept in the entry named by `keyring_service` and
+///   `keyring_account`, and generated and stored there the first time a new database is opened.
+///   Needs the `keyring` feature.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MasterKeySource {
+    #[default]
+    File,
+    Keyring,
+}
+
+/// Which cryptography a database may use.
+///
+/// Set in `Vibra.toml` as `compliance_mode = "standard"` or `compliance_mode = "strict"`.
+///
+/// # Variants
+///
+/// * `Standard` - Anything Vibra supports. The default.
+/// * `Strict` - Only values sealed with exactly one layer of AES-256-GCM, under keys derived with
+///   HKDF-SHA256 from the master key or a table's data key, that authenticate the key they are
+///   stored under as associated data. The config must not set another layer count or cipher,
+///   unencrypted tables, or sensitive or deterministic columns, and the master key must be
+///   supplied from outside rather than unlocked with a passphrase. A database holding records
+///   that don't conform fails to open with `VibraError::NonConformantDatabase`, and a record
+///   that doesn't conform fails to read with `VibraError::NonConformant`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComplianceMode {
+    #[default]
+    Standard,
+    Strict,
 }
 
 /// Initializes the `VibraConfig` by reading the configuration from a `Vibra.toml` file.
 ///
 /// If the `Vibra.toml` file does not exist, it uses default values for the configuration.
+/// The `VIBRA_DB_PATH` environment variable, when set, overrides the configured path, so the
+/// path is taken from the environment variable, then `Vibra.toml`, then the platform default.
 ///
 /// # Returns
 ///
 /// * `Ok(Self)` - If the configuration is successfully initialized from the file or defaults.
-/// * `Err(io::Error)` - If there is an error reading the configuration file.
+/// * `Err(io::Error)` - If there is an error reading the configuration file, or if it contains
+///   invalid values (see `validate`).
 ///
 /// # Default Values
 ///
-/// * `path`: "vibra.db"
+/// * `path`: `<data dir>/vibra/<executable name>.db`, where the data dir is the platform's
+///   (e.g. `~/.local/share` on Linux). Falls back to "vibra.db" in the working directory if the
+///   platform has no data dir.
 /// * `cache_size`: 1024
+/// * `cache_shards`: 16, or `cache_size` if that is smaller
+/// * `cache_plaintext`: true, so cache hits skip decryption
 /// * `encryption_layers`: 10
+/// * `cipher`: "aes256gcm"; the only other choice is "chacha20poly1305". Any other value is an
+///   error naming both.
+/// * `log_operations`: false
+/// * `encrypt_names`: false, so sled keys hold table names and row ids as they are
+/// * `upgrade_on_read`: false, so rows keep the settings they were written with until rekeyed
+/// * `strict_nonces`: false, so reopening the database skips ahead of nonces a crash may have
+///   left unrecorded
+/// * `audit_log`: false, so changes aren't recorded
+/// * `compliance_mode`: "standard"; the only other choice is "strict", under which
+///   `encryption_layers` defaults to 1 and may not be set to anything else
+/// * `write_gitignore`: unset, which writes a `.gitignore` unless the directory already has one
+/// * `key_file`: unset
+/// * `key_source`: "file"; the only other choice is "keyring"
+/// * `keyring_service`: unset, and required with `key_source = "keyring"`
+/// * `keyring_account`: "master-key"
+/// * `tables`: empty, so every table is encrypted
+///
+/// # Master key
+///
+/// `VibraDB::new` needs a master key and fails with `VibraError::MissingKey` without one. It is
+/// taken from the first of these that is set:
+///
+/// 1. `master_key`, set in code, e.g. with `with_master_key`.
+/// 2. The `VIBRA_MASTER_KEY` environment variable, holding 32 bytes as 64 hex digits or as base64.
+/// 3. The file named by `key_file`, holding the key in the same encoding, or with
+///    `key_source = "keyring"` the OS keychain entry named by `keyring_service` and
+///    `keyring_account`. `VibraDB::new` generates a key and stores it there when the entry is
+///    missing and the database is new.
+///
+/// A key that is set but unusable is an error rather than a reason to try the next source.
+///
+/// # Strict parsing
+///
+/// Keys Vibra doesn't know, such as a misspelled `cache_szie`, are rejected with a `ConfigError`
+/// that names the key and its line and suggests the closest known key. A file shared with newer
+/// versions of Vibra can set `strict = false` to have unknown keys logged and ignored instead.
+/// Values of the wrong type are always rejected.
 ///
 /// # Example
 ///
@@ -41,27 +288,427 @@ impl VibraConfig {
     pub fn init() -> Result<Self, io::Error> {
         let file_path = "Vibra.toml";
         // Check if the file exists
-        if !Path::new(file_path).exists() {
+        let config = if Path::new(file_path).exists() {
+            Self::from_file(file_path)?
+        } else {
             info!("Vibra.toml not found, using default values");
-            return Ok(VibraConfig {
-                path: Some(String::from("vibra.db")),
-                cache_size: Some(1024),
-                encryption_layers: Some(10),
-            });
+            Self::from_toml("")?
+        };
+        config.apply_env()
+    }
+
+    // Set the database path, e.g. `VibraConfig::default().with_path(dir.join("vibra.db"))`
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    // Encrypt new records with `cipher`, e.g. `config.with_cipher(CipherSuite::ChaCha20Poly1305)`
+    pub fn with_cipher(mut self, cipher: CipherSuite) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    // Apply `table` to the named table, e.g. `config.with_table("countries", TableConfig { .. })`
+    pub fn with_table(mut self, table_name: &str, table: TableConfig) -> Self {
+        self.tables.insert(table_name.to_string(), table);
+        self
+    }
+
+    // Whether the config has the named table created unencrypted
+    pub(crate) fn is_plaintext_table(&self, table_name: &str) -> bool {
+        self.tables.get(table_name).and_then(|table| table.encrypted) == Some(false)
+    }
+
+    // Whether `compliance_mode = "strict"` is set
+    pub(crate) fn is_strict(&self) -> bool {
+        self.compliance_mode == Some(ComplianceMode::Strict)
+    }
+
+    // Whether the named table's rows are cached decrypted rather than as stored
+    pub(crate) fn caches_plaintext(&self, table_name: &str) -> bool {
+        let table = self.tables.get(table_name).and_then(|table| table.cache_plaintext);
+        table.or(self.cache_plaintext).unwrap_or(true)
+    }
+
+    // The columns the config has encrypted for the named table, if it only encrypts some
+    pub(crate) fn sensitive_columns(&self, table_name: &str) -> Option<&[String]> {
+        self.tables.get(table_name)?.sensitive.as_deref()
+    }
+
+    // The columns the config encrypts deterministically for the named table, if any
+    pub(crate) fn deterministic_columns(&self, table_name: &str) -> Option<&[String]> {
+        self.tables.get(table_name)?.deterministic.as_deref()
+    }
+
+    // Derive every record's keys from `key`, e.g. `VibraConfig::init()?.with_master_key(key)`
+    pub fn with_master_key(mut self, key: MasterKey) -> Self {
+        self.master_key = Some(key);
+        self
+    }
+
+    // Load a configuration from a TOML file at any path, filling in defaults for missing values.
+    // Unlike `init`, the file must exist and VIBRA_DB_PATH is not consulted.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let config_content = fs::read_to_string(path)?;
+        Self::from_toml(&config_content)
+    }
+
+    // Override the path with VIBRA_DB_PATH, if it is set
+    fn apply_env(self) -> Result<Self, io::Error> {
+        self.apply_env_from(|name| env::var_os(name))
+    }
+
+    // apply_env, reading variables with `var`, so tests needn't change the process environment
+    fn apply_env_from(mut self, var: impl Fn(&str) -> Option<OsString>) -> Result<Self, io::Error> {
+        match var(PATH_ENV_VAR) {
+            Some(path) if !path.is_empty() => {
+                info!("Using database path from {}", PATH_ENV_VAR);
+                self.path = Some(PathBuf::from(path));
+            }
+            _ => {}
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    // The master key to open a database with, from the first source that is set: `master_key`,
+    // then VIBRA_MASTER_KEY, then `key_file` or the keychain, as `key_source` says
+    pub(crate) fn resolve_master_key(&self) -> Result<MasterKey, VibraError> {
+        self.resolve_master_key_from(|name| env::var_os(name))
+    }
+
+    // resolve_master_key, reading variables with `var` as apply_env_from does
+    fn resolve_master_key_from(
+        &self,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<MasterKey, VibraError> {
+        if let Some(key) = &self.master_key {
+            return Ok(key.clone());
+        }
+        // The key's text is wiped as soon as it is parsed
+        match var(MASTER_KEY_ENV_VAR).map(|text| text.into_string().map(Zeroizing::new)) {
+            Some(Ok(text)) if !text.is_empty() => {
+                info!("Using master key from {}", MASTER_KEY_ENV_VAR);
+                return MasterKey::parse(&text).map_err(|reason| VibraError::InvalidKey {
+                    origin: MASTER_KEY_ENV_VAR.to_string(),
+                    reason,
+                });
+            }
+            Some(Err(_)) => {
+                return Err(VibraError::InvalidKey {
+                    origin: MASTER_KEY_ENV_VAR.to_string(),
+                    reason: "not valid UTF-8".to_string(),
+                })
+            }
+            _ => {}
+        }
+        if self.key_source == Some(MasterKeySource::Keyring) {
+            #[cfg(feature = "keyring")]
+            return crate::db::KeyringEntry::for_config(self)?.load();
+            #[cfg(not(feature = "keyring"))]
+            return Err(VibraError::InvalidConfig(KEYRING_DISABLED.to_string()));
+        }
+        read_key_file(self.key_file.as_ref().ok_or(VibraError::MissingKey)?)
+    }
+
+    // Platform data dir path for apps that don't configure one, named after the executable so
+    // different apps don't share a database
+    fn default_path() -> PathBuf {
+        let app = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("vibra"));
+        match dirs::data_dir() {
+            Some(dir) => dir.join("vibra").join(format!("{}.db", app)),
+            None => PathBuf::from("vibra.db"),
         }
+    }
 
-        let config_content = fs::read_to_string(file_path)?;
-        let config: VibraConfig = toml::from_str(&config_content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // Parse a configuration from TOML, filling in defaults and validating the result
+    fn from_toml(config_content: &str) -> Result<Self, io::Error> {
+        let invalid = |err: ConfigError| io::Error::new(io::ErrorKind::InvalidData, err);
+        let table: toml::Table = toml::from_str(config_content)
+            .map_err(|e| invalid(Self::toml_error(config_content, e)))?;
+        let strict = match table.get(STRICT_KEY) {
+            None => true,
+            Some(toml::Value::Boolean(strict)) => *strict,
+            Some(_) => {
+                return Err(invalid(ConfigError::Invalid {
+                    key: Some(STRICT_KEY.to_string()),
+                    line: Self::line_of_key(config_content, STRICT_KEY),
+                    message: "expected a boolean".to_string(),
+                }))
+            }
+        };
+        // Keys of `[tables.<name>]` sections, named by their full path
+        let table_keys = match table.get(TABLES_KEY) {
+            Some(toml::Value::Table(tables)) => tables
+                .iter()
+                .filter_map(|(name, section)| section.as_table().map(|section| (name, section)))
+                .flat_map(|(name, section)| {
+                    section.keys().map(move |key| (format!("{}.{}.{}", TABLES_KEY, name, key), key))
+                })
+                .filter(|(_, key)| !KNOWN_TABLE_KEYS.contains(&key.as_str()))
+                .collect(),
+            _ => vec![],
+        };
+        let unknown = table
+            .keys()
+            .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+            .map(|key| (key.clone(), key, KNOWN_KEYS))
+            .chain(table_keys.into_iter().map(|(path, key)| (path, key, KNOWN_TABLE_KEYS)));
+        for (path, key, known) in unknown {
+            let err = ConfigError::UnknownKey {
+                key: path,
+                line: Self::line_of_key(config_content, key),
+                suggestion: Self::closest_key(key, known).map(str::to_string),
+            };
+            if strict {
+                return Err(invalid(err));
+            }
+            warn!("Ignoring Vibra.toml setting: {}", err);
+        }
+        let config: VibraConfig = toml::from_str(config_content)
+            .map_err(|e| invalid(Self::toml_error(config_content, e)))?;
 
         // Fill in the default values
-        let path = config.path.unwrap_or_else(|| String::from("vibra.db"));
+        let strict_mode = config.is_strict();
+        let path = config.path.unwrap_or_else(Self::default_path);
         let cache_size = config.cache_size.unwrap_or(1024);
-        let encryption_layers = config.encryption_layers.unwrap_or(10);
+        let encryption_layers = config.encryption_layers.unwrap_or(match strict_mode {
+            true => 1,
+            false => 10,
+        });
 
-        Ok(VibraConfig {
+        let config = VibraConfig {
             path: Some(path),
             cache_size: Some(cache_size),
+            cache_bytes: config.cache_bytes,
+            cache_shards: config.cache_shards,
+            cache_plaintext: config.cache_plaintext,
             encryption_layers: Some(encryption_layers),
+            cipher: Some(config.cipher.unwrap_or_default()),
+            log_operations: config.log_operations,
+            encrypt_names: config.encrypt_names,
+            upgrade_on_read: config.upgrade_on_read,
+            strict_nonces: config.strict_nonces,
+            audit_log: config.audit_log,
+            compliance_mode: Some(config.compliance_mode.unwrap_or_default()),
+            write_gitignore: config.write_gitignore,
+            key_file: config.key_file,
+            key_source: Some(config.key_source.unwrap_or_default()),
+            keyring_service: config.keyring_service,
+            keyring_account: config.keyring_account,
+            tables: config.tables,
+            master_key: None,
+            seed: None,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Describe a TOML error by the line it points at and the key set on that line, if any
+    fn toml_error(content: &str, err: toml::de::Error) -> ConfigError {
+        let line = err
+            .span()
+            .map(|span| content[..span.start.min(content.len())].matches('\n').count() + 1);
+        let key = line
+            .and_then(|line| content.lines().nth(line - 1))
+            .and_then(|text| text.split_once('='))
+            .map(|(key, _)| key.trim().trim_matches('"').to_string())
+            .filter(|key| !key.is_empty());
+        ConfigError::Invalid {
+            key,
+            line,
+            message: err.message().to_string(),
+        }
+    }
+
+    // The 1-based line a top-level key is set on
+    fn line_of_key(content: &str, key: &str) -> Option<usize> {
+        content.lines().position(|line| {
+            line.split_once('=')
+                .is_some_and(|(name, _)| name.trim().trim_matches('"') == key)
         })
+        .map(|index| index + 1)
+    }
+
+    // The known key closest to a misspelled one, if any is close enough to be a likely typo
+    fn closest_key(key: &str, known_keys: &[&'static str]) -> Option<&'static str> {
+        known_keys
+            .iter()
+            .map(|known| (edit_distance(key, known), *known))
+            .filter(|(distance, known)| *distance <= known.len() / 3)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, known)| known)
     }
-}
\ No newline at end of file
+
+    // Check that the configured values are usable: a non-empty path and key_file, a cache size
+    // between 1 and MAX_CACHE_SIZE, a non-zero cache_bytes and cache_shards if set, and between 1
+    // and MAX_ENCRYPTION_LAYERS encryption layers, and no sensitive or deterministic columns for
+    // unencrypted tables, nor columns that are both.
+    // Layer counts whose fixed per-row overhead exceeds OVERHEAD_WARNING_BYTES are allowed but
+    // logged as a warning.
+    pub fn validate(&self) -> Result<(), io::Error> {
+        let invalid = |message: String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if let Some(path) = &self.path {
+            if path.to_string_lossy().trim().is_empty() {
+                return invalid("path must not be empty".to_string());
+            }
+        }
+        if self.key_file.as_ref().is_some_and(|path| path.as_os_str().is_empty()) {
+            return invalid("key_file must not be empty".to_string());
+        }
+        for (name, value) in [
+            ("keyring_service", &self.keyring_service),
+            ("keyring_account", &self.keyring_account),
+        ] {
+            if value.as_ref().is_some_and(|value| value.trim().is_empty()) {
+                return invalid(format!("{} must not be empty", name));
+            }
+        }
+        if self.key_source == Some(MasterKeySource::Keyring) {
+            if cfg!(not(feature = "keyring")) {
+                return invalid(KEYRING_DISABLED.to_string());
+            }
+            if self.keyring_service.is_none() {
+                return invalid("keyring_service must be set when key_source is keyring".to_string());
+            }
+        }
+        if let Some(cache_size) = self.cache_size {
+            if cache_size == 0 || cache_size > MAX_CACHE_SIZE {
+                return invalid(format!(
+                    "cache_size must be between 1 and {}, got {}",
+                    MAX_CACHE_SIZE, cache_size
+                ));
+            }
+        }
+        if self.cache_bytes == Some(0) {
+            return invalid("cache_bytes must be at least 1".to_string());
+        }
+        if self.cache_shards == Some(0) {
+            return invalid("cache_shards must be at least 1".to_string());
+        }
+        if let Some(layers) = self.encryption_layers {
+            if layers == 0 || layers > MAX_ENCRYPTION_LAYERS {
+                return invalid(format!(
+                    "encryption_layers must be between 1 and {}, got {}",
+                    MAX_ENCRYPTION_LAYERS, layers
+                ));
+            }
+            let overhead = per_row_overhead(layers);
+            if overhead > OVERHEAD_WARNING_BYTES {
+                warn!(
+                    "encryption_layers = {} adds {} bytes to every row, which dominates small values",
+                    layers, overhead
+                );
+            }
+        }
+        let unencrypted_sensitive = self.tables.keys().find(|table_name| {
+            self.is_plaintext_table(table_name) && self.sensitive_columns(table_name).is_some()
+        });
+        if let Some(table_name) = unencrypted_sensitive {
+            return invalid(format!(
+                "table {} is unencrypted, so it can't have sensitive columns",
+                table_name
+            ));
+        }
+        for (table_name, table) in &self.tables {
+            let Some(deterministic) = &table.deterministic else {
+                continue;
+            };
+            if self.is_plaintext_table(table_name) {
+                return invalid(format!(
+                    "table {} is unencrypted, so it can't have deterministic columns",
+                    table_name
+                ));
+            }
+            let sensitive = table.sensitive.as_deref().unwrap_or_default();
+            if let Some(column) = deterministic.iter().find(|column| sensitive.contains(column)) {
+                return invalid(format!(
+                    "column {} of table {} can't be both sensitive and deterministic",
+                    column, table_name
+                ));
+            }
+        }
+        if self.is_strict() {
+            return self.validate_strict().map_or(Ok(()), invalid);
+        }
+        Ok(())
+    }
+
+    // Why the config allows something `compliance_mode = "strict"` doesn't, if it does
+    fn validate_strict(&self) -> Option<String> {
+        const STRICT: &str = "compliance_mode = \"strict\"";
+        if let Some(layers) = self.encryption_layers.filter(|layers| *layers != 1) {
+            return Some(format!(
+                "{} allows exactly one encryption layer, got encryption_layers = {}",
+                STRICT, layers
+            ));
+        }
+        if let Some(cipher) = self.cipher.filter(|cipher| *cipher != CipherSuite::Aes256Gcm) {
+            return Some(format!("{} only allows aes256gcm, got cipher = \"{}\"", STRICT, cipher));
+        }
+        let mut table_names: Vec<&String> = self.tables.keys().collect();
+        table_names.sort();
+        for table_name in table_names {
+            let table = &self.tables[table_name];
+            let setting = if table.encrypted == Some(false) {
+                "encrypted = false"
+            } else if table.sensitive.is_some() {
+                "sensitive columns"
+            } else if table.deterministic.is_some() {
+                "deterministic columns"
+            } else {
+                continue;
+            };
+            return Some(format!(
+                "{} encrypts every value whole, so table {} can't have {}",
+                STRICT, table_name, setting
+            ));
+        }
+        None
+    }
+}
+
+// Read a master key from a file holding it as 64 hex digits or as base64
+pub(crate) fn read_key_file(path: &Path) -> Result<MasterKey, VibraError> {
+    // The key's text is wiped as soon as it is parsed
+    let text = fs::read_to_string(path).map(Zeroizing::new).map_err(|source| {
+        VibraError::KeyFile {
+            path: path.to_path_buf(),
+            source,
+        }
+    })?;
+    MasterKey::parse(&text).map_err(|reason| VibraError::InvalidKey {
+        origin: format!("key file {}", path.display()),
+        reason,
+    })
+}
+
+// Levenshtein distance, counting a swap of adjacent characters as a single edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod config_tests;
\ No newline at end of file
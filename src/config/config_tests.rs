@@ -0,0 +1,514 @@
+use super::*;
+use crate::VibraDB;
+
+#[test]
+fn test_valid_config_loads() {
+    let config = VibraConfig::from_toml(
+        r#"
+        path = "vibra_db"
+        cache_size = 100
+        encryption_layers = 5
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.path.as_deref(), Some(Path::new("vibra_db")));
+    assert_eq!(config.cache_size, Some(100));
+    assert_eq!(config.encryption_layers, Some(5));
+}
+
+#[test]
+fn test_missing_values_use_defaults() {
+    let config = VibraConfig::from_toml("").unwrap();
+    assert_eq!(config.path, Some(VibraConfig::default_path()));
+    assert_eq!(config.cache_size, Some(1024));
+    assert_eq!(config.encryption_layers, Some(10));
+}
+
+#[test]
+fn test_zero_cache_size_is_rejected() {
+    let err = VibraConfig::from_toml("cache_size = 0").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("cache_size"));
+}
+
+#[test]
+fn test_absurd_cache_size_is_rejected() {
+    let err = VibraConfig::from_toml("cache_size = 100000000000").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("cache_size"));
+}
+
+#[test]
+fn test_zero_encryption_layers_is_rejected() {
+    let err = VibraConfig::from_toml("encryption_layers = 0").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("encryption_layers"));
+}
+
+#[test]
+fn test_empty_path_is_rejected() {
+    let err = VibraConfig::from_toml(r#"path = """#).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("path"));
+}
+
+#[test]
+fn test_default_path_is_not_the_working_directory() {
+    let path = VibraConfig::default_path();
+    assert_eq!(path.extension(), Some("db".as_ref()));
+    if dirs::data_dir().is_some() {
+        assert_ne!(path, Path::new("vibra.db"));
+        assert!(path.is_absolute());
+    }
+}
+
+#[test]
+fn test_env_var_overrides_toml_path() {
+    // A stand-in for the environment, as other tests read the real one in parallel
+    let env = |path: &'static str| move |name: &str| (name == PATH_ENV_VAR).then(|| path.into());
+    let config = || VibraConfig::from_toml(r#"path = "from_toml""#).unwrap();
+
+    let from_env = config().apply_env_from(env("from_env"));
+    let empty_env = config().apply_env_from(env(""));
+    let without_env = config().apply_env_from(|_| None);
+
+    assert_eq!(from_env.unwrap().path.as_deref(), Some(Path::new("from_env")));
+    assert_eq!(empty_env.unwrap().path.as_deref(), Some(Path::new("from_toml")));
+    assert_eq!(without_env.unwrap().path.as_deref(), Some(Path::new("from_toml")));
+}
+
+#[test]
+fn test_master_key_sources() {
+    // VIBRA_MASTER_KEY is stood in for, as other tests read the real environment in parallel
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("vibra.key");
+    let file_key = "ab".repeat(32);
+    fs::write(&key_file, format!("{}\n", file_key)).unwrap();
+    let env_key = "cd".repeat(32);
+    let config = |key_file: &Path| {
+        let content = format!("path = {:?}\nkey_file = {:?}\n", dir.path().join("db"), key_file);
+        VibraConfig::from_toml(&content).unwrap()
+    };
+    let resolve = |config: &VibraConfig, env_key: Option<&str>| {
+        let env = |name: &str| env_key.filter(|_| name == MASTER_KEY_ENV_VAR).map(OsString::from);
+        config.resolve_master_key_from(env).map(|key| key.to_hex().to_string())
+    };
+
+    // The environment variable wins over the key file, and a key set in code over both
+    let from_env = resolve(&config(&key_file), Some(&env_key));
+    let in_code = config(&key_file).with_master_key(MasterKey::from_bytes([1; 32]));
+    let from_code = resolve(&in_code, Some(&env_key));
+    // A bad key in the environment is an error, not a reason to fall back to the file
+    let short_env = resolve(&config(&key_file), Some("abcdef"));
+    let empty_env = resolve(&config(&key_file), Some(""));
+    let from_file = resolve(&config(&key_file), None);
+
+    assert_eq!(from_env.unwrap(), env_key);
+    assert_eq!(from_code.unwrap(), "01".repeat(32));
+    assert!(matches!(
+        short_env,
+        Err(VibraError::InvalidKey { ref origin, ref reason })
+            if origin == MASTER_KEY_ENV_VAR && reason.contains("decodes to 3 bytes")
+    ));
+    assert_eq!(empty_env.unwrap(), file_key);
+    assert_eq!(from_file.unwrap(), file_key);
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        let not_utf8 = config(&key_file).resolve_master_key_from(|_| {
+            Some(OsString::from_vec(vec![0xff]))
+        });
+        assert!(matches!(not_utf8, Err(VibraError::InvalidKey { .. })));
+    }
+    // Without any key source, opening fails before anything is created. Opening reads the real
+    // environment, so only where it has no key.
+    let keyless = || VibraConfig::from_toml("").unwrap().with_path(dir.path().join("db"));
+    assert!(matches!(resolve(&keyless(), None), Err(VibraError::MissingKey)));
+    let no_key_env = env::var_os(MASTER_KEY_ENV_VAR).is_none_or(|key| key.is_empty());
+    if no_key_env {
+        assert!(matches!(VibraDB::new(keyless()), Err(VibraError::MissingKey)));
+        assert!(!dir.path().join("db").exists());
+    }
+
+    // A passphrase doesn't make a key up for a database that already has tables without one
+    let db = VibraDB::new(config(&key_file)).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(db.create_table("users", None)).unwrap();
+    runtime.block_on(db.close()).unwrap();
+    if no_key_env {
+        let no_key = VibraDB::open_with_passphrase(keyless(), "passphrase");
+        assert!(matches!(no_key, Err(VibraError::MissingKey)));
+        assert!(!dir.path().join("db").join("passphrase.json").exists());
+    }
+
+    // Unreadable key files and keys of the wrong length are reported with the file's path
+    let missing = resolve(&config(&dir.path().join("missing.key")), None);
+    assert!(matches!(
+        missing,
+        Err(VibraError::KeyFile { ref source, .. }) if source.kind() == io::ErrorKind::NotFound
+    ));
+    assert!(matches!(resolve(&config(dir.path()), None), Err(VibraError::KeyFile { .. })));
+    fs::write(&key_file, "ab".repeat(16)).unwrap();
+    let err = resolve(&config(&key_file), None).err().unwrap();
+    assert!(err.to_string().contains(&key_file.display().to_string()));
+    assert!(err.to_string().contains("decodes to 16 bytes, but a master key is 32 bytes"));
+    assert!(!err.to_string().contains(&"ab".repeat(16)));
+}
+
+#[test]
+fn test_master_key_parsing() {
+    let hex = "0123456789abcdef".repeat(4);
+    let key = MasterKey::parse(&hex).unwrap();
+    assert_eq!(key.to_hex().as_str(), hex);
+    assert_eq!(MasterKey::parse(&hex.to_uppercase()).unwrap().to_hex().as_str(), hex);
+    assert_eq!(MasterKey::parse(&format!("  {}\r\n", hex)).unwrap().to_hex().as_str(), hex);
+
+    // Base64 of the same bytes, with or without padding
+    let base64 = "ASNFZ4mrze8BI0VniavN7wEjRWeJq83vASNFZ4mrze8=";
+    assert_eq!(MasterKey::parse(base64).unwrap().to_hex().as_str(), hex);
+    assert_eq!(MasterKey::parse(base64.trim_end_matches('=')).unwrap().to_hex().as_str(), hex);
+
+    for (text, reason) in [
+        ("", "the key is empty"),
+        (&hex[..62], "decodes to 31 bytes"),
+        (&format!("{}00", hex), "decodes to 33 bytes"),
+        ("QUJD", "decodes to 3 bytes"),
+        (&"Q".repeat(64), "decodes to 48 bytes"),
+        ("not a key!", "expected 32 bytes written as 64 hex digits or as base64"),
+    ] {
+        let err = MasterKey::parse(text).err().unwrap();
+        assert!(err.contains(reason), "{:?}: {}", text, err);
+    }
+    assert!(VibraConfig::from_toml("key_file = \"\"").is_err());
+}
+
+#[test]
+fn test_from_file_at_custom_location() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("nested").join("service.toml");
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(&file, "path = \"custom_db\"\ncache_size = 64\n").unwrap();
+
+    let config = VibraConfig::from_file(&file).unwrap();
+    assert_eq!(config.path.as_deref(), Some(Path::new("custom_db")));
+    assert_eq!(config.cache_size, Some(64));
+    assert_eq!(config.encryption_layers, Some(10));
+
+    let err = VibraConfig::from_file(dir.path().join("missing.toml")).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_encryption_layer_bounds() {
+    for layers in [1, MAX_ENCRYPTION_LAYERS] {
+        let config = VibraConfig::from_toml(&format!("encryption_layers = {}", layers)).unwrap();
+        assert_eq!(config.encryption_layers, Some(layers));
+    }
+    let err = VibraConfig::from_toml(&format!("encryption_layers = {}", MAX_ENCRYPTION_LAYERS + 1))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("between 1 and 64"));
+}
+
+#[test]
+fn test_per_row_overhead() {
+    // Only a salt and its key source are stored for the keys, however many layers there are
+    assert_eq!(per_row_overhead(0), 103);
+    assert_eq!(per_row_overhead(1), 131);
+    assert_eq!(per_row_overhead(10), 383);
+    // The warning threshold falls between the default and the maximum
+    assert!(per_row_overhead(10) <= OVERHEAD_WARNING_BYTES);
+    assert!(per_row_overhead(MAX_ENCRYPTION_LAYERS) > OVERHEAD_WARNING_BYTES);
+}
+
+#[test]
+fn test_cache_bytes() {
+    let config = VibraConfig::from_toml("cache_bytes = 1048576").unwrap();
+    assert_eq!(config.cache_bytes, Some(1048576));
+    assert_eq!(VibraConfig::from_toml("").unwrap().cache_bytes, None);
+    let err = VibraConfig::from_toml("cache_bytes = 0").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_cache_shards() {
+    let config = VibraConfig::from_toml("cache_shards = 4").unwrap();
+    assert_eq!(config.cache_shards, Some(4));
+    assert_eq!(VibraConfig::from_toml("").unwrap().cache_shards, None);
+    let err = VibraConfig::from_toml("cache_shards = 0").err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+}
+
+// The ConfigError an io::Error from parsing carries
+fn config_error(err: &io::Error) -> &ConfigError {
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    err.get_ref().and_then(|e| e.downcast_ref::<ConfigError>()).unwrap()
+}
+
+#[test]
+fn test_unknown_keys_are_rejected_with_a_suggestion() {
+    let err = VibraConfig::from_toml("path = \"vibra_db\"\ncache_szie = 100\n").err().unwrap();
+    match config_error(&err) {
+        ConfigError::UnknownKey { key, line, suggestion } => {
+            assert_eq!(key, "cache_szie");
+            assert_eq!(*line, Some(2));
+            assert_eq!(suggestion.as_deref(), Some("cache_size"));
+        }
+        other => panic!("expected an unknown key, got {:?}", other),
+    }
+    assert_eq!(
+        err.to_string(),
+        "unknown key `cache_szie` on line 2, did you mean `cache_size`?"
+    );
+
+    // Nothing is suggested for keys that don't resemble a known one, or that can't be set
+    let err = VibraConfig::from_toml("colour = \"blue\"").err().unwrap();
+    assert!(matches!(
+        config_error(&err),
+        ConfigError::UnknownKey { suggestion: None, line: Some(1), .. }
+    ));
+    let err = VibraConfig::from_toml("seed = 42").err().unwrap();
+    assert!(matches!(config_error(&err), ConfigError::UnknownKey { .. }));
+    let err = VibraConfig::from_toml("[cache]\nsize = 10\n").err().unwrap();
+    assert!(matches!(config_error(&err), ConfigError::UnknownKey { .. }));
+}
+
+#[test]
+fn test_lenient_mode_ignores_unknown_keys() {
+    let config = VibraConfig::from_toml("strict = false\ncache_size = 64\nnew_setting = 1\n");
+    let config = config.unwrap();
+    assert_eq!(config.cache_size, Some(64));
+    assert!(VibraConfig::from_toml("strict = true\nnew_setting = 1\n").is_err());
+
+    // Lenient mode doesn't excuse bad values
+    assert!(VibraConfig::from_toml("strict = false\ncache_size = \"big\"\n").is_err());
+    let err = VibraConfig::from_toml("strict = \"no\"").err().unwrap();
+    assert!(matches!(
+        config_error(&err),
+        ConfigError::Invalid { key: Some(key), .. } if key == "strict"
+    ));
+}
+
+#[test]
+fn test_wrong_types_name_the_key_and_line() {
+    let err = VibraConfig::from_toml("path = \"vibra_db\"\ncache_size = \"big\"\n").err().unwrap();
+    match config_error(&err) {
+        ConfigError::Invalid { key, line, message } => {
+            assert_eq!(key.as_deref(), Some("cache_size"));
+            assert_eq!(*line, Some(2));
+            assert!(message.contains("usize"), "{}", message);
+        }
+        other => panic!("expected an invalid value, got {:?}", other),
+    }
+    assert!(err.to_string().starts_with("invalid cache_size on line 2: "));
+
+    let err = VibraConfig::from_toml("path = \"unterminated\n").err().unwrap();
+    assert!(matches!(
+        config_error(&err),
+        ConfigError::Invalid { line: Some(1), .. }
+    ));
+}
+
+#[test]
+fn test_cipher_suite() {
+    let config = VibraConfig::from_toml("cipher = \"chacha20poly1305\"").unwrap();
+    assert_eq!(config.cipher, Some(CipherSuite::ChaCha20Poly1305));
+    let config = VibraConfig::from_toml("cipher = \"aes256gcm\"").unwrap();
+    assert_eq!(config.cipher, Some(CipherSuite::Aes256Gcm));
+    assert_eq!(CipherSuite::default(), CipherSuite::Aes256Gcm);
+    assert_eq!(CipherSuite::ChaCha20Poly1305.to_string(), "chacha20poly1305");
+
+    // Leaving it out picks AES-256-GCM; a config built in code gets it from VibraDB::new
+    assert_eq!(VibraConfig::from_toml("").unwrap().cipher, Some(CipherSuite::Aes256Gcm));
+    assert_eq!(VibraConfig::default().cipher, None);
+    let config = VibraConfig::default().with_cipher(CipherSuite::ChaCha20Poly1305);
+    assert_eq!(config.cipher, Some(CipherSuite::ChaCha20Poly1305));
+}
+
+#[test]
+fn test_key_source() {
+    assert_eq!(VibraConfig::from_toml("").unwrap().key_source, Some(MasterKeySource::File));
+    let content = "key_source = \"keyring\"\nkeyring_service = \"myapp\"\nkeyring_account = \"db\"";
+    let keyring = VibraConfig::from_toml(content);
+    let unnamed = VibraConfig::from_toml("key_source = \"keyring\"");
+    let empty = VibraConfig::from_toml("keyring_service = \" \"");
+
+    if cfg!(feature = "keyring") {
+        let config = keyring.unwrap();
+        assert_eq!(config.key_source, Some(MasterKeySource::Keyring));
+        assert_eq!(config.keyring_service.as_deref(), Some("myapp"));
+        assert_eq!(config.keyring_account.as_deref(), Some("db"));
+        assert!(unnamed.err().unwrap().to_string().contains("keyring_service must be set"));
+    } else {
+        // Builds without the feature say so rather than falling back to key_file
+        assert!(keyring.err().unwrap().to_string().contains("keyring feature"));
+    }
+    assert!(empty.err().unwrap().to_string().contains("keyring_service must not be empty"));
+}
+
+#[test]
+fn test_unknown_cipher_lists_the_valid_ones() {
+    for value in ["\"aes128gcm\"", "\"AES256GCM\"", "\"\"", "1"] {
+        let content = format!("cache_size = 64\ncipher = {}\n", value);
+        let err = VibraConfig::from_toml(&content).err().unwrap();
+        match config_error(&err) {
+            ConfigError::Invalid { key, line, message } => {
+                assert_eq!(key.as_deref(), Some("cipher"));
+                assert_eq!(*line, Some(2));
+                if value.starts_with('"') {
+                    assert!(message.contains("`aes256gcm`"), "{}", message);
+                    assert!(message.contains("`chacha20poly1305`"), "{}", message);
+                }
+            }
+            other => panic!("expected an invalid value, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_cache_plaintext() {
+    let config = VibraConfig::from_toml(
+        "cache_plaintext = false\n[tables.countries]\ncache_plaintext = true\n[tables.users]\n",
+    )
+    .unwrap();
+    assert!(config.caches_plaintext("countries"));
+    assert!(!config.caches_plaintext("users"));
+    assert!(!config.caches_plaintext("orders"));
+    let config = VibraConfig::from_toml("[tables.users]\ncache_plaintext = false\n").unwrap();
+    assert!(!config.caches_plaintext("users"));
+    assert!(config.caches_plaintext("orders"));
+}
+
+#[test]
+fn test_strict_compliance_mode() {
+    let config = VibraConfig::from_toml("compliance_mode = \"strict\"").unwrap();
+    assert_eq!(config.compliance_mode, Some(ComplianceMode::Strict));
+    assert_eq!(config.encryption_layers, Some(1));
+    let config = VibraConfig::from_toml("").unwrap();
+    assert_eq!(config.compliance_mode, Some(ComplianceMode::Standard));
+    assert!(VibraConfig::from_toml("compliance_mode = \"lax\"").is_err());
+
+    // Only exactly one AES-256-GCM layer, with every value encrypted whole, is allowed
+    for (toml, error) in [
+        ("encryption_layers = 2", "exactly one encryption layer, got encryption_layers = 2"),
+        ("cipher = \"chacha20poly1305\"", "only allows aes256gcm"),
+        ("[tables.countries]\nencrypted = false", "table countries can't have encrypted = false"),
+        ("[tables.users]\nsensitive = [\"email\"]", "table users can't have sensitive columns"),
+    ] {
+        let toml = format!("compliance_mode = \"strict\"\n{}\n", toml);
+        let err = VibraConfig::from_toml(&toml).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains(error), "{}", err);
+    }
+    let config = VibraConfig::from_toml(
+        "compliance_mode = \"strict\"\nencryption_layers = 1\ncipher = \"aes256gcm\"\n",
+    );
+    assert!(config.is_ok());
+}
+
+#[test]
+fn test_table_settings() {
+    let config = VibraConfig::from_toml(
+        r#"
+        cache_size = 64
+
+        [tables.countries]
+        encrypted = false
+
+        [tables.users]
+        encrypted = true
+        sensitive = ["email", "ssn"]
+        deterministic = ["country"]
+
+        [tables.orders]
+        "#,
+    )
+    .unwrap();
+    assert!(config.is_plaintext_table("countries"));
+    assert!(!config.is_plaintext_table("users"));
+    assert!(!config.is_plaintext_table("orders"));
+    assert!(!config.is_plaintext_table("cities"));
+    let sensitive = vec!["email".to_string(), "ssn".to_string()];
+    assert_eq!(config.sensitive_columns("users"), Some(sensitive.as_slice()));
+    assert_eq!(config.sensitive_columns("orders"), None);
+    let deterministic = vec!["country".to_string()];
+    assert_eq!(config.deterministic_columns("users"), Some(deterministic.as_slice()));
+    assert_eq!(config.deterministic_columns("orders"), None);
+    assert!(VibraConfig::from_toml("").unwrap().tables.is_empty());
+    let config = VibraConfig::default().with_table(
+        "countries",
+        TableConfig {
+            encrypted: Some(false),
+            ..Default::default()
+        },
+    );
+    assert!(config.is_plaintext_table("countries"));
+
+    // Only encrypted tables can have sensitive columns
+    let err = VibraConfig::from_toml(
+        "[tables.countries]\nencrypted = false\nsensitive = [\"capital\"]\n",
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("table countries is unencrypted"));
+    assert!(VibraConfig::from_toml("[tables.users]\nsensitive = []\n").is_ok());
+    let err = VibraConfig::from_toml(
+        "[tables.countries]\nencrypted = false\ndeterministic = [\"capital\"]\n",
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("can't have deterministic columns"));
+    let err = VibraConfig::from_toml(
+        "[tables.users]\nsensitive = [\"email\"]\ndeterministic = [\"email\"]\n",
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("both sensitive and deterministic"));
+
+    // Unknown table settings are reported by their full path, like unknown top-level keys
+    let content = "[tables.countries]\nencryptd = false\n";
+    let err = VibraConfig::from_toml(content).err().unwrap();
+    match config_error(&err) {
+        ConfigError::UnknownKey {
+            key,
+            line,
+            suggestion,
+        } => {
+            assert_eq!(key, "tables.countries.encryptd");
+            assert_eq!(*line, Some(2));
+            assert_eq!(suggestion.as_deref(), Some("encrypted"));
+        }
+        other => panic!("expected an unknown key, got {:?}", other),
+    }
+    let lenient = VibraConfig::from_toml(&format!("strict = false\n{}", content)).unwrap();
+    assert!(!lenient.is_plaintext_table("countries"));
+
+    let err = VibraConfig::from_toml("[tables.countries]\nencrypted = \"no\"\n").err().unwrap();
+    assert!(matches!(
+        config_error(&err),
+        ConfigError::Invalid { line: Some(2), .. }
+    ));
+}
+
+#[test]
+fn test_empty_files_use_defaults() {
+    for content in ["", "\n\n", "# nothing configured yet\n"] {
+        let config = VibraConfig::from_toml(content).unwrap();
+        assert_eq!(config.cache_size, Some(1024));
+        assert_eq!(config.encryption_layers, Some(10));
+    }
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("cache_size", "cache_size"), 0);
+    assert_eq!(edit_distance("cache_szie", "cache_size"), 1);
+    assert_eq!(edit_distance("cache_sze", "cache_size"), 1);
+    assert_eq!(edit_distance("pth", "path"), 1);
+    assert_eq!(edit_distance("", "path"), 4);
+    let closest = |key| VibraConfig::closest_key(key, KNOWN_KEYS);
+    assert_eq!(closest("encryption_layer"), Some("encryption_layers"));
+    assert_eq!(closest("pat"), Some("path"));
+    assert_eq!(closest("colour"), None);
+}
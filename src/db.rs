@@ -1,423 +1,3394 @@
-use crate::config::VibraConfig;
-use crate::models::Row;
-use aes_gcm::aead::generic_array::typenum::U12;
-use aes_gcm::aead::{Aead, KeyInit};
-use aes_gcm::{Aes256Gcm, Key, Nonce};
-use log::{error, info};
-use lru::LruCache;
-use rand::Rng;
+use crate::config::{CipherSuite, VibraConfig, MAX_ENCRYPTION_LAYERS};
+use crate::error::VibraError;
+#[cfg(feature = "metrics")]
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::models::{CacheStats, Column, Row, RowMeta, Value};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::Aes256Gcm;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::ChaCha20Poly1305;
+use log::{debug, error, info, log, warn, Level};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
 use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sled::Db;
+use sled::transaction::{ConflictableTransactionError, Transactional, TransactionalTree};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::RwLock;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+#[cfg(feature = "metrics")]
+use std::time::Instant;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio;
 use tokio::task;
-use futures::future::join_all;
+use tokio::task::JoinHandle;
+use zeroize::Zeroizing;
 
-const AES_LAYERS: usize = 25; // 25 layers of encryption
+mod audit;
+mod backup;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod blind_index;
+mod cache;
+mod columns;
+mod compliance;
+mod crypto;
+mod csv_io;
+mod dump;
+mod integrity;
+#[cfg(feature = "keyring")]
+mod keychain;
+mod keys;
+mod migration;
+mod names;
+mod nonces;
+mod passphrase;
+mod record;
+mod rotation;
+mod shred;
+mod snapshot;
+mod transaction;
+
+use audit::{AuditLog, AuditOp};
+use cache::ShardedCache;
+#[cfg(feature = "keyring")]
+pub(crate) use keychain::KeyringEntry;
+use keys::{DataKey, KeyRing, SecretBytes};
+use names::Names;
+use nonces::NonceSequence;
+use passphrase::PassphraseLock;
+use record::{KeySource, RecordHeader, Timestamp};
+#[cfg(feature = "blocking")]
+pub use blocking::VibraDbBlocking;
+pub use keys::MasterKey;
+pub use passphrase::PassphraseParams;
+pub use snapshot::VibraSnapshot;
+pub use transaction::{TransactionResult, VibraTransaction};
+
+const AES_LAYERS: usize = 25; // Layers of encryption when the config doesn't set a count
+const CACHE_SHARDS: usize = 16; // Cache shards when the config doesn't set a count
+const CHUNK_SIZE: usize = 64 * 1024; // Values are encrypted in 64 KiB chunks
+const EXPIRY_TREE: &str = "__vibra_expiry"; // row key -> expiry (unix millis, big endian)
+const SCHEMA_TREE: &str = "__vibra_schema"; // table name -> encrypted Vec<Column> JSON
+const TABLES_TREE: &str = "__vibra_tables"; // table name -> TableMeta JSON
+const META_TREE: &str = "__vibra_meta"; // Database-wide state, such as rotation progress
+const INDEX_TREE: &str = "__vibra_index"; // Blind index entries, see blind_index.rs
+const RESERVED_PREFIX: &str = "__vibra"; // Internal trees and keys; no table name may start with it
+const TABLE_META_VERSION: u32 = 2; // Format of the TableMeta records; 2 added data keys
+const LOCK_RETRIES: u32 = 20; // Attempts to take sled's lock, LOCK_RETRY_DELAY apart
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_ATOMIC_ROWS: usize = 10_000; // insert_rows_atomic holds the whole batch in memory
+const GITIGNORE: &[u8] = b"*\n"; // Written into the database directory
+const HEALTH_KEY: &str = "__vibra_health/sentinel"; // Written and removed by health_check
+const ENCRYPTION_KEY: &str = "encryption"; // EncryptionSettings JSON in the meta tree
+
+// A row ready to write: its key, the row itself for the cache, and its encrypted record
+type SealedRow = (String, Arc<Row>, Vec<u8>);
+
+#[cfg(test)]
+thread_local! {
+    // Rows deserialized on this thread, so tests can check which paths parse JSON
+    static ROW_PARSES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
 
 #[derive(Clone)]
 pub struct VibraDB {
     db: Arc<Db>,
-    cache: Arc<RwLock<LruCache<String, String>>>,
-    path: String,
+    expiry: sled::Tree,
+    schema: sled::Tree,
+    tables: sled::Tree,
+    meta: sled::Tree,
+    index: sled::Tree,
+    audit: Arc<AuditLog>, // Records changes, if the config keeps an audit log
+    cache: Arc<ShardedCache>,
+    cache_plaintext: bool, // Whether rows are cached decrypted, unless their table says otherwise
+    cache_plaintext_tables: Arc<HashMap<String, bool>>, // What tables the config sets it for say
+    path: Option<PathBuf>, // Unknown when wrapping a sled database opened elsewhere
+    rng: Arc<KeyRng>,
+    nonces: Arc<NonceSequence>, // Where every record's nonces come from
+    master_keys: Arc<RwLock<KeyRing>>,
+    table_keys: Arc<RwLock<HashMap<String, Arc<DataKey>>>>, // Unwrapped data keys, by table
+    plaintext_tables: Arc<RwLock<HashSet<String>>>, // Tables created without encryption
+    unencrypted: Arc<HashSet<String>>, // Tables the config has created without encryption
+    sensitive: Arc<HashMap<String, HashSet<String>>>, // Columns the config encrypts, by table
+    deterministic: Arc<HashMap<String, HashSet<String>>>, // Columns it encrypts deterministically
+    blind_indexes: Arc<RwLock<HashMap<String, HashSet<String>>>>, // Indexed columns, by table
+    names: Option<Arc<Names>>, // Encrypts table names and row ids, if the database does
+    rotation: Arc<Mutex<()>>, // Held by the one rotate_master_key or migrate_legacy call running
+    writers: Arc<RwLock<()>>, // Shared by writes changing rows, taken whole by snapshot
+    shredding: Arc<Mutex<HashSet<String>>>, // Keys of rows shred_row may have left noise under
+    layers: Arc<AtomicUsize>, // Layers used for new writes; each record stores its own count
+    cipher: CipherSuite,      // Cipher used for new writes; each record names its own
+    cache_counters: Arc<CacheCounters>,
+    log_operations: bool, // Whether per-row operations are logged
+    upgrade_on_read: bool, // Whether get_row re-encrypts rows written with older settings
+    strict: bool,          // Whether only records strict compliance mode allows are read or written
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+}
+
+// What is stored about each table in the tables tree, apart from its schema
+#[derive(Clone, Serialize, Deserialize)]
+struct TableMeta {
+    version: u32,    // TABLE_META_VERSION when written
+    created_at: u64, // Unix millis; when the table was migrated, for tables older than the tree
+    // The table's DataKey wrapped by the master key, in base64; none for tables older than them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data_key: Option<String>,
+    // Created without encryption, so it has no data key and its records are stored as is
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    plaintext: bool,
+    // Columns with a blind index, kept up to date as rows are written
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    blind_indexes: Vec<String>,
+    // The table's name encrypted with the names key, in base64, if the database encrypts names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+// The layer count and cipher a database was last rekeyed to. New writes go on using them after
+// a reopen, unless the config sets its own.
+#[derive(Serialize, Deserialize)]
+struct EncryptionSettings {
+    layers: usize,
+    cipher: CipherSuite,
+}
+
+// A table about to be created, as new_table prepares it
+struct NewTable {
+    stored_table: String, // The name it is stored under
+    meta: TableMeta,
+    data_key: Arc<DataKey>, // Unused if the table is created without encryption
+    schema: Option<Vec<Column>>,
+    sealed_schema: Option<Vec<u8>>, // Its schema record
+}
+
+impl NewTable {
+    // Store the table's entry and schema record, in a transaction over the tables and schema
+    // trees
+    fn store_in(
+        &self,
+        tables: &TransactionalTree,
+        schemas: &TransactionalTree,
+    ) -> TransactionResult<()> {
+        let name = self.stored_table.as_bytes();
+        tables.insert(name, self.meta.encode()?)?;
+        match &self.sealed_schema {
+            Some(sealed) => schemas.insert(name, sealed.as_slice())?,
+            None => schemas.remove(name)?,
+        };
+        Ok(())
+    }
+}
+
+impl TableMeta {
+    fn new() -> Self {
+        TableMeta {
+            version: TABLE_META_VERSION,
+            created_at: now_millis(),
+            data_key: None,
+            plaintext: false,
+            blind_indexes: vec![],
+            name: None,
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, VibraError> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    fn decode(stored: &[u8]) -> Result<Self, VibraError> {
+        Ok(serde_json::from_slice(stored)?)
+    }
+
+    // Store a data key wrapped by `master_key` for the named table
+    fn set_data_key(&mut self, master_key: &MasterKey, table_name: &str, data_key: &DataKey) {
+        self.version = TABLE_META_VERSION;
+        self.data_key = Some(STANDARD.encode(master_key.wrap(table_name, data_key)));
+    }
+
+    // The table's wrapped data key, as stored
+    fn wrapped_key(&self) -> Option<Vec<u8>> {
+        self.data_key.as_ref().and_then(|key| STANDARD.decode(key).ok())
+    }
+
+    // The table's data key, if it has one and `master_key` wrapped it
+    fn data_key(&self, master_key: &MasterKey, table_name: &str) -> Option<DataKey> {
+        master_key.unwrap(table_name, &self.wrapped_key()?)
+    }
+}
+
+// What the layer keys of a record being sealed are derived from
+enum RecordKey {
+    Master(Arc<MasterKey>), // For records outside any table, and tables without a data key
+    Table(Arc<DataKey>),
+}
+
+// Cache lookups made by get_row, reported by cache_stats
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// Source of randomness for keys, salts and nonce prefixes
+enum KeyRng {
+    Os,
+    Seeded(Box<Mutex<StdRng>>), // Reproducible, for tests only
+}
+
+impl KeyRng {
+    // Run `f` with this RNG
+    fn with<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match self {
+            KeyRng::Os => f(&mut rand::thread_rng()),
+            KeyRng::Seeded(rng) => f(&mut *rng.lock().unwrap()),
+        }
+    }
+}
+
+/// Handle to a background task started by `VibraDB::start_expiry_sweeper`.
+///
+/// The sweeper is stopped when the handle is dropped or when `stop` is called.
+pub struct ExpirySweeper {
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpirySweeper {
+    // Stop the sweeper and wait for the task to finish
+    pub async fn stop(mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Drop for ExpirySweeper {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+    }
+}
+
+// Bytes encryption adds to a value that fits in one chunk: the record header (with one chunk
+// length), checksum, key source and salt to derive the keys from, plus a nonce and GCM tag per
+// layer
+pub(crate) fn per_row_overhead(layers: usize) -> usize {
+    const FIXED: usize = record::FIXED_HEADER_LEN + 4 + 32 + 1 + keys::SALT_LEN;
+    const PER_LAYER: usize = 12 + 16;
+    FIXED + layers * PER_LAYER
+}
+
+// Check whether an expiry entry (unix millis, big endian) is in the past
+fn has_expired(expires_at: Option<&sled::IVec>) -> bool {
+    expires_at
+        .and_then(|ivec| <[u8; 8]>::try_from(ivec.as_ref()).ok())
+        .is_some_and(|bytes| u64::from_be_bytes(bytes) <= now_millis())
+}
+
+// Rebuild a path from its components, dropping trailing and repeated separators and `.`
+// segments, so the directory sled opens and the files put next to it agree on every platform
+fn normalize_path(path: &Path) -> PathBuf {
+    path.components().collect()
+}
+
+// Current time as unix milliseconds
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 /// `VibraDB` is a database abstraction that provides functionalities for creating, managing, and interacting with a database.
-/// It supports encryption with multiple layers of AES, caching, and asynchronous operations.
+/// It supports encryption with multiple layers of AES-256-GCM or ChaCha20-Poly1305, caching, and
+/// asynchronous operations.
+///
+/// Every table has a random data key, stored wrapped by a `MasterKey` (envelope encryption). The
+/// keys of each record are derived from its table's data key and only a salt is stored, so the
+/// sled files alone don't decrypt, deleting a table destroys its data key along with it, and
+/// changing the master key only re-wraps the data keys. Every layer also authenticates
+/// the key the record is stored under as associated data, so a record copied under another key
+/// fails to decrypt rather than reading as another row. The master key comes from the config,
+/// `VIBRA_MASTER_KEY` or a key file (see `VibraConfig`), and opening fails with
+/// `VibraError::MissingKey` without one. Records written by older versions store their own keys
+/// next to the ciphertext or derive them from the master key; they still read, and `rekey`
+/// rewrites every record with keys derived from its table's data key. Nonces are never random:
+/// each is the database's random 4-byte prefix followed by a 64-bit counter whose high-water mark
+/// is persisted in sled before the nonces below it are used, so none repeats.
+///
+/// Tables the config sets `encrypted = false` for (see `TableConfig`) have no data key: their
+/// records are the serialized value behind a header that marks them unencrypted, and only records
+/// of such tables are ever read that way. Tables it lists `sensitive` columns for store their
+/// rows with only those columns encrypted, sealed in a record inside the row that also
+/// authenticates the clear columns. Those it lists as `deterministic` are encrypted in place with
+/// AES-256-GCM-SIV under a key of the column's own, so equal values have equal ciphertext. Columns
+/// given a blind index, or encrypted deterministically, can be looked up by value with `find_by`
+/// without storing the value anywhere in the clear.
+///
+/// Rows are stored under `table/id` keys, so table names and row ids must not contain `/`.
+/// Operations given such a name return `VibraError::InvalidName`. Databases created with
+/// `encrypt_names` store a pseudonym of the table name and the row id encrypted in its place.
+///
+/// Tables must be created with `create_table` before use. Reading, writing, deleting or scanning
+/// rows of a table that doesn't exist returns `VibraError::TableNotFound`, while a missing row in
+/// an existing table is simply `Ok(None)`.
 ///
 /// # Methods
 ///
-/// - `new(config: VibraConfig) -> VibraDB`
-///   - Creates a new instance of `VibraDB` with custom configurations.
+/// - `new(config: VibraConfig) -> Result<VibraDB, VibraError>`
+///   - Creates a new instance of `VibraDB` with custom configurations. Fails if the config is
+///     incomplete or invalid, if no master key is configured (`VibraError::MissingKey`) or the
+///     configured one is unusable, or if the database can't be opened at the configured path.
+///     With `key_source = "keyring"`, a new database whose keychain entry is empty gets a random
+///     master key stored there, while one that already has tables fails with
+///     `VibraError::KeyringEntryMissing`.
+///
+/// - `with_sled(db: sled::Db, config: VibraConfig) -> Result<VibraDB, VibraError>`
+///   - Wraps an already open sled database instead of opening one at `config.path`, so sled can
+///     be tuned or shared. `config.path` is optional and only needed by `delete_db`.
+///
+/// - `generate_key_file(path: impl AsRef<Path>) -> Result<(), VibraError>`
+///   - Writes a new random master key to a file that doesn't exist yet, as 64 hex digits, for
+///     `key_file` or `VIBRA_MASTER_KEY`. On unix the file is only readable by its owner.
+///
+/// - `export_keyring_key(config: &VibraConfig, path: impl AsRef<Path>) -> Result<(), VibraError>`
+///   - Writes the master key kept in the keychain entry `config` names to a new key file, as a
+///     backup. Available with the `keyring` feature.
+///
+/// - `import_keyring_key(config: &VibraConfig, path: impl AsRef<Path>) -> Result<(), VibraError>`
+///   - Stores the master key from a key file in the keychain entry `config` names, unless the
+///     entry holds another key. Available with the `keyring` feature.
+///
+/// - `open_with_passphrase(config: VibraConfig, passphrase: &str) -> Result<VibraDB, VibraError>`
+///   - Opens the database with the master key stored in its directory, encrypted under a key
+///     derived from `passphrase` with Argon2id. Fails with `VibraError::InvalidPassphrase` if the
+///     passphrase is wrong. A new database gets a random master key (or the configured one),
+///     locked with the default `PassphraseParams`.
+///
+/// - `set_passphrase(&self, passphrase: &str, params: PassphraseParams) -> Result<(), VibraError>`
+///   - Locks the master key under a new passphrase and/or Argon2id parameters. Rows are not
+///     rewritten, since the master key stays the same.
+///
+/// - `generate_key(rng: &mut impl RngCore) -> SecretBytes`
+///   - Generates a random 256-bit layer key, wiped from memory when dropped.
+///
+/// - `encrypt_value(&self, context: &str, value: &[u8]) -> Result<Vec<u8>, VibraError>`
+///   - Encrypts a value with the configured cipher and number of layers, in 64 KiB chunks. `context` is
+///     the key the record is stored under, which the derived keys are bound to.
+///
+/// - `decrypt_value(&self, context: &str, stored: &[u8]) -> Result<String, VibraError>`
+///   - Decrypts a text value with the cipher and number of layers it was written with,
+///     reassembling its chunks.
+///
+/// - `decrypt_bytes(&self, context: &str, stored: &[u8]) -> Result<Vec<u8>, VibraError>`
+///   - Decrypts a value to raw bytes, without requiring it to be valid UTF-8.
+///
+/// - `create_table(&self, table_name: &str, schema: Option<Vec<Column>>) -> Result<bool, VibraError>`
+///   - Creates a new table in the database, with a new data key, returning whether it was
+///     created. A table the config sets `encrypted = false` for is created without one, and its
+///     rows and schema are stored unencrypted. Creating an existing table is a no-op, except that
+///     an encrypted one from before data keys is given one. When a schema is given, rows inserted
+///     into the table must only use declared columns with values of the declared type.
+///
+/// - `delete_table(&self, table_name: &str) -> Result<usize, VibraError>`
+///   - Deletes a table from the database, along with its rows, schema and data key, returning
+///     how many rows were removed. Without the data key, copies of the table's records can't be
+///     decrypted, failing with `VibraError::MissingTableKey` or `VibraError::Decryption`.
+///
+/// - `shred_table(&self, table_name: &str) -> Result<usize, VibraError>`
+///   - Overwrites a table's rows, schema and wrapped data key with random bytes and flushes
+///     before deleting it as `delete_table` does, returning how many rows it had.
+///
+/// - `table_exists(&self, table_name: &str) -> Result<bool, VibraError>`
+///   - Checks whether a table has been created.
+///
+/// - `insert_row(&self, table_name: &str, row: Row) -> Result<Option<Row>, VibraError>`
+///   - Inserts a row into a table, validating it against the table's schema. Rows that use a
+///     column name twice are rejected with `VibraError::DuplicateColumn`. Returns the row it
+///     replaced, if any.
+///
+/// - `insert_row_if_absent(&self, table_name: &str, row: Row) -> Result<bool, VibraError>`
+///   - Inserts a row only if no live row has its id, returning whether it was inserted. Of several
+///     concurrent calls for the same id, exactly one succeeds.
+///
+/// - `insert_row_with_timeout(&self, table_name: &str, row: Row, timeout: Duration) -> Result<Option<Row>, VibraError>`
+///   - Like `insert_row`, but fails with `VibraError::Timeout` if the write takes longer than
+///     `timeout`. The row may still be stored afterwards.
 ///
-/// - `generate_key() -> Key<Aes256Gcm>`
-///   - Generates a random AES256 key.
+/// - `insert_rows(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError>`
+///   - Inserts multiple rows into a table, one at a time. Rows before a failing one stay stored.
 ///
-/// - `generate_nonce() -> Nonce<U12>`
-///   - Generates a random nonce.
+/// - `insert_many_rows(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError>`
+///   - Inserts multiple rows into a table, encrypting them in parallel and writing them in a single batch.
 ///
-/// - `encrypt_value(&self, value: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>)`
-///   - Encrypts a value with 25 layers of AES encryption.
+/// - `insert_rows_atomic(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError>`
+///   - Inserts multiple rows in one transaction, so either all of them are stored or none are.
+///     Batches with a repeated row id fail with `VibraError::DuplicateRow`, and batches of more
+///     than 10,000 rows with `VibraError::BatchTooLarge`.
 ///
-/// - `decrypt_value(&self, encrypted_data: &[u8], key: &[u8], nonce: &[u8]) -> Result<String, String>`
-///   - Decrypts a value with 25 layers of AES decryption.
+/// - `insert_auto(&self, table_name: &str, columns: Vec<(String, Value)>) -> Result<String, VibraError>`
+///   - Inserts a row under a generated unique id and returns the id.
 ///
-/// - `create_table(&self, table_name: &str)`
-///   - Creates a new table in the database.
+/// - `insert_typed<T: Serialize>(&self, table_name: &str, id: &str, value: &T) -> Result<(), VibraError>`
+///   - Stores any serializable value as encrypted JSON, bypassing the column model. Typed values
+///     are not cached and must be read back with `get_typed`.
 ///
-/// - `delete_table(&self, table_name: &str)`
-///   - Deletes a table from the database.
+/// - `get_typed<T: DeserializeOwned>(&self, table_name: &str, id: &str) -> Result<Option<T>, VibraError>`
+///   - Retrieves a value stored with `insert_typed`.
 ///
-/// - `insert_row(&self, table_name: &str, row: Row)`
-///   - Inserts a row into a table.
+/// - `insert_row_with_ttl(&self, table_name: &str, row: Row, ttl: Duration) -> Result<(), VibraError>`
+///   - Inserts a row that expires after `ttl`.
 ///
-/// - `insert_rows(&self, table_name: &str, rows: Vec<Row>)`
-///   - Inserts multiple rows into a table.
+/// - `get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError>`
+///   - Retrieves a row from a table. Expired rows are removed lazily and never returned.
+///     `Ok(None)` means the row is absent; a stored record that can't be decrypted or parsed is
+///     an error, such as `VibraError::Decryption`. With `upgrade_on_read`, rows read from disk
+///     that were written with older settings are re-encrypted with the current ones in the
+///     background.
 ///
-/// - `get_row(&self, table_name: &str, row_id: &str) -> Option<Row>`
-///   - Retrieves a row from a table.
+/// - `get_row_with_timeout(&self, table_name: &str, row_id: &str, timeout: Duration) -> Result<Option<Row>, VibraError>`
+///   - Like `get_row`, but fails with `VibraError::Timeout` if the lookup takes longer than `timeout`.
 ///
-/// - `update_row(&self, table_name: &str, row: Row)`
+/// - `prefetch(&self, table_name: &str, ids: &[&str]) -> Result<(), VibraError>`
+///   - Reads and decrypts the given rows into the cache, so upcoming `get_row` calls are hits.
+///
+/// - `get_row_meta(&self, table_name: &str, row_id: &str) -> Result<Option<RowMeta>, VibraError>`
+///   - Retrieves when a row was created and last written, and how many bytes it takes on disk.
+///
+/// - `scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError>`
+///   - Retrieves every row of a table, ordered by row id.
+///
+/// - `scan_table_lenient(&self, table_name: &str) -> Result<Vec<Result<Row, VibraError>>, VibraError>`
+///   - Like `scan_table`, but returns a result per row, so a corrupt row is reported as a
+///     `VibraError::CorruptRow` naming its key instead of failing the whole scan.
+///
+/// - `load_table(&self, table_name: &str) -> Result<HashMap<String, Row>, VibraError>`
+///   - Retrieves every row of a table keyed by row id, for loading lookup tables into memory.
+///
+/// - `scan_paginated(&self, table_name: &str, after: Option<&str>, limit: usize) -> Result<(Vec<Row>, Option<String>), VibraError>`
+///   - Retrieves up to `limit` rows whose id sorts after the `after` cursor, plus the cursor for the
+///     next page (`None` once the table is exhausted). With `encrypt_names`, every page reads all
+///     of the table's keys to sort its ids.
+///
+/// - `scan_columns(&self, table_name: &str, columns: &[&str]) -> Result<Vec<Row>, VibraError>`
+///   - Retrieves every row of a table with only the named columns, ordered by row id. Rows of a
+///     table with sensitive columns are read without decrypting them unless a named column is
+///     sealed in them, and such clear columns aren't authenticated.
+///
+/// - `create_blind_index(&self, table_name: &str, column: &str) -> Result<usize, VibraError>`
+///   - Indexes a column for `find_by`, including the rows already stored, and returns how many
+///     rows hold a value for it. Tables with a schema can only index a declared column.
+///
+/// - `find_by(&self, table_name: &str, column: &str, value: impl Into<Value>) -> Result<Vec<Row>, VibraError>`
+///   - Retrieves the rows whose column equals `value`, ordered by row id, through the column's
+///     blind index: HMACs of its values under a key derived from the master key, maintained by
+///     every write. Text matches ignoring case and surrounding whitespace. Columns the config
+///     encrypts deterministically match exactly by comparing ciphertext, decrypting only the
+///     matching rows. Fails with `VibraError::NotIndexed` for any other column.
+///
+/// - `increment_column(&self, table_name: &str, row_id: &str, column: &str, delta: i64) -> Result<i64, VibraError>`
+///   - Atomically adds `delta` to an integer column and returns the new value.
+///
+/// - `append_to_column(&self, table_name: &str, row_id: &str, column: &str, suffix: &[u8]) -> Result<(), VibraError>`
+///   - Atomically appends to a text or binary column, creating the column if it's absent.
+///
+/// - `transaction<F, T>(&self, tables: &[&str], f: F) -> Result<T, VibraError>`
+///   - Runs `f` as one atomic transaction over rows of `tables`: everything it reads and writes
+///     through its `VibraTransaction` commits together, or nothing does if it returns an error.
+///
+/// - `async fn snapshot(&self) -> Result<VibraSnapshot, VibraError>`
+///   - Captures a consistent, read-only view of the database. Writes made afterwards are not
+///     visible through it, and writes to rows wait while it is being captured.
+///
+/// - `update_row(&self, table_name: &str, row: Row) -> Result<(), VibraError>`
 ///   - Updates a row in a table.
 ///
-/// - `delete_row(&self, table_name: &str, row_id: &str)`
-///   - Deletes a row from a table.
+/// - `delete_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError>`
+///   - Deletes a row from a table, returning the row that was removed, if any.
 ///
-/// - `truncate_table(&self, table_name: &str)`
+/// - `shred_row(&self, table_name: &str, row_id: &str) -> Result<bool, VibraError>`
+///   - Overwrites a row's record with random bytes and flushes before deleting it, returning
+///     whether there was one. Older copies in sled's log may remain; see the README.
+///
+/// - `truncate_table(&self, table_name: &str) -> Result<(), VibraError>`
 ///   - Truncates a table, removing all its rows.
 ///
-/// - `truncate_db(&self)`
+/// - `truncate_tables_with_prefix(&self, prefix: &str) -> Result<usize, VibraError>`
+///   - Truncates every table whose name starts with `prefix`, returning how many tables were
+///     truncated.
+///
+/// - `list_tables(&self) -> Result<Vec<String>, VibraError>`
+///   - Lists the names of all tables in the database.
+///
+/// - `sweep_expired(&self, table_name: &str) -> Result<usize, VibraError>`
+///   - Removes all expired rows from a table, returning how many were removed.
+///
+/// - `start_expiry_sweeper(&self, interval: Duration) -> ExpirySweeper`
+///   - Spawns a background task that sweeps expired rows from every table each `interval`.
+///
+/// - `export_csv(&self, table_name: &str, dest: &Path) -> Result<(), VibraError>`
+///   - Exports a table to a CSV file with an `id` column followed by the union of all rows' columns.
+///
+/// - `import_csv(&self, table_name: &str, src: &Path) -> Result<usize, VibraError>`
+///   - Imports rows from a CSV file (which must have an `id` column), returning how many were inserted.
+///
+/// - `export_table(&self, table_name: &str, dest: &Path) -> Result<(), VibraError>`
+///   - Writes a table's encrypted records, schema, expiries and wrapped data key to a portable
+///     dump file.
+///
+/// - `import_table(&self, table_name: &str, src: &Path) -> Result<usize, VibraError>`
+///   - Loads a dump written by `export_table` into a table, which may have a different name and
+///     is created if missing, returning how many rows were imported. Files that aren't table
+///     dumps are rejected with `VibraError::InvalidDump`.
+///
+/// - `backup_encrypted(&self, dest: impl AsRef<Path>, backup_key: &MasterKey) -> Result<usize, VibraError>`
+///   - Writes every table, with its schema and blind indexes, and every live row to one file
+///     encrypted under `backup_key` instead of the master key, returning how many rows it holds.
+///
+/// - `restore_encrypted(src: impl AsRef<Path>, backup_key: &MasterKey, config: VibraConfig) -> Result<VibraDB, VibraError>`
+///   - Opens a database with no tables with `config` and rebuilds it from a backup, sealing its
+///     rows with the config's master key. The backup is authenticated first, so a wrong key
+///     (`VibraError::InvalidBackupKey`) or a damaged file (`VibraError::InvalidBackup`) writes
+///     nothing.
+///
+/// - `encryption_layers(&self) -> usize`
+///   - Returns the number of encryption layers new writes are encrypted with.
+///
+/// - `cipher(&self) -> CipherSuite`
+///   - Returns the cipher new writes are encrypted with, set by `cipher` in the config. Records
+///     name the cipher they were sealed with, so those written with another still read, and
+///     `rekey` moves them to this one.
+///
+/// - `rekey(&self, new_layers: usize, new_master_key: Option<String>) -> Result<usize, VibraError>`
+///   - Re-encrypts every row under fresh keys with `new_layers` layers, returning how many rows
+///     were rotated. Each row is replaced atomically, so an interrupted rekey leaves every row
+///     readable. Tables from before data keys are given one, and rows written by older versions
+///     are rewritten with keys derived from their table's. With `new_master_key`, written as 64
+///     hex digits or as base64, the database then moves to that key as `rotate_master_key` does.
+///     The layer count and cipher are stored in the database, and used after it is reopened with
+///     a config that doesn't set `encryption_layers` or `cipher`.
+///
+/// - `migrate_sensitive_columns(&self, table_name: &str) -> Result<usize, VibraError>`
+///   - Rewrites the rows of a table stored with other columns sealed or deterministic than the
+///     config now lists as `sensitive` or `deterministic`, e.g. after a column was reclassified,
+///     returning how many were rewritten. Rows written since then are already stored the new way.
+///
+/// - `rotate_master_key(&self, new_key: MasterKey) -> Result<RotationReport, VibraError>`
+///   - Re-wraps every table's data key with `new_key`. Only records from before data keys are
+///     re-encrypted, in batches written atomically along with the progress made. Reports how
+///     many keys were re-wrapped and records rotated, skipped and failed. Reads keep working
+///     while it runs. An interrupted rotation resumes
+///     where it stopped when called again with the same key on the database opened with the old
+///     one; rotating to another key before it is finished fails with `VibraError::InvalidKey`.
+///
+/// - `verify_integrity(&self) -> Result<IntegrityReport, VibraError>`
+///   - Decrypts every row of every table, checking its integrity and that it parses, without
+///     returning any plaintext. Reports how many rows were intact and the key of every corrupt
+///     one with why. Rows are decrypted in parallel, a batch at a time.
+///
+/// - `verify_table_integrity(&self, table_name: &str) -> Result<IntegrityReport, VibraError>`
+///   - Like `verify_integrity`, for the rows of one table.
+///
+/// - `verify_audit_log(&self) -> Result<AuditReport, VibraError>`
+///   - Walks the audit log kept with `audit_log = true`, checking each entry's MAC and its hash
+///     of the entry before, and reports the first broken link.
+///
+/// - `migrate_legacy(&self) -> Result<MigrationReport, VibraError>`
+///   - Rewrites every record still in the legacy format, with its keys inline, in the current one,
+///     in batches written atomically along with the progress made. Reports how many records were
+///     migrated, skipped and failed. Legacy records stay readable, so this can run at any time; an
+///     interrupted migration resumes where it stopped when called again.
+///
+/// - `metrics_snapshot(&self) -> MetricsSnapshot`
+///   - Returns operation counts, cache hits/misses and latency histograms. Only available with
+///     the `metrics` feature.
+///
+/// - `health_check(&self) -> Result<(), VibraError>`
+///   - Writes, reads back and removes a sentinel row, bypassing the cache, then samples every
+///     table: its data key has to unwrap, and its schema and first row by key to decrypt. Other
+///     rows aren't read. Fails if sled is unusable or values don't round-trip with the current
+///     config, with `MissingTableKey` naming a table whose key doesn't unwrap and `CorruptRow`
+///     the key of any other entry that doesn't read.
+///
+/// - `cache_stats(&self) -> CacheStats`
+///   - Returns how many `get_row` calls hit and missed the cache, and how full it is.
+///
+/// - `clear_cache(&self)`
+///   - Drops every cached row, e.g. after sled was modified out-of-band or restored.
+///
+/// - `invalidate(&self, table_name: &str, row_id: &str)`
+///   - Drops a single row from the cache.
+///
+/// - `truncate_db(&self) -> Result<(), VibraError>`
 ///   - Truncates the entire database, removing all data.
 ///
-/// - `delete_db(&self)`
-///   - Deletes the entire database, including its directory.
+/// - `close(self) -> Result<(), VibraError>`
+///   - Flushes and closes the database, releasing its files so it can be reopened. Fails with
+///     `VibraError::InUse` while clones, snapshots or expiry sweepers still hold it open.
+///
+/// - `delete_db(self) -> Result<(), VibraError>`
+///   - Closes the database and deletes its directory. Fails with `VibraError::InUse` while clones,
+///     snapshots or expiry sweepers still hold the database open.
 impl VibraDB {
     // Create a new instance of VibraDB with custom configurations
-    pub fn new(config: VibraConfig) -> VibraDB {
-        let db_path = config.path.as_ref().expect("Config path is None");
-        let db = sled::open(db_path).expect("Failed to open VibraDB");
-        info!("VibraDB initialized at {:?}", config.path);
-        let cache = LruCache::new(std::num::NonZero::new(config.cache_size.expect("Cache size is None")).unwrap());
-        let lpath = config.path.clone().expect("Config path is None") + "/";
-        let rpath = ".gitignore".to_string();
-        let path = lpath + &rpath;
-        fs::write(path, b"*\n").expect("Failed to write .gitignore");
-        VibraDB {
+    pub fn new(config: VibraConfig) -> Result<VibraDB, VibraError> {
+        let master_key = match Self::check_config(&config) {
+            #[cfg(feature = "keyring")]
+            Err(VibraError::KeyringEntryMissing { .. }) => {
+                return Self::new_with_keyring_key(config)
+            }
+            resolved => resolved?,
+        };
+        let db_path = config.path.as_deref().ok_or(VibraError::MissingConfig("path"))?;
+        let db_path = normalize_path(db_path);
+        if let Some(parent) = db_path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to create directory {}: {}", parent.display(), err),
+                )
+            })?;
+        }
+        let db = Self::open_sled(&db_path).map_err(|source| VibraError::Open {
+            path: db_path.clone(),
+            source,
+        })?;
+        info!("VibraDB initialized at {:?}", db_path);
+        Self::write_gitignore(&db_path, config.write_gitignore);
+        Self::from_sled(db, config, master_key)
+    }
+
+    // Open sled, retrying for a moment while its lock is taken. Sled's background threads can
+    // hold the lock briefly after the last handle to a database is dropped, so reopening it right
+    // after `close` would otherwise fail now and then.
+    fn open_sled(path: &Path) -> Result<Db, sled::Error> {
+        let mut attempts = 1;
+        loop {
+            match sled::open(path) {
+                Err(sled::Error::Io(err))
+                    if attempts < LOCK_RETRIES
+                        && err.to_string().contains("could not acquire lock") =>
+                {
+                    attempts += 1;
+                    std::thread::sleep(LOCK_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Keep the database directory out of git. `None` leaves a user's own `.gitignore` alone,
+    // `Some(true)` overwrites it. The database is usable without one, so failures are only logged.
+    fn write_gitignore(db_path: &Path, write: Option<bool>) {
+        let gitignore = db_path.join(".gitignore");
+        if write.unwrap_or_else(|| !gitignore.exists()) {
+            if let Err(err) = fs::write(&gitignore, GITIGNORE) {
+                warn!("Failed to write {:?}: {}", gitignore, err);
+            }
+        }
+    }
+
+    // Wrap an already open sled database, e.g. one opened with a tuned sled::Config or shared
+    // with other code. `config.path` is optional and only used by delete_db.
+    pub fn with_sled(db: Db, config: VibraConfig) -> Result<VibraDB, VibraError> {
+        let master_key = Self::check_config(&config)?;
+        Self::from_sled(db, config, master_key)
+    }
+
+    // Write a new random master key to `path` as 64 hex digits. The file must not exist yet, so a
+    // key that may still be needed is never overwritten; on unix only its owner can read it.
+    pub fn generate_key_file(path: impl AsRef<Path>) -> Result<(), VibraError> {
+        Self::write_key_file(path.as_ref(), &MasterKey::generate())?;
+        info!("Wrote a new master key to {:?}", path.as_ref());
+        Ok(())
+    }
+
+    // Write a master key to a new file at `path`, readable only by its owner on unix
+    fn write_key_file(path: &Path, key: &MasterKey) -> Result<(), VibraError> {
+        let key_file_error = |source| VibraError::KeyFile {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path).map_err(key_file_error)?;
+        writeln!(file, "{}", key.to_hex().as_str()).map_err(key_file_error)?;
+        file.sync_all().map_err(key_file_error)
+    }
+
+    // Open a database whose master key is kept in its directory, locked with a passphrase. A new
+    // database gets the configured master key, or a random one if none is configured; a database
+    // that already has tables but no lock needs its key configured, so none is made up for it.
+    pub fn open_with_passphrase(
+        config: VibraConfig,
+        passphrase: &str,
+    ) -> Result<VibraDB, VibraError> {
+        if config.is_strict() {
+            return Err(VibraError::InvalidConfig(
+                "compliance_mode = \"strict\" needs the master key supplied from outside, not \
+                 unlocked with a passphrase"
+                    .to_string(),
+            ));
+        }
+        let db_path = config.path.as_deref().ok_or(VibraError::MissingConfig("path"))?;
+        let lock_path = normalize_path(db_path).join(passphrase::LOCK_FILE);
+        if let Some(lock) = PassphraseLock::load(&lock_path)? {
+            let master_key = lock.unlock(passphrase, &lock_path)?;
+            return Self::new(config.with_master_key(master_key));
+        }
+        let (master_key, generated) = match config.resolve_master_key() {
+            Err(VibraError::MissingKey) => (MasterKey::generate(), true),
+            resolved => (resolved?, false),
+        };
+        let db = Self::new(config.with_master_key(master_key))?;
+        if generated && !db.tables.is_empty() {
+            return Err(VibraError::MissingKey);
+        }
+        db.lock_master_key(passphrase, PassphraseParams::default())?;
+        info!("Locked the master key with a passphrase");
+        Ok(db)
+    }
+
+    // Lock the master key under a new passphrase or stronger Argon2id parameters. Only the lock
+    // file changes; the master key, and so every row, stays as it is.
+    pub async fn set_passphrase(
+        &self,
+        passphrase: &str,
+        params: PassphraseParams,
+    ) -> Result<(), VibraError> {
+        let this = self.clone();
+        let passphrase = passphrase.to_string();
+        task::spawn_blocking(move || this.lock_master_key(&passphrase, params))
+            .await
+            .unwrap()?;
+        info!("Changed the passphrase");
+        Ok(())
+    }
+
+    // Write the master key to the lock file, encrypted under a passphrase
+    fn lock_master_key(
+        &self,
+        passphrase: &str,
+        params: PassphraseParams,
+    ) -> Result<(), VibraError> {
+        let path = self.path.as_ref().ok_or(VibraError::MissingConfig("path"))?;
+        PassphraseLock::seal(&self.master_key(), passphrase, params)?
+            .save(&path.join(passphrase::LOCK_FILE))
+    }
+
+    // Check that a config has everything VibraDB needs besides a path, returning its master key
+    fn check_config(config: &VibraConfig) -> Result<MasterKey, VibraError> {
+        config
+            .validate()
+            .map_err(|e| VibraError::InvalidConfig(e.to_string()))?;
+        config.cache_size.ok_or(VibraError::MissingConfig("cache_size"))?;
+        Self::check_layers(config.encryption_layers.unwrap_or(Self::default_layers(config)))?;
+        config.resolve_master_key()
+    }
+
+    // Build a VibraDB around an open sled database and a checked config
+    fn from_sled(
+        db: Db,
+        config: VibraConfig,
+        master_key: MasterKey,
+    ) -> Result<VibraDB, VibraError> {
+        let cache_size = config
+            .cache_size
+            .and_then(std::num::NonZero::new)
+            .ok_or(VibraError::MissingConfig("cache_size"))?;
+        let shards = std::num::NonZero::new(config.cache_shards.unwrap_or(CACHE_SHARDS))
+            .ok_or(VibraError::MissingConfig("cache_shards"))?;
+        let cache = ShardedCache::new(cache_size, config.cache_bytes, shards);
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let schema = db.open_tree(SCHEMA_TREE)?;
+        let tables = db.open_tree(TABLES_TREE)?;
+        let meta = db.open_tree(META_TREE)?;
+        let (layers, cipher) = Self::load_encryption_settings(&meta, &config)?;
+        let index = db.open_tree(INDEX_TREE)?;
+        let audit = AuditLog::open(&db, config.audit_log)?;
+        Self::migrate_table_markers(&db, &tables)?;
+        let rng = match config.seed {
+            Some(seed) => KeyRng::Seeded(Box::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+            None => KeyRng::Os,
+        };
+        let names = Self::load_names(&db, &tables, &meta, &master_key, &config, &rng)?;
+        let nonces = NonceSequence::open(&meta, &rng, config.strict_nonces)?;
+        // The per-table maps are keyed by the name each table is stored under
+        let stored_table =
+            |table_name: &str| names::stored_table(names.as_ref(), table_name).into_owned();
+        let table_keys = Self::load_table_keys(&tables, &master_key)?;
+        let unencrypted: HashSet<String> = config
+            .tables
+            .keys()
+            .filter(|table_name| config.is_plaintext_table(table_name))
+            .map(|table_name| stored_table(table_name))
+            .collect();
+        let plaintext_tables = Self::load_plaintext_tables(&tables, &config, names.as_ref())?;
+        let blind_indexes = Self::load_blind_indexes(&tables)?;
+        let sensitive = config
+            .tables
+            .keys()
+            .filter_map(|table_name| {
+                let columns = config.sensitive_columns(table_name)?;
+                Some((stored_table(table_name), columns.iter().cloned().collect()))
+            })
+            .collect();
+        let cache_plaintext_tables = config
+            .tables
+            .keys()
+            .map(|table_name| (stored_table(table_name), config.caches_plaintext(table_name)))
+            .collect();
+        let deterministic = config
+            .tables
+            .keys()
+            .filter_map(|table_name| {
+                let columns = config.deterministic_columns(table_name)?;
+                Some((stored_table(table_name), columns.iter().cloned().collect()))
+            })
+            .collect();
+        let vibra_db = VibraDB {
             db: Arc::new(db),
-            cache: Arc::new(RwLock::new(cache)),
-            path: config.path.expect("Config path is None"),
+            expiry,
+            schema,
+            tables,
+            meta,
+            index,
+            audit: Arc::new(audit),
+            cache: Arc::new(cache),
+            cache_plaintext: config.cache_plaintext.unwrap_or(true),
+            cache_plaintext_tables: Arc::new(cache_plaintext_tables),
+            path: config.path.as_deref().map(normalize_path),
+            rng: Arc::new(rng),
+            nonces: Arc::new(nonces),
+            master_keys: Arc::new(RwLock::new(KeyRing {
+                current: Arc::new(master_key),
+                previous: None,
+            })),
+            table_keys: Arc::new(RwLock::new(table_keys)),
+            plaintext_tables: Arc::new(RwLock::new(plaintext_tables)),
+            unencrypted: Arc::new(unencrypted),
+            sensitive: Arc::new(sensitive),
+            deterministic: Arc::new(deterministic),
+            blind_indexes: Arc::new(RwLock::new(blind_indexes)),
+            names: names.map(Arc::new),
+            rotation: Arc::new(Mutex::new(())),
+            writers: Arc::new(RwLock::new(())),
+            shredding: Arc::new(Mutex::new(HashSet::new())),
+            layers: Arc::new(AtomicUsize::new(layers)),
+            cipher,
+            cache_counters: Arc::new(CacheCounters::default()),
+            log_operations: config.log_operations,
+            upgrade_on_read: config.upgrade_on_read,
+            strict: config.is_strict(),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::default()),
+        };
+        if vibra_db.strict {
+            vibra_db.require_conformant()?;
+        }
+        vibra_db.warn_unsealed_tables(&config)?;
+        Ok(vibra_db)
+    }
+
+    // Move table markers from before the tables tree, bare table names stored among the rows,
+    // into it. Each is copied before it is removed, so an interrupted migration just resumes on
+    // the next open.
+    fn migrate_table_markers(db: &Db, tables: &sled::Tree) -> Result<(), VibraError> {
+        let mut migrated = 0;
+        for key in db.iter().keys() {
+            let key = key?;
+            if key.contains(&b'/') {
+                continue;
+            }
+            if !tables.contains_key(&key)? {
+                tables.insert(&key, TableMeta::new().encode()?)?;
+            }
+            db.remove(&key)?;
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!("Migrated {} table markers to the tables tree", migrated);
+        }
+        Ok(())
+    }
+
+    // Unwrap the data key of every table that has one. Keys the master key doesn't unwrap are left
+    // out, so their tables fail to decrypt, as with any wrong key, instead of the database failing
+    // to open.
+    fn load_table_keys(
+        tables: &sled::Tree,
+        master_key: &MasterKey,
+    ) -> Result<HashMap<String, Arc<DataKey>>, VibraError> {
+        let mut table_keys = HashMap::new();
+        let mut failed = 0;
+        for entry in tables.iter() {
+            let (name, stored) = entry?;
+            let meta = TableMeta::decode(&stored)?;
+            if meta.data_key.is_none() {
+                continue;
+            }
+            let table_name = String::from_utf8_lossy(&name).into_owned();
+            match meta.data_key(master_key, &table_name) {
+                Some(data_key) => {
+                    table_keys.insert(table_name, Arc::new(data_key));
+                }
+                None => failed += 1,
+            }
+        }
+        if failed > 0 {
+            warn!("The master key doesn't unwrap the data keys of {} tables", failed);
+        }
+        Ok(table_keys)
+    }
+
+    // Find the tables created without encryption, warning about those the config now wants created
+    // the other way, which only a new table would be
+    fn load_plaintext_tables(
+        tables: &sled::Tree,
+        config: &VibraConfig,
+        names: Option<&Names>,
+    ) -> Result<HashSet<String>, VibraError> {
+        let mut plaintext_tables = HashSet::new();
+        for entry in tables.iter() {
+            let (name, stored) = entry?;
+            let meta = TableMeta::decode(&stored)?;
+            let table_name = names::table_name_of(names, &name, &meta)?;
+            let configured = config.tables.get(&table_name).and_then(|table| table.encrypted);
+            if configured.is_some_and(|encrypted| encrypted == meta.plaintext) {
+                warn!(
+                    "Table {} was created {}; delete and recreate it to change that",
+                    table_name,
+                    if meta.plaintext { "unencrypted" } else { "encrypted" }
+                );
+            }
+            if meta.plaintext {
+                plaintext_tables.insert(String::from_utf8_lossy(&name).into_owned());
+            }
+        }
+        Ok(plaintext_tables)
+    }
+
+    // Give every table created before data keys one, wrapped by the current master key, returning
+    // how many were given one. Their records move to it as they are rewritten.
+    fn assign_table_keys(&self) -> Result<usize, VibraError> {
+        let mut assigned = 0;
+        for entry in self.tables.iter() {
+            let (name, stored) = entry?;
+            let mut meta = TableMeta::decode(&stored)?;
+            if meta.data_key.is_some() || meta.plaintext {
+                continue;
+            }
+            let table_name = String::from_utf8_lossy(&name).into_owned();
+            let data_key = self.with_rng(DataKey::generate);
+            meta.set_data_key(&self.master_key(), &table_name, &data_key);
+            // Skipped if the table was deleted or given a key since it was read
+            if self.tables.compare_and_swap(&name, Some(stored), Some(meta.encode()?))?.is_ok() {
+                self.table_keys_mut().insert(table_name, Arc::new(data_key));
+                assigned += 1;
+            }
+        }
+        if assigned > 0 {
+            info!("Gave {} tables a data key", assigned);
+        }
+        Ok(assigned)
+    }
+
+    // The layer count and cipher new writes use: those the config sets, or else those the database
+    // was last rekeyed to, or else the defaults. Strict mode goes by its own defaults.
+    fn load_encryption_settings(
+        meta: &sled::Tree,
+        config: &VibraConfig,
+    ) -> Result<(usize, CipherSuite), VibraError> {
+        let stored = match meta.get(ENCRYPTION_KEY)? {
+            Some(stored) => Some(serde_json::from_slice::<EncryptionSettings>(&stored)?),
+            None => None,
+        };
+        let stored = stored.filter(|_| !config.is_strict());
+        let layers = config
+            .encryption_layers
+            .or(stored.as_ref().map(|s| s.layers))
+            .unwrap_or(Self::default_layers(config));
+        Self::check_layers(layers)?;
+        let cipher = config.cipher.or(stored.as_ref().map(|s| s.cipher)).unwrap_or_default();
+        if let Some(stored) = stored.filter(|s| (s.layers, s.cipher) != (layers, cipher)) {
+            warn!(
+                "The database was rekeyed to {} layers of {}, but new writes use the config's {} \
+                 layers of {}",
+                stored.layers, stored.cipher, layers, cipher
+            );
+        }
+        Ok((layers, cipher))
+    }
+
+    // The layer count new writes use when the config doesn't set one
+    fn default_layers(config: &VibraConfig) -> usize {
+        if config.is_strict() {
+            1
+        } else {
+            AES_LAYERS
+        }
+    }
+
+    // Check that a layer count is within the supported range
+    fn check_layers(layers: usize) -> Result<(), VibraError> {
+        if layers == 0 || layers > MAX_ENCRYPTION_LAYERS {
+            return Err(VibraError::InvalidConfig(format!(
+                "encryption_layers must be between 1 and {}, got {}",
+                MAX_ENCRYPTION_LAYERS, layers
+            )));
+        }
+        Ok(())
+    }
+
+    fn generate_key(rng: &mut impl RngCore) -> SecretBytes {
+        let mut key = SecretBytes::zeroed(32);
+        rng.fill_bytes(&mut key);
+        key
+    }
+
+    // Run `f` with the database's key RNG: the OS RNG, or a seeded one if configured
+    fn with_rng<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        self.rng.with(f)
+    }
+
+    // Encrypt one chunk with a layer of `suite` per key, each layer wrapping the previous one and
+    // authenticating `aad`
+    fn encrypt_chunk(
+        suite: CipherSuite,
+        chunk: &[u8],
+        keys: &[u8],
+        nonces: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VibraError> {
+        match suite {
+            CipherSuite::Aes256Gcm => Self::encrypt_layers::<Aes256Gcm>(chunk, keys, nonces, aad),
+            CipherSuite::ChaCha20Poly1305 => {
+                Self::encrypt_layers::<ChaCha20Poly1305>(chunk, keys, nonces, aad)
+            }
+        }
+    }
+
+    // Decrypt one chunk with a layer of `suite` per key, peeling the outermost layer first.
+    // Records from before associated data was used decrypt with an empty `aad`.
+    fn decrypt_chunk(
+        suite: CipherSuite,
+        chunk: &[u8],
+        keys: &[u8],
+        nonces: &[u8],
+        aad: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, VibraError> {
+        match suite {
+            CipherSuite::Aes256Gcm => Self::decrypt_layers::<Aes256Gcm>(chunk, keys, nonces, aad),
+            CipherSuite::ChaCha20Poly1305 => {
+                Self::decrypt_layers::<ChaCha20Poly1305>(chunk, keys, nonces, aad)
+            }
+        }
+    }
+
+    // Both suites take 32-byte keys and 12-byte nonces. Every buffer but the finished ciphertext
+    // is wiped when it is replaced, since the first holds the plaintext.
+    fn encrypt_layers<C: Aead + KeyInit>(
+        chunk: &[u8],
+        keys: &[u8],
+        nonces: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, VibraError> {
+        let mut data = Zeroizing::new(chunk.to_vec());
+        for i in 0..keys.len() / 32 {
+            let cipher = C::new(GenericArray::from_slice(&keys[i * 32..(i + 1) * 32]));
+            let n = GenericArray::from_slice(&nonces[i * 12..(i + 1) * 12]);
+            // Both only reject plaintexts of 64 GiB or more, far beyond a chunk plus its tags
+            data = Zeroizing::new(
+                cipher
+                    .encrypt(n, Payload { msg: &data, aad })
+                    .map_err(|_| VibraError::Encryption { layer: i })?,
+            );
+        }
+        Ok(std::mem::take(&mut *data))
+    }
+
+    fn decrypt_layers<C: Aead + KeyInit>(
+        chunk: &[u8],
+        keys: &[u8],
+        nonces: &[u8],
+        aad: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, VibraError> {
+        let mut data = Zeroizing::new(chunk.to_vec());
+        for i in (0..keys.len() / 32).rev() {
+            let cipher = C::new(GenericArray::from_slice(&keys[i * 32..(i + 1) * 32]));
+            let n = GenericArray::from_slice(&nonces[i * 12..(i + 1) * 12]);
+            data = Zeroizing::new(
+                cipher
+                    .decrypt(n, Payload { msg: &data, aad })
+                    .map_err(|_| VibraError::Decryption { layer: i })?,
+            );
+        }
+        Ok(data)
+    }
+
+    // Encrypt a value into its stored form.
+    //
+    // The encrypted envelope is the SHA-256 of the value followed by the value itself. It is split
+    // into CHUNK_SIZE chunks which go through the layer stack independently, so only a chunk at a
+    // time is copied per layer. The stored form is a `RecordHeader` followed by
+    // [key material][nonces per chunk][chunks], where the key material is a salt the layer keys
+    // are derived from with `context` and the data key of the table `context` belongs to, or the
+    // master key for records outside any table, after a byte saying which. Every layer
+    // authenticates `context` as associated data.
+    // Both timestamps are set to now; writers that replace a record carry its creation time over.
+    fn encrypt_value(&self, context: &str, value: &[u8]) -> Result<Vec<u8>, VibraError> {
+        self.encrypt_with_layers(context, value, self.encryption_layers())
+    }
+
+    // Encrypt a value into its stored form with an explicit number of layers. Records of tables
+    // created without encryption are stored as is instead, and rows of tables with sensitive
+    // columns with only those sealed.
+    fn encrypt_with_layers(
+        &self,
+        context: &str,
+        value: &[u8],
+        layers: usize,
+    ) -> Result<Vec<u8>, VibraError> {
+        let plaintext = self.is_plaintext(context);
+        self.encrypt_for(context, value, layers, plaintext, self.table_key(context))
+    }
+
+    // Encrypt a value as encrypt_with_layers does, for a table that is unencrypted if
+    // `plaintext` and has `data_key`, whether or not the table is in place yet
+    fn encrypt_for(
+        &self,
+        context: &str,
+        value: &[u8],
+        layers: usize,
+        plaintext: bool,
+        data_key: Option<Arc<DataKey>>,
+    ) -> Result<Vec<u8>, VibraError> {
+        if self.strict && (layers != 1 || plaintext) {
+            return Err(VibraError::NonConformant {
+                key: self.loggable(context).to_string(),
+                reason: match layers {
+                    1 => "its table is unencrypted".to_string(),
+                    _ => format!("it would have {} encryption layers", layers),
+                },
+            });
+        }
+        if plaintext {
+            return Ok(record::encode_plaintext(value, now_millis()));
+        }
+        let key = match data_key {
+            Some(data_key) => match self.seal_columns(context, value, layers, &data_key)? {
+                Some(stored) => return Ok(stored),
+                None => RecordKey::Table(data_key),
+            },
+            None => RecordKey::Master(self.master_key()),
+        };
+        self.seal(context, value, layers, Some(&key))
+    }
+
+    // The master key new records are sealed with
+    fn master_key(&self) -> Arc<MasterKey> {
+        self.key_ring().current.clone()
+    }
+
+    // What a record for `context` is sealed with: its table's data key, or the master key for
+    // records outside any table and tables without one
+    fn record_key(&self, context: &str) -> RecordKey {
+        match self.table_key(context) {
+            Some(data_key) => RecordKey::Table(data_key),
+            None => RecordKey::Master(self.master_key()),
+        }
+    }
+
+    // The data key of the table `context` belongs to, if it has one
+    fn table_key(&self, context: &str) -> Option<Arc<DataKey>> {
+        let table_name = Self::context_table(context)?;
+        let table_keys = self.table_keys.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        table_keys.get(table_name).cloned()
+    }
+
+    // Whether `context` belongs to a table created without encryption
+    fn is_plaintext(&self, context: &str) -> bool {
+        Self::context_table(context).is_some_and(|table_name| {
+            let plaintext_tables = self.plaintext_tables.read().unwrap_or_else(|p| p.into_inner());
+            plaintext_tables.contains(table_name)
+        })
+    }
+
+    // Whether the row stored under `key` is cached decrypted, rather than as its record
+    fn caches_plaintext(&self, key: &str) -> bool {
+        Self::context_table(key)
+            .and_then(|table_name| self.cache_plaintext_tables.get(table_name).copied())
+            .unwrap_or(self.cache_plaintext)
+    }
+
+    // Cache a row that was just written. Rows cached as their records are only evicted, to be
+    // cached on their next read: the record is only final once stored.
+    fn cache_written(&self, key: String, row: Arc<Row>) {
+        if self.caches_plaintext(&key) {
+            self.cache.put(key, row);
+        } else {
+            self.cache.pop(&key);
+        }
+    }
+
+    // Entries are only ever inserted or removed whole, so a panic elsewhere can't leave one half
+    // written
+    fn table_keys_mut(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, Arc<DataKey>>> {
+        self.table_keys.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The table a record's context belongs to: a row's table, or the table a schema describes.
+    // Vibra's own records, such as the health check's sentinel, belong to none.
+    fn context_table(context: &str) -> Option<&str> {
+        let schema_of = context.strip_prefix(SCHEMA_TREE).and_then(|rest| rest.strip_prefix('/'));
+        match schema_of {
+            Some(table_name) => Some(table_name),
+            None if context.starts_with(RESERVED_PREFIX) => None,
+            None => context.split_once('/').map(|(table_name, _)| table_name),
+        }
+    }
+
+    // Held while committing a change to which rows exist or what they hold, so a snapshot sees
+    // all of it or none. Rewrites that only re-encrypt rows don't need it. Never take it twice on
+    // one thread: a waiting snapshot blocks the second attempt.
+    fn writing(&self) -> RwLockReadGuard<'_, ()> {
+        self.writers.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The ring is only ever swapped whole, so a panic elsewhere can't leave it half updated
+    fn key_ring(&self) -> RwLockReadGuard<'_, KeyRing> {
+        self.master_keys.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Encrypt a value with keys derived from `key`, or with random keys stored in the record as
+    // versions before master keys did. Only tests still write the latter.
+    fn seal(
+        &self,
+        context: &str,
+        value: &[u8],
+        layers: usize,
+        key: Option<&RecordKey>,
+    ) -> Result<Vec<u8>, VibraError> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        // A copy of the plaintext, so it is wiped once sealed
+        let mut envelope = Zeroizing::new(Vec::with_capacity(32 + value.len()));
+        envelope.extend_from_slice(&Sha256::digest(value));
+        envelope.extend_from_slice(value);
+        let chunks: Vec<&[u8]> = envelope.chunks(CHUNK_SIZE).collect();
+
+        // Draw all key material up front so a seeded RNG is consumed in a fixed order
+        let derived = key.is_some();
+        let key_material = self.with_rng(|mut rng| {
+            let mut key_material = Vec::with_capacity(layers * 32);
+            if derived {
+                let mut salt = [0u8; keys::SALT_LEN];
+                rng.fill_bytes(&mut salt);
+                key_material.extend_from_slice(&salt);
+            } else {
+                for _ in 0..layers {
+                    key_material.extend_from_slice(&Self::generate_key(&mut rng));
+                }
+            }
+            key_material
+        });
+        let nonces = self.nonces.take(chunks.len() * layers)?;
+        let (keys, key_source) = match key {
+            Some(RecordKey::Master(master_key)) => (
+                master_key.layer_keys(&key_material, context, layers),
+                KeySource::Master,
+            ),
+            Some(RecordKey::Table(data_key)) => (
+                data_key.layer_keys(&key_material, context, layers),
+                KeySource::Table,
+            ),
+            // Stored in the record anyway
+            None => (SecretBytes::new(key_material.clone()), KeySource::Inline),
+        };
+        // Inline keys are only written to test reading old records, so they stay unbound
+        let aad = if derived { context.as_bytes() } else { &[] };
+
+        let encrypted_chunks: Vec<Vec<u8>> = chunks
+            .par_iter()
+            .enumerate()
+            .map(|(c, chunk)| {
+                let chunk_nonces = &nonces[c * layers * 12..(c + 1) * layers * 12];
+                Self::encrypt_chunk(self.cipher, chunk, &keys, chunk_nonces, aad)
+            })
+            .collect::<Result<_, _>>()?;
+        let key_material = if derived {
+            [&[key_source.tag()], key_material.as_slice()].concat()
+        } else {
+            key_material
+        };
+
+        let now = now_millis();
+        let header = RecordHeader {
+            version: if derived {
+                record::VERSION_BOUND
+            } else {
+                record::VERSION_INLINE_KEYS
+            },
+            cipher: self.cipher.id(),
+            flags: record::FLAG_COUNTER_NONCES,
+            value_len: value.len() as u64,
+            layers: layers as u16,
+            created_at: now,
+            updated_at: now,
+            chunk_lens: encrypted_chunks.iter().map(|c| c.len() as u32).collect(),
+        };
+        let stored = record::encode_record(&header, &key_material, &nonces, &encrypted_chunks);
+        #[cfg(feature = "metrics")]
+        self.metrics.record_encryption(started.elapsed());
+        Ok(stored)
+    }
+
+    // The layer keys of a record: its key material as is, or derived from the table's data key or
+    // the master key, as the record says, when the key material is a salt
+    fn layer_keys(
+        master_key: &MasterKey,
+        data_key: Option<&DataKey>,
+        record: &record::Record,
+        context: &str,
+    ) -> Result<SecretBytes, VibraError> {
+        let layers = record.header.layers as usize;
+        match record.key_source {
+            KeySource::Table => {
+                let data_key = data_key.ok_or_else(|| {
+                    let table_name = Self::context_table(context).unwrap_or(context);
+                    VibraError::MissingTableKey(table_name.to_string())
+                })?;
+                Ok(data_key.layer_keys(record.key_material, context, layers))
+            }
+            KeySource::Master => Ok(master_key.layer_keys(record.key_material, context, layers)),
+            KeySource::Inline => Ok(SecretBytes::new(record.key_material.to_vec())),
+        }
+    }
+
+    // Decrypt a value from its stored form as text
+    fn decrypt_value(&self, context: &str, stored: &[u8]) -> Result<String, VibraError> {
+        String::from_utf8(self.decrypt_bytes(context, stored)?).map_err(|_| VibraError::InvalidUtf8)
+    }
+
+    // Decrypt a value from its stored form, reassembling its chunks and verifying its checksum.
+    // The record header says how, so records written with any layer count or format version
+    // (including legacy ones without a version) decrypt alike. `context` must be the one the
+    // value was encrypted with, or a record with derived keys fails to decrypt and, from version
+    // 4, to authenticate. While a master key rotation is under way, records the new key doesn't
+    // decrypt are tried with the old one.
+    // Tables created without encryption store values as is, and only their records are read that
+    // way, so a plaintext record can't pass for an encrypted table's. Rows with sealed columns
+    // read in any table, since their sealed record vouches for the rest.
+    fn decrypt_bytes(&self, context: &str, stored: &[u8]) -> Result<Vec<u8>, VibraError> {
+        self.check_conformance(context, stored)?;
+        if self.is_plaintext(context) {
+            if let Some(value) = record::plaintext_value(stored)? {
+                return Ok(value.to_vec());
+            }
+        }
+        if record::holds_sealed_columns(stored) {
+            if let Some(value) = record::sealed_columns_value(stored)? {
+                let data_key = self.table_key(context);
+                return self.open_sealed_columns(context, value, data_key.as_deref());
+            }
+        }
+        self.decrypt_for(context, stored, self.table_key(context).as_deref())
+    }
+
+    // Decrypt a value whose table's data key is `data_key`, rather than the one the context names
+    fn decrypt_for(
+        &self,
+        context: &str,
+        stored: &[u8],
+        data_key: Option<&DataKey>,
+    ) -> Result<Vec<u8>, VibraError> {
+        let (current, previous) = {
+            let ring = self.key_ring();
+            (ring.current.clone(), ring.previous.clone())
+        };
+        match (Self::decrypt_with(&current, data_key, context, stored), previous) {
+            (Err(VibraError::Decryption { .. }), Some(previous)) => {
+                Self::decrypt_with(&previous, data_key, context, stored)
+            }
+            (result, _) => result,
+        }
+    }
+
+    // Decrypt a value with one particular master key and table data key
+    fn decrypt_with(
+        master_key: &MasterKey,
+        data_key: Option<&DataKey>,
+        context: &str,
+        stored: &[u8],
+    ) -> Result<Vec<u8>, VibraError> {
+        let record = record::decode_record(stored)?;
+        if record.header.is_plaintext() {
+            return Err(VibraError::MalformedRecord(
+                "unencrypted record outside an unencrypted table".to_string(),
+            ));
+        }
+        let layers = record.header.layers as usize;
+        let value_len = record.header.value_len as usize;
+        let keys = Self::layer_keys(master_key, data_key, &record, context)?;
+        let suite = record.header.cipher_suite();
+        let aad = if record.header.binds_context() {
+            context.as_bytes()
+        } else {
+            &[]
+        };
+        let decrypted_chunks = record
+            .chunks
+            .into_par_iter()
+            .enumerate()
+            .map(|(c, chunk)| {
+                let chunk_nonces = &record.nonces[c * layers * 12..(c + 1) * layers * 12];
+                Self::decrypt_chunk(suite, chunk, &keys, chunk_nonces, aad)
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+
+        // Only the verified value outlives this; every other copy of the plaintext is wiped
+        let mut envelope = Zeroizing::new(Vec::with_capacity(32 + value_len));
+        for chunk in decrypted_chunks {
+            envelope.extend_from_slice(&chunk);
+        }
+        if envelope.len() < 32 {
+            return Err(VibraError::IntegrityCheckFailed);
+        }
+        let mut value = Zeroizing::new(envelope.split_off(32));
+        value.truncate(value_len);
+        if value.len() != value_len || Sha256::digest(&*value).as_slice() != envelope.as_slice() {
+            return Err(VibraError::IntegrityCheckFailed);
+        }
+
+        Ok(std::mem::take(&mut *value))
+    }
+
+    // Copy a timestamp from a stored record's header into a freshly encrypted record's, which sit
+    // outside the ciphertext, so the new record can keep e.g. the creation time of the one it
+    // replaces
+    fn copy_record_time(dest: &mut [u8], src: &[u8], field: Timestamp) {
+        if let Ok((header, _)) = RecordHeader::decode(src) {
+            record::set_time(dest, field, header.time(field));
+        }
+    }
+
+    // Check that a table name or row id can be used as part of a key.
+    //
+    // Rows are stored under `table/id`, so a `/` in either part would make keys ambiguous
+    // (table `a` row `b/c` vs table `a/b` row `c`) and break prefix scans.
+    fn validate_name(name: &str) -> Result<(), VibraError> {
+        if name.is_empty() {
+            return Err(VibraError::InvalidName {
+                name: name.to_string(),
+                reason: "names must not be empty".to_string(),
+            });
+        }
+        if name.contains('/') {
+            return Err(VibraError::InvalidName {
+                name: name.to_string(),
+                reason: "names must not contain '/'".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // Check that a table name is valid and not reserved for vibra's own keys and trees
+    fn validate_table_name(name: &str) -> Result<(), VibraError> {
+        Self::validate_name(name)?;
+        if name.starts_with(RESERVED_PREFIX) {
+            return Err(VibraError::InvalidName {
+                name: name.to_string(),
+                reason: format!("table names starting with {:?} are reserved", RESERVED_PREFIX),
+            });
+        }
+        Ok(())
+    }
+
+    // Fail with TableNotFound, aborting the transaction, unless the table exists as of `tables`
+    fn check_table_in(
+        &self,
+        tables: &TransactionalTree,
+        table_name: &str,
+    ) -> Result<(), ConflictableTransactionError<VibraError>> {
+        if tables.get(self.stored_table(table_name).as_bytes())?.is_none() {
+            return Err(VibraError::TableNotFound(table_name.to_string()).into());
+        }
+        Ok(())
+    }
+
+    // Build the key a row is stored under
+    fn row_key(&self, table_name: &str, row_id: &str) -> Result<String, VibraError> {
+        Self::validate_table_name(table_name)?;
+        Self::validate_name(row_id)?;
+        let stored_table = self.stored_table(table_name);
+        let stored_id = self.stored_id(&stored_table, row_id)?;
+        Ok(format!("{}/{}", stored_table, stored_id))
+    }
+
+    // What a table's schema record is encrypted for, distinct from any row key, given the name
+    // the table is stored under
+    fn schema_context(stored_table: &str) -> String {
+        format!("{}/{}", SCHEMA_TREE, stored_table)
+    }
+
+    // Create a new table, optionally with a schema that its rows must conform to
+    //
+    // Each table gets a random data key, stored wrapped by the master key with the table's entry,
+    // that its rows and schema are encrypted with, unless the config has it created without
+    // encryption. Creating a table that already exists is a no-op that leaves its schema alone,
+    // except that an encrypted table from before data keys is given one; the returned bool says
+    // whether a new table was created.
+    pub async fn create_table(
+        &self,
+        table_name: &str,
+        schema: Option<Vec<Column>>,
+    ) -> Result<bool, VibraError> {
+        let table = self.new_table(table_name, schema)?;
+        let tables_tree = self.tables.clone();
+        let schema_tree = self.schema.clone();
+        let (created, keyed, table) = task::spawn_blocking(move || {
+            let name = table.stored_table.as_bytes();
+            // The table's entry and schema are written in one transaction, so concurrent creators
+            // agree on a single winner and no one sees the table without its schema
+            let (created, keyed) = (&tables_tree, &schema_tree)
+                .transaction(|(tables, schemas)| {
+                    if let Some(stored) = tables.get(name)? {
+                        let existing = TableMeta::decode(&stored)?;
+                        let meta = &table.meta;
+                        if existing.data_key.is_some() || existing.plaintext || meta.plaintext {
+                            return Ok((false, false));
+                        }
+                        let upgraded = TableMeta {
+                            created_at: existing.created_at,
+                            blind_indexes: existing.blind_indexes,
+                            ..meta.clone()
+                        };
+                        tables.insert(name, upgraded.encode()?)?;
+                        return Ok((false, true));
+                    }
+                    table.store_in(tables, schemas)?;
+                    Ok::<_, ConflictableTransactionError<VibraError>>((true, !table.meta.plaintext))
+                })?;
+            Ok::<_, VibraError>((created, keyed, table))
+        })
+        .await
+        .unwrap()?;
+        // Writes made before the key is in place use the master key, and still read
+        if created {
+            self.load_new_table(table);
+        } else if keyed {
+            self.table_keys_mut().insert(table.stored_table, table.data_key);
+        }
+        if created {
+            self.log_op(Level::Debug, format_args!("Created table: {}", table_name));
+        }
+        Ok(created)
+    }
+
+    // Prepare a table to be created: its entry, with a new data key unless the config has it
+    // created without encryption, and its schema, checked and encrypted with that key
+    fn new_table(
+        &self,
+        table_name: &str,
+        schema: Option<Vec<Column>>,
+    ) -> Result<NewTable, VibraError> {
+        Self::validate_table_name(table_name)?;
+        let stored_table = self.stored_table(table_name).into_owned();
+        let plaintext = self.unencrypted.contains(&stored_table);
+        let data_key = Arc::new(self.with_rng(DataKey::generate));
+        let mut meta = TableMeta::new();
+        self.name_table(&mut meta, table_name)?;
+        if plaintext {
+            meta.plaintext = true;
+        } else {
+            meta.set_data_key(&self.master_key(), &stored_table, &data_key);
+        }
+        let table_key = RecordKey::Table(data_key.clone());
+        let sealed_schema = match &schema {
+            Some(columns) => {
+                if let Some(column) = columns.iter().find(|c| !c.is_known_type()) {
+                    return Err(VibraError::SchemaViolation {
+                        table: table_name.to_string(),
+                        column: column.name.clone(),
+                        reason: format!("unknown data type {}", column.data_type),
+                    });
+                }
+                let json = serde_json::to_string(columns)?;
+                let context = Self::schema_context(&stored_table);
+                let layers = self.encryption_layers();
+                if plaintext {
+                    Some(record::encode_plaintext(json.as_bytes(), now_millis()))
+                } else {
+                    Some(self.seal(&context, json.as_bytes(), layers, Some(&table_key))?)
+                }
+            }
+            None => None,
+        };
+        Ok(NewTable {
+            stored_table,
+            meta,
+            data_key,
+            schema,
+            sealed_schema,
+        })
+    }
+
+    // Put the key of a table that was just created in place, or note that it has none
+    fn load_new_table(&self, table: NewTable) {
+        if table.meta.plaintext {
+            let mut plaintext = self.plaintext_tables.write().unwrap_or_else(|p| p.into_inner());
+            plaintext.insert(table.stored_table);
+        } else {
+            self.table_keys_mut().insert(table.stored_table, table.data_key);
+        }
+    }
+
+    // Log a per-row operation at `level`, if the config enabled it. These messages name tables
+    // and rows, so by default they are never formatted at all.
+    fn log_op(&self, level: Level, message: fmt::Arguments) {
+        if self.log_operations {
+            log!(level, "{}", message);
+        }
+    }
+
+    // A table name, row id or key as it may appear in an error log: redacted unless per-row
+    // logging is enabled
+    fn loggable<'a>(&self, name: &'a str) -> &'a str {
+        if self.log_operations {
+            name
+        } else {
+            "<redacted>"
+        }
+    }
+
+    // An error as it may appear in a log. serde_json errors can quote the decrypted value they
+    // failed on, so only their kind and position are kept; errors naming a row or key are
+    // redacted like `loggable` names.
+    fn loggable_error(&self, err: &VibraError) -> String {
+        match err {
+            VibraError::Serialization(err) => format!(
+                "{:?} error in row JSON at line {} column {}",
+                err.classify(),
+                err.line(),
+                err.column()
+            ),
+            VibraError::RowIdMismatch { .. } | VibraError::CorruptEntry { .. }
+                if !self.log_operations =>
+            {
+                "<redacted>".to_string()
+            }
+            err => err.to_string(),
+        }
+    }
+
+    // Fail with TableNotFound unless the table has been created
+    fn require_table(&self, table_name: &str) -> Result<(), VibraError> {
+        if self.tables.contains_key(self.stored_table(table_name).as_bytes())? {
+            Ok(())
+        } else {
+            Err(VibraError::TableNotFound(table_name.to_string()))
+        }
+    }
+
+    // Load the schema declared for a table, if any
+    fn table_schema(&self, table_name: &str) -> Result<Option<Vec<Column>>, VibraError> {
+        let stored_table = self.stored_table(table_name);
+        match self.schema.get(stored_table.as_bytes())? {
+            Some(sealed) => {
+                let json = self.decrypt_value(&Self::schema_context(&stored_table), &sealed)?;
+                Ok(Some(serde_json::from_str(&json)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Reject rows that use a column name more than once, which would make lookups by name ambiguous
+    fn check_unique_columns(table_name: &str, row: &Row) -> Result<(), VibraError> {
+        match row.duplicate_column() {
+            Some(column) => Err(VibraError::DuplicateColumn {
+                table: table_name.to_string(),
+                column: column.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Check a row against its table's schema; schemaless tables accept any row without duplicate
+    // column names
+    fn validate_row(&self, table_name: &str, row: &Row) -> Result<(), VibraError> {
+        Self::check_unique_columns(table_name, row)?;
+        match self.table_schema(table_name)? {
+            Some(schema) => Self::check_row(table_name, &schema, row),
+            None => Ok(()),
+        }
+    }
+
+    // Check a row against an already loaded schema
+    fn check_row(table_name: &str, schema: &[Column], row: &Row) -> Result<(), VibraError> {
+        for (name, value) in &row.columns {
+            let violation = |reason: String| VibraError::SchemaViolation {
+                table: table_name.to_string(),
+                column: name.clone(),
+                reason,
+            };
+            match schema.iter().find(|c| &c.name == name) {
+                Some(column) if column.accepts(value) => {}
+                Some(column) => {
+                    return Err(violation(format!("expected a value of type {}", column.data_type)))
+                }
+                None => return Err(violation("column is not declared in the schema".to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    // Delete a table along with its rows, schema and data key, returning how many rows it had.
+    //
+    // The rows go first, then their cache entries, and the table's entry last, so a table that
+    // still exists never has rows missing from sled but present in the cache.
+    pub async fn delete_table(&self, table_name: &str) -> Result<usize, VibraError> {
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        let schema = self.schema.clone();
+        let tables = self.tables.clone();
+        let cache = self.cache.clone();
+        let table_keys = self.table_keys.clone();
+        let plaintext_tables = self.plaintext_tables.clone();
+        let this = self.clone();
+        let name = self.stored_table(table_name).into_owned();
+        let removed = task::spawn_blocking(move || {
+            let table_name = name;
+            let prefix = format!("{}/", table_name);
+            let _writing = this.writing();
+            let removed = this.clear_rows(&prefix, (AuditOp::DeleteTable, table_name.clone()))?;
+            this.drop_blind_indexes(&table_name)?;
+            cache.pop_matching(|key| key.starts_with(&prefix));
+
+            schema.remove(table_name.as_bytes())?;
+            // Removing the entry destroys the only copy of the table's data key, so its records
+            // can't be decrypted even if they are restored
+            table_keys.write().unwrap_or_else(|p| p.into_inner()).remove(&table_name);
+            plaintext_tables.write().unwrap_or_else(|p| p.into_inner()).remove(&table_name);
+            tables.remove(table_name.as_bytes())?;
+            Ok::<_, VibraError>(removed)
+        })
+        .await
+        .unwrap()?;
+        self.log_op(
+            Level::Debug,
+            format_args!("Deleted table {} and its {} rows", table_name, removed),
+        );
+        Ok(removed)
+    }
+
+    // Insert a row into a table, returning the row it replaced
+    pub async fn insert_row(&self, table_name: &str, row: Row) -> Result<Option<Row>, VibraError> {
+        self.insert_row_expiring(table_name, row, None).await
+    }
+
+    // Insert a row that expires at `expires_at` (unix millis), or never, returning the row it
+    // replaced. The row and its expiry are written together.
+    async fn insert_row_expiring(
+        &self,
+        table_name: &str,
+        row: Row,
+        expires_at: Option<u64>,
+    ) -> Result<Option<Row>, VibraError> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let key = self.row_key(table_name, &row.id)?;
+        self.validate_row(table_name, &row)?;
+        self.require_table(table_name)?;
+        let data = row.to_json()?;
+        let combined_data = self.encrypt_value(&key, data.as_bytes())?;
+
+        let this = self.clone();
+        let table_name_clone = table_name.to_string(); // Clone table_name here
+        let row_id = row.id.clone();
+        let (prior, prior_expiry) = task::spawn_blocking(move || {
+            let replaced = this.store_record(&table_name_clone, &key, combined_data, expires_at)?;
+            this.log_op(
+                Level::Debug,
+                format_args!("Inserted row into table {}: {}", table_name_clone, row.id),
+            );
+            // Only cache what is stored. This runs even if the caller stopped waiting, so a
+            // write that lands after a timeout is cached too.
+            this.cache_written(key, Arc::new(row));
+            Ok::<_, VibraError>(replaced)
+        })
+        .await
+        .unwrap()?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_insert(started.elapsed());
+        self.prior_row(table_name, &row_id, prior, prior_expiry)
+    }
+
+    // Insert a row unless the table already has a live row with its id, returning whether it was
+    // inserted. The check and the write are one transaction, so a row written concurrently is
+    // never overwritten; an expired row counts as absent and is replaced.
+    pub async fn insert_row_if_absent(
+        &self,
+        table_name: &str,
+        row: Row,
+    ) -> Result<bool, VibraError> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let key = self.row_key(table_name, &row.id)?;
+        self.validate_row(table_name, &row)?;
+        self.require_table(table_name)?;
+        let data = row.to_json()?;
+        let sealed = self.encrypt_value(&key, data.as_bytes())?;
+
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        let inserted = task::spawn_blocking(move || {
+            let inserted = this.commit_rows(|rows, expiry, tables, changes| {
+                this.check_table_in(tables, &table_name)?;
+                let expires_at = expiry.get(key.as_bytes())?;
+                if rows.get(key.as_bytes())?.is_some() && !has_expired(expires_at.as_ref()) {
+                    return Ok(false);
+                }
+                expiry.remove(key.as_bytes())?;
+                rows.insert(key.as_bytes(), sealed.clone())?;
+                changes.push((AuditOp::Insert, key.clone()));
+                Ok(true)
+            })?;
+            if inserted {
+                this.reindex_row(&key)?;
+                this.log_op(
+                    Level::Debug,
+                    format_args!("Inserted row into table {}: {}", table_name, row.id),
+                );
+                this.cache_written(key, Arc::new(row));
+            }
+            Ok::<_, VibraError>(inserted)
+        })
+        .await
+        .unwrap()?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_insert(started.elapsed());
+        Ok(inserted)
+    }
+
+    // Insert a row, failing with VibraError::Timeout if it takes longer than `timeout`.
+    //
+    // A write that has already been handed to sled can't be recalled, so after a timeout the row
+    // may or may not end up stored. Its stale cache entry is dropped either way, and the new row
+    // is only cached once stored, so later reads see whichever it was.
+    pub async fn insert_row_with_timeout(
+        &self,
+        table_name: &str,
+        row: Row,
+        timeout: Duration,
+    ) -> Result<Option<Row>, VibraError> {
+        let key = self.row_key(table_name, &row.id)?;
+        match tokio::time::timeout(timeout, self.insert_row(table_name, row)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.cache.pop(&key);
+                Err(VibraError::Timeout(timeout))
+            }
+        }
+    }
+
+    // Write an encrypted record, returning the record it replaced and that record's expiry.
+    //
+    // The table check, the write and setting the key's expiry to `expires_at`, or clearing it,
+    // happen in one transaction, so a record is never written into a missing table or seen with
+    // the wrong TTL, and the creation time of the record being replaced carries over. Blocks on
+    // sled.
+    fn store_record(
+        &self,
+        table_name: &str,
+        key: &str,
+        sealed: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<(Option<sled::IVec>, Option<sled::IVec>), VibraError> {
+        let replaced = self.commit_rows(|rows, expiry, tables, changes| {
+            self.check_table_in(tables, table_name)?;
+            let prior = rows.get(key.as_bytes())?;
+            let prior_expiry = match expires_at {
+                Some(expires_at) => expiry.insert(key.as_bytes(), &expires_at.to_be_bytes())?,
+                None => expiry.remove(key.as_bytes())?,
+            };
+            let mut sealed = sealed.clone();
+            let live = prior.as_ref().filter(|_| !has_expired(prior_expiry.as_ref()));
+            if let Some(stored) = live {
+                Self::copy_record_time(&mut sealed, stored, Timestamp::Created);
+            }
+            rows.insert(key.as_bytes(), sealed)?;
+            changes.push((Self::write_op(live.is_some()), key.to_string()));
+            Ok((prior, prior_expiry))
+        })?;
+        self.reindex_row(key)?;
+        Ok(replaced)
+    }
+
+    // How the audit log records a write, by whether it replaced a live row
+    fn write_op(replaced: bool) -> AuditOp {
+        match replaced {
+            true => AuditOp::Update,
+            false => AuditOp::Insert,
+        }
+    }
+
+    // Store any serializable value under `table/id`, bypassing the column model.
+    //
+    // Typed values are encrypted like rows but are never cached, so the row cache only ever holds
+    // column lists. Read them back with get_typed, not get_row. In a table with a schema the value
+    // must serialize to an object whose fields are columns of it, checked like a row's.
+    pub async fn insert_typed<T: Serialize>(
+        &self,
+        table_name: &str,
+        id: &str,
+        value: &T,
+    ) -> Result<(), VibraError> {
+        let key = self.row_key(table_name, id)?;
+        if let Some(schema) = self.table_schema(table_name)? {
+            let row = Self::typed_row(table_name, id, serde_json::to_value(value)?)?;
+            Self::check_row(table_name, &schema, &row)?;
+        }
+        let sealed = self.encrypt_value(&key, &serde_json::to_vec(value)?)?;
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        let key_clone = key.clone();
+        task::spawn_blocking(move || this.store_record(&table_name, &key_clone, sealed, None))
+            .await
+            .unwrap()?;
+        // A row may have been cached under the same key
+        self.cache.pop(&key);
+        Ok(())
+    }
+
+    // A typed value as the row its schema is checked against: strings are text, numbers and
+    // booleans their JSON text, arrays of bytes binary, and null fields absent
+    fn typed_row(table_name: &str, id: &str, value: serde_json::Value) -> Result<Row, VibraError> {
+        let violation = |column: &str, reason: &str| VibraError::SchemaViolation {
+            table: table_name.to_string(),
+            column: column.to_string(),
+            reason: reason.to_string(),
+        };
+        let serde_json::Value::Object(fields) = value else {
+            return Err(violation("", "a typed value in a table with a schema must be an object"));
+        };
+        let mut columns = Vec::with_capacity(fields.len());
+        for (name, field) in fields {
+            let value = match field {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(text) => Value::Text(text),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                    Value::Text(field.to_string())
+                }
+                field => match serde_json::from_value::<Vec<u8>>(field) {
+                    Ok(bytes) => Value::Bytes(bytes),
+                    Err(_) => return Err(violation(&name, "not a value a column can hold")),
+                },
+            };
+            columns.push((name, value));
+        }
+        Ok(Row {
+            id: id.to_string(),
+            columns,
+        })
+    }
+
+    // Retrieve a value stored with insert_typed
+    pub async fn get_typed<T: DeserializeOwned>(
+        &self,
+        table_name: &str,
+        id: &str,
+    ) -> Result<Option<T>, VibraError> {
+        let key = self.row_key(table_name, id)?;
+        self.require_table(table_name)?;
+        if self.is_expired(&key) {
+            return Ok(None);
+        }
+        let db = self.db.clone();
+        let key_clone = key.clone();
+        let stored = task::spawn_blocking(move || db.get(key_clone.as_bytes()))
+            .await
+            .unwrap()?;
+        match stored {
+            Some(stored) => {
+                Self::check_record_len(&key, &stored)?;
+                Ok(Some(serde_json::from_slice(&self.decrypt_bytes(&key, &stored)?)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Decode a row returned by a write, unless it had already expired and so was never visible.
+    //
+    // The write has already happened by now, so a replaced record that can't be decoded is logged
+    // rather than reported as a failed write.
+    fn prior_row(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        prior: Option<sled::IVec>,
+        prior_expiry: Option<sled::IVec>,
+    ) -> Result<Option<Row>, VibraError> {
+        match prior {
+            Some(stored) if !has_expired(prior_expiry.as_ref()) => {
+                match self.decode_row(table_name, row_id, &stored) {
+                    Ok(row) => Ok(Some(row)),
+                    Err(err) => {
+                        error!(
+                            "Replaced row {} could not be decoded: {}",
+                            self.loggable(row_id),
+                            self.loggable_error(&err)
+                        );
+                        Ok(None)
+                    }
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Insert a row under a freshly generated id, returning the id
+    pub async fn insert_auto(
+        &self,
+        table_name: &str,
+        columns: Vec<(String, Value)>,
+    ) -> Result<String, VibraError> {
+        // sled ids are unique and monotonic across the whole database, even across restarts.
+        // Zero-padding keeps the lexicographic key order equal to insertion order.
+        let id = format!("{:020}", self.db.generate_id()?);
+        self.insert_row(
+            table_name,
+            Row {
+                id: id.clone(),
+                columns,
+            },
+        )
+        .await?;
+        Ok(id)
+    }
+
+    // Insert a row into a table that expires after `ttl`
+    pub async fn insert_row_with_ttl(
+        &self,
+        table_name: &str,
+        row: Row,
+        ttl: Duration,
+    ) -> Result<(), VibraError> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.insert_row_expiring(table_name, row, Some(expires_at)).await?;
+        Ok(())
+    }
+
+    // Check whether a row key has an expiry in the past
+    fn is_expired(&self, key: &str) -> bool {
+        match self.expiry.get(key.as_bytes()) {
+            Ok(expires_at) => has_expired(expires_at.as_ref()),
+            Err(e) => {
+                error!("Error reading expiry for {}: {}", self.loggable(key), e);
+                false
+            }
         }
     }
 
-    fn generate_key() -> Key<Aes256Gcm> {
-        let mut key = [0u8; 32];
-        rand::thread_rng().fill(&mut key);
-        Key::<Aes256Gcm>::from_slice(&key).clone()
+    // Insert rows into a table, one insert_row at a time. A failure stops the loop, leaving the
+    // rows before it stored; use insert_rows_atomic for all or nothing.
+    pub async fn insert_rows(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError> {
+        for row in rows {
+            self.insert_row(table_name, row).await?;
+        }
+        Ok(())
     }
 
-    fn generate_nonce() -> Nonce<U12> {
-        let mut nonce = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce);
-        Nonce::<U12>::from_slice(&nonce).clone()
+    // Retrieve a row from a table
+    pub async fn get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let row = self.read_row(table_name, row_id).await?;
+        #[cfg(feature = "metrics")]
+        self.metrics.record_get(started.elapsed());
+        Ok(row)
     }
 
-    // Encrypt value with 25 layers of AES
-    fn encrypt_value(&self, value: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
-        let encrypted_data = value.as_bytes().to_vec();
-        let key = Mutex::new(vec![0u8; AES_LAYERS * 32]);
-        let nonce = Mutex::new(vec![0u8; AES_LAYERS * 12]);
+    // Retrieve a row, failing with VibraError::Timeout if it takes longer than `timeout`.
+    //
+    // Giving up leaves the cache as it was; a sled read that is still running finishes in the
+    // background and is discarded.
+    pub async fn get_row_with_timeout(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        timeout: Duration,
+    ) -> Result<Option<Row>, VibraError> {
+        tokio::time::timeout(timeout, self.get_row(table_name, row_id))
+            .await
+            .map_err(|_| VibraError::Timeout(timeout))?
+    }
 
-        let encrypted_data = (0..AES_LAYERS)
-            .into_par_iter()
-            .fold(
-                || encrypted_data.clone(),
-                |mut data, i| {
-                    let k = Self::generate_key();
-                    let cipher = Aes256Gcm::new(&k);
-                    let n = Self::generate_nonce();
-                    data = cipher
-                        .encrypt(&n, data.as_ref())
-                        .expect("Encryption failed");
-
-                    {
-                        let mut key_guard = key.lock().unwrap();
-                        key_guard[i * 32..(i + 1) * 32].copy_from_slice(k.as_slice());
-                    }
+    // Look a row up in the cache, falling back to decrypting it from sled
+    async fn read_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError> {
+        let key = self.row_key(table_name, row_id)?;
+        self.require_table(table_name)?;
+        if self.is_expired(&key) {
+            let this = self.clone();
+            task::spawn_blocking(move || this.reap_expired(key.as_bytes())).await.unwrap()?;
+            return Ok(None);
+        }
+        let plaintext = self.caches_plaintext(&key);
+        // Cached rows are already deserialized, so a hit never touches serde_json
+        if let Some(row) = self.cache.get(&key).filter(|_| plaintext) {
+            if row.id == row_id {
+                self.log_op(Level::Trace, format_args!("Cache hit for key: {}", key));
+                self.count_cache_hit();
+                return Ok(Some(Row::clone(&row)));
+            }
+            // The cache is only a copy, so a bad entry is dropped and the row read from sled
+            warn!("Cached row for key {} has id {}", self.loggable(&key), self.loggable(&row.id));
+            self.cache.pop(&key);
+        }
+        // Cached records save the read from sled, but not decrypting them
+        if let Some(stored) = self.cache.get_sealed(&key).filter(|_| !plaintext) {
+            self.log_op(Level::Trace, format_args!("Cache hit for record: {}", key));
+            self.count_cache_hit();
+            return self.open_row(&key, row_id, &stored).map(|(row, _)| Some(row));
+        }
+        self.cache_counters.misses.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.record_cache_miss();
+        // Taken before the read, so a write or delete that lands meanwhile voids the fill below
+        let ticket = self.cache.ticket(&key);
+        let db = self.db.clone();
+        let key_clone = key.clone();
+        let stored = task::spawn_blocking(move || db.get(key_clone.as_bytes()))
+            .await
+            .unwrap()?;
+        let Some(ivec) = stored else {
+            return Ok(None);
+        };
+        let (row, mut decrypted_value) = match self.open_row(&key, row_id, &ivec) {
+            Ok(opened) => opened,
+            Err(err) => return self.read_past_shredding(&key, row_id, &ivec, err).await,
+        };
+        if self.upgrade_on_read && self.is_outdated(&key, &ivec) {
+            self.upgrade_record(key.clone(), ivec.clone(), std::mem::take(&mut decrypted_value));
+        }
+        match plaintext {
+            true => self.cache.fill(key.clone(), Arc::new(row.clone()), ticket),
+            false => self.cache.fill(key.clone(), ivec, ticket),
+        }
+        self.log_op(
+            Level::Trace,
+            format_args!("Cache miss, fetched from DB and decrypted: {}", key),
+        );
+        Ok(Some(row))
+    }
 
-                    {
-                        let mut nonce_guard = nonce.lock().unwrap();
-                        nonce_guard[i * 12..(i + 1) * 12].copy_from_slice(n.as_slice());
-                    }
+    // Read a row again after its record, `stored`, failed to open with `err`. The record may be
+    // the noise shred_row writes over a row before removing it, if the row is being shredded or
+    // its record has changed since: the row is then gone, or was written again since, and the
+    // record stored now is read instead. Any other failure is returned as is.
+    async fn read_past_shredding(
+        &self,
+        key: &str,
+        row_id: &str,
+        stored: &sled::IVec,
+        err: VibraError,
+    ) -> Result<Option<Row>, VibraError> {
+        // Checked before reading again: noise is only ever stored while its key is listed
+        let shredding = self.shredding.lock().unwrap_or_else(|p| p.into_inner()).contains(key);
+        let this = self.clone();
+        let key_owned = key.to_string();
+        let current = task::spawn_blocking(move || this.db.get(key_owned.as_bytes()))
+            .await
+            .unwrap()?;
+        match current {
+            Some(current) if current == *stored && shredding => Ok(None),
+            Some(current) if current != *stored => {
+                Ok(Some(self.open_row(key, row_id, &current)?.0))
+            }
+            Some(_) => Err(err),
+            None => Ok(None),
+        }
+    }
 
-                    data
-                },
-            )
-            .reduce(|| encrypted_data.clone(), |a, _| a);
+    fn count_cache_hit(&self) {
+        self.cache_counters.hits.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.record_cache_hit();
+    }
+
+    // Decrypt and parse the record of the row stored under `key`, returning the row with its
+    // plaintext
+    fn open_row(
+        &self,
+        key: &str,
+        row_id: &str,
+        stored: &[u8],
+    ) -> Result<(Row, Zeroizing<Vec<u8>>), VibraError> {
+        Self::check_record_len(key, stored)?;
+        // A record that is present but unreadable is corruption or a wrong key, never a missing
+        // row
+        let decrypted_value = self.decrypt_bytes(key, stored).inspect_err(|err| {
+            let reason = self.loggable_error(err);
+            error!("Failed to decrypt value for key {:?}: {}", self.loggable(key), reason);
+        })?;
+        let decrypted_value = Zeroizing::new(decrypted_value);
+        let row = Self::parse_row(row_id, &decrypted_value).inspect_err(|err| {
+            let (key, reason) = (self.loggable(key), self.loggable_error(err));
+            error!("Stored value for key {} is not a row: {}", key, reason);
+        })?;
+        Ok((row, decrypted_value))
+    }
 
-        let key = key.into_inner().unwrap();
-        let nonce = nonce.into_inner().unwrap();
+    // Whether a record was written with other settings than new ones are: an older format,
+    // another cipher or layer count, random nonces, or the master key where its table now has a
+    // data key.
+    // Unencrypted records and rows with sealed columns are left to rekey.
+    fn is_outdated(&self, context: &str, stored: &[u8]) -> bool {
+        let Ok(record) = record::decode_record(stored) else {
+            return false;
+        };
+        let header = &record.header;
+        if header.is_plaintext() || header.has_sealed_columns() {
+            return false;
+        }
+        let key_source = match self.record_key(context) {
+            RecordKey::Table(_) => KeySource::Table,
+            RecordKey::Master(_) => KeySource::Master,
+        };
+        header.version < record::VERSION_BOUND
+            || record.key_source != key_source
+            || header.cipher != self.cipher.id()
+            || header.layers as usize != self.encryption_layers()
+            || !header.has_counter_nonces()
+    }
+
+    // Re-encrypt a record read with outdated settings with the current ones, in the background
+    // so the read doesn't wait for it. It is only replaced if it is still the record that was
+    // read, so a write that lands meanwhile is never undone; failures are only logged, and the
+    // record is upgraded on a later read.
+    fn upgrade_record(&self, key: String, stored: sled::IVec, value: Vec<u8>) {
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let value = Zeroizing::new(value);
+            let upgraded = this.encrypt_value(&key, &value).and_then(|mut sealed| {
+                Self::copy_record_time(&mut sealed, &stored, Timestamp::Created);
+                Self::copy_record_time(&mut sealed, &stored, Timestamp::Updated);
+                Ok(this.db.compare_and_swap(key.as_bytes(), Some(stored), Some(sealed))?.is_ok())
+            });
+            match upgraded {
+                Ok(true) => this.log_op(Level::Debug, format_args!("Upgraded record: {}", key)),
+                Ok(false) => {}
+                Err(err) => debug!(
+                    "Failed to upgrade record {}: {}",
+                    this.loggable(&key),
+                    this.loggable_error(&err)
+                ),
+            }
+        });
+    }
+
+    // Read and decrypt rows into the cache so upcoming get_row calls for them are hits.
+    //
+    // Rows that are missing, expired or already cached are skipped, and rows written or deleted
+    // while the prefetch runs aren't cached, so it never replaces or revives a newer value. Rows
+    // of tables cached as their records are cached as read, leaving decryption to get_row.
+    pub async fn prefetch(&self, table_name: &str, ids: &[&str]) -> Result<(), VibraError> {
+        let keys = ids
+            .iter()
+            .map(|id| Ok((self.row_key(table_name, id)?, id.to_string())))
+            .collect::<Result<Vec<_>, VibraError>>()?;
+        let this = self.clone();
+        let stored = task::spawn_blocking(move || {
+            let mut stored = Vec::with_capacity(keys.len());
+            for (key, id) in keys {
+                if this.is_expired(&key) || this.cache.contains(&key) {
+                    continue;
+                }
+                let ticket = this.cache.ticket(&key);
+                if let Some(value) = this.db.get(key.as_bytes())? {
+                    stored.push((key, id, value, ticket));
+                }
+            }
+            Ok::<_, VibraError>(stored)
+        })
+        .await
+        .unwrap()?;
+
+        let (stored, sealed): (Vec<_>, Vec<_>) =
+            stored.into_iter().partition(|(key, ..)| self.caches_plaintext(key));
+        for (key, _, value, ticket) in sealed {
+            self.cache.fill(key, value, ticket);
+        }
+        let decrypted = stored
+            .par_iter()
+            .map(|(key, id, value, ticket)| {
+                let row = self.decode_row(table_name, id, value)?;
+                Ok((key.clone(), row, *ticket))
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+        for (key, row, ticket) in decrypted {
+            self.cache.fill(key, Arc::new(row), ticket);
+        }
+        Ok(())
+    }
 
-        (encrypted_data, key, nonce)
+    // Retrieve when a row was created and last written, and its stored size, without decrypting it
+    pub async fn get_row_meta(
+        &self,
+        table_name: &str,
+        row_id: &str,
+    ) -> Result<Option<RowMeta>, VibraError> {
+        let key = self.row_key(table_name, row_id)?;
+        self.require_table(table_name)?;
+        if self.is_expired(&key) {
+            return Ok(None);
+        }
+        let stored = match self.db.get(key.as_bytes())? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let (header, _) = RecordHeader::decode(&stored)?;
+        let millis = |field| UNIX_EPOCH + Duration::from_millis(header.time(field));
+        Ok(Some(RowMeta {
+            created_at: millis(Timestamp::Created),
+            updated_at: millis(Timestamp::Updated),
+            size_bytes: stored.len(),
+        }))
     }
 
-    // Decrypt value with 25 layers of AES
-    fn decrypt_value(
+    // Decrypt and deserialize a stored row
+    fn decode_row(
         &self,
-        encrypted_data: &[u8],
-        key: &[u8],
-        nonce: &[u8],
-    ) -> Result<String, String> {
-        let data = encrypted_data.to_vec();
+        table_name: &str,
+        row_id: &str,
+        stored: &[u8],
+    ) -> Result<Row, VibraError> {
+        let key = self.row_key(table_name, row_id)?;
+        Self::parse_row(row_id, &self.decrypt_bytes(&key, stored)?)
+    }
 
-        let data = (0..AES_LAYERS)
-            .into_par_iter()
-            .fold(
-                || data.clone(),
-                |mut data, i| {
-                    let k = Key::<Aes256Gcm>::from_slice(&key[i * 32..(i + 1) * 32]);
-                    let cipher = Aes256Gcm::new(k);
-                    let n = Nonce::<U12>::from_slice(&nonce[i * 12..(i + 1) * 12]);
-                    data = match cipher.decrypt(n, data.as_ref()) {
-                        Ok(decrypted_data) => decrypted_data,
-                        Err(_) => return data, // Return the current data in case of decryption failure
-                    };
-                    data
-                },
-            )
-            .reduce(|| data.clone(), |a, _| a);
+    // Decrypt and deserialize a row stored under `key`
+    fn decode_entry(&self, key: &str, stored: &[u8]) -> Result<Row, VibraError> {
+        let row_id = self.row_id_of(key)?;
+        Self::parse_row(&row_id, &self.decrypt_bytes(key, stored)?)
+    }
+
+    // Parse a row's plaintext, checking that it belongs to the row id it was read for.
+    //
+    // Rows are stored as a whole `Row`. Rows written before the id was part of the payload are
+    // just a list of columns, and take their id from the key.
+    fn parse_row(row_id: &str, json: &[u8]) -> Result<Row, VibraError> {
+        #[cfg(test)]
+        ROW_PARSES.with(|parses| parses.set(parses.get() + 1));
+        if json.trim_ascii_start().starts_with(b"[") {
+            return Ok(Row {
+                id: row_id.to_string(),
+                columns: serde_json::from_slice(json)?,
+            });
+        }
+        let row: Row = serde_json::from_slice(json)?;
+        if row.id != row_id {
+            return Err(VibraError::RowIdMismatch {
+                expected: row_id.to_string(),
+                stored: row.id,
+            });
+        }
+        Ok(row)
+    }
+
+    // Drop entries whose rows have expired
+    fn retain_live(&self, entries: &mut Vec<(sled::IVec, sled::IVec)>) {
+        entries.retain(|(k, _)| str::from_utf8(k).map(|key| !self.is_expired(key)).unwrap_or(true));
+    }
 
-        match String::from_utf8(data) {
-            Ok(valid_string) => Ok(valid_string),
-            Err(_) => Err("Invalid UTF-8 sequence".to_string()),
+    // Reject a stored value too short to be a record before trying to decode it
+    fn check_record_len(key: &str, stored: &[u8]) -> Result<(), VibraError> {
+        let expected_min = record::min_record_len(stored);
+        if stored.len() < expected_min {
+            return Err(VibraError::CorruptEntry {
+                key: key.to_string(),
+                expected_min,
+                actual: stored.len(),
+            });
         }
+        Ok(())
     }
 
-    // Create a new table
-    pub async fn create_table(&self, table_name: &str) {
+    // Decrypt stored (key, value) pairs of a table in parallel
+    fn decode_rows(&self, entries: &[(sled::IVec, sled::IVec)]) -> Result<Vec<Row>, VibraError> {
+        entries
+            .par_iter()
+            .filter_map(|(k, v)| {
+                let key = str::from_utf8(k).ok()?;
+                Some(Self::check_record_len(key, v).and_then(|()| self.decode_entry(key, v)))
+            })
+            .collect()
+    }
+
+    // Read the stored entries of every live row in a table, ordered by row id
+    async fn table_entries(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<(sled::IVec, sled::IVec)>, VibraError> {
+        self.require_table(table_name)?;
+        let prefix = self.table_prefix(table_name);
+        let db = self.db.clone();
+        let mut entries = task::spawn_blocking(move || {
+            db.scan_prefix(prefix.as_bytes()).collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .unwrap()?;
+        self.retain_live(&mut entries);
+        self.sort_by_row_id(&mut entries);
+        Ok(entries)
+    }
+
+    // Retrieve every row of a table, ordered by row id
+    pub async fn scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        self.decode_rows(&entries)
+    }
+
+    // Retrieve every row of a table, ordered by row id, with a result per row so rows that fail to
+    // decode don't hide the rest
+    pub async fn scan_table_lenient(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<Result<Row, VibraError>>, VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        Ok(entries
+            .par_iter()
+            .map(|(k, v)| {
+                let key = String::from_utf8_lossy(k);
+                self.decode_entry(&key, v)
+                    .map_err(|source| VibraError::CorruptRow {
+                        key: key.to_string(),
+                        source: Box::new(source),
+                    })
+            })
+            .collect())
+    }
+
+    // Retrieve every row of a table keyed by row id, decrypting them in parallel
+    pub async fn load_table(&self, table_name: &str) -> Result<HashMap<String, Row>, VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        entries
+            .par_iter()
+            .filter_map(|(k, v)| {
+                let key = str::from_utf8(k).ok()?;
+                Some(self.decode_entry(key, v).map(|row| (row.id.clone(), row)))
+            })
+            .collect()
+    }
+
+    // Retrieve up to `limit` rows whose id sorts after `after`, plus the cursor for the next page
+    pub async fn scan_paginated(
+        &self,
+        table_name: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<Row>, Option<String>), VibraError> {
+        self.require_table(table_name)?;
+        if self.names.is_some() {
+            return self.scan_paginated_by_id(table_name, after, limit).await;
+        }
+        let prefix = format!("{}/", table_name);
+        let start = match after {
+            Some(after) => Bound::Excluded(format!("{}{}", prefix, after).into_bytes()),
+            None => Bound::Included(prefix.clone().into_bytes()),
+        };
         let db = self.db.clone();
+        let entries = task::spawn_blocking(move || {
+            // Fetch one extra entry to learn whether another page follows
+            db.range::<Vec<u8>, _>((start, Bound::Unbounded))
+                .take_while(|entry| match entry {
+                    Ok((k, _)) => k.starts_with(prefix.as_bytes()),
+                    Err(_) => true,
+                })
+                .take(limit + 1)
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .unwrap()?;
+
+        let has_more = entries.len() > limit;
+        let mut page: Vec<_> = entries.into_iter().take(limit).collect();
+        let cursor = match page.last() {
+            Some((k, _)) if has_more => {
+                Some(String::from_utf8_lossy(&k[table_name.len() + 1..]).into_owned())
+            }
+            _ => None,
+        };
+        self.retain_live(&mut page);
+        Ok((self.decode_rows(&page)?, cursor))
+    }
+
+    // scan_paginated for databases that encrypt row ids, which sled doesn't keep in id order: the
+    // table's ids are decrypted and sorted to find the page
+    async fn scan_paginated_by_id(
+        &self,
+        table_name: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<Row>, Option<String>), VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        let mut ids = Vec::with_capacity(entries.len());
+        for (k, _) in &entries {
+            ids.push(self.row_id_of(&String::from_utf8_lossy(k))?);
+        }
+        let start = after.map_or(0, |after| ids.partition_point(|id| id.as_str() <= after));
+        let end = entries.len().min(start.saturating_add(limit));
+        let cursor = (end < entries.len() && end > start).then(|| ids[end - 1].clone());
+        Ok((self.decode_rows(&entries[start..end])?, cursor))
+    }
+
+    // Atomically add `delta` to an integer column, returning the new value.
+    //
+    // Rows and columns that don't exist yet start from zero. Concurrent increments never lose
+    // updates (see modify_column).
+    pub async fn increment_column(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        column: &str,
+        delta: i64,
+    ) -> Result<i64, VibraError> {
+        let name = column.to_string();
+        self.modify_column(table_name, row_id, column, move |value| {
+            let old = match value {
+                Some(Value::Text(text)) => {
+                    text.parse::<i64>().map_err(|_| VibraError::InvalidValue {
+                        column: name.clone(),
+                        reason: format!("{:?} is not an integer", text),
+                    })?
+                }
+                Some(Value::Bytes(_)) => {
+                    return Err(VibraError::InvalidValue {
+                        column: name.clone(),
+                        reason: "binary values cannot be incremented".to_string(),
+                    })
+                }
+                None => 0,
+            };
+            let new = old.checked_add(delta).ok_or_else(|| VibraError::InvalidValue {
+                column: name.clone(),
+                reason: "counter overflowed".to_string(),
+            })?;
+            Ok((Value::Text(new.to_string()), new))
+        })
+        .await
+    }
+
+    // Atomically append `suffix` to a text or binary column, creating the column if it's absent.
+    //
+    // Text columns stay text, so the suffix must be valid UTF-8 for them. A new column is text if
+    // the suffix is valid UTF-8 and binary otherwise. Concurrent appends are never lost (see
+    // modify_column), though they land in whichever order they commit.
+    pub async fn append_to_column(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        column: &str,
+        suffix: &[u8],
+    ) -> Result<(), VibraError> {
+        let name = column.to_string();
+        let suffix = suffix.to_vec();
+        self.modify_column(table_name, row_id, column, move |value| {
+            let appended = match value {
+                Some(Value::Text(text)) => {
+                    let suffix = str::from_utf8(&suffix).map_err(|_| VibraError::InvalidValue {
+                        column: name.clone(),
+                        reason: "only UTF-8 can be appended to a text value".to_string(),
+                    })?;
+                    Value::Text(format!("{}{}", text, suffix))
+                }
+                Some(Value::Bytes(bytes)) => Value::Bytes([bytes.as_slice(), &suffix].concat()),
+                None => match String::from_utf8(suffix.clone()) {
+                    Ok(text) => Value::Text(text),
+                    Err(_) => Value::Bytes(suffix.clone()),
+                },
+            };
+            Ok((appended, ()))
+        })
+        .await
+    }
+
+    // Replace one column of a row with a value computed from its current one, returning whatever
+    // `f` reports alongside the new value. The row is created if it doesn't exist; an expired
+    // row counts as not existing, and the write clears its TTL.
+    //
+    // Concurrent modifications never lose updates: the row is decrypted, modified and
+    // re-encrypted, then written in a transaction that checks the table still exists and that
+    // neither the row nor its expiry changed since they were read, retrying (and calling `f`
+    // again) if another writer got there first.
+    async fn modify_column<T, F>(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        column: &str,
+        f: F,
+    ) -> Result<T, VibraError>
+    where
+        T: Send + 'static,
+        F: Fn(Option<&Value>) -> Result<(Value, T), VibraError> + Send + 'static,
+    {
+        let key = self.row_key(table_name, row_id)?;
+        self.require_table(table_name)?;
+        let schema = self.table_schema(table_name)?;
+        let this = self.clone();
         let table_name = table_name.to_string();
-        task::spawn_blocking(move || {
-            let result = db.insert(table_name.as_bytes(), b"");
-            match result {
-                Ok(_) => info!("Created table: {}", table_name),
-                Err(e) => error!("Failed to create table: {}", e),
+        let row_id = row_id.to_string();
+        let column = column.to_string();
+        let key_clone = key.clone();
+        let result = task::spawn_blocking(move || loop {
+            let stored = this.db.get(key_clone.as_bytes())?;
+            let stored_expiry = this.expiry.get(key_clone.as_bytes())?;
+            let current = stored.clone().filter(|_| !has_expired(stored_expiry.as_ref()));
+            let mut row = match &current {
+                Some(stored) => this.decode_row(&table_name, &row_id, stored)?,
+                None => Row {
+                    id: row_id.clone(),
+                    columns: vec![],
+                },
+            };
+            let position = row.columns.iter().position(|(name, _)| name == &column);
+            let (value, result) = f(position.map(|i| &row.columns[i].1))?;
+            match position {
+                Some(i) => row.columns[i].1 = value,
+                None => row.columns.push((column.clone(), value)),
+            }
+            if let Some(schema) = &schema {
+                Self::check_row(&table_name, schema, &row)?;
             }
-            // Verify the table creation
-            match db.get(table_name.as_bytes()) {
-                Ok(Some(_)) => info!("Verified table creation: {}", table_name),
-                Ok(None) => error!("Table creation not verified: {}", table_name),
-                Err(e) => error!("Error verifying table creation: {}", e),
+
+            let data = row.to_json()?;
+            let mut updated = this.encrypt_value(&key_clone, data.as_bytes())?;
+            if let Some(stored) = &current {
+                Self::copy_record_time(&mut updated, stored, Timestamp::Created);
+            }
+            let written = this.commit_rows(|rows, expiry, tables, changes| {
+                this.check_table_in(tables, &table_name)?;
+                let key = key_clone.as_bytes();
+                if rows.get(key)? != stored || expiry.get(key)? != stored_expiry {
+                    return Ok(false);
+                }
+                rows.insert(key, updated.as_slice())?;
+                expiry.remove(key)?;
+                changes.push((Self::write_op(current.is_some()), key_clone.clone()));
+                Ok(true)
+            })?;
+            if written {
+                this.reindex_row(&key_clone)?;
+                return Ok::<_, VibraError>(result);
             }
         })
         .await
-        .unwrap();
+        .unwrap()?;
+
+        // Concurrent writers finish in any order, so drop the entry instead of caching ours
+        self.cache.pop(&key);
+        Ok(result)
     }
 
-    // Delete a table
-    pub async fn delete_table(&self, table_name: &str) {
-        let db = self.db.clone();
-        let table_name = table_name.to_string();
+    // Capture a read-only view of every live row that later writes won't affect.
+    //
+    // Writers are held off while the rows are copied, so the view is the database as of one
+    // moment: a change that commits several rows at once is in it whole or not at all. Rows
+    // whose TTL has run out by the time the copy starts are left out.
+    pub async fn snapshot(&self) -> Result<VibraSnapshot, VibraError> {
+        let this = self.clone();
         task::spawn_blocking(move || {
-            // Remove all rows associated with the table
-            let prefix = format!("{}/", table_name);
-            let mut batch = sled::Batch::default();
-            for key in db.scan_prefix(&prefix) {
-                if let Ok((k, _)) = key {
-                    batch.remove(k);
+            let _writers = this.writers.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut expired = HashSet::new();
+            for entry in this.expiry.iter() {
+                let (k, expires_at) = entry?;
+                if has_expired(Some(&expires_at)) {
+                    expired.insert(k);
                 }
             }
-            db.apply_batch(batch).expect("Delete table failed");
-
-            // Remove the table entry itself
-            let result = db.remove(table_name.as_bytes());
-            match result {
-                Ok(_) => println!("Deleted table: {}", table_name),
-                Err(e) => println!("Failed to delete table: {}", e),
+            let mut entries = BTreeMap::new();
+            for entry in this.db.iter() {
+                let (k, v) = entry?;
+                if !expired.contains(&k) {
+                    entries.insert(k, v);
+                }
             }
+            Ok(VibraSnapshot::new(this.clone(), entries))
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
-    // Insert a row into a table
-    pub async fn insert_row(&self, table_name: &str, row: Row) {
-        let key = format!("{}/{}", table_name, row.id);
-        let data = serde_json::to_string(&row.columns).expect("Serialization failed");
-        let (encrypted_value, key_data, nonce_data) = self.encrypt_value(&data);
-        let mut combined_data = encrypted_value;
-        combined_data.extend_from_slice(&key_data);
-        combined_data.extend_from_slice(&nonce_data);
-
-        {
-            let mut cache = self.cache.write().unwrap();
-            cache.put(key.clone(), data.clone()); // Cache stores the plaintext
-        }
+    // Update a row in a table
+    pub async fn update_row(&self, table_name: &str, row: Row) -> Result<(), VibraError> {
+        // Overwrite in place, in a single transaction: deleting first would let readers see the
+        // row missing and reset its creation time
+        self.insert_row(table_name, row).await?;
+        Ok(())
+    }
 
-        let db = self.db.clone();
-        let key_clone = key.clone();
-        let table_name_clone = table_name.to_string(); // Clone table_name here
-        task::spawn_blocking(move || {
-            db.insert(key_clone, combined_data)
-                .expect("Insert row failed");
-            info!("Inserted row into table {}: {}", table_name_clone, row.id); // Use cloned table_name
+    // Insert many rows into a table, encrypting them in parallel off the async runtime.
+    //
+    // Rows are written MAX_ATOMIC_ROWS at a time, each chunk in one transaction with the table
+    // check and clearing the rows' TTLs, so no row lands in a deleted table or keeps an old
+    // expiry. A failure leaves the chunks before it stored; use insert_rows_atomic for all or
+    // nothing.
+    pub async fn insert_many_rows(
+        &self,
+        table_name: &str,
+        rows: Vec<Row>,
+    ) -> Result<(), VibraError> {
+        let this = self.clone();
+        let table_name_owned = table_name.to_string();
+        let sealed = task::spawn_blocking(move || {
+            let sealed = this.seal_rows(&table_name_owned, rows)?;
+            for chunk in sealed.chunks(MAX_ATOMIC_ROWS) {
+                this.store_rows(&table_name_owned, chunk)?;
+            }
+            Ok::<_, VibraError>(sealed)
         })
         .await
-        .unwrap();
-    }
+        .unwrap()?;
+        self.log_op(
+            Level::Debug,
+            format_args!("Inserted {} rows into table {}", sealed.len(), table_name),
+        );
 
-    // Insert rows into a table
-    pub async fn insert_rows(&self, table_name: &str, rows: Vec<Row>) {
-        for row in rows {
-            self.insert_row(table_name, row).await;
+        for (key, row, _) in sealed {
+            self.cache_written(key, row);
         }
+        Ok(())
     }
 
-    // Retrieve a row from a table
-    pub async fn get_row(&self, table_name: &str, row_id: &str) -> Option<Row> {
-        let key = format!("{}/{}", table_name, row_id);
-        {
-            let mut cache = self.cache.write().unwrap();
-            if let Some(value) = cache.get(&key) {
-                info!("Cache hit for key: {}", key);
-                let columns: Vec<(String, String)> =
-                    serde_json::from_str(value).expect("Deserialization failed");
-                return Some(Row {
-                    id: row_id.to_string(),
-                    columns,
+    // Insert rows into a table in a single transaction: either every row is stored, or none is
+    // and the error is returned.
+    //
+    // The table check, the writes and clearing the rows' TTLs commit together, and rows are only
+    // cached once they have. A batch can't name a row twice, since one copy would silently win.
+    // Sled holds the whole transaction in memory, so batches are capped at MAX_ATOMIC_ROWS rows;
+    // larger inputs have to be split, and each call is then atomic on its own.
+    pub async fn insert_rows_atomic(
+        &self,
+        table_name: &str,
+        rows: Vec<Row>,
+    ) -> Result<(), VibraError> {
+        if rows.len() > MAX_ATOMIC_ROWS {
+            return Err(VibraError::BatchTooLarge {
+                rows: rows.len(),
+                max: MAX_ATOMIC_ROWS,
+            });
+        }
+        let mut ids = HashSet::with_capacity(rows.len());
+        for row in &rows {
+            if !ids.insert(row.id.as_str()) {
+                return Err(VibraError::DuplicateRow {
+                    table: table_name.to_string(),
+                    id: row.id.clone(),
                 });
             }
         }
-        if let Some(ivec) = self.db.get(&key).expect("Get row failed") {
-            let (encrypted_data, key_nonce) = ivec.split_at(ivec.len() - (AES_LAYERS * (32 + 12)));
-            let (key, nonce) = key_nonce.split_at(AES_LAYERS * 32);
-            match self.decrypt_value(encrypted_data, key, nonce) {
-                Ok(decrypted_value) => {
-                    let columns: Vec<(String, String)> =
-                        serde_json::from_str(&decrypted_value).expect("Deserialization failed");
-                    let mut cache = self.cache.write().unwrap();
-                    cache.put(
-                        String::from_utf8(key.to_vec()).expect("Invalid UTF-8 sequence"),
-                        decrypted_value.clone(),
-                    );
-                    info!("Cache miss, fetched from DB and decrypted: {:?}", key);
-                    Some(Row {
-                        id: row_id.to_string(),
-                        columns,
-                    })
-                }
-                Err(err) => {
-                    info!("Failed to decrypt value for key {:?}: {}", key, err);
-                    None
+        Self::validate_table_name(table_name)?;
+
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        let sealed = task::spawn_blocking(move || {
+            let sealed = this.seal_rows(&table_name, rows)?;
+            if sealed.is_empty() {
+                return Ok(sealed);
+            }
+            this.store_rows(&table_name, &sealed)?;
+            this.log_op(
+                Level::Debug,
+                format_args!("Inserted {} rows into table {}", sealed.len(), table_name),
+            );
+            Ok::<_, VibraError>(sealed)
+        })
+        .await
+        .unwrap()?;
+
+        for (key, row, _) in sealed {
+            self.cache_written(key, row);
+        }
+        Ok(())
+    }
+
+    // Write sealed rows in one transaction with the table check, clearing their TTLs and carrying
+    // over the creation time of the live rows they replace. Blocks on sled.
+    fn store_rows(&self, table_name: &str, sealed: &[SealedRow]) -> Result<(), VibraError> {
+        self.commit_rows(|tree, expiry, tables, changes| {
+            self.check_table_in(tables, table_name)?;
+            for (key, _, combined_data) in sealed {
+                let prior = tree.get(key.as_bytes())?;
+                let prior_expiry = expiry.remove(key.as_bytes())?;
+                let mut combined_data = combined_data.clone();
+                let live = prior.filter(|_| !has_expired(prior_expiry.as_ref()));
+                if let Some(stored) = &live {
+                    Self::copy_record_time(&mut combined_data, stored, Timestamp::Created);
                 }
+                tree.insert(key.as_bytes(), combined_data)?;
+                changes.push((Self::write_op(live.is_some()), key.clone()));
+            }
+            Ok(())
+        })?;
+        self.reindex_rows(sealed.iter().map(|(key, _, _)| key.as_str()))
+    }
+
+    // Validate rows for a table and encrypt them, returning each row's key, the row ready to
+    // cache and its sealed record. Fails if the table doesn't exist or any row is invalid.
+    // Blocks on sled and the rayon pool.
+    fn seal_rows(
+        &self,
+        table_name: &str,
+        rows: Vec<Row>,
+    ) -> Result<Vec<SealedRow>, VibraError> {
+        for row in &rows {
+            Self::validate_name(&row.id)?;
+            Self::check_unique_columns(table_name, row)?;
+        }
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        if let Some(schema) = self.table_schema(table_name)? {
+            for row in &rows {
+                Self::check_row(table_name, &schema, row)?;
             }
+        }
+
+        // Encryption is CPU-bound, so spread it over the rayon pool
+        rows.into_par_iter()
+            .map(|row| {
+                let key = self.row_key(table_name, &row.id)?;
+                let data = row.to_json()?;
+                let combined_data = self.encrypt_value(&key, data.as_bytes())?;
+                Ok((key, Arc::new(row), combined_data))
+            })
+            .collect()
+    }
+
+    // Check if a table exists. Only the table's entry in the tables tree counts; rows left under
+    // its prefix without one don't make a table.
+    pub async fn table_exists(&self, table_name: &str) -> Result<bool, VibraError> {
+        let tables = self.tables.clone();
+        let name = self.stored_table(table_name).into_owned();
+        let exists = task::spawn_blocking(move || tables.contains_key(name))
+            .await
+            .unwrap()?;
+        if exists {
+            self.log_op(Level::Trace, format_args!("Table {} exists", table_name));
         } else {
-            None
+            self.log_op(Level::Trace, format_args!("Table {} does not exist", table_name));
         }
+        Ok(exists)
     }
 
-    // Update a row in a table
-    pub async fn update_row(&self, table_name: &str, row: Row) {
-        self.delete_row(table_name, &row.id).await;
-        self.insert_row(table_name, row).await;
+    // Delete a row from a table, returning the row that was removed
+    pub async fn delete_row(
+        &self,
+        table_name: &str,
+        row_id: &str,
+    ) -> Result<Option<Row>, VibraError> {
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
+        let key = self.row_key(table_name, row_id)?;
+        self.require_table(table_name)?;
+        let this = self.clone();
+        let (prior, prior_expiry) = task::spawn_blocking(move || -> Result<_, VibraError> {
+            let (prior, prior_expiry) = this.commit_rows(|rows, expiry, _, changes| {
+                let prior = rows.remove(key.as_bytes())?;
+                if prior.is_some() {
+                    changes.push((AuditOp::Delete, key.clone()));
+                }
+                Ok((prior, expiry.remove(key.as_bytes())?))
+            })?;
+            this.cache.pop(key.as_str());
+            this.reindex_row(&key)?;
+            Ok((prior, prior_expiry))
+        })
+        .await
+        .unwrap()?;
+        self.log_op(
+            Level::Debug,
+            format_args!("Deleted row from table {}: {}", table_name, row_id),
+        );
+        #[cfg(feature = "metrics")]
+        self.metrics.record_delete(started.elapsed());
+        self.prior_row(table_name, row_id, prior, prior_expiry)
     }
 
-    // Insert many rows into a table
-    pub async fn insert_many_rows(&self, table_name: &str, rows: Vec<Row>) {
-        let mut handles = vec![];
+    // Truncate a table, removing its rows but keeping the table and its schema.
+    //
+    // Only keys under the exact `table/` prefix are touched, so tables whose names merely start
+    // with this one's, such as `users_archive` for `users`, are left alone.
+    pub async fn truncate_table(&self, table_name: &str) -> Result<(), VibraError> {
+        Self::validate_name(table_name)?;
+        self.require_table(table_name)?;
+        let prefix = self.table_prefix(table_name);
+        let this = self.clone();
+        let table_name_clone = self.stored_table(table_name).into_owned();
+        task::spawn_blocking(move || {
+            let _writing = this.writing();
+            this.clear_rows(&prefix, (AuditOp::TruncateTable, table_name_clone.clone()))?;
+            this.clear_index_entries(&table_name_clone)?;
+            this.cache.pop_matching(|key| key.starts_with(&prefix));
+            Ok::<_, VibraError>(())
+        })
+        .await
+        .unwrap()?;
+        self.log_op(Level::Debug, format_args!("Truncated table: {}", table_name));
+        Ok(())
+    }
 
-        for row in rows {
-            let table_name = table_name.to_string();
-            let db_clone = self.clone();
-            let handle = tokio::spawn(async move {
-                db_clone.insert_row(&table_name, row).await;
-            });
-            handles.push(handle);
+    // Truncate every table whose name starts with `prefix`, returning how many were truncated.
+    //
+    // Only each table's own `table/` keys are removed, so a table whose name merely contains the
+    // prefix, or extends a matching table's name, is left alone. The tables themselves remain.
+    pub async fn truncate_tables_with_prefix(&self, prefix: &str) -> Result<usize, VibraError> {
+        let this = self.clone();
+        let cache = self.cache.clone();
+        let prefix = prefix.to_string();
+        task::spawn_blocking(move || {
+            let tables = this.table_names(&prefix)?;
+            for (stored_table, table_name) in &tables {
+                let row_prefix = format!("{}/", stored_table);
+                let _writing = this.writing();
+                this.clear_rows(&row_prefix, (AuditOp::TruncateTable, stored_table.clone()))?;
+                this.clear_index_entries(stored_table)?;
+
+                cache.pop_matching(|key| key.starts_with(&row_prefix));
+                this.log_op(Level::Debug, format_args!("Truncated table: {}", table_name));
+            }
+            Ok(tables.len())
+        })
+        .await
+        .unwrap()
+    }
+
+    // Remove every row under `prefix` and its expiry, returning how many rows there were. The
+    // rows go MAX_ATOMIC_ROWS at a time through commit_rows_held, and `entry` is recorded in the
+    // audit log with the first of them, so a removal that stops part way is still on record.
+    // Blocks on sled; the caller holds writing().
+    fn clear_rows(&self, prefix: &str, entry: (AuditOp, String)) -> Result<usize, VibraError> {
+        let keys = self.db.scan_prefix(prefix.as_bytes()).keys().collect::<Result<Vec<_>, _>>()?;
+        let mut entry = Some(entry);
+        // A table without rows still gets its entry
+        let empty: &[sled::IVec] = &[];
+        for chunk in keys.chunks(MAX_ATOMIC_ROWS).chain(keys.is_empty().then_some(empty)) {
+            self.commit_rows_held(|rows, expiry, _, changes| {
+                for key in chunk {
+                    rows.remove(key)?;
+                    expiry.remove(key)?;
+                }
+                changes.extend(entry.clone());
+                Ok(())
+            })?;
+            entry = None;
         }
+        // Expiries of rows that are already gone
+        let mut expiry_batch = sled::Batch::default();
+        for key in self.expiry.scan_prefix(prefix.as_bytes()).keys() {
+            expiry_batch.remove(key?);
+        }
+        self.expiry.apply_batch(expiry_batch)?;
+        Ok(keys.len())
+    }
 
-        // Wait for all tasks to complete
-        join_all(handles).await;
+    // List all tables
+    pub async fn list_tables(&self) -> Result<Vec<String>, VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let tables = this.table_names("")?;
+            Ok(tables.into_iter().map(|(_, table_name)| table_name).collect())
+        })
+        .await
+        .unwrap()
     }
 
-    // Check if a table exists
-    pub async fn table_exists(&self, table_name: &str) -> bool {
-        match self.db.get(table_name.as_bytes()) {
-            Ok(Some(_)) => {
-                info!("Table {} exists", table_name);
-                true
+    // Remove expired rows from a table.
+    //
+    // Each row is removed along with its expiry in one transaction that reads the expiry again,
+    // so a row rewritten since the scan, without a TTL or with a later one, is left alone.
+    pub async fn sweep_expired(&self, table_name: &str) -> Result<usize, VibraError> {
+        let prefix = self.table_prefix(table_name);
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let mut reaped = 0;
+            for entry in this.expiry.scan_prefix(&prefix) {
+                let (k, v) = entry?;
+                if has_expired(Some(&v)) && this.reap_expired(&k)? {
+                    reaped += 1;
+                }
             }
-            Ok(None) => {
-                info!("Table {} does not exist", table_name);
-                false
+            Ok(reaped)
+        })
+        .await
+        .unwrap()
+    }
+
+    // Remove the row under `key` if it is still expired, returning whether it was. The check
+    // and the removal are one transaction, so a row written again since it expired stays. It
+    // is recorded as expired rather than deleted, as no one deleted it. Blocks on sled.
+    fn reap_expired(&self, key: &[u8]) -> Result<bool, VibraError> {
+        let removed = self.commit_rows(|rows, expiry, _, changes| {
+            if !has_expired(expiry.get(key)?.as_ref()) {
+                return Ok(false);
             }
-            Err(e) => {
-                error!("Error checking if table {} exists: {}", table_name, e);
-                false
+            if rows.remove(key)?.is_some() {
+                changes.push((AuditOp::Expire, String::from_utf8_lossy(key).into_owned()));
+            }
+            expiry.remove(key)?;
+            Ok(true)
+        })?;
+        if removed {
+            if let Ok(key) = str::from_utf8(key) {
+                self.cache.pop(key);
+                self.reindex_row(key)?;
             }
         }
+        Ok(removed)
     }
 
-    // Delete a row from a table
-    pub async fn delete_row(&self, table_name: &str, row_id: &str) {
-        let key = format!("{}/{}", table_name, row_id);
-        let table_name_clone = table_name.to_string();
-        let db = self.db.clone();
-        let cache = self.cache.clone();
-        let row_id_clone = row_id.to_string();
+    // Remove expired rows from every table, returning how many were removed
+    async fn sweep_all_expired(&self) -> Result<usize, VibraError> {
+        let mut reaped = 0;
+        for table_name in self.list_tables().await? {
+            reaped += self.sweep_expired(&table_name).await?;
+        }
+        Ok(reaped)
+    }
+
+    // Start a background task that sweeps expired rows from every table
+    pub fn start_expiry_sweeper(&self, interval: Duration) -> ExpirySweeper {
+        let db = self.clone();
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match db.sweep_all_expired().await {
+                    Ok(reaped) => debug!("Expiry sweeper reaped {} rows", reaped),
+                    Err(err) => error!("Expiry sweep failed: {}", err),
+                }
+            }
+        });
+        ExpirySweeper {
+            handle: Some(handle),
+        }
+    }
+
+    // Number of encryption layers new writes are encrypted with
+    pub fn encryption_layers(&self) -> usize {
+        self.layers.load(Ordering::SeqCst)
+    }
+
+    // The cipher new writes are encrypted with, from the config
+    pub fn cipher(&self) -> CipherSuite {
+        self.cipher
+    }
+
+    // Re-encrypt every row under fresh keys and `new_layers` layers, returning the rows rotated.
+    //
+    // New writes switch to `new_layers` before the walk starts. Every record carries its own layer
+    // count and is swapped in with compare_and_swap, so a rekey that stops part way leaves a mix
+    // of old and new records that all still decrypt. The cache holds plaintext and stays valid.
+    // Tables from before data keys are given one first, so their rows move to it. Once every row
+    // is rewritten the layer count and cipher are stored, for a reopen to keep using them.
+    //
+    // With `new_master_key`, the master key is rotated to it once every row is rewritten (see
+    // rotate_master_key), and the settings are stored in the same transaction as its key swap.
+    // The key is parsed before anything changes, so a malformed one leaves the database as it
+    // was.
+    pub async fn rekey(
+        &self,
+        new_layers: usize,
+        new_master_key: Option<String>,
+    ) -> Result<usize, VibraError> {
+        Self::check_layers(new_layers)?;
+        if self.strict && new_layers != 1 {
+            return Err(VibraError::InvalidConfig(format!(
+                "compliance_mode = \"strict\" allows exactly one encryption layer, got {}",
+                new_layers
+            )));
+        }
+        let new_master_key = new_master_key
+            .map(|text| {
+                MasterKey::parse(&Zeroizing::new(text)).map_err(|reason| VibraError::InvalidKey {
+                    origin: "new_master_key".to_string(),
+                    reason,
+                })
+            })
+            .transpose()?;
+        let settings = EncryptionSettings {
+            layers: new_layers,
+            cipher: self.cipher,
+        };
+        let settings = serde_json::to_vec(&settings)?;
+        self.layers.store(new_layers, Ordering::SeqCst);
+
+        let this = self.clone();
         task::spawn_blocking(move || {
-            db.remove(&key).expect("Delete row failed");
-            {
-                let mut cache = cache.write().unwrap();
-                cache.pop(key.as_str());
+            this.assign_table_keys()?;
+            // Schemas are encrypted too, but aren't rows
+            this.rekey_tree(&this.schema, new_layers, Self::schema_context)?;
+            let rotated = this.rekey_tree(&this.db, new_layers, str::to_string)?;
+            info!("Rekeyed {} rows to {} layers", rotated, new_layers);
+            match new_master_key {
+                Some(new_master_key) => {
+                    this.rotate_blocking(Arc::new(new_master_key), None, Some(&settings))?;
+                }
+                None => {
+                    this.meta.insert(ENCRYPTION_KEY, settings)?;
+                }
             }
-            info!(
-                "Deleted row from table {}: {}",
-                table_name_clone, row_id_clone
-            );
+            Ok(rotated)
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
-    // Truncate a table
-    pub async fn truncate_table(&self, table_name: &str) {
-        let table_name = table_name.to_string();
-        let db = self.db.clone();
-        let cache = self.cache.clone();
-        task::spawn_blocking(move || {
-            let mut cache = cache.write().unwrap();
-            let mut keys_to_remove = vec![];
-            for key in cache.iter() {
-                if key.0.starts_with(&table_name) {
-                    keys_to_remove.push(key.0.clone());
+    // Re-encrypt the entries of one tree, returning how many were replaced. `context` maps an
+    // entry's key to the context its record is encrypted for.
+    fn rekey_tree(
+        &self,
+        tree: &sled::Tree,
+        layers: usize,
+        context: fn(&str) -> String,
+    ) -> Result<usize, VibraError> {
+        let mut rotated = 0;
+        for key in tree.iter().keys() {
+            let key = key?;
+            let context = context(&String::from_utf8_lossy(&key));
+            loop {
+                let current = match tree.get(&key)? {
+                    Some(current) => current,
+                    None => break, // Deleted since the walk started
+                };
+                // Records of tables created without encryption have no keys to rotate. Rows with
+                // sealed columns do, and are sealed again as their table's columns are configured.
+                let unencrypted = RecordHeader::decode(&current)
+                    .is_ok_and(|(header, _)| header.is_plaintext() && !header.has_sealed_columns());
+                if unencrypted {
+                    break;
                 }
-            }
-            for key in keys_to_remove {
-                cache.pop(&key);
-            }
-            let mut keys_to_remove = vec![];
-            for key in db.iter() {
-                if let Ok((k, _)) = key {
-                    if let Ok(key_str) = str::from_utf8(&k) {
-                        if key_str.starts_with(&table_name) {
-                            keys_to_remove.push(key_str.to_string());
-                        }
-                    }
+                let value = self.decrypt_bytes(&context, &current)?;
+                let mut sealed = self.encrypt_with_layers(&context, &value, layers)?;
+                // Rekeying isn't a write as far as the row is concerned
+                Self::copy_record_time(&mut sealed, &current, Timestamp::Created);
+                Self::copy_record_time(&mut sealed, &current, Timestamp::Updated);
+                if tree.compare_and_swap(&key, Some(current), Some(sealed))?.is_ok() {
+                    rotated += 1;
+                    break;
                 }
             }
-            for key in keys_to_remove {
-                db.remove(key.as_bytes()).expect("Truncate table failed");
+        }
+        Ok(rotated)
+    }
+
+    // Verify that sled works, that values round-trip through encryption with the current config
+    // and that what is stored still reads with it.
+    //
+    // The sentinel goes straight to sled, skipping the cache, so the whole encrypt/store/decrypt
+    // path is exercised. Then each table is sampled, not read in full: its entry has to decode,
+    // its data key unwrap with the master key, and its schema record and its first row by key
+    // decrypt. A failure names what failed: MissingTableKey the table whose key doesn't unwrap,
+    // CorruptRow the key of any other entry or record.
+    pub async fn health_check(&self) -> Result<(), VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let mut sentinel = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut sentinel);
+            this.db.insert(HEALTH_KEY, this.encrypt_value(HEALTH_KEY, &sentinel)?)?;
+            let stored = this.db.remove(HEALTH_KEY)?;
+            let stored = stored.ok_or_else(|| {
+                VibraError::MalformedRecord("health check sentinel was not stored".to_string())
+            })?;
+            if this.decrypt_bytes(HEALTH_KEY, &stored)? != sentinel {
+                return Err(VibraError::IntegrityCheckFailed);
+            }
+
+            for entry in this.tables.iter() {
+                let (name, stored) = entry?;
+                this.check_table_health(&String::from_utf8_lossy(&name), &stored)?;
             }
-            info!("Truncated table: {}", table_name);
+            debug!("Health check passed");
+            Ok(())
         })
         .await
-        .unwrap();
+        .unwrap()
+    }
+
+    // Check health_check's sample of one table, given the name it is stored under and its entry
+    fn check_table_health(&self, table_name: &str, stored: &[u8]) -> Result<(), VibraError> {
+        let corrupt = |key: &str, source| VibraError::CorruptRow {
+            key: key.to_string(),
+            source: Box::new(source),
+        };
+        let meta = TableMeta::decode(stored).map_err(|err| corrupt(table_name, err))?;
+        if meta.data_key.is_some() {
+            let ring = self.key_ring();
+            let mut master_keys = std::iter::once(&ring.current).chain(&ring.previous);
+            if !master_keys.any(|key| meta.data_key(key, table_name).is_some()) {
+                return Err(VibraError::MissingTableKey(table_name.to_string()));
+            }
+        }
+        if let Some(sealed) = self.schema.get(table_name.as_bytes())? {
+            let context = Self::schema_context(table_name);
+            self.decrypt_bytes(&context, &sealed).map_err(|err| corrupt(&context, err))?;
+        }
+        if let Some(entry) = self.db.scan_prefix(format!("{}/", table_name)).next() {
+            let (key, stored) = entry?;
+            let key = String::from_utf8_lossy(&key);
+            self.decrypt_bytes(&key, &stored).map_err(|err| corrupt(&key, err))?;
+        }
+        Ok(())
+    }
+
+    // Copy the operation counters and latency histograms
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    // Report cache hits and misses of get_row, and how full the cache is
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache_counters.hits.load(Ordering::Relaxed),
+            misses: self.cache_counters.misses.load(Ordering::Relaxed),
+            len: self.cache.len(),
+            capacity: self.cache.cap(),
+            bytes: self.cache.bytes(),
+            max_bytes: self.cache.max_bytes(),
+            shards: self.cache.shard_count(),
+            poisoned: self.cache.poisoned(),
+        }
+    }
+
+    // Drop every cached row without touching sled
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    // Drop one row from the cache without touching sled
+    pub fn invalidate(&self, table_name: &str, row_id: &str) {
+        if let Ok(key) = self.row_key(table_name, row_id) {
+            self.cache.pop(&key);
+        }
     }
 
     // Truncate DB
-    pub async fn truncate_db(&self) {
+    pub async fn truncate_db(&self) -> Result<(), VibraError> {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let schema = self.schema.clone();
+        let tables = self.tables.clone();
+        let meta = self.meta.clone();
         let cache = self.cache.clone();
+        let table_keys = self.table_keys.clone();
+        let plaintext_tables = self.plaintext_tables.clone();
+        let blind_indexes = self.blind_indexes.clone();
+        let index = self.index.clone();
+        // Tables created from now on are still named with the names key, so it stays
+        let names_key = self.names.as_ref().map(|names| names.wrapped(&self.master_key()));
+        // As does the nonce sequence, so no nonce is handed out twice
+        let nonces = self.nonces.clone();
+        let this = self.clone();
         task::spawn_blocking(move || {
-            let mut cache = cache.write().unwrap();
+            let _writing = this.writing();
+            // Every table goes, so each is removed and recorded as deleted in one transaction
+            // before the rest is cleared
+            let names = tables.iter().keys().collect::<Result<Vec<_>, _>>()?;
+            this.commit_rows_held(|_, _, tables, changes| {
+                for name in &names {
+                    tables.remove(name)?;
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    changes.push((AuditOp::DeleteTable, name));
+                }
+                Ok(())
+            })?;
             cache.clear();
-            db.clear().expect("Truncate DB failed");
+            table_keys.write().unwrap_or_else(|p| p.into_inner()).clear();
+            plaintext_tables.write().unwrap_or_else(|p| p.into_inner()).clear();
+            blind_indexes.write().unwrap_or_else(|p| p.into_inner()).clear();
+            db.clear()?;
+            index.clear()?;
+            expiry.clear()?;
+            schema.clear()?;
+            tables.clear()?;
+            // As are the settings a rekey left, which new writes still use
+            let settings = meta.get(ENCRYPTION_KEY)?;
+            meta.clear()?;
+            if let Some(wrapped) = names_key {
+                meta.insert(names::NAMES_KEY, wrapped)?;
+            }
+            if let Some(settings) = settings {
+                meta.insert(ENCRYPTION_KEY, settings)?;
+            }
+            nonces.restore()?;
             info!("Truncated DB");
+            Ok(())
         })
         .await
-        .unwrap();
+        .unwrap()
     }
 
-    // Delete DB
-    pub async fn delete_db(&self) {
-        // Close the DB first
-        drop(self.db.clone());
+    // Flush the DB and close it, releasing its files and lock so it can be reopened.
+    //
+    // sled only closes once every handle is gone, and clones, snapshots and sweepers all share
+    // this one, so refuse to close while any of them are alive.
+    pub async fn close(self) -> Result<(), VibraError> {
+        if Arc::strong_count(&self.db) > 1 {
+            return Err(VibraError::InUse);
+        }
+        let VibraDB {
+            db,
+            expiry,
+            schema,
+            tables,
+            meta,
+            index,
+            audit,
+            ..
+        } = self;
+        task::spawn_blocking(move || {
+            db.flush()?;
+            // The trees share sled's internals, so they have to go too
+            drop(expiry);
+            drop(schema);
+            drop(tables);
+            drop(meta);
+            drop(index);
+            drop(audit);
+            drop(db);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
 
-        // Delete the DB directory
-        let path = &self.path;
-        fs::remove_dir_all(path).expect("Delete DB failed");
+    // Close the DB, as `close` does, and delete its directory
+    pub async fn delete_db(self) -> Result<(), VibraError> {
+        let path = self.path.clone().ok_or(VibraError::MissingConfig("path"))?;
+        self.close().await?;
+        task::spawn_blocking(move || {
+            fs::remove_dir_all(&path)?;
+            info!("Deleted DB at {:?}", path);
+            Ok(())
+        })
+        .await
+        .unwrap()
     }
 }
 
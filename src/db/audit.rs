@@ -0,0 +1,313 @@
+use super::crypto::ct_eq;
+use super::keys::MasterKey;
+use super::{now_millis, TransactionResult, VibraDB};
+use crate::error::VibraError;
+use crate::models::AuditReport;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::transaction::{TransactionalTree, Transactional};
+use std::sync::Mutex;
+use tokio::task;
+
+pub(super) const AUDIT_TREE: &str = "__vibra_audit"; // Entry number (u64, big endian) -> entry
+const MAC_LEN: usize = 32; // Bytes of HMAC-SHA256 each entry starts with
+
+type Hash = [u8; 32];
+
+// The changes a write made, each with the `table/id` key or table name it was made to
+pub(super) type Changes = Vec<(AuditOp, String)>;
+
+// A change the audit log records
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum AuditOp {
+    Insert,
+    Update,
+    Delete,
+    Expire, // A row removed once its TTL ran out, rather than deleted by a user
+    TruncateTable,
+    DeleteTable,
+}
+
+// One entry of the audit log. Entries are numbered from 0 with no gaps, and each holds the
+// SHA-256 of the one before it, so one can't be edited, removed or reordered without breaking
+// the chain. They are stored as [HMAC-SHA256 of the JSON][JSON], keyed by the master key, so the
+// chain can't be rebuilt past an edit without it either. Tables and ids are as they are stored,
+// i.e. pseudonymized in databases with `encrypt_names`.
+#[derive(Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    op: AuditOp,
+    table: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    row_id: Option<String>,
+    at: u64,      // Unix millis
+    prev: String, // Hex SHA-256 of the previous entry's JSON; all zeros for the first entry
+}
+
+// The audit log's tree, and where its next entry goes
+pub(super) struct AuditLog {
+    tree: sled::Tree,
+    enabled: bool, // Whether changes are recorded; a log written before is verified either way
+    head: Mutex<(u64, Hash)>, // The next entry's number and the hash of the last entry
+}
+
+impl AuditLog {
+    pub(super) fn open(db: &sled::Db, enabled: bool) -> Result<AuditLog, VibraError> {
+        let tree = db.open_tree(AUDIT_TREE)?;
+        // The next entry chains to the last one, whether or not that one is intact
+        let head = match tree.last()? {
+            Some((key, stored)) => (entry_number(&key).map_or(0, |seq| seq + 1), hash(&stored)),
+            None => (0, [0; 32]),
+        };
+        Ok(AuditLog {
+            tree,
+            enabled,
+            head: Mutex::new(head),
+        })
+    }
+
+    // Seal entries for `changes`, authenticated with `master_key` and chained on from `head`,
+    // passing each to `store` with its number. Returns the head after the last of them.
+    fn seal_entries<E: From<VibraError>>(
+        master_key: &MasterKey,
+        head: (u64, Hash),
+        changes: &[(AuditOp, String)],
+        mut store: impl FnMut(u64, Vec<u8>) -> Result<(), E>,
+    ) -> Result<(u64, Hash), E> {
+        let (mut seq, mut prev) = head;
+        for (op, key) in changes {
+            let (table, row_id) = match key.split_once('/') {
+                Some((table, row_id)) => (table, Some(row_id)),
+                None => (key.as_str(), None),
+            };
+            let entry = AuditEntry {
+                seq,
+                op: *op,
+                table: table.to_string(),
+                row_id: row_id.map(str::to_string),
+                at: now_millis(),
+                prev: hex(&prev),
+            };
+            let body = serde_json::to_vec(&entry).map_err(VibraError::from)?;
+            let stored = [mac(master_key, &body).as_slice(), &body].concat();
+            prev = hash(&stored);
+            store(seq, stored)?;
+            seq += 1;
+        }
+        Ok((seq, prev))
+    }
+}
+
+// The number an entry is stored under
+fn entry_number(key: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(key.try_into().ok()?))
+}
+
+// The hash the next entry chains to: that of the JSON, leaving out the MAC, so re-keying the MACs
+// leaves the chain as it is
+fn hash(stored: &[u8]) -> Hash {
+    Sha256::digest(stored.get(MAC_LEN..).unwrap_or_default()).into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(master_key: &MasterKey) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(&master_key.audit_key())
+        .expect("HMAC takes keys of any length")
+}
+
+fn mac(master_key: &MasterKey, body: &[u8]) -> Vec<u8> {
+    let mut mac = hmac(master_key);
+    mac.update(body);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Whether a stored entry's MAC is `master_key`'s, compared in constant time
+fn authenticates(master_key: &MasterKey, stored: &[u8]) -> bool {
+    let Some((tag, body)) = stored.split_at_checked(MAC_LEN) else {
+        return false;
+    };
+    ct_eq(&mac(master_key, body), tag)
+}
+
+// Why the entry stored as entry `seq` doesn't follow the one hashing to `prev`, if it doesn't
+fn check_entry(master_key: &MasterKey, seq: u64, prev: &Hash, stored: &[u8]) -> Option<String> {
+    if !authenticates(master_key, stored) {
+        return Some("its MAC doesn't match".to_string());
+    }
+    let entry: AuditEntry = match serde_json::from_slice(&stored[MAC_LEN..]) {
+        Ok(entry) => entry,
+        Err(err) => return Some(format!("it doesn't parse: {}", err)),
+    };
+    if entry.seq != seq {
+        return Some(format!("it is numbered {}", entry.seq));
+    }
+    if entry.prev != hex(prev) {
+        return Some(match seq {
+            0 => "it chains to an entry before the first".to_string(),
+            _ => format!("it doesn't chain to entry {}", seq - 1),
+        });
+    }
+    None
+}
+
+impl VibraDB {
+    // Commit a write to rows: `f` runs as one transaction over the rows, expiry and tables
+    // trees, reporting each change it makes in `changes`, and their audit log entries are stored
+    // in the same transaction, so a write is never committed without its entries or the other
+    // way round. Every change the audit log records is made this way. Sled retries `f` when it
+    // conflicts with a concurrent writer, so it may run more than once. Snapshots wait until it
+    // has committed. Blocks on sled.
+    pub(super) fn commit_rows<T, F>(&self, f: F) -> Result<T, VibraError>
+    where
+        F: Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &mut Changes,
+        ) -> TransactionResult<T>,
+    {
+        let _writing = self.writing();
+        self.commit_rows_held(f)
+    }
+
+    // commit_rows, for a caller already holding writing() across several commits, so snapshots
+    // see all of them or none
+    pub(super) fn commit_rows_held<T, F>(&self, f: F) -> Result<T, VibraError>
+    where
+        F: Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &mut Changes,
+        ) -> TransactionResult<T>,
+    {
+        self.commit(|rows, expiry, tables, _, changes| f(rows, expiry, tables, changes))
+    }
+
+    // commit_rows, for a write that creates a table along with its rows, so `f` is given the
+    // schema tree as well, after the tables tree
+    pub(super) fn commit_table_rows<T, F>(&self, f: F) -> Result<T, VibraError>
+    where
+        F: Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &mut Changes,
+        ) -> TransactionResult<T>,
+    {
+        let _writing = self.writing();
+        self.commit(f)
+    }
+
+    fn commit<T, F>(&self, f: F) -> Result<T, VibraError>
+    where
+        F: Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &TransactionalTree,
+            &mut Changes,
+        ) -> TransactionResult<T>,
+    {
+        let master_key = self.master_key();
+        // Held until the transaction commits, so entries are chained in the order writes land
+        let mut head = self
+            .audit
+            .enabled
+            .then(|| self.audit.head.lock().unwrap_or_else(|p| p.into_inner()));
+        let trees = (&**self.db, &self.expiry, &self.tables, &self.schema, &self.audit.tree);
+        let (value, next) = trees.transaction(|(rows, expiry, tables, schemas, log)| {
+            let mut changes = Vec::new();
+            let value = f(rows, expiry, tables, schemas, &mut changes)?;
+            let next = match &head {
+                Some(head) => Some(AuditLog::seal_entries(
+                    &master_key,
+                    **head,
+                    &changes,
+                    |seq, stored| {
+                        log.insert(&seq.to_be_bytes(), stored)?;
+                        TransactionResult::Ok(())
+                    },
+                )?),
+                None => None,
+            };
+            Ok((value, next))
+        })?;
+        if let (Some(head), Some(next)) = (&mut head, next) {
+            **head = next;
+        }
+        Ok(value)
+    }
+
+    // Walk the audit log from its first entry, checking that each one is authenticated with the
+    // master key and chains to the one before it, and stop at the first that doesn't
+    pub async fn verify_audit_log(&self) -> Result<AuditReport, VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let master_key = this.master_key();
+            let mut report = AuditReport::default();
+            let mut prev = [0; 32];
+            for (seq, entry) in (0u64..).zip(this.audit.tree.iter()) {
+                let (key, stored) = entry?;
+                let broken = match entry_number(&key) {
+                    Some(number) if number == seq => check_entry(&master_key, seq, &prev, &stored),
+                    _ => Some("it is missing".to_string()),
+                };
+                if let Some(reason) = broken {
+                    warn!("The audit log is broken at entry {}: {}", seq, reason);
+                    report.first_broken = Some((seq, reason));
+                    return Ok(report);
+                }
+                prev = hash(&stored);
+                report.verified += 1;
+            }
+            // Entries this handle wrote that are gone from the end
+            let next = this.audit.head.lock().unwrap_or_else(|p| p.into_inner()).0;
+            if (report.verified as u64) < next {
+                let seq = report.verified as u64;
+                warn!("The audit log is broken at entry {}: it is missing", seq);
+                report.first_broken = Some((seq, "it is missing".to_string()));
+                return Ok(report);
+            }
+            info!("Verified {} audit log entries: the chain is intact", report.verified);
+            Ok(report)
+        })
+        .await
+        .unwrap()
+    }
+
+    // Authenticate the audit log's entries with the current master key where they are with the
+    // previous one, after a rotation. Entries neither key authenticates are left to fail
+    // verification. Blocks on sled.
+    pub(super) fn reseal_audit_log(&self) -> Result<usize, VibraError> {
+        let (current, previous) = {
+            let ring = self.key_ring();
+            (ring.current.clone(), ring.previous.clone())
+        };
+        let Some(previous) = previous else {
+            return Ok(0);
+        };
+        let mut resealed = 0;
+        for entry in self.audit.tree.iter() {
+            let (key, stored) = entry?;
+            if authenticates(&current, &stored) || !authenticates(&previous, &stored) {
+                continue;
+            }
+            let body = &stored[MAC_LEN..];
+            let sealed = [mac(&current, body).as_slice(), body].concat();
+            self.audit.tree.insert(key, sealed)?;
+            resealed += 1;
+        }
+        if resealed > 0 {
+            info!("Re-authenticated {} audit log entries with the new master key", resealed);
+        }
+        Ok(resealed)
+    }
+}
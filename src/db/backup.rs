@@ -0,0 +1,414 @@
+use super::audit::AuditOp;
+use super::keys::{MasterKey, SALT_LEN};
+use super::{has_expired, record, RecordHeader, TableMeta, Timestamp, VibraDB};
+use crate::config::VibraConfig;
+use crate::error::VibraError;
+use crate::models::Column;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use log::info;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tokio::sync::mpsc;
+use tokio::task;
+use zeroize::Zeroizing;
+
+// Backups start with these bytes, followed by the format version and the backup's salt
+const BACKUP_MAGIC: &[u8; 8] = b"VIBRABAK";
+const BACKUP_VERSION: u8 = 1;
+const RESTORE_BATCH: usize = 256; // Rows re-encrypted and written together while restoring
+
+// A backup holds every table and live row of a database, decrypted and sealed again under keys
+// derived from a backup key, so it can be restored without the master key. Layout, big endian:
+// [magic: 8 bytes][version: u8][salt: 32 bytes] then frames of [length: u32][ciphertext].
+// Each frame is sealed with AES-256-GCM under a key derived from the backup key and the salt,
+// with the frame's index as its nonce and the bytes before the first frame as associated data,
+// so frames can't be reordered, dropped or moved to another backup unnoticed. A frame's
+// plaintext is [metadata length: u32][metadata JSON][value]: a table, followed by its rows,
+// whose values are their plaintext as stored, and a last frame counting them.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum FrameMeta {
+    Table {
+        name: String,
+        schema: Option<Vec<Column>>,
+        blind_indexes: Vec<String>,
+    },
+    Row(BackupRow),
+    End {
+        tables: u64,
+        rows: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupRow {
+    id: String,
+    expires_at: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+struct Frame {
+    meta: FrameMeta,
+    value: Zeroizing<Vec<u8>>, // Only rows have one
+}
+
+// A frame's nonce: its index, which no other frame of the backup shares
+fn frame_nonce(index: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+struct BackupWriter {
+    out: BufWriter<File>,
+    cipher: Aes256Gcm,
+    header: Vec<u8>, // Every frame's associated data
+    frames: u64,
+}
+
+impl BackupWriter {
+    fn create(dest: &Path, backup_key: &MasterKey, salt: &[u8]) -> Result<Self, VibraError> {
+        let header = [&BACKUP_MAGIC[..], &[BACKUP_VERSION], salt].concat();
+        let mut out = BufWriter::new(File::create(dest)?);
+        out.write_all(&header)?;
+        Ok(BackupWriter {
+            out,
+            cipher: backup_key.backup_cipher(salt),
+            header,
+            frames: 0,
+        })
+    }
+
+    fn write(&mut self, meta: &FrameMeta, value: &[u8]) -> Result<(), VibraError> {
+        let meta = serde_json::to_vec(meta)?;
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(4 + meta.len() + value.len()));
+        plaintext.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(&meta);
+        plaintext.extend_from_slice(value);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &self.header,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&frame_nonce(self.frames), payload)
+            .map_err(|_| VibraError::Encryption { layer: 0 })?;
+        self.out.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        self.out.write_all(&ciphertext)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), VibraError> {
+        let file = self.out.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+struct BackupReader {
+    input: BufReader<File>,
+    cipher: Aes256Gcm,
+    header: Vec<u8>,
+    frames: u64,
+}
+
+impl BackupReader {
+    fn open(src: &Path, backup_key: &MasterKey) -> Result<Self, VibraError> {
+        let mut input = BufReader::new(File::open(src)?);
+        let mut header = vec![0u8; BACKUP_MAGIC.len() + 1 + SALT_LEN];
+        if input.read_exact(&mut header).is_err() || !header.starts_with(BACKUP_MAGIC) {
+            return Err(invalid("not a vibra backup"));
+        }
+        let version = header[BACKUP_MAGIC.len()];
+        if version != BACKUP_VERSION {
+            return Err(invalid(&format!("unsupported backup version {}", version)));
+        }
+        let salt = &header[BACKUP_MAGIC.len() + 1..];
+        Ok(BackupReader {
+            cipher: backup_key.backup_cipher(salt),
+            input,
+            header,
+            frames: 0,
+        })
+    }
+
+    // The next frame, or None at the end of the file
+    fn next_frame(&mut self) -> Result<Option<Frame>, VibraError> {
+        let mut len = [0u8; 4];
+        match self.input.read(&mut len[..1])? {
+            0 => return Ok(None),
+            _ => self.read_exact(&mut len[1..])?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        // Only allocate what the file holds, whatever the length claims
+        let mut ciphertext = Vec::new();
+        self.input.by_ref().take(len as u64).read_to_end(&mut ciphertext)?;
+        if ciphertext.len() != len {
+            return Err(invalid("unexpected end of file"));
+        }
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &self.header,
+        };
+        // Nothing authenticates under a wrong key, the first frame included
+        let plaintext = match self.cipher.decrypt(&frame_nonce(self.frames), payload) {
+            Ok(plaintext) => Zeroizing::new(plaintext),
+            Err(_) if self.frames == 0 => return Err(VibraError::InvalidBackupKey),
+            Err(_) => {
+                return Err(invalid(&format!("frame {} fails to authenticate", self.frames)))
+            }
+        };
+        self.frames += 1;
+        let meta_len = plaintext
+            .get(..4)
+            .map(|len| u32::from_be_bytes(len.try_into().unwrap()) as usize)
+            .filter(|len| 4 + len <= plaintext.len())
+            .ok_or_else(|| invalid("truncated frame"))?;
+        let meta = serde_json::from_slice(&plaintext[4..4 + meta_len])?;
+        let value = Zeroizing::new(plaintext[4 + meta_len..].to_vec());
+        Ok(Some(Frame { meta, value }))
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), VibraError> {
+        self.input
+            .read_exact(buf)
+            .map_err(|_| invalid("unexpected end of file"))
+    }
+}
+
+// Read every frame of a backup, in order, checking that it is whole: rows follow a table, and
+// the last frame counts every table and row before it
+fn read_backup(
+    src: &Path,
+    backup_key: &MasterKey,
+    mut each: impl FnMut(Frame) -> Result<(), VibraError>,
+) -> Result<(), VibraError> {
+    let mut reader = BackupReader::open(src, backup_key)?;
+    let (mut tables, mut rows) = (0, 0);
+    loop {
+        let frame = reader.next_frame()?.ok_or_else(|| invalid("unexpected end of file"))?;
+        match &frame.meta {
+            FrameMeta::Table { .. } => tables += 1,
+            FrameMeta::Row(_) if tables == 0 => return Err(invalid("row outside any table")),
+            FrameMeta::Row(_) => rows += 1,
+            FrameMeta::End {
+                tables: counted_tables,
+                rows: counted_rows,
+            } => {
+                if (*counted_tables, *counted_rows) != (tables, rows) {
+                    return Err(invalid("frames are missing"));
+                }
+                if reader.next_frame()?.is_some() {
+                    return Err(invalid("trailing data after the last frame"));
+                }
+                return each(frame);
+            }
+        }
+        each(frame)?;
+    }
+}
+
+fn invalid(reason: &str) -> VibraError {
+    VibraError::InvalidBackup(reason.to_string())
+}
+
+impl VibraDB {
+    // Write every table and live row to a single file at `dest`, encrypted under `backup_key`
+    // rather than the master key, returning how many rows it holds.
+    //
+    // Rows are decrypted and sealed again one at a time as they are read, so the database is
+    // never held in memory, and with its schema and blind indexes every table can be rebuilt by
+    // `restore_encrypted` with only the backup key. Rows written while the backup runs may or may
+    // not be in it. A backup that fails is removed rather than left half written.
+    pub async fn backup_encrypted(
+        &self,
+        dest: impl AsRef<Path>,
+        backup_key: &MasterKey,
+    ) -> Result<usize, VibraError> {
+        let dest = dest.as_ref().to_path_buf();
+        let backup_key = backup_key.clone();
+        let this = self.clone();
+        let path = dest.clone();
+        let rows = task::spawn_blocking(move || {
+            let written = this.write_backup(&path, &backup_key);
+            if written.is_err() {
+                let _ = fs::remove_file(&path);
+            }
+            written
+        })
+        .await
+        .unwrap()?;
+        info!("Backed up {} rows to {:?}", rows, dest);
+        Ok(rows)
+    }
+
+    // Write a backup's frames: each table, then its rows. Blocks on sled.
+    fn write_backup(&self, dest: &Path, backup_key: &MasterKey) -> Result<usize, VibraError> {
+        let mut salt = [0u8; SALT_LEN];
+        self.with_rng(|rng| rng.fill_bytes(&mut salt));
+        let mut out = BackupWriter::create(dest, backup_key, &salt)?;
+        let (mut tables, mut rows) = (0, 0);
+        for (stored_table, table_name) in self.table_names("")? {
+            // A table deleted since it was listed is left out
+            let Some(stored) = self.tables.get(stored_table.as_bytes())? else {
+                continue;
+            };
+            let table = FrameMeta::Table {
+                schema: self.table_schema(&table_name)?,
+                blind_indexes: TableMeta::decode(&stored)?.blind_indexes,
+                name: table_name,
+            };
+            out.write(&table, &[])?;
+            tables += 1;
+            for entry in self.db.scan_prefix(format!("{}/", stored_table).as_bytes()) {
+                let (key, stored) = entry?;
+                let key = String::from_utf8_lossy(&key).into_owned();
+                let expires_at = self.expiry.get(key.as_bytes())?;
+                if has_expired(expires_at.as_ref()) {
+                    continue;
+                }
+                Self::check_record_len(&key, &stored)?;
+                let value = Zeroizing::new(self.decrypt_bytes(&key, &stored)?);
+                let (header, _) = RecordHeader::decode(&stored)?;
+                let row = BackupRow {
+                    id: self.row_id_of(&key)?,
+                    expires_at: expires_at
+                        .and_then(|ivec| <[u8; 8]>::try_from(ivec.as_ref()).ok())
+                        .map(u64::from_be_bytes),
+                    created_at: header.time(Timestamp::Created),
+                    updated_at: header.time(Timestamp::Updated),
+                };
+                out.write(&FrameMeta::Row(row), &value)?;
+                rows += 1;
+            }
+        }
+        out.write(&FrameMeta::End { tables, rows }, &[])?;
+        out.finish()?;
+        Ok(rows as usize)
+    }
+
+    // Rebuild a database from a backup written by `backup_encrypted`, opening it with `config`.
+    //
+    // The whole backup is read and authenticated before the database is opened, so a wrong
+    // backup key fails with InvalidBackupKey, and a damaged backup with InvalidBackup, without
+    // anything being written. The database must not have any tables yet. Tables are created with
+    // their schemas, and settings such as encryption or sensitive columns, from `config`; rows
+    // are encrypted with its master key, keeping their write times and expiry, and blind indexes
+    // are rebuilt once every row is in.
+    pub async fn restore_encrypted(
+        src: impl AsRef<Path>,
+        backup_key: &MasterKey,
+        config: VibraConfig,
+    ) -> Result<VibraDB, VibraError> {
+        let src = src.as_ref().to_path_buf();
+        let (path, key) = (src.clone(), backup_key.clone());
+        task::spawn_blocking(move || read_backup(&path, &key, |_| Ok(())))
+            .await
+            .unwrap()?;
+
+        let db = Self::new(config)?;
+        if !db.tables.is_empty() {
+            return Err(VibraError::InvalidConfig(
+                "a backup can only be restored into a database without tables".to_string(),
+            ));
+        }
+        // The file is read again, a bounded number of frames ahead of the writes
+        let (frames_tx, mut frames) = mpsc::channel(RESTORE_BATCH);
+        let (path, key) = (src.clone(), backup_key.clone());
+        let reader = task::spawn_blocking(move || {
+            read_backup(&path, &key, |frame| {
+                frames_tx
+                    .blocking_send(frame)
+                    .map_err(|_| invalid("the restore stopped early"))
+            })
+        });
+        let restored = db.restore_frames(&mut frames).await;
+        drop(frames);
+        let read = reader.await.unwrap();
+        let rows = restored?;
+        read?;
+        info!("Restored {} rows from {:?}", rows, src);
+        Ok(db)
+    }
+
+    // Create each table of a backup and write its rows in batches, returning how many were
+    // written
+    async fn restore_frames(&self, frames: &mut mpsc::Receiver<Frame>) -> Result<usize, VibraError> {
+        let mut table_name = String::new();
+        let mut batch = Vec::with_capacity(RESTORE_BATCH);
+        let mut blind_indexes = vec![];
+        let mut rows = 0;
+        while let Some(frame) = frames.recv().await {
+            match frame.meta {
+                FrameMeta::Table {
+                    name,
+                    schema,
+                    blind_indexes: columns,
+                } => {
+                    rows += self.restore_rows(&table_name, std::mem::take(&mut batch)).await?;
+                    self.create_table(&name, schema).await?;
+                    blind_indexes.extend(columns.into_iter().map(|column| (name.clone(), column)));
+                    table_name = name;
+                }
+                FrameMeta::Row(row) => {
+                    batch.push((row, frame.value));
+                    if batch.len() == RESTORE_BATCH {
+                        rows += self.restore_rows(&table_name, std::mem::take(&mut batch)).await?;
+                    }
+                }
+                FrameMeta::End { .. } => {
+                    rows += self.restore_rows(&table_name, std::mem::take(&mut batch)).await?;
+                }
+            }
+        }
+        for (table_name, column) in blind_indexes {
+            self.create_blind_index(&table_name, &column).await?;
+        }
+        Ok(rows)
+    }
+
+    // Encrypt rows read from a backup and write them to a table in one batch
+    async fn restore_rows(
+        &self,
+        table_name: &str,
+        rows: Vec<(BackupRow, Zeroizing<Vec<u8>>)>,
+    ) -> Result<usize, VibraError> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        task::spawn_blocking(move || {
+            let sealed = rows
+                .par_iter()
+                .map(|(row, value)| {
+                    let key = this.row_key(&table_name, &row.id)?;
+                    let mut sealed = this.encrypt_value(&key, value)?;
+                    record::set_time(&mut sealed, Timestamp::Created, row.created_at);
+                    record::set_time(&mut sealed, Timestamp::Updated, row.updated_at);
+                    Ok((key, row.expires_at, sealed))
+                })
+                .collect::<Result<Vec<_>, VibraError>>()?;
+            this.commit_rows(|rows, expiry, _, changes| {
+                for (key, expires_at, sealed) in &sealed {
+                    if let Some(expires_at) = expires_at {
+                        expiry.insert(key.as_bytes(), &expires_at.to_be_bytes())?;
+                    }
+                    rows.insert(key.as_bytes(), sealed.as_slice())?;
+                    // The database had no tables before the restore, so every row is new
+                    changes.push((AuditOp::Insert, key.clone()));
+                }
+                Ok(())
+            })?;
+            Ok(sealed.len())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -0,0 +1,314 @@
+use super::crypto::ct_eq;
+use super::keys::SecretBytes;
+use super::{TableMeta, VibraDB};
+use crate::error::VibraError;
+use crate::models::{Row, Value};
+use hmac::{Hmac, Mac};
+use log::{info, Level};
+use sha2::Sha256;
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use tokio::task;
+use zeroize::Zeroizing;
+
+const MAC_LEN: usize = 32; // HMAC-SHA256
+
+// Blind indexes map an HMAC of a column's value to the rows holding it, so rows can be found by
+// value without the index revealing any. The index tree holds two kinds of entries:
+//
+// - `table/mac/id` -> empty, one per indexed value of a row, the MAC in hex
+// - `table/id` -> the MACs of the row's indexed values, concatenated, so they can be removed
+//   when the row changes without knowing what it held before
+//
+// Tables and rows appear under the names they are stored under (see names.rs). A MAC is 64 hex
+// digits and row ids never contain '/', so the two never overlap, and the lookups for a value are
+// the keys under `table/mac/`.
+impl VibraDB {
+    // Index a column of a table for find_by, returning how many of its rows hold a value for it.
+    //
+    // The index is kept up to date by every write from then on, and rebuilt from the rows already
+    // stored, so calling this again for an indexed column repairs its index. Tables with a schema
+    // can only index a declared column.
+    pub async fn create_blind_index(
+        &self,
+        table_name: &str,
+        column: &str,
+    ) -> Result<usize, VibraError> {
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        if let Some(schema) = self.table_schema(table_name)? {
+            if !schema.iter().any(|c| c.name == column) {
+                return Err(VibraError::SchemaViolation {
+                    table: table_name.to_string(),
+                    column: column.to_string(),
+                    reason: "can't index a column the schema doesn't declare".to_string(),
+                });
+            }
+        }
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        let column = column.to_string();
+        let indexed = task::spawn_blocking(move || {
+            this.register_blind_index(&this.stored_table(&table_name), &column)?;
+            // Writes from now on index themselves, so only rows stored before need indexing
+            let prefix = this.table_prefix(&table_name);
+            let mut indexed = 0;
+            for key in this.db.scan_prefix(prefix.as_bytes()).keys() {
+                let key = String::from_utf8_lossy(&key?).into_owned();
+                let row = this.reindex_row(&key)?;
+                if row.is_some_and(|row| row.columns.iter().any(|(name, _)| name == &column)) {
+                    indexed += 1;
+                }
+            }
+            this.log_op(
+                Level::Debug,
+                format_args!("Indexed {} rows of {} by {}", indexed, table_name, column),
+            );
+            Ok::<_, VibraError>(indexed)
+        })
+        .await
+        .unwrap()?;
+        Ok(indexed)
+    }
+
+    // Retrieve the rows of a table whose indexed column equals `value`, ordered by row id.
+    //
+    // Text is compared with surrounding whitespace trimmed and case folded, binary values as they
+    // are. Columns without a blind index that the config encrypts deterministically are matched
+    // exactly by their ciphertext instead. Fails with NotIndexed for any other column.
+    pub async fn find_by(
+        &self,
+        table_name: &str,
+        column: &str,
+        value: impl Into<Value>,
+    ) -> Result<Vec<Row>, VibraError> {
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        let value = value.into();
+        let stored_table = self.stored_table(table_name).into_owned();
+        if !self.indexed_columns(&stored_table).is_some_and(|columns| columns.contains(column)) {
+            let deterministic = self.deterministic.get(&stored_table);
+            if deterministic.is_some_and(|columns| columns.contains(column)) {
+                return self.find_by_ciphertext(table_name, column, &value).await;
+            }
+            return Err(VibraError::NotIndexed {
+                table: table_name.to_string(),
+                column: column.to_string(),
+            });
+        }
+        let mac = Self::index_mac(&self.master_key().index_key(&stored_table, column), &value);
+        let prefix = format!("{}/{}/", stored_table, to_hex(&mac));
+        let this = self.clone();
+        let mut ids = task::spawn_blocking(move || {
+            let mut ids = vec![];
+            for key in this.index.scan_prefix(prefix.as_bytes()).keys() {
+                let stored_id = String::from_utf8_lossy(&key?[prefix.len()..]).into_owned();
+                ids.push(this.logical_id(&stored_table, &stored_id)?);
+            }
+            Ok::<_, VibraError>(ids)
+        })
+        .await
+        .unwrap()?;
+        // Encrypted ids aren't stored in id order
+        ids.sort();
+
+        // Entries can outlive a change to their row for a moment, and MACs can collide, so each
+        // row is checked against the value
+        let (wanted_kind, wanted) = normalize(&value);
+        let mut rows = vec![];
+        for id in ids {
+            if let Some(row) = self.get_row(table_name, &id).await? {
+                let matches = row.columns.iter().any(|(name, v)| {
+                    let (kind, normalized) = normalize(v);
+                    name == column && kind == wanted_kind && ct_eq(&normalized, &wanted)
+                });
+                if matches {
+                    rows.push(row);
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    // Find the columns every table has a blind index for
+    pub(super) fn load_blind_indexes(
+        tables: &sled::Tree,
+    ) -> Result<HashMap<String, HashSet<String>>, VibraError> {
+        let mut blind_indexes = HashMap::new();
+        for entry in tables.iter() {
+            let (name, stored) = entry?;
+            let meta = TableMeta::decode(&stored)?;
+            if !meta.blind_indexes.is_empty() {
+                let table_name = String::from_utf8_lossy(&name).into_owned();
+                blind_indexes.insert(table_name, meta.blind_indexes.into_iter().collect());
+            }
+        }
+        Ok(blind_indexes)
+    }
+
+    // Record a column's index in the entry of the table stored under `table_name`, so it is kept
+    // after reopening. Blocks on sled.
+    fn register_blind_index(&self, table_name: &str, column: &str) -> Result<(), VibraError> {
+        loop {
+            let stored = self
+                .tables
+                .get(table_name.as_bytes())?
+                .ok_or_else(|| VibraError::TableNotFound(table_name.to_string()))?;
+            let mut meta = TableMeta::decode(&stored)?;
+            if meta.blind_indexes.iter().any(|indexed| indexed == column) {
+                break;
+            }
+            meta.blind_indexes.push(column.to_string());
+            let name = table_name.as_bytes();
+            if self.tables.compare_and_swap(name, Some(stored), Some(meta.encode()?))?.is_ok() {
+                break;
+            }
+        }
+        self.blind_indexes
+            .write()
+            .unwrap_or_else(|p| p.into_inner())
+            .entry(table_name.to_string())
+            .or_default()
+            .insert(column.to_string());
+        Ok(())
+    }
+
+    // The columns of the table stored under `table_name` with a blind index, if it has any
+    fn indexed_columns(&self, table_name: &str) -> Option<HashSet<String>> {
+        let blind_indexes = self.blind_indexes.read().unwrap_or_else(|p| p.into_inner());
+        blind_indexes.get(table_name).cloned()
+    }
+
+    // Bring the index entries of a row in line with what is stored under its key, returning the
+    // row as indexed. Rows that are gone, or don't decode as rows, have no entries. Blocks on sled.
+    //
+    // The row is read and its entries replaced in one transaction, so of concurrent calls for a
+    // row, the last to commit saw its latest value. Every write calls this once it has landed.
+    pub(super) fn reindex_row(&self, key: &str) -> Result<Option<Row>, VibraError> {
+        // The stored table name and row id, which the index entries are made of
+        let Some((table_name, row_id)) = key.split_once('/') else {
+            return Ok(None);
+        };
+        let Some(columns) = self.indexed_columns(table_name) else {
+            return Ok(None);
+        };
+        let master_key = self.master_key();
+        let index_keys: HashMap<String, SecretBytes> = columns
+            .into_iter()
+            .map(|column| {
+                let index_key = master_key.index_key(table_name, &column);
+                (column, index_key)
+            })
+            .collect();
+        let row = (&**self.db, &self.index).transaction(|(rows, index)| {
+            let row = match rows.get(key.as_bytes())? {
+                // A value stored with insert_typed isn't a row, and has nothing to index
+                Some(stored) => self.decode_entry(key, &stored).ok(),
+                None => None,
+            };
+            let macs: BTreeSet<[u8; MAC_LEN]> = row
+                .iter()
+                .flat_map(|row| &row.columns)
+                .filter_map(|(name, value)| Some(Self::index_mac(index_keys.get(name)?, value)))
+                .collect();
+            if let Some(previous) = index.get(key.as_bytes())? {
+                for mac in previous.chunks_exact(MAC_LEN) {
+                    if !macs.contains(mac) {
+                        index.remove(Self::lookup_key(table_name, mac, row_id).as_bytes())?;
+                    }
+                }
+            }
+            for mac in &macs {
+                index.insert(Self::lookup_key(table_name, mac, row_id).as_bytes(), &[])?;
+            }
+            if macs.is_empty() {
+                index.remove(key.as_bytes())?;
+            } else {
+                let macs: Vec<u8> = macs.iter().flatten().copied().collect();
+                index.insert(key.as_bytes(), macs)?;
+            }
+            Ok::<_, ConflictableTransactionError<VibraError>>(row)
+        })?;
+        Ok(row)
+    }
+
+    // Reindex rows after writing them, skipping the work for tables without a blind index
+    pub(super) fn reindex_rows<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), VibraError> {
+        for key in keys {
+            self.reindex_row(key)?;
+        }
+        Ok(())
+    }
+
+    // Recompute every blind index, e.g. with a new master key, returning how many rows were
+    // reindexed. Blocks on sled.
+    pub(super) fn rebuild_blind_indexes(&self) -> Result<usize, VibraError> {
+        let tables: Vec<String> = {
+            let blind_indexes = self.blind_indexes.read().unwrap_or_else(|p| p.into_inner());
+            blind_indexes.keys().cloned().collect()
+        };
+        let mut reindexed = 0;
+        for table_name in tables {
+            let prefix = format!("{}/", table_name);
+            for key in self.db.scan_prefix(prefix.as_bytes()).keys() {
+                self.reindex_row(&String::from_utf8_lossy(&key?))?;
+                reindexed += 1;
+            }
+        }
+        if reindexed > 0 {
+            info!("Rebuilt the blind indexes of {} rows", reindexed);
+        }
+        Ok(reindexed)
+    }
+
+    // Remove the index entries of every row of the table stored under `table_name`, keeping its
+    // indexes. Blocks on sled.
+    pub(super) fn clear_index_entries(&self, table_name: &str) -> Result<(), VibraError> {
+        let prefix = format!("{}/", table_name);
+        let mut batch = sled::Batch::default();
+        for key in self.index.scan_prefix(prefix.as_bytes()).keys() {
+            batch.remove(key?);
+        }
+        self.index.apply_batch(batch)?;
+        Ok(())
+    }
+
+    // Remove the indexes of the table stored under `table_name` along with their entries, for a
+    // table being deleted
+    pub(super) fn drop_blind_indexes(&self, table_name: &str) -> Result<(), VibraError> {
+        self.blind_indexes.write().unwrap_or_else(|p| p.into_inner()).remove(table_name);
+        self.clear_index_entries(table_name)
+    }
+
+    // The MAC a value is indexed under: HMAC-SHA256 with the column's index key over its
+    // normalized form
+    fn index_mac(index_key: &[u8], value: &Value) -> [u8; MAC_LEN] {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(index_key)
+            .expect("HMAC takes keys of any length");
+        let (kind, bytes) = normalize(value);
+        mac.update(&[kind]);
+        mac.update(&bytes);
+        mac.finalize().into_bytes().into()
+    }
+
+    // The key of the entry that finds a row by one of its indexed values
+    fn lookup_key(table_name: &str, mac: &[u8], row_id: &str) -> String {
+        format!("{}/{}/{}", table_name, to_hex(mac), row_id)
+    }
+}
+
+// What a value is indexed and compared as: its kind, so text and bytes never match, and text with
+// surrounding whitespace trimmed and lowercased
+fn normalize(value: &Value) -> (u8, Zeroizing<Vec<u8>>) {
+    match value {
+        Value::Text(text) => (0, Zeroizing::new(text.trim().to_lowercase().into_bytes())),
+        Value::Bytes(bytes) => (1, Zeroizing::new(bytes.clone())),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,127 @@
+use super::VibraDB;
+use crate::config::VibraConfig;
+use crate::error::VibraError;
+use crate::models::{Column, Row};
+use tokio::runtime::{Builder, Runtime};
+
+/// A synchronous wrapper around `VibraDB` for callers that don't run a tokio runtime, such as
+/// CLIs or sync services. Available with the `blocking` feature.
+///
+/// The wrapper owns a small runtime of its own and drives each async method to completion on it,
+/// so every method returns the same `Result` as its async counterpart. Because it blocks the
+/// calling thread, it must not be used from inside an async context; use `VibraDB` there.
+///
+/// # Methods
+///
+/// - `new(config: VibraConfig) -> Result<VibraDbBlocking, VibraError>`
+///   - Opens the database like `VibraDB::new`.
+///
+/// - `open_with_passphrase(config: VibraConfig, passphrase: &str) -> Result<VibraDbBlocking, VibraError>`
+///   - Opens the database like `VibraDB::open_with_passphrase`.
+///
+/// - `from_async(db: VibraDB) -> Result<VibraDbBlocking, VibraError>`
+///   - Wraps an already-open `VibraDB`.
+///
+/// - `as_async(&self) -> &VibraDB`
+///   - Returns the wrapped `VibraDB`.
+///
+/// - `create_table(&self, table_name: &str, schema: Option<Vec<Column>>) -> Result<bool, VibraError>`
+///   - Creates a table, returning whether it was created.
+///
+/// - `table_exists(&self, table_name: &str) -> Result<bool, VibraError>`
+///   - Checks whether a table exists.
+///
+/// - `insert_row(&self, table_name: &str, row: Row) -> Result<Option<Row>, VibraError>`
+///   - Inserts a row, returning the row it replaced, if any.
+///
+/// - `insert_row_if_absent(&self, table_name: &str, row: Row) -> Result<bool, VibraError>`
+///   - Inserts a row only if its id isn't taken, returning whether it was inserted.
+///
+/// - `insert_many_rows(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError>`
+///   - Inserts multiple rows in a single batch.
+///
+/// - `update_row(&self, table_name: &str, row: Row) -> Result<(), VibraError>`
+///   - Updates a row.
+///
+/// - `get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError>`
+///   - Retrieves a row.
+///
+/// - `scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError>`
+///   - Retrieves every row of a table, ordered by row id.
+///
+/// - `delete_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError>`
+///   - Deletes a row, returning the row that was removed, if any.
+pub struct VibraDbBlocking {
+    db: VibraDB,
+    runtime: Runtime,
+}
+
+impl VibraDbBlocking {
+    // Open a database with a runtime of its own
+    pub fn new(config: VibraConfig) -> Result<VibraDbBlocking, VibraError> {
+        Self::from_async(VibraDB::new(config)?)
+    }
+
+    // Open a database whose master key is locked with a passphrase
+    pub fn open_with_passphrase(
+        config: VibraConfig,
+        passphrase: &str,
+    ) -> Result<VibraDbBlocking, VibraError> {
+        Self::from_async(VibraDB::open_with_passphrase(config, passphrase)?)
+    }
+
+    // Wrap an already-open database
+    pub fn from_async(db: VibraDB) -> Result<VibraDbBlocking, VibraError> {
+        // Storage work already runs on the blocking pool, so one worker thread is enough
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        Ok(VibraDbBlocking { db, runtime })
+    }
+
+    // The wrapped async database
+    pub fn as_async(&self) -> &VibraDB {
+        &self.db
+    }
+
+    pub fn create_table(
+        &self,
+        table_name: &str,
+        schema: Option<Vec<Column>>,
+    ) -> Result<bool, VibraError> {
+        self.runtime.block_on(self.db.create_table(table_name, schema))
+    }
+
+    pub fn table_exists(&self, table_name: &str) -> Result<bool, VibraError> {
+        self.runtime.block_on(self.db.table_exists(table_name))
+    }
+
+    pub fn insert_row(&self, table_name: &str, row: Row) -> Result<Option<Row>, VibraError> {
+        self.runtime.block_on(self.db.insert_row(table_name, row))
+    }
+
+    pub fn insert_row_if_absent(&self, table_name: &str, row: Row) -> Result<bool, VibraError> {
+        self.runtime.block_on(self.db.insert_row_if_absent(table_name, row))
+    }
+
+    pub fn insert_many_rows(&self, table_name: &str, rows: Vec<Row>) -> Result<(), VibraError> {
+        self.runtime.block_on(self.db.insert_many_rows(table_name, rows))
+    }
+
+    pub fn update_row(&self, table_name: &str, row: Row) -> Result<(), VibraError> {
+        self.runtime.block_on(self.db.update_row(table_name, row))
+    }
+
+    pub fn get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError> {
+        self.runtime.block_on(self.db.get_row(table_name, row_id))
+    }
+
+    pub fn scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError> {
+        self.runtime.block_on(self.db.scan_table(table_name))
+    }
+
+    pub fn delete_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError> {
+        self.runtime.block_on(self.db.delete_row(table_name, row_id))
+    }
+}
+
+#[cfg(test)]
+mod blocking_tests;
@@ -0,0 +1,58 @@
+use super::*;
+use crate::MasterKey;
+use tempfile::tempdir;
+
+fn open() -> VibraDbBlocking {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: Some(MasterKey::from_bytes([7; 32])),
+        ..Default::default()
+    };
+    VibraDbBlocking::new(config).unwrap()
+}
+
+#[test]
+fn test_blocking_round_trip() {
+    let db = open();
+    assert!(db.create_table("users", None).unwrap());
+    assert!(db.table_exists("users").unwrap());
+
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    assert_eq!(db.insert_row("users", row.clone()).unwrap(), None);
+    assert_eq!(db.get_row("users", "user1").unwrap(), Some(row.clone()));
+
+    let updated = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.update_row("users", updated.clone()).unwrap();
+    assert_eq!(db.scan_table("users").unwrap(), vec![updated.clone()]);
+
+    assert_eq!(db.delete_row("users", "user1").unwrap(), Some(updated));
+    assert_eq!(db.get_row("users", "user1").unwrap(), None);
+}
+
+#[test]
+fn test_blocking_returns_the_same_errors() {
+    let db = open();
+    assert!(matches!(
+        db.get_row("missing", "user1"),
+        Err(VibraError::TableNotFound(_))
+    ));
+
+    db.create_table("users", None).unwrap();
+    let rows: Vec<Row> = (0..10)
+        .map(|i| Row {
+            id: format!("user{}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).unwrap();
+    assert_eq!(db.scan_table("users").unwrap().len(), 10);
+    assert!(db.as_async().cache_stats().len > 0);
+}
@@ -0,0 +1,319 @@
+use crate::models::{Row, Value};
+use log::warn;
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZero;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+const GENERATION_STRIPES: usize = 64; // Write counters per shard; keys share them by hash
+
+/// The row cache shared by every clone of a `VibraDB`, split into independently locked
+/// `RowCache` shards so lookups of unrelated keys don't contend.
+///
+/// A key always maps to the same shard, picked by its hash. The entry count and byte budget are
+/// divided evenly between the shards, so each shard evicts on its own: with several shards an
+/// entry is evicted once its shard is full, even if others have room, and an entry larger than a
+/// shard's share of the byte budget is not kept.
+///
+/// A shard whose lock was poisoned by a panic is cleared rather than trusted, since the panic
+/// may have left it half updated; the cache is only a copy of sled, so nothing is lost.
+///
+/// Writes and invalidations bump a generation counter for their key. A reader that missed takes
+/// a `FillTicket` before going to sled and caches what it read with `fill`, which drops the value
+/// if the key's generation moved in the meantime: whatever the reader saw may already have been
+/// replaced or deleted. Keys share counters by hash, so an unrelated write can also cost a fill,
+/// but never lets a stale one through.
+pub(crate) struct ShardedCache {
+    shards: Box<[Mutex<RowCache>]>,
+    max_bytes: Option<usize>,
+    poisoned: AtomicU64, // Shards recovered from a poisoned lock
+}
+
+impl ShardedCache {
+    // Every shard needs room for at least one entry, so there are never more shards than entries
+    pub(crate) fn new(
+        capacity: NonZero<usize>,
+        max_bytes: Option<usize>,
+        shards: NonZero<usize>,
+    ) -> Self {
+        let count = shards.min(capacity).get();
+        let shards = (0..count)
+            .map(|i| {
+                // Spread the remainder over the first shards so the totals are exact
+                let share = |total: usize| total / count + usize::from(i < total % count);
+                let capacity = NonZero::new(share(capacity.get())).unwrap();
+                Mutex::new(RowCache::new(capacity, max_bytes.map(share)))
+            })
+            .collect();
+        ShardedCache {
+            shards,
+            max_bytes,
+            poisoned: AtomicU64::new(0),
+        }
+    }
+
+    // Lock a shard, clearing it first if a panic poisoned its lock
+    fn lock<'a>(&self, shard: &'a Mutex<RowCache>) -> MutexGuard<'a, RowCache> {
+        shard.lock().unwrap_or_else(|poisoned| {
+            warn!("A row cache shard was poisoned by a panic; clearing it");
+            let mut guard = poisoned.into_inner();
+            guard.clear();
+            shard.clear_poison();
+            self.poisoned.fetch_add(1, Ordering::Relaxed);
+            guard
+        })
+    }
+
+    // Lock the shard a key belongs to, returning it with the key's generation stripe
+    fn shard(&self, key: &str) -> (MutexGuard<'_, RowCache>, usize) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let index = (hash % self.shards.len() as u64) as usize;
+        let stripe = (hash / self.shards.len() as u64 % GENERATION_STRIPES as u64) as usize;
+        (self.lock(&self.shards[index]), stripe)
+    }
+
+    // Cache a value that was just written, invalidating fills already in progress for its key
+    pub(crate) fn put(&self, key: String, value: impl Into<Cached>) {
+        let (mut shard, stripe) = self.shard(&key);
+        shard.bump(stripe);
+        shard.put(key, value);
+    }
+
+    // Note a key's generation before reading it from sled, to `fill` with afterwards
+    pub(crate) fn ticket(&self, key: &str) -> FillTicket {
+        let (shard, stripe) = self.shard(key);
+        FillTicket(shard.generations[stripe])
+    }
+
+    // Cache a value read from sled, unless the key was written or invalidated since `ticket` was
+    // taken or is already cached. Checked and inserted under one lock.
+    pub(crate) fn fill(&self, key: String, value: impl Into<Cached>, ticket: FillTicket) {
+        let (mut shard, stripe) = self.shard(&key);
+        if shard.generations[stripe] == ticket.0 && !shard.contains(&key) {
+            shard.put(key, value);
+        }
+    }
+
+    // Look a key's row up, marking it as recently used
+    pub(crate) fn get(&self, key: &str) -> Option<Arc<Row>> {
+        match self.shard(key).0.get(key)? {
+            Cached::Row(row) => Some(row.clone()),
+            Cached::Sealed(_) => None,
+        }
+    }
+
+    // Look a key's record up, marking it as recently used
+    pub(crate) fn get_sealed(&self, key: &str) -> Option<sled::IVec> {
+        match self.shard(key).0.get(key)? {
+            Cached::Row(_) => None,
+            Cached::Sealed(stored) => Some(stored.clone()),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.shard(key).0.contains(key)
+    }
+
+    // Drop a key, invalidating fills in progress for it even if it wasn't cached
+    pub(crate) fn pop(&self, key: &str) -> Option<Cached> {
+        let (mut shard, stripe) = self.shard(key);
+        shard.bump(stripe);
+        shard.pop(key)
+    }
+
+    // Drop every entry whose key matches, returning how many were dropped. Matching keys that
+    // aren't cached can't be told apart, so fills in progress for any key are invalidated.
+    pub(crate) fn pop_matching(&self, matches: impl Fn(&str) -> bool) -> usize {
+        let mut dropped = 0;
+        for shard in self.shards.iter() {
+            let mut shard = self.lock(shard);
+            shard.bump_all();
+            let stale: Vec<String> = shard
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| matches(key))
+                .cloned()
+                .collect();
+            for key in stale {
+                shard.pop(&key);
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    pub(crate) fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = self.lock(shard);
+            shard.bump_all();
+            shard.clear();
+        }
+    }
+
+    // Sum a per-shard figure over every shard
+    fn total(&self, figure: impl Fn(&RowCache) -> usize) -> usize {
+        self.shards.iter().map(|shard| figure(&self.lock(shard))).sum()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.total(RowCache::len)
+    }
+
+    pub(crate) fn cap(&self) -> usize {
+        self.total(|shard| shard.cap().get())
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.total(RowCache::bytes)
+    }
+
+    pub(crate) fn max_bytes(&self) -> Option<usize> {
+        self.max_bytes
+    }
+
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub(crate) fn poisoned(&self) -> u64 {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+}
+
+/// The generation of a key's stripe when a reader missed the cache, see `ShardedCache::fill`.
+#[derive(Clone, Copy)]
+pub(crate) struct FillTicket(u64);
+
+/// What the cache holds for a key: the row itself, or with `cache_plaintext = false` the record
+/// sled stores for it, which every hit decrypts so no plaintext is kept between reads.
+#[derive(Clone)]
+pub(crate) enum Cached {
+    Row(Arc<Row>),
+    Sealed(sled::IVec),
+}
+
+impl From<Arc<Row>> for Cached {
+    fn from(row: Arc<Row>) -> Self {
+        Cached::Row(row)
+    }
+}
+
+impl From<sled::IVec> for Cached {
+    fn from(stored: sled::IVec) -> Self {
+        Cached::Sealed(stored)
+    }
+}
+
+/// One shard of the row cache: an LRU keyed by row key, bounded by entry count and optionally by
+/// the total size of its entries.
+///
+/// Rows are cached deserialized, behind an `Arc`, so a hit costs a reference count rather than
+/// a parse. An entry's size is the length of its key plus the lengths of the row's id, column
+/// names and values, or of the record for a cached record, which is what dominates the memory
+/// it holds. When either limit is exceeded,
+/// least recently used entries are evicted until both hold again; an entry larger than the whole
+/// byte budget is not kept.
+pub(crate) struct RowCache {
+    entries: LruCache<String, Cached>,
+    bytes: usize,
+    max_bytes: Option<usize>,
+    generations: [u64; GENERATION_STRIPES], // Bumped by writes to keys in each stripe
+}
+
+impl RowCache {
+    pub(crate) fn new(capacity: NonZero<usize>, max_bytes: Option<usize>) -> Self {
+        RowCache {
+            entries: LruCache::new(capacity),
+            bytes: 0,
+            max_bytes,
+            generations: [0; GENERATION_STRIPES],
+        }
+    }
+
+    fn bump(&mut self, stripe: usize) {
+        self.generations[stripe] = self.generations[stripe].wrapping_add(1);
+    }
+
+    fn bump_all(&mut self) {
+        for stripe in 0..GENERATION_STRIPES {
+            self.bump(stripe);
+        }
+    }
+
+    fn entry_size(key: &str, value: &Cached) -> usize {
+        let row = match value {
+            Cached::Row(row) => row,
+            Cached::Sealed(stored) => return key.len() + stored.len(),
+        };
+        let columns: usize = row
+            .columns
+            .iter()
+            .map(|(name, value)| {
+                name.len()
+                    + match value {
+                        Value::Text(text) => text.len(),
+                        Value::Bytes(bytes) => bytes.len(),
+                    }
+            })
+            .sum();
+        key.len() + row.id.len() + columns
+    }
+
+    pub(crate) fn put(&mut self, key: String, value: impl Into<Cached>) {
+        let value = value.into();
+        self.bytes += Self::entry_size(&key, &value);
+        // `push` hands back whatever it displaced: the old value for this key, or the entry
+        // evicted to stay within the entry count
+        if let Some((old_key, old_value)) = self.entries.push(key, value) {
+            self.bytes -= Self::entry_size(&old_key, &old_value);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            while self.bytes > max_bytes {
+                match self.entries.pop_lru() {
+                    Some((key, value)) => self.bytes -= Self::entry_size(&key, &value),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &str) -> Option<&Cached> {
+        self.entries.get(key)
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.entries.contains(key)
+    }
+
+    pub(crate) fn pop(&mut self, key: &str) -> Option<Cached> {
+        let value = self.entries.pop(key)?;
+        self.bytes -= Self::entry_size(key, &value);
+        Some(value)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &Cached)> {
+        self.entries.iter()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn cap(&self) -> NonZero<usize> {
+        self.entries.cap()
+    }
+
+    // Total size of the cached entries, as counted against the byte budget
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
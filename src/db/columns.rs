@@ -0,0 +1,480 @@
+use super::crypto::ct_eq;
+use super::keys::{self, DataKey, DETERMINISTIC_NONCE_LEN};
+use super::{now_millis, record, RecordHeader, RecordKey, Timestamp, VibraDB, RESERVED_PREFIX};
+use crate::config::VibraConfig;
+use crate::error::VibraError;
+use crate::models::{Row, Value};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{warn, Level};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::str;
+use std::sync::Arc;
+use tokio::task;
+use zeroize::Zeroizing;
+
+// Appended to a row's key to name the record its sensitive columns are sealed in, which no row
+// key can be, since neither table names nor row ids contain '/'
+const SEALED_SUFFIX: &str = "sealed";
+// First byte of a deterministically encrypted value: [version][synthetic nonce][ciphertext]
+const DETERMINISTIC_VERSION: u8 = 1;
+
+// How a column of a table with sensitive or deterministic columns is stored
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ColumnMode {
+    Clear,
+    Sealed,        // In the row's sealed record
+    Deterministic, // Encrypted in place with AES-GCM-SIV, equal values to equal ciphertext
+}
+
+// The columns the config encrypts for a table that doesn't have its rows encrypted whole
+struct ColumnLayout<'a> {
+    sensitive: Option<&'a HashSet<String>>,
+    deterministic: Option<&'a HashSet<String>>,
+}
+
+impl ColumnLayout<'_> {
+    // How the config has a column stored. Without a sensitive list, every column that isn't
+    // deterministic is sealed, so listing deterministic columns never leaves others in the clear.
+    fn mode(&self, column: &str) -> ColumnMode {
+        if self.deterministic.is_some_and(|columns| columns.contains(column)) {
+            ColumnMode::Deterministic
+        } else if self.sensitive.is_none_or(|columns| columns.contains(column)) {
+            ColumnMode::Sealed
+        } else {
+            ColumnMode::Clear
+        }
+    }
+}
+
+// A row of a table with sensitive columns, as stored: its columns in order, with the values of
+// the sensitive ones left out and sealed together in a record of their own
+#[derive(Serialize, Deserialize)]
+struct SplitRow {
+    id: String,
+    columns: Vec<(String, Option<Value>)>, // None for a sealed column
+    sealed: String, // The SealedColumns record, in base64
+    // Columns whose value is their deterministic ciphertext, as Value::Bytes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deterministic: Vec<String>,
+}
+
+impl SplitRow {
+    // How a column is stored in this row
+    fn mode(&self, column: &str, value: &Option<Value>) -> ColumnMode {
+        if value.is_none() {
+            ColumnMode::Sealed
+        } else if self.deterministic.iter().any(|name| name == column) {
+            ColumnMode::Deterministic
+        } else {
+            ColumnMode::Clear
+        }
+    }
+}
+
+// What the sealed record of a SplitRow holds
+#[derive(Serialize, Deserialize)]
+struct SealedColumns {
+    values: Vec<Value>, // The sealed columns' values, in column order
+    // SHA-256 of the row's id and columns as stored, and which are deterministic, in base64, so
+    // the other columns can't be changed without the sealed record noticing
+    digest: String,
+}
+
+impl VibraDB {
+    // Retrieve every row of a table with only the named columns, ordered by row id.
+    //
+    // Rows whose sensitive columns are sealed are read without decrypting anything, unless one of
+    // the named columns is sealed in them; the rest are decrypted like for scan_table. Clear
+    // columns read this way aren't checked against the sealed record, which is what vouches for
+    // them, so use get_row or scan_table where tampering with the files matters.
+    pub async fn scan_columns(
+        &self,
+        table_name: &str,
+        columns: &[&str],
+    ) -> Result<Vec<Row>, VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        entries
+            .par_iter()
+            .filter_map(|(k, v)| {
+                let key = str::from_utf8(k).ok()?;
+                let row = Self::check_record_len(key, v)
+                    .and_then(|()| self.select_columns(key, v, columns));
+                Some(row)
+            })
+            .collect()
+    }
+
+    // Rewrite the rows of a table that aren't stored the way its sensitive columns are configured
+    // now, returning how many were rewritten.
+    //
+    // Rows are written with the configured sensitive columns sealed whenever they are written, so
+    // this only matters for rows stored before a column was reclassified. Each row is swapped in
+    // with compare_and_swap and keeps its timestamps, so an interrupted migration leaves every row
+    // readable, and running it again carries on.
+    pub async fn migrate_sensitive_columns(&self, table_name: &str) -> Result<usize, VibraError> {
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        let this = self.clone();
+        let prefix = self.table_prefix(table_name);
+        let migrated = task::spawn_blocking(move || {
+            let mut migrated = 0;
+            for key in this.db.scan_prefix(prefix.as_bytes()).keys() {
+                let key = key?;
+                let context = String::from_utf8_lossy(&key).into_owned();
+                loop {
+                    let current = match this.db.get(&key)? {
+                        Some(current) => current,
+                        None => break, // Deleted since the walk started
+                    };
+                    if !this.has_stale_columns(&context, &current)? {
+                        break;
+                    }
+                    let value = this.decrypt_bytes(&context, &current)?;
+                    let mut sealed = this.encrypt_value(&context, &value)?;
+                    // Values that aren't rows, and rows of tables without a data key, are
+                    // encrypted whole either way
+                    if !record::holds_sealed_columns(&current)
+                        && !record::holds_sealed_columns(&sealed)
+                    {
+                        break;
+                    }
+                    Self::copy_record_time(&mut sealed, &current, Timestamp::Created);
+                    Self::copy_record_time(&mut sealed, &current, Timestamp::Updated);
+                    if this.db.compare_and_swap(&key, Some(current), Some(sealed))?.is_ok() {
+                        migrated += 1;
+                        break;
+                    }
+                }
+            }
+            Ok::<_, VibraError>(migrated)
+        })
+        .await
+        .unwrap()?;
+        self.log_op(
+            Level::Debug,
+            format_args!("Migrated the sensitive columns of {} rows in {}", migrated, table_name),
+        );
+        Ok(migrated)
+    }
+
+    // The columns the config has encrypted for the table a row key belongs to, if it doesn't
+    // encrypt its rows whole. Schemas and Vibra's own records are always encrypted whole.
+    fn column_layout(&self, context: &str) -> Option<ColumnLayout<'_>> {
+        if context.starts_with(RESERVED_PREFIX) {
+            return None;
+        }
+        let (table_name, _) = context.split_once('/')?;
+        let sensitive = self.sensitive.get(table_name);
+        let deterministic = self.deterministic.get(table_name);
+        if sensitive.is_none() && deterministic.is_none() {
+            return None;
+        }
+        Some(ColumnLayout {
+            sensitive,
+            deterministic,
+        })
+    }
+
+    // The key a row's sealed columns are encrypted for, distinct from any row key
+    fn sealed_context(context: &str) -> String {
+        format!("{}/{}", context, SEALED_SUFFIX)
+    }
+
+    // What the sealed record of a row vouches for: its id and its columns, sealed ones left out,
+    // and which of them are deterministic. Rows without any hash what rows did before there were.
+    fn clear_digest(split: &SplitRow) -> Result<String, VibraError> {
+        let json = if split.deterministic.is_empty() {
+            serde_json::to_vec(&(&split.id, &split.columns))?
+        } else {
+            serde_json::to_vec(&(&split.id, &split.columns, &split.deterministic))?
+        };
+        Ok(STANDARD.encode(Sha256::digest(json)))
+    }
+
+    // Store a row of a table with sensitive or deterministic columns: the clear columns as is,
+    // the deterministic ones encrypted in place, and the sensitive ones sealed with the table's
+    // data key in a record inside it. None for values that aren't a row's JSON, such as typed
+    // values, which are encrypted whole instead.
+    pub(super) fn seal_columns(
+        &self,
+        context: &str,
+        value: &[u8],
+        layers: usize,
+        data_key: &Arc<DataKey>,
+    ) -> Result<Option<Vec<u8>>, VibraError> {
+        let layout = match self.column_layout(context) {
+            Some(layout) => layout,
+            None => return Ok(None),
+        };
+        // Only values reading reassembles exactly
+        let row = match serde_json::from_slice::<Row>(value) {
+            Ok(row) if row.to_json()?.as_bytes() == value => row,
+            _ => return Ok(None),
+        };
+        let Row { id, columns } = row;
+        let mut values = Vec::new();
+        let mut deterministic = Vec::new();
+        let columns = columns
+            .into_iter()
+            .map(|(name, value)| match layout.mode(&name) {
+                ColumnMode::Clear => Ok((name, Some(value))),
+                ColumnMode::Sealed => {
+                    values.push(value);
+                    Ok((name, None))
+                }
+                ColumnMode::Deterministic => {
+                    let ciphertext = Self::encrypt_deterministic(data_key, &name, &value)?;
+                    deterministic.push(name.clone());
+                    Ok((name, Some(Value::Bytes(ciphertext))))
+                }
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+        let mut split = SplitRow {
+            id,
+            columns,
+            sealed: String::new(),
+            deterministic,
+        };
+        let digest = Self::clear_digest(&split)?;
+        let json = Zeroizing::new(serde_json::to_vec(&SealedColumns { values, digest })?);
+        let key = RecordKey::Table(data_key.clone());
+        let sealed = self.seal(&Self::sealed_context(context), &json, layers, Some(&key))?;
+        split.sealed = STANDARD.encode(sealed);
+        let json = serde_json::to_vec(&split)?;
+        Ok(Some(if split.deterministic.is_empty() {
+            record::encode_sealed_columns(&json, now_millis())
+        } else {
+            record::encode_deterministic_columns(&json, now_millis())
+        }))
+    }
+
+    // Reassemble the JSON of a row stored with its sensitive columns sealed, `value` being the
+    // stored row. The sealed record has to decrypt with `data_key` for this row and vouch for the
+    // other columns, so neither part can be swapped or edited on its own. Deterministic columns
+    // are decrypted with keys derived from `data_key` too.
+    pub(super) fn open_sealed_columns(
+        &self,
+        context: &str,
+        value: &[u8],
+        data_key: Option<&DataKey>,
+    ) -> Result<Vec<u8>, VibraError> {
+        let split: SplitRow = serde_json::from_slice(value)?;
+        let stored = STANDARD.decode(&split.sealed).map_err(|_| {
+            VibraError::MalformedRecord("sealed columns are not base64".to_string())
+        })?;
+        let context = Self::sealed_context(context);
+        let json = Zeroizing::new(self.decrypt_for(&context, &stored, data_key)?);
+        let sealed: SealedColumns = serde_json::from_slice(&json)?;
+        if sealed.digest != Self::clear_digest(&split)? {
+            return Err(VibraError::IntegrityCheckFailed);
+        }
+        let mut values = sealed.values.into_iter();
+        let columns = split
+            .columns
+            .iter()
+            .map(|(name, value)| match (split.mode(name, value), value) {
+                (ColumnMode::Clear, Some(value)) => Ok((name.clone(), value.clone())),
+                (ColumnMode::Deterministic, Some(Value::Bytes(ciphertext))) => {
+                    let data_key = data_key.ok_or_else(|| {
+                        let table_name = context.split('/').next().unwrap_or_default();
+                        VibraError::MissingTableKey(table_name.to_string())
+                    })?;
+                    let value = Self::decrypt_deterministic(data_key, name, ciphertext)?;
+                    Ok((name.clone(), value))
+                }
+                (ColumnMode::Sealed, _) => match values.next() {
+                    Some(value) => Ok((name.clone(), value)),
+                    None => Err(VibraError::IntegrityCheckFailed),
+                },
+                _ => Err(VibraError::IntegrityCheckFailed),
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+        if values.next().is_some() {
+            return Err(VibraError::IntegrityCheckFailed);
+        }
+        let row = Row {
+            id: split.id,
+            columns,
+        };
+        Ok(row.to_json()?.into_bytes())
+    }
+
+    // The row stored under `key` with only the named columns, read from its clear columns when
+    // none of those is sealed, and decrypted otherwise
+    fn select_columns(
+        &self,
+        key: &str,
+        stored: &[u8],
+        columns: &[&str],
+    ) -> Result<Row, VibraError> {
+        let wanted = |name: &str| columns.contains(&name);
+        if let Some(value) = record::sealed_columns_value(stored)? {
+            let split: SplitRow = serde_json::from_slice(value)?;
+            let sealed_wanted = split
+                .columns
+                .iter()
+                .any(|(name, value)| split.mode(name, value) != ColumnMode::Clear && wanted(name));
+            if !sealed_wanted {
+                let row_id = self.row_id_of(key)?;
+                if split.id != row_id {
+                    return Err(VibraError::RowIdMismatch {
+                        expected: row_id,
+                        stored: split.id,
+                    });
+                }
+                let columns = split
+                    .columns
+                    .into_iter()
+                    .filter(|(name, _)| wanted(name))
+                    .filter_map(|(name, value)| Some((name, value?)))
+                    .collect();
+                return Ok(Row {
+                    id: split.id,
+                    columns,
+                });
+            }
+        }
+        let mut row = self.decode_entry(key, stored)?;
+        row.columns.retain(|(name, _)| wanted(name));
+        Ok(row)
+    }
+
+    // Whether a stored row isn't stored the way the table's columns are configured now: encrypted
+    // whole although the table has sensitive or deterministic columns, or with other ones
+    fn has_stale_columns(&self, context: &str, stored: &[u8]) -> Result<bool, VibraError> {
+        match (record::sealed_columns_value(stored)?, self.column_layout(context)) {
+            (None, None) => Ok(false),
+            (Some(_), None) | (None, Some(_)) => Ok(true),
+            (Some(value), Some(layout)) => {
+                let split: SplitRow = serde_json::from_slice(value)?;
+                Ok(split
+                    .columns
+                    .iter()
+                    .any(|(name, value)| split.mode(name, value) != layout.mode(name)))
+            }
+        }
+    }
+
+    // Retrieve the rows of a table whose deterministic column equals `value`, ordered by row id.
+    //
+    // Rows stored with the column's ciphertext are matched by comparing it, so only the matches
+    // are decrypted; rows stored another way, e.g. before the column was made deterministic, are
+    // decrypted and compared.
+    pub(super) async fn find_by_ciphertext(
+        &self,
+        table_name: &str,
+        column: &str,
+        value: &Value,
+    ) -> Result<Vec<Row>, VibraError> {
+        let ciphertext = match self.table_key(&self.table_prefix(table_name)) {
+            Some(data_key) => Some(Value::Bytes(Self::encrypt_deterministic(
+                &data_key, column, value,
+            )?)),
+            None => None,
+        };
+        let entries = self.table_entries(table_name).await?;
+        let rows = entries
+            .par_iter()
+            .filter_map(|(k, v)| {
+                let key = str::from_utf8(k).ok()?;
+                let row = Self::check_record_len(key, v).and_then(|()| {
+                    let (header, _) = RecordHeader::decode(v)?;
+                    let split = match record::sealed_columns_value(v)? {
+                        Some(stored) if header.has_deterministic_columns() => Some(stored),
+                        _ => None,
+                    };
+                    if let Some(stored) = split {
+                        let split: SplitRow = serde_json::from_slice(stored)?;
+                        if split.deterministic.iter().any(|name| name == column) {
+                            let stored = split.columns.iter().find(|(name, _)| name == column);
+                            if !same_ciphertext(stored.map(|(_, value)| value), &ciphertext) {
+                                return Ok(None);
+                            }
+                        }
+                    }
+                    let row = self.decode_entry(key, v)?;
+                    Ok(row.get(column).is_some_and(|stored| stored == value).then_some(row))
+                });
+                row.transpose()
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+        Ok(rows)
+    }
+
+    // Encrypt a column's value so that equal values always have equal ciphertext: AES-256-GCM-SIV
+    // with a nonce that is an HMAC of the value, under keys of the column's own
+    fn encrypt_deterministic(
+        data_key: &DataKey,
+        column: &str,
+        value: &Value,
+    ) -> Result<Vec<u8>, VibraError> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(value)?);
+        let keys = data_key.column_keys(column);
+        let sealed = keys::seal_deterministic(&keys, column.as_bytes(), &plaintext)?;
+        Ok([&[DETERMINISTIC_VERSION], sealed.as_slice()].concat())
+    }
+
+    // Decrypt a value encrypted by encrypt_deterministic
+    fn decrypt_deterministic(
+        data_key: &DataKey,
+        column: &str,
+        stored: &[u8],
+    ) -> Result<Value, VibraError> {
+        let sealed = match stored.split_first() {
+            Some((&DETERMINISTIC_VERSION, rest)) if rest.len() >= DETERMINISTIC_NONCE_LEN => rest,
+            _ => {
+                return Err(VibraError::MalformedRecord(format!(
+                    "column {} is not a deterministic ciphertext",
+                    column
+                )))
+            }
+        };
+        let keys = data_key.column_keys(column);
+        let plaintext = keys::open_deterministic(&keys, column.as_bytes(), sealed)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    // Warn about tables the config has sensitive or deterministic columns for that can't encrypt
+    // them: those created unencrypted store them in the clear, and those without a data key encrypt
+    // rows whole
+    pub(super) fn warn_unsealed_tables(&self, config: &VibraConfig) -> Result<(), VibraError> {
+        let table_names = config.tables.keys().filter(|table_name| {
+            config.sensitive_columns(table_name).is_some()
+                || config.deterministic_columns(table_name).is_some()
+        });
+        for table_name in table_names {
+            if !self.tables.contains_key(self.stored_table(table_name).as_bytes())? {
+                continue;
+            }
+            let context = self.table_prefix(table_name);
+            if self.is_plaintext(&context) {
+                warn!(
+                    "Table {} was created unencrypted, so its sensitive and deterministic columns \
+                     are stored in the clear; delete and recreate it to encrypt them",
+                    table_name
+                );
+            } else if self.table_key(&context).is_none() {
+                warn!(
+                    "Table {} has no data key to encrypt its columns with, so its rows are \
+                     encrypted whole; rekey gives it one",
+                    table_name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether a deterministic column's stored value is `ciphertext`, or both are missing, with the
+// bytes compared in constant time
+fn same_ciphertext(stored: Option<&Option<Value>>, ciphertext: &Option<Value>) -> bool {
+    match (stored, ciphertext) {
+        (Some(Some(Value::Bytes(stored))), Some(Value::Bytes(wanted))) => ct_eq(stored, wanted),
+        (Some(None), None) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,119 @@
+use super::record::{RecordHeader, CIPHER_LAYERED_AES_GCM};
+use super::{TableMeta, VibraDB};
+use crate::error::VibraError;
+use log::{info, warn};
+use tokio::task;
+
+// Databases opened with `compliance_mode = "strict"` only hold records sealed with exactly one
+// layer of AES-256-GCM, under keys derived with HKDF-SHA256 from the master key or their table's
+// data key, that authenticate the key they are stored under as associated data: version 4 records
+// with one layer and the AES-GCM cipher id. Opening checks every record's header, reading checks
+// the record read, and writes only ever produce such records, as the config is validated to allow
+// nothing else.
+
+// Why a stored record doesn't conform to strict compliance mode, if it doesn't
+pub(super) fn nonconformance(stored: &[u8]) -> Option<String> {
+    let header = match RecordHeader::decode(stored) {
+        Ok((header, _)) => header,
+        Err(err) => return Some(format!("it doesn't decode: {}", err)),
+    };
+    if header.is_plaintext() && header.has_sealed_columns() {
+        return Some("it only encrypts its sensitive columns".to_string());
+    }
+    if header.is_plaintext() {
+        return Some("it is stored unencrypted".to_string());
+    }
+    if !header.derives_keys() {
+        return Some("its keys are stored inline rather than derived".to_string());
+    }
+    if !header.binds_context() {
+        return Some("it doesn't authenticate the key it is stored under".to_string());
+    }
+    if header.cipher != CIPHER_LAYERED_AES_GCM {
+        return Some(format!("it is sealed with {}", header.cipher_suite()));
+    }
+    if header.layers != 1 {
+        return Some(format!("it has {} encryption layers", header.layers));
+    }
+    None
+}
+
+impl VibraDB {
+    // Fail with NonConformant if strict compliance mode is on and `stored`, the record stored
+    // for `context`, doesn't conform to it
+    pub(super) fn check_conformance(&self, context: &str, stored: &[u8]) -> Result<(), VibraError> {
+        if !self.strict {
+            return Ok(());
+        }
+        match nonconformance(stored) {
+            Some(reason) => Err(VibraError::NonConformant {
+                key: self.loggable(context).to_string(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Fail with NonConformantDatabase if anything in the database doesn't conform to strict
+    // compliance mode. Run on open in that mode. Blocks on sled.
+    pub(super) fn require_conformant(&self) -> Result<(), VibraError> {
+        let nonconformant = self.nonconformant_records()?;
+        let Some((key, reason)) = nonconformant.first() else {
+            return Ok(());
+        };
+        warn!("{} records don't conform to strict compliance mode", nonconformant.len());
+        Err(VibraError::NonConformantDatabase {
+            records: nonconformant.len(),
+            key: self.loggable(key).to_string(),
+            reason: reason.clone(),
+        })
+    }
+
+    // List everything that doesn't conform to strict compliance mode, whether or not the
+    // database is opened in it: the `table/id` key of every row that doesn't, and the name of
+    // every table created without encryption or whose schema doesn't, with why, ordered by key.
+    // Rows whose id is encrypted and doesn't decrypt are listed under the id they are stored
+    // under. Only headers are read, so nothing is decrypted.
+    pub async fn scan_nonconformant(&self) -> Result<Vec<(String, String)>, VibraError> {
+        let this = self.clone();
+        let nonconformant = task::spawn_blocking(move || this.nonconformant_records())
+            .await
+            .unwrap()?;
+        match nonconformant.len() {
+            0 => info!("Every record conforms to strict compliance mode"),
+            records => warn!("{} records don't conform to strict compliance mode", records),
+        }
+        Ok(nonconformant)
+    }
+
+    // scan_nonconformant without the logging. Blocks on sled.
+    fn nonconformant_records(&self) -> Result<Vec<(String, String)>, VibraError> {
+        let mut nonconformant = vec![];
+        for (stored_table, table_name) in self.table_names("")? {
+            if let Some(stored) = self.tables.get(stored_table.as_bytes())? {
+                if TableMeta::decode(&stored)?.plaintext {
+                    nonconformant.push((table_name.clone(), "it is unencrypted".to_string()));
+                }
+            }
+            if let Some(stored) = self.schema.get(stored_table.as_bytes())? {
+                if let Some(reason) = nonconformance(&stored) {
+                    nonconformant.push((table_name.clone(), format!("its schema: {}", reason)));
+                }
+            }
+            let prefix = format!("{}/", stored_table);
+            for entry in self.db.scan_prefix(prefix.as_bytes()) {
+                let (key, stored) = entry?;
+                let Some(reason) = nonconformance(&stored) else {
+                    continue;
+                };
+                let key = String::from_utf8_lossy(&key);
+                let stored_id = &key[prefix.len()..];
+                let row_id = self.logical_id(&stored_table, stored_id);
+                let row_id = row_id.unwrap_or_else(|_| stored_id.to_string());
+                nonconformant.push((format!("{}/{}", table_name, row_id), reason));
+            }
+        }
+        nonconformant.sort();
+        Ok(nonconformant)
+    }
+}
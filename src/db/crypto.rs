@@ -0,0 +1,12 @@
+use subtle::ConstantTimeEq;
+
+// Compare secret-derived bytes, such as MACs, key check values or ciphertext, in time that
+// depends on their length but not on where they first differ. Every such comparison goes through
+// here rather than `==`, which returns at the first byte that differs and so leaks how much of a
+// guess was right. Lengths are public, so slices of different lengths are simply unequal.
+pub(crate) fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[cfg(test)]
+mod crypto_tests;
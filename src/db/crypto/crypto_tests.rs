@@ -0,0 +1,17 @@
+use super::*;
+
+#[test]
+fn test_ct_eq() {
+    assert!(ct_eq(b"", b""));
+    assert!(ct_eq(b"check value", b"check value"));
+    assert!(!ct_eq(b"check value", b"check valuf"));
+    assert!(!ct_eq(b"check value", b"Check value"));
+    // A prefix isn't equal, nor is the empty slice
+    assert!(!ct_eq(b"check", b"check value"));
+    assert!(!ct_eq(b"check value", b""));
+    let mac = [0xa5; 32];
+    let mut other = mac;
+    assert!(ct_eq(&mac, &other));
+    other[31] ^= 1;
+    assert!(!ct_eq(&mac, &other));
+}
@@ -0,0 +1,105 @@
+use super::VibraDB;
+use crate::error::VibraError;
+use crate::models::{Row, Value};
+use std::path::Path;
+use tokio::task;
+
+const ID_COLUMN: &str = "id";
+
+impl VibraDB {
+    // Export a table to a CSV file with a header row of column names.
+    //
+    // The header is the union of every row's columns, led by `id`. Cells for columns a row
+    // doesn't have are left empty, like those of empty values: CSV can't tell them apart, so
+    // `import_csv` reads both as absent. Binary values can't be represented and are rejected.
+    pub async fn export_csv(&self, table_name: &str, dest: &Path) -> Result<(), VibraError> {
+        let rows = self.scan_table(table_name).await?;
+        let dest = dest.to_path_buf();
+        task::spawn_blocking(move || Self::write_csv(&rows, &dest)).await.unwrap()
+    }
+
+    // Write rows to a CSV file as export_csv lays them out
+    fn write_csv(rows: &[Row], dest: &Path) -> Result<(), VibraError> {
+        let mut header: Vec<&str> = vec![ID_COLUMN];
+        for row in rows {
+            for (name, value) in &row.columns {
+                if name == ID_COLUMN {
+                    return Err(VibraError::InvalidValue {
+                        column: name.clone(),
+                        reason: "the id column is reserved in CSV exports".to_string(),
+                    });
+                }
+                if let Value::Bytes(_) = value {
+                    return Err(VibraError::InvalidValue {
+                        column: name.clone(),
+                        reason: "binary values can't be exported to CSV".to_string(),
+                    });
+                }
+                if !header.contains(&name.as_str()) {
+                    header.push(name);
+                }
+            }
+        }
+
+        let mut writer = csv::Writer::from_path(dest)?;
+        writer.write_record(&header)?;
+        for row in rows {
+            let record = header.iter().map(|column| {
+                if *column == ID_COLUMN {
+                    return row.id.as_str();
+                }
+                row.columns
+                    .iter()
+                    .find(|(name, _)| name == column)
+                    .and_then(|(_, value)| value.as_text())
+                    .unwrap_or("")
+            });
+            writer.write_record(record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Import rows from a CSV file whose header names the columns, returning how many were inserted.
+    //
+    // One header column must be `id`. Empty cells are treated as absent columns, so files
+    // written by `export_csv` for rows with differing columns round-trip, but columns that held
+    // an empty value come back absent.
+    pub async fn import_csv(&self, table_name: &str, src: &Path) -> Result<usize, VibraError> {
+        let src = src.to_path_buf();
+        let rows = task::spawn_blocking(move || Self::read_csv(&src)).await.unwrap()?;
+        let count = rows.len();
+        self.insert_many_rows(table_name, rows).await?;
+        Ok(count)
+    }
+
+    // Read the rows of a CSV file as import_csv takes them
+    fn read_csv(src: &Path) -> Result<Vec<Row>, VibraError> {
+        let mut reader = csv::Reader::from_path(src)?;
+        let header = reader.headers()?.clone();
+        let id_index = header
+            .iter()
+            .position(|column| column == ID_COLUMN)
+            .ok_or_else(|| VibraError::InvalidValue {
+                column: ID_COLUMN.to_string(),
+                reason: "CSV header has no id column".to_string(),
+            })?;
+
+        let mut rows = vec![];
+        for record in reader.records() {
+            let record = record?;
+            let columns = header
+                .iter()
+                .zip(record.iter())
+                .enumerate()
+                .filter(|(i, (_, cell))| *i != id_index && !cell.is_empty())
+                .map(|(_, (name, cell))| (name.to_string(), Value::from(cell)))
+                .collect();
+            rows.push(Row {
+                id: record.get(id_index).unwrap_or_default().to_string(),
+                columns,
+            });
+        }
+        Ok(rows)
+    }
+}
@@ -1,41 +1,58 @@
 use super::*;
+use crate::config::{ComplianceMode, TableConfig};
+use crate::models::{AuditReport, IntegrityReport, MigrationReport, RotationReport};
 use tempfile::tempdir;
 use tokio;
 
+// The master key every test database is opened with, unless a test needs another
+fn test_key() -> Option<MasterKey> {
+    Some(MasterKey::from_bytes([7; 32]))
+}
+
 #[tokio::test]
 async fn test_create_table() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
-    assert!(db.table_exists("test_table").await);
+    assert!(!db.table_exists("test_table").await.unwrap());
+    db.create_table("test_table", None).await.unwrap();
+    assert!(db.table_exists("test_table").await.unwrap());
+
+    // Row keys without a marker, e.g. left behind by an older version, aren't a table
+    let sealed = db.encrypt_value("orphans/row1", b"[]").unwrap();
+    db.db.insert("orphans/row1", sealed).unwrap();
+    assert!(!db.table_exists("orphans").await.unwrap());
 }
 
 #[tokio::test]
 async fn test_insert_and_get_row() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
 
     let row = Row {
         id: "row1".to_string(),
         columns: vec![
-            ("name".to_string(), "John Doe".to_string()),
-            ("email".to_string(), "john.doe@example.com".to_string()),
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
         ],
     };
 
-    db.insert_row("test_table", row.clone()).await;
-    let retrieved_row = db.get_row("test_table", "row1").await;
+    db.insert_row("test_table", row.clone()).await.unwrap();
+    let retrieved_row = db.get_row("test_table", "row1").await.unwrap();
 
     assert_eq!(retrieved_row, Some(row));
 }
@@ -43,118 +60,234 @@ async fn test_insert_and_get_row() {
 #[tokio::test]
 async fn test_delete_table() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
-    db.delete_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
+    let rows: Vec<Row> = (0..3)
+        .map(|i| Row {
+            id: format!("row{}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("test_table", rows.clone()).await.unwrap();
+    db.insert_row_with_ttl("test_table", rows[0].clone(), Duration::from_secs(60))
+        .await
+        .unwrap();
+    assert_eq!(db.delete_table("test_table").await.unwrap(), 3);
 
-    assert!(!db.table_exists("test_table").await);
+    assert!(!db.table_exists("test_table").await.unwrap());
+    assert!(db.db.scan_prefix("test_table").next().is_none());
+    assert!(db.expiry.iter().next().is_none());
+    assert_eq!(db.cache_stats().len, 0);
+
+    // Recreating the table doesn't bring the old rows back
+    db.create_table("test_table", None).await.unwrap();
+    for row in &rows {
+        assert_eq!(db.get_row("test_table", &row.id).await.unwrap(), None);
+    }
+    db.delete_table("test_table").await.unwrap();
+    assert!(matches!(
+        db.delete_table("test_table").await,
+        Err(VibraError::TableNotFound(ref table)) if table == "test_table"
+    ));
 }
 
 #[tokio::test]
 async fn test_delete_db() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let path = db.path.clone().unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+    // Other handles keep sled open, so deleting has to wait for them
+    let handle = db.clone();
+    assert!(matches!(handle.delete_db().await, Err(VibraError::InUse)));
+    db.delete_db().await.unwrap();
+
+    assert!(!path.exists());
+
+    // The lock is released, so a new database can be opened at the same path
+    let config = VibraConfig {
+        path: Some(path.clone()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert!(!db.table_exists("test_table").await.unwrap());
+    db.create_table("test_table", None).await.unwrap();
+    db.delete_db().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_close_and_reopen() {
+    let path = tempdir().unwrap().path().to_path_buf();
+    let open = || {
+        VibraDB::new(VibraConfig {
+            path: Some(path.clone()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        })
     };
-    let db = VibraDB::new(config);
+    let db = open().unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
 
-    db.create_table("test_table").await;
-    db.delete_db().await;
+    // While another handle is alive the database stays open, and locked
+    assert!(matches!(open(), Err(VibraError::Open { .. })));
+    let handle = db.clone();
+    assert!(matches!(handle.close().await, Err(VibraError::InUse)));
+    db.close().await.unwrap();
 
-    assert!(!std::path::Path::new(&db.path).exists());
+    // Closing released sled's lock, and flushed the row
+    let db = open().unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+    db.delete_db().await.unwrap();
 }
 
 #[tokio::test]
 async fn test_truncate_table() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
 
     let row = Row {
         id: "row1".to_string(),
         columns: vec![
-            ("name".to_string(), "John Doe".to_string()),
-            ("email".to_string(), "john.doe@example.com".to_string()),
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
         ],
     };
 
-    db.insert_row("test_table", row.clone()).await;
+    db.insert_row("test_table", row.clone()).await.unwrap();
 
-    db.truncate_table("test_table").await;
+    db.truncate_table("test_table").await.unwrap();
 
-    let retrieved_row = db.get_row("test_table", "row1").await;
+    let retrieved_row = db.get_row("test_table", "row1").await.unwrap();
     assert_eq!(retrieved_row, None);
+    assert!(db.table_exists("test_table").await.unwrap());
+    assert!(matches!(
+        db.truncate_table("missing").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_truncate_table_spares_tables_sharing_its_prefix() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let row = Row {
+        id: "row1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    for table in ["users", "users_archive", "users2"] {
+        db.create_table(table, None).await.unwrap();
+        db.insert_row(table, row.clone()).await.unwrap();
+    }
+
+    db.truncate_table("users").await.unwrap();
+    assert_eq!(db.get_row("users", "row1").await.unwrap(), None);
+    for table in ["users_archive", "users2"] {
+        assert_eq!(db.get_row(table, "row1").await.unwrap(), Some(row.clone()));
+        // Still in sled too, not just the cache
+        assert!(db.db.get(format!("{}/row1", table)).unwrap().is_some());
+    }
 }
 
 #[tokio::test]
 async fn test_truncate_db() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
 
     let row = Row {
         id: "row1".to_string(),
         columns: vec![
-            ("name".to_string(), "John Doe".to_string()),
-            ("email".to_string(), "john.doe@example.com".to_string()),
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
         ],
     };
 
-    db.insert_row("test_table", row.clone()).await;
+    db.insert_row("test_table", row.clone()).await.unwrap();
 
-    db.truncate_db().await;
+    db.truncate_db().await.unwrap();
 
     let retrieved_row = db.get_row("test_table", "row1").await;
-    assert_eq!(retrieved_row, None);
+    assert!(matches!(retrieved_row, Err(VibraError::TableNotFound(_))));
 }
 
 #[tokio::test]
 async fn test_insert_many_rows_manual() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
 
     let row1 = Row {
         id: "row1".to_string(),
         columns: vec![
-            ("name".to_string(), "John Doe".to_string()),
-            ("email".to_string(), "john.doe@example.com".to_string()),
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
         ],
     };
 
     let row2 = Row {
         id: "row2".to_string(),
         columns: vec![
-            ("name".to_string(), "Jane Doe".to_string()),
-            ("email".to_string(), "jane.doe@example.com".to_string()),
+            ("name".to_string(), "Jane Doe".into()),
+            ("email".to_string(), "jane.doe@example.com".into()),
         ],
     };
 
-    db.insert_rows("test_table", vec![row1.clone(), row2.clone()]).await;
+    db.insert_rows("test_table", vec![row1.clone(), row2.clone()]).await.unwrap();
 
-    let retrieved_row1 = db.get_row("test_table", "row1").await;
-    let retrieved_row2 = db.get_row("test_table", "row2").await;
+    let retrieved_row1 = db.get_row("test_table", "row1").await.unwrap();
+    let retrieved_row2 = db.get_row("test_table", "row2").await.unwrap();
 
     assert_eq!(retrieved_row1, Some(row1));
     assert_eq!(retrieved_row2, Some(row2));
@@ -163,35 +296,5303 @@ async fn test_insert_many_rows_manual() {
 #[tokio::test]
 async fn test_insert_many_rows() {
     let config = VibraConfig {
-        path: Some(tempdir().unwrap().path().to_str().unwrap().to_string()),
+        path: Some(tempdir().unwrap().path().to_path_buf()),
         cache_size: Some(1024),
         encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
     };
-    let db = VibraDB::new(config);
+    let db = VibraDB::new(config).unwrap();
 
-    db.create_table("test_table").await;
+    db.create_table("test_table", None).await.unwrap();
 
     let row1 = Row {
         id: "row1".to_string(),
         columns: vec![
-            ("name".to_string(), "John Doe".to_string()),
-            ("email".to_string(), "john.doe@example.com".to_string()),
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
         ],
     };
 
     let row2 = Row {
         id: "row2".to_string(),
         columns: vec![
-            ("name".to_string(), "Jane Doe".to_string()),
-            ("email".to_string(), "jane.doe@example.com".to_string()),
+            ("name".to_string(), "Jane Doe".into()),
+            ("email".to_string(), "jane.doe@example.com".into()),
         ],
     };
 
-    db.insert_many_rows("test_table", vec![row1.clone(), row2.clone()]).await;
+    db.insert_many_rows("test_table", vec![row1.clone(), row2.clone()]).await.unwrap();
 
-    let retrieved_row1 = db.get_row("test_table", "row1").await;
-    let retrieved_row2 = db.get_row("test_table", "row2").await;
+    let retrieved_row1 = db.get_row("test_table", "row1").await.unwrap();
+    let retrieved_row2 = db.get_row("test_table", "row2").await.unwrap();
 
     assert_eq!(retrieved_row1, Some(row1));
     assert_eq!(retrieved_row2, Some(row2));
 }
+
+#[tokio::test]
+async fn test_expiry_sweeper_reaps_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+
+    for id in ["row1", "row2"] {
+        let row = Row {
+            id: id.to_string(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row_with_ttl("test_table", row, Duration::from_millis(50)).await.unwrap();
+    }
+    let row3 = Row {
+        id: "row3".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.insert_row("test_table", row3).await.unwrap();
+
+    let sweeper = db.start_expiry_sweeper(Duration::from_millis(20));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    sweeper.stop().await;
+
+    // Check sled directly so no lazy expiry on read is involved
+    assert!(db.db.get("test_table/row1").unwrap().is_none());
+    assert!(db.db.get("test_table/row2").unwrap().is_none());
+    assert!(db.db.get("test_table/row3").unwrap().is_some());
+    assert!(db.expiry.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sweep_never_removes_a_rewritten_row() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(1),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("sessions", None).await.unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("token".to_string(), "abc".into())],
+    };
+
+    // A row written with a TTL has it from the start
+    db.insert_row_with_ttl("sessions", row("s0"), Duration::from_secs(3600)).await.unwrap();
+    assert!(db.expiry.get("sessions/s0").unwrap().is_some());
+    db.insert_row("sessions", row("s0")).await.unwrap();
+    assert!(db.expiry.get("sessions/s0").unwrap().is_none());
+
+    // Rows rewritten without a TTL while a sweep runs survive it, whichever goes first
+    for round in 0..20 {
+        let ids: Vec<String> = (0..50).map(|i| format!("r{}-{}", round, i)).collect();
+        for id in &ids {
+            db.insert_row_with_ttl("sessions", row(id), Duration::ZERO).await.unwrap();
+        }
+        let sweeper = {
+            let db = db.clone();
+            tokio::spawn(async move { db.sweep_expired("sessions").await.unwrap() })
+        };
+        for id in &ids {
+            db.insert_row("sessions", row(id)).await.unwrap();
+        }
+        sweeper.await.unwrap();
+        for id in &ids {
+            assert!(db.db.get(format!("sessions/{}", id)).unwrap().is_some(), "{} was swept", id);
+            assert!(db.expiry.get(format!("sessions/{}", id)).unwrap().is_none());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_schema_rejects_nonconforming_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    let schema = vec![
+        Column {
+            name: "name".to_string(),
+            data_type: "string".to_string(),
+        },
+        Column {
+            name: "age".to_string(),
+            data_type: "integer".to_string(),
+        },
+    ];
+    db.create_table("test_table", Some(schema)).await.unwrap();
+
+    let valid = Row {
+        id: "row1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("age".to_string(), "42".into()),
+        ],
+    };
+    db.insert_row("test_table", valid.clone()).await.unwrap();
+    assert_eq!(db.get_row("test_table", "row1").await.unwrap(), Some(valid));
+
+    let wrong_type = Row {
+        id: "row2".to_string(),
+        columns: vec![("age".to_string(), "forty-two".into())],
+    };
+    let result = db.insert_row("test_table", wrong_type).await;
+    assert!(matches!(result, Err(VibraError::SchemaViolation { ref column, .. }) if column == "age"));
+
+    let extra_column = Row {
+        id: "row3".to_string(),
+        columns: vec![
+            ("name".to_string(), "Jane Doe".into()),
+            ("email".to_string(), "jane.doe@example.com".into()),
+        ],
+    };
+    let result = db.insert_row("test_table", extra_column).await;
+    assert!(matches!(result, Err(VibraError::SchemaViolation { ref column, .. }) if column == "email"));
+
+    assert_eq!(db.get_row("test_table", "row2").await.unwrap(), None);
+    assert_eq!(db.get_row("test_table", "row3").await.unwrap(), None);
+
+    // Tables created without a schema accept any columns
+    db.create_table("schemaless", None).await.unwrap();
+    let row = Row {
+        id: "row1".to_string(),
+        columns: vec![("anything".to_string(), "goes".into())],
+    };
+    db.insert_row("schemaless", row).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_insert_rows_atomic() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let schema = vec![Column {
+        name: "age".to_string(),
+        data_type: "integer".to_string(),
+    }];
+    db.create_table("people", Some(schema)).await.unwrap();
+    let person = |id: &str, age: &str| Row {
+        id: id.to_string(),
+        columns: vec![("age".to_string(), age.into())],
+    };
+    db.insert_row("people", person("p0", "30")).await.unwrap();
+
+    // One bad row fails the whole batch, including the row that would have replaced p0
+    let batch = vec![person("p0", "31"), person("p1", "40"), person("p2", "not a number")];
+    assert!(matches!(
+        db.insert_rows_atomic("people", batch).await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+    assert_eq!(db.get_row("people", "p0").await.unwrap(), Some(person("p0", "30")));
+    assert_eq!(db.get_row("people", "p1").await.unwrap(), None);
+
+    // A repeated id is rejected rather than one copy silently winning
+    let batch = vec![person("p1", "40"), person("p2", "50"), person("p1", "41")];
+    assert!(matches!(
+        db.insert_rows_atomic("people", batch).await,
+        Err(VibraError::DuplicateRow { ref id, .. }) if id == "p1"
+    ));
+    assert_eq!(db.get_row("people", "p1").await.unwrap(), None);
+
+    let batch = vec![person("p0", "31"), person("p1", "40"), person("p2", "50")];
+    db.insert_rows_atomic("people", batch.clone()).await.unwrap();
+    assert_eq!(db.cache_stats().len, 3);
+    db.clear_cache();
+    assert_eq!(db.scan_table("people").await.unwrap(), batch);
+
+    // Empty batches are a no-op, but still need the table; oversized ones are refused up front
+    db.insert_rows_atomic("people", Vec::new()).await.unwrap();
+    assert!(matches!(
+        db.insert_rows_atomic("missing", Vec::new()).await,
+        Err(VibraError::TableNotFound(_))
+    ));
+    let huge: Vec<Row> = (0..10_001).map(|i| person(&format!("q{}", i), "1")).collect();
+    assert!(matches!(
+        db.insert_rows_atomic("people", huge).await,
+        Err(VibraError::BatchTooLarge { rows: 10_001, max: 10_000 })
+    ));
+    assert_eq!(db.scan_table("people").await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_insert_many_rows_bulk() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+
+    let rows: Vec<Row> = (0..1000)
+        .map(|i| Row {
+            id: format!("row{}", i),
+            columns: vec![("value".to_string(), i.to_string().into())],
+        })
+        .collect();
+
+    db.insert_many_rows("test_table", rows.clone()).await.unwrap();
+
+    assert_eq!(db.db.scan_prefix("test_table/").count(), 1000);
+    for row in rows {
+        let key = format!("test_table/{}", row.id);
+        let stored = db.db.get(&key).unwrap().unwrap();
+        // The whole row is stored, id included
+        let stored = Row::from_json(&db.decrypt_value(&key, &stored).unwrap()).unwrap();
+        assert_eq!(stored, row);
+        assert_eq!(db.get_row("test_table", &row.id).await.unwrap(), Some(row));
+    }
+}
+
+#[tokio::test]
+async fn test_insert_many_rows_clears_ttls_and_checks_the_table() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(1),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("sessions", None).await.unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("token".to_string(), "abc".into())],
+    };
+
+    // Rewriting a row in bulk drops the TTL it had
+    db.insert_row_with_ttl("sessions", row("s0"), Duration::from_secs(3600)).await.unwrap();
+    db.insert_many_rows("sessions", vec![row("s0"), row("s1")]).await.unwrap();
+    assert!(db.expiry.is_empty());
+
+    // Nothing lands in a table once it is gone
+    db.delete_table("sessions").await.unwrap();
+    assert!(db.insert_many_rows("sessions", vec![row("s2")]).await.is_err());
+    assert!(db.db.get("sessions/s2").unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_chunked_encryption_large_value() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    let value: String = (0..5 * 1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let stored = db.encrypt_value("test/value", value.as_bytes()).unwrap();
+
+    // 5 MB plus the checksum spans 81 chunks of 64 KiB
+    let (header, _) = RecordHeader::decode(&stored).unwrap();
+    assert_eq!(header.chunk_lens.len(), 81);
+    assert_eq!(db.decrypt_value("test/value", &stored).unwrap(), value);
+
+    // Values smaller than a chunk, including empty ones, still round-trip
+    for small in ["", "hello"] {
+        let stored = db.encrypt_value("test/value", small.as_bytes()).unwrap();
+        assert_eq!(stored.len(), small.len() + per_row_overhead(10));
+        assert_eq!(db.decrypt_value("test/value", &stored).unwrap(), small);
+    }
+}
+
+#[tokio::test]
+async fn test_layers_round_trip_on_many_threads() {
+    // Several chunks, so a multi-threaded pool really does decrypt them in parallel
+    let value: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+    for layers in [1, 10, 25] {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(layers),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        let db = VibraDB::new(config).unwrap();
+        pool.install(|| {
+            for _ in 0..5 {
+                let stored = db.encrypt_value("test/value", &value).unwrap();
+                let (header, _) = RecordHeader::decode(&stored).unwrap();
+                assert_eq!(header.layers as usize, layers);
+                assert!(header.chunk_lens.len() > 1);
+                assert_eq!(db.decrypt_bytes("test/value", &stored).unwrap(), value);
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_ciphertext_does_not_depend_on_thread_count() {
+    let seeded_db = || {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            seed: Some(42),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        VibraDB::new(config).unwrap()
+    };
+    // Lengths around the chunk boundaries, filled with a simple deterministic pattern
+    let values: Vec<Vec<u8>> = [0, 1, CHUNK_SIZE - 33, CHUNK_SIZE - 32, CHUNK_SIZE, 3 * CHUNK_SIZE + 7]
+        .iter()
+        .map(|&len| (0..len).map(|i| (i * 31 % 256) as u8).collect())
+        .collect();
+    let encrypt_all = |threads| {
+        let db = seeded_db();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| {
+            values
+                .iter()
+                .map(|value| {
+                    let mut stored = db.encrypt_value("test/value", value).unwrap();
+                    assert_eq!(&db.decrypt_bytes("test/value", &stored).unwrap(), value);
+                    stored[Timestamp::Created.range().start..Timestamp::Updated.range().end].fill(0);
+                    stored
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+
+    // The same seed gives the same key material, so any scheduling dependence would show up as
+    // a difference between the pools
+    let sequential = encrypt_all(1);
+    for threads in [2, 8] {
+        assert_eq!(encrypt_all(threads), sequential);
+    }
+}
+
+#[tokio::test]
+async fn test_reopen_with_more_layers() {
+    let path = tempdir().unwrap().path().to_path_buf();
+    let config = VibraConfig {
+        path: Some(path.clone()),
+        cache_size: Some(1024),
+        encryption_layers: Some(5),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let old = Row {
+        id: "old".to_string(),
+        columns: vec![("name".to_string(), "Written with 5 layers".into())],
+    };
+    db.insert_row("users", old.clone()).await.unwrap();
+    db.db.flush().unwrap();
+    drop(db);
+
+    let config = VibraConfig {
+        path: Some(path),
+        cache_size: Some(1024),
+        encryption_layers: Some(25),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert_eq!(db.get_row("users", "old").await.unwrap(), Some(old.clone()));
+    let new = Row {
+        id: "new".to_string(),
+        columns: vec![("name".to_string(), "Written with 25 layers".into())],
+    };
+    db.insert_row("users", new.clone()).await.unwrap();
+    db.clear_cache();
+
+    // Each record keeps the layer count it was written with
+    let layers = |key: &str| {
+        let stored = db.db.get(key).unwrap().unwrap();
+        RecordHeader::decode(&stored).unwrap().0.layers
+    };
+    assert_eq!(layers("users/old"), 5);
+    assert_eq!(layers("users/new"), 25);
+    assert_eq!(db.scan_table("users").await.unwrap(), vec![new, old]);
+}
+
+#[tokio::test]
+async fn test_integrity_check_detects_corrupt_length() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    // The value ends in a multi-byte character, so truncating it would also break UTF-8
+    let value = "caf\u{e9}";
+    let mut stored = db.encrypt_value("test/value", value.as_bytes()).unwrap();
+    assert_eq!(db.decrypt_value("test/value", &stored).unwrap(), value);
+
+    let corrupt_len = (value.len() as u64 - 1).to_be_bytes();
+    stored[4..12].copy_from_slice(&corrupt_len);
+    assert!(matches!(
+        db.decrypt_value("test/value", &stored),
+        Err(VibraError::IntegrityCheckFailed)
+    ));
+}
+
+#[tokio::test]
+async fn test_insert_and_get_bytes_column() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+
+    // 0xff and 0xfe never appear in valid UTF-8
+    let blob = vec![0x00, 0xff, 0xfe, 0x80, 0x01];
+    assert!(String::from_utf8(blob.clone()).is_err());
+    let row = Row {
+        id: "row1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("avatar".to_string(), Value::Bytes(blob.clone())),
+        ],
+    };
+    db.insert_row("test_table", row.clone()).await.unwrap();
+
+    // Read through the cache, then straight from the stored record
+    assert_eq!(db.get_row("test_table", "row1").await.unwrap(), Some(row.clone()));
+    db.cache.clear();
+    let retrieved_row = db.get_row("test_table", "row1").await.unwrap().unwrap();
+    assert_eq!(retrieved_row, row);
+    assert_eq!(retrieved_row.columns[1].1.as_bytes(), Some(blob.as_slice()));
+
+    let stored = db.encrypt_value("test/blob", &blob).unwrap();
+    assert_eq!(db.decrypt_bytes("test/blob", &stored).unwrap(), blob);
+    let text = db.decrypt_value("test/blob", &stored);
+    assert!(matches!(text, Err(VibraError::InvalidUtf8)));
+}
+
+#[tokio::test]
+async fn test_large_binary_column_round_trips() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("blobs", None).await.unwrap();
+
+    // Every byte value, including NULs and bytes that are never valid UTF-8, over 3 MiB
+    let blob: Vec<u8> = (0..3 * 1024 * 1024).map(|i: usize| (i * 7 % 256) as u8).collect();
+    assert!(String::from_utf8(blob.clone()).is_err());
+    let row = Row {
+        id: "thumbnail".to_string(),
+        columns: vec![
+            ("data".to_string(), Value::Bytes(blob.clone())),
+            ("nul".to_string(), Value::Bytes(vec![0x00; 16])),
+        ],
+    };
+    db.insert_row("blobs", row.clone()).await.unwrap();
+
+    assert_eq!(db.get_row("blobs", "thumbnail").await.unwrap(), Some(row.clone()));
+    db.clear_cache();
+    let stored = db.get_row("blobs", "thumbnail").await.unwrap().unwrap();
+    assert_eq!(stored.get("data").and_then(Value::as_bytes), Some(blob.as_slice()));
+    assert_eq!(stored, row);
+}
+
+#[tokio::test]
+async fn test_scan_paginated() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+    db.create_table("test_table_other", None).await.unwrap();
+
+    let rows: Vec<Row> = (0..25)
+        .map(|i| Row {
+            id: format!("row{:02}", i),
+            columns: vec![("value".to_string(), i.to_string().into())],
+        })
+        .collect();
+    db.insert_many_rows("test_table", rows.clone()).await.unwrap();
+    let other = Row {
+        id: "row99".to_string(),
+        columns: vec![("value".to_string(), "other".into())],
+    };
+    db.insert_row("test_table_other", other).await.unwrap();
+
+    let mut seen = vec![];
+    let mut cursor: Option<String> = None;
+    let mut pages = 0;
+    loop {
+        let (page, next) = db
+            .scan_paginated("test_table", cursor.as_deref(), 10)
+            .await
+            .unwrap();
+        pages += 1;
+        assert!(page.len() <= 10);
+        seen.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    assert_eq!(pages, 3);
+    assert_eq!(seen, rows);
+    assert_eq!(db.scan_table("test_table").await.unwrap(), rows);
+}
+
+#[tokio::test]
+async fn test_insert_auto_concurrent() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let columns = vec![("value".to_string(), i.to_string().into())];
+                db.insert_auto("test_table", columns).await.unwrap()
+            })
+        })
+        .collect();
+    let mut ids = vec![];
+    for handle in handles {
+        ids.push(handle.await.unwrap());
+    }
+
+    let distinct: std::collections::HashSet<_> = ids.iter().collect();
+    assert_eq!(distinct.len(), 100);
+    for id in &ids {
+        assert!(db.get_row("test_table", id).await.unwrap().is_some());
+    }
+    assert_eq!(db.scan_table("test_table").await.unwrap().len(), 100);
+}
+
+#[tokio::test]
+async fn test_increment_column_concurrent() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+    let row = Row {
+        id: "page1".to_string(),
+        columns: vec![("title".to_string(), "Home".into())],
+    };
+    db.insert_row("test_table", row).await.unwrap();
+
+    let handles: Vec<_> = (1..=50)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.increment_column("test_table", "page1", "hits", i)
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    let expected: i64 = (1..=50).sum();
+    let row = db.get_row("test_table", "page1").await.unwrap().unwrap();
+    assert_eq!(
+        row.columns,
+        vec![
+            ("title".to_string(), "Home".into()),
+            ("hits".to_string(), expected.to_string().into()),
+        ]
+    );
+    assert_eq!(
+        db.increment_column("test_table", "page1", "hits", -1).await.unwrap(),
+        expected - 1
+    );
+    assert!(matches!(
+        db.increment_column("test_table", "page1", "title", 1).await,
+        Err(VibraError::InvalidValue { .. })
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_append_to_column_concurrent() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("logs", None).await.unwrap();
+
+    // Each piece is a distinct, fixed-width line, so lost appends show up in length and content
+    let handles: Vec<_> = (0..50)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let line = format!("line {:03}\n", i);
+                db.append_to_column("logs", "today", "text", line.as_bytes())
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    let row = db.get_row("logs", "today").await.unwrap().unwrap();
+    let text = row.get("text").and_then(Value::as_text).unwrap();
+    assert_eq!(text.len(), 50 * "line 000\n".len());
+    for i in 0..50 {
+        assert!(text.contains(&format!("line {:03}\n", i)));
+    }
+
+    // Binary columns take any bytes; text columns only take UTF-8
+    db.append_to_column("logs", "today", "raw", &[0xff]).await.unwrap();
+    db.append_to_column("logs", "today", "raw", &[0x00]).await.unwrap();
+    let row = db.get_row("logs", "today").await.unwrap().unwrap();
+    assert_eq!(row.get("raw"), Some(&Value::Bytes(vec![0xff, 0x00])));
+    assert!(matches!(
+        db.append_to_column("logs", "today", "text", &[0xff]).await,
+        Err(VibraError::InvalidValue { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_increment_column_restarts_an_expired_row() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(1),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("counters", None).await.unwrap();
+    let row = Row {
+        id: "page1".to_string(),
+        columns: vec![("hits".to_string(), "41".into())],
+    };
+    db.insert_row_with_ttl("counters", row, Duration::from_millis(1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // The expired count is gone, and the row written in its place doesn't expire
+    assert_eq!(db.increment_column("counters", "page1", "hits", 1).await.unwrap(), 1);
+    assert!(db.expiry.get("counters/page1").unwrap().is_none());
+    db.sweep_expired("counters").await.unwrap();
+    let row = db.get_row("counters", "page1").await.unwrap().unwrap();
+    assert_eq!(row.get("hits"), Some(&"1".into()));
+
+    db.delete_table("counters").await.unwrap();
+    assert!(db.increment_column("counters", "page1", "hits", 1).await.is_err());
+    assert!(db.db.get("counters/page1").unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_separator_in_names_is_rejected() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("a", None).await.unwrap();
+    assert!(matches!(
+        db.create_table("a/b", None).await,
+        Err(VibraError::InvalidName { .. })
+    ));
+
+    // Table `a` row `b/c` would share the key `a/b/c` with table `a/b` row `c`
+    let row = Row {
+        id: "b/c".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    assert!(matches!(
+        db.insert_row("a", row).await,
+        Err(VibraError::InvalidName { ref name, .. }) if name == "b/c"
+    ));
+    assert!(matches!(
+        db.get_row("a/b", "c").await,
+        Err(VibraError::InvalidName { ref name, .. }) if name == "a/b"
+    ));
+    assert!(matches!(
+        db.get_row("a", "b/c").await,
+        Err(VibraError::InvalidName { .. })
+    ));
+    assert!(matches!(
+        db.delete_row("a", "b/c").await,
+        Err(VibraError::InvalidName { .. })
+    ));
+    assert!(matches!(
+        db.truncate_table("a/b").await,
+        Err(VibraError::InvalidName { .. })
+    ));
+    assert!(db.db.get("a/b/c").unwrap().is_none());
+
+    // Empty names would make `a/` a row key and `/c` a row of no table
+    let row = Row {
+        id: String::new(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    assert!(matches!(
+        db.insert_row("a", row).await,
+        Err(VibraError::InvalidName { ref name, .. }) if name.is_empty()
+    ));
+    assert!(matches!(
+        db.get_row("a", "").await,
+        Err(VibraError::InvalidName { .. })
+    ));
+    assert!(matches!(
+        db.create_table("", None).await,
+        Err(VibraError::InvalidName { .. })
+    ));
+
+    // Long ids have no special limit
+    let long_id = "x".repeat(10_000);
+    let row = Row {
+        id: long_id.clone(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("a", row.clone()).await.unwrap();
+    db.clear_cache();
+    assert_eq!(db.get_row("a", &long_id).await.unwrap(), Some(row.clone()));
+    assert_eq!(db.delete_row("a", &long_id).await.unwrap(), Some(row));
+}
+
+#[tokio::test]
+async fn test_new_fails_on_regular_file() {
+    let dir = tempdir().unwrap();
+    let file_path = dir.path().join("not_a_db");
+    std::fs::write(&file_path, b"just a file").unwrap();
+
+    let config = VibraConfig {
+        path: Some(file_path.clone()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        VibraDB::new(config),
+        Err(VibraError::Open { ref path, .. }) if *path == file_path
+    ));
+
+    let config = VibraConfig {
+        path: None,
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        VibraDB::new(config),
+        Err(VibraError::MissingConfig("path"))
+    ));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_new_fails_on_read_only_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("db");
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions don't apply to root, so there is nothing to test there
+    if std::fs::write(dir.path().join("probe"), b"").is_ok() {
+        return;
+    }
+
+    let config = VibraConfig {
+        path: Some(db_path.clone()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let result = VibraDB::new(config);
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(matches!(
+        result,
+        Err(VibraError::Open { ref path, .. }) if *path == db_path
+    ));
+}
+
+#[tokio::test]
+async fn test_new_opens_when_gitignore_cant_be_written() {
+    let dir = tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".gitignore")).unwrap();
+
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        write_gitignore: Some(true),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    assert!(dir.path().join(".gitignore").is_dir());
+}
+
+#[tokio::test]
+async fn test_write_gitignore_option() {
+    let open = |path: &Path, write_gitignore| {
+        VibraDB::new(VibraConfig {
+            path: Some(path.to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            write_gitignore,
+            master_key: test_key(),
+            ..Default::default()
+        })
+        .unwrap()
+    };
+
+    // By default a missing .gitignore is written and an existing one is preserved
+    let dir = tempdir().unwrap();
+    open(dir.path(), None).close().await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join(".gitignore")).unwrap(), b"*\n");
+    std::fs::write(dir.path().join(".gitignore"), b"*.log\n").unwrap();
+    open(dir.path(), None).close().await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join(".gitignore")).unwrap(), b"*.log\n");
+
+    // Forcing it overwrites the user's file
+    open(dir.path(), Some(true)).close().await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join(".gitignore")).unwrap(), b"*\n");
+
+    // Disabling it writes nothing
+    let dir = tempdir().unwrap();
+    open(dir.path(), Some(false)).close().await.unwrap();
+    assert!(!dir.path().join(".gitignore").exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_read_only_db_dir_still_opens() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir().unwrap();
+    let config = || VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    VibraDB::new(config()).unwrap().close().await.unwrap();
+    std::fs::remove_file(dir.path().join(".gitignore")).unwrap();
+
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions don't apply to root, so there is nothing to test there
+    if std::fs::write(dir.path().join("probe"), b"").is_ok() {
+        return;
+    }
+    let result = VibraDB::new(config());
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(result.is_ok());
+    assert!(!dir.path().join(".gitignore").exists());
+}
+
+#[tokio::test]
+async fn test_new_creates_missing_parent_directories() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("data").join("prod").join("vibra.db");
+
+    let config = VibraConfig {
+        path: Some(db_path.clone()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert!(dir.path().join("data").join("prod").is_dir());
+    assert!(db_path.join(".gitignore").is_file());
+    db.create_table("users", None).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_new_reports_parent_that_cant_be_created() {
+    let dir = tempdir().unwrap();
+    // A file where a parent directory should be can't be turned into one
+    std::fs::write(dir.path().join("data"), b"just a file").unwrap();
+
+    let config = VibraConfig {
+        path: Some(dir.path().join("data").join("prod").join("vibra.db")),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    match VibraDB::new(config) {
+        Err(VibraError::Io(err)) => {
+            let parent = dir.path().join("data").join("prod");
+            assert!(err.to_string().contains(&parent.display().to_string()));
+        }
+        Err(err) => panic!("expected an io error, got {}", err),
+        Ok(_) => panic!("expected an io error"),
+    }
+}
+
+#[tokio::test]
+async fn test_paths_with_trailing_separators() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("vibra_db");
+    let mut with_separator = db_path.clone().into_os_string();
+    with_separator.push(std::path::MAIN_SEPARATOR_STR);
+
+    let config = VibraConfig {
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config.with_path(with_separator)).unwrap();
+    assert_eq!(db.path.as_deref(), Some(db_path.as_path()));
+    assert_eq!(std::fs::read(db_path.join(".gitignore")).unwrap(), b"*\n");
+
+    db.delete_db().await.unwrap();
+    assert!(!db_path.exists());
+}
+
+#[test]
+fn test_normalize_path_is_relative_or_absolute_as_given() {
+    assert_eq!(normalize_path(Path::new("vibra_db")), Path::new("vibra_db"));
+    assert!(normalize_path(Path::new("vibra_db")).is_relative());
+    assert_eq!(
+        normalize_path(Path::new("data/./prod/vibra.db")),
+        Path::new("data").join("prod").join("vibra.db")
+    );
+    let absolute = std::env::temp_dir().join("vibra_db");
+    assert_eq!(normalize_path(&absolute), absolute);
+    assert!(normalize_path(&absolute).is_absolute());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_normalize_unix_paths() {
+    assert_eq!(normalize_path(Path::new("data/vibra/")), Path::new("data/vibra"));
+    assert_eq!(normalize_path(Path::new("/srv//vibra/")), Path::new("/srv/vibra"));
+    assert_eq!(
+        normalize_path(Path::new("/srv/vibra/")).join(".gitignore"),
+        Path::new("/srv/vibra/.gitignore")
+    );
+}
+
+#[cfg(windows)]
+#[test]
+fn test_normalize_windows_paths() {
+    assert_eq!(normalize_path(Path::new(r"data\vibra\")), Path::new(r"data\vibra"));
+    assert_eq!(normalize_path(Path::new(r"C:\data\vibra\")), Path::new(r"C:\data\vibra"));
+    // Forward slashes are separators on Windows too, so joins don't mix the two styles
+    assert_eq!(
+        normalize_path(Path::new("C:/data/vibra/")).join(".gitignore"),
+        Path::new(r"C:\data\vibra\.gitignore")
+    );
+}
+
+#[tokio::test]
+async fn test_snapshot_ignores_later_writes() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+    let before = Row {
+        id: "row1".to_string(),
+        columns: vec![("balance".to_string(), "100".into())],
+    };
+    db.insert_row("test_table", before.clone()).await.unwrap();
+
+    let snapshot = db.snapshot().await.unwrap();
+
+    let after = Row {
+        id: "row1".to_string(),
+        columns: vec![("balance".to_string(), "50".into())],
+    };
+    db.update_row("test_table", after.clone()).await.unwrap();
+    let added = Row {
+        id: "row2".to_string(),
+        columns: vec![("balance".to_string(), "10".into())],
+    };
+    db.insert_row("test_table", added).await.unwrap();
+
+    assert_eq!(db.get_row("test_table", "row1").await.unwrap(), Some(after));
+    assert_eq!(snapshot.get_row("test_table", "row1").unwrap(), Some(before.clone()));
+    assert_eq!(snapshot.get_row("test_table", "row2").unwrap(), None);
+    assert_eq!(snapshot.scan_table("test_table").unwrap(), vec![before]);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_snapshot_never_sees_half_a_write() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(1),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let row = |id: &str, version: usize| Row {
+        id: id.to_string(),
+        columns: vec![("version".to_string(), version.to_string().into())],
+    };
+    // Rows of other tables sort between the two, so copying them takes a while
+    for table_name in ["accounts", "filler", "ledger"] {
+        db.create_table(table_name, None).await.unwrap();
+    }
+    let filler: Vec<Row> = (0..5000).map(|i| row(&format!("f{}", i), 0)).collect();
+    db.insert_many_rows("filler", filler).await.unwrap();
+    db.insert_row("accounts", row("x", 0)).await.unwrap();
+    db.insert_row("ledger", row("x", 0)).await.unwrap();
+
+    // Both rows always change together, so every snapshot has to agree on them
+    let writer = {
+        let db = db.clone();
+        tokio::spawn(async move {
+            for n in 1..=200 {
+                db.transaction(&["accounts", "ledger"], move |tx| {
+                    tx.insert("accounts", row("x", n))?;
+                    tx.insert("ledger", row("x", n))?;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            }
+        })
+    };
+    while !writer.is_finished() {
+        let snapshot = db.snapshot().await.unwrap();
+        let account = snapshot.get_row("accounts", "x").unwrap().unwrap();
+        let entry = snapshot.get_row("ledger", "x").unwrap().unwrap();
+        assert_eq!(account, entry);
+    }
+    writer.await.unwrap();
+    let snapshot = db.snapshot().await.unwrap();
+    assert_eq!(snapshot.get_row("accounts", "x").unwrap(), Some(row("x", 200)));
+}
+
+#[tokio::test]
+async fn test_invalidate_after_out_of_band_write() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("test_table", None).await.unwrap();
+    let original = Row {
+        id: "row1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("test_table", original.clone()).await.unwrap();
+    let other = Row {
+        id: "row2".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.insert_row("test_table", other.clone()).await.unwrap();
+
+    // Poke sled directly, bypassing the cache
+    let changed = Row {
+        id: "row1".to_string(),
+        columns: vec![("name".to_string(), "Changed".into())],
+    };
+    let json = serde_json::to_string(&changed.columns).unwrap();
+    db.db
+        .insert("test_table/row1", db.encrypt_value("test_table/row1", json.as_bytes()).unwrap())
+        .unwrap();
+    db.db.remove("test_table/row2").unwrap();
+
+    // The cache still serves the stale rows until they are invalidated
+    assert_eq!(db.get_row("test_table", "row1").await.unwrap(), Some(original));
+    db.invalidate("test_table", "row1");
+    assert_eq!(db.get_row("test_table", "row1").await.unwrap(), Some(changed));
+
+    assert_eq!(db.get_row("test_table", "row2").await.unwrap(), Some(other));
+    db.clear_cache();
+    assert_eq!(db.get_row("test_table", "row2").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_csv_round_trip() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+
+    db.create_table("users", None).await.unwrap();
+    let rows = vec![
+        Row {
+            id: "user1".to_string(),
+            columns: vec![
+                ("name".to_string(), "John Doe".into()),
+                ("email".to_string(), "john.doe@example.com".into()),
+            ],
+        },
+        Row {
+            id: "user2".to_string(),
+            columns: vec![
+                ("name".to_string(), "Doe, Jane \"JD\"".into()),
+                ("phone".to_string(), "555-0100".into()),
+            ],
+        },
+    ];
+    db.insert_rows("users", rows.clone()).await.unwrap();
+
+    let dir = tempdir().unwrap();
+    let csv_path = dir.path().join("users.csv");
+    db.export_csv("users", &csv_path).await.unwrap();
+
+    let exported = std::fs::read_to_string(&csv_path).unwrap();
+    assert!(exported.starts_with("id,name,email,phone\n"));
+
+    db.create_table("users_copy", None).await.unwrap();
+    assert_eq!(db.import_csv("users_copy", &csv_path).await.unwrap(), 2);
+    assert_eq!(db.scan_table("users_copy").await.unwrap(), rows);
+
+    // An empty value is written like a missing one, and read back as missing
+    let blank = Row {
+        id: "user3".to_string(),
+        columns: vec![("name".to_string(), "".into()), ("phone".to_string(), "555-0101".into())],
+    };
+    db.insert_row("users", blank).await.unwrap();
+    db.export_csv("users", &csv_path).await.unwrap();
+    let exported = std::fs::read_to_string(&csv_path).unwrap();
+    assert!(exported.ends_with("user3,,,555-0101\n"));
+    db.truncate_table("users_copy").await.unwrap();
+    assert_eq!(db.import_csv("users_copy", &csv_path).await.unwrap(), 3);
+    let imported = db.get_row("users_copy", "user3").await.unwrap().unwrap();
+    assert_eq!(imported.columns, vec![("phone".to_string(), "555-0101".into())]);
+    assert_eq!(db.scan_table("users_copy").await.unwrap()[..2], rows[..]);
+}
+
+#[tokio::test]
+async fn test_table_dump_round_trip() {
+    let open = || {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        VibraDB::new(config).unwrap()
+    };
+    let db = open();
+    let schema = vec![
+        Column {
+            name: "name".to_string(),
+            data_type: "text".to_string(),
+        },
+        Column {
+            name: "avatar".to_string(),
+            data_type: "bytes".to_string(),
+        },
+    ];
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    let rows: Vec<Row> = (0..20)
+        .map(|i| Row {
+            id: format!("user{:02}", i),
+            columns: vec![
+                ("name".to_string(), format!("User {}", i).into()),
+                ("avatar".to_string(), Value::Bytes(vec![i as u8, 0, 255])),
+            ],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+
+    let dir = tempdir().unwrap();
+    let dump_path = dir.path().join("users.vdump");
+    db.export_table("users", &dump_path).await.unwrap();
+
+    // Into a new table of the same database, and into another database altogether
+    assert_eq!(db.import_table("users_copy", &dump_path).await.unwrap(), 20);
+    assert_eq!(db.scan_table("users_copy").await.unwrap(), rows);
+    let other = open();
+    assert_eq!(other.import_table("users_copy", &dump_path).await.unwrap(), 20);
+    assert_eq!(other.scan_table("users_copy").await.unwrap(), rows);
+    assert_eq!(other.table_schema("users_copy").unwrap(), Some(schema));
+
+    // Anything that isn't a dump is rejected without creating the table
+    let csv_path = dir.path().join("users.csv");
+    std::fs::write(&csv_path, "id,name\nuser1,John Doe\n").unwrap();
+    assert!(matches!(
+        db.import_table("from_csv", &csv_path).await,
+        Err(VibraError::InvalidDump(_))
+    ));
+    assert!(!db.table_exists("from_csv").await.unwrap());
+    let mut truncated = std::fs::read(&dump_path).unwrap();
+    truncated.pop();
+    std::fs::write(&dump_path, truncated).unwrap();
+    assert!(matches!(
+        db.import_table("users_copy", &dump_path).await,
+        Err(VibraError::InvalidDump(_))
+    ));
+
+    // Nor is a dump with a record that doesn't decrypt, which imports none of the others
+    db.export_table("users", &dump_path).await.unwrap();
+    let mut tampered = std::fs::read(&dump_path).unwrap();
+    *tampered.last_mut().unwrap() ^= 1;
+    std::fs::write(&dump_path, tampered).unwrap();
+    assert!(db.import_table("tampered", &dump_path).await.is_err());
+    assert!(!db.table_exists("tampered").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_master_key_derives_record_keys() {
+    let dir = tempdir().unwrap();
+    let config = |master_key: Option<MasterKey>| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key,
+        ..Default::default()
+    };
+    let key = MasterKey::from_bytes([7; 32]);
+    let db = VibraDB::new(config(Some(key.clone()))).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row("alice")).await.unwrap();
+
+    // Only a salt is stored in place of the ten layer keys, which come from the table's data key
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    let record = record::decode_record(&stored).unwrap();
+    assert_eq!(record.header.version, record::VERSION_BOUND);
+    assert_eq!(record.key_source, KeySource::Table);
+    assert_eq!(record.key_material.len(), keys::SALT_LEN);
+    let json = row("alice").to_json().unwrap();
+    assert_eq!(stored.len(), json.len() + per_row_overhead(10));
+
+    // The keys are bound to the record's key, so a copy under another id doesn't decrypt
+    db.db.insert("users/bob", stored.clone()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "bob").await,
+        Err(VibraError::Decryption { layer: 9 })
+    ));
+    db.db.remove("users/bob").unwrap();
+    db.close().await.unwrap();
+
+    // Another master key can't unwrap the table's data key, so it can't decrypt the rows
+    let other = VibraDB::new(config(Some(MasterKey::generate()))).unwrap();
+    assert!(matches!(
+        other.get_row("users", "alice").await,
+        Err(VibraError::MissingTableKey(ref table)) if table == "users"
+    ));
+    other.close().await.unwrap();
+
+    let db = VibraDB::new(config(Some(key))).unwrap();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice")));
+    assert!(db.health_check().await.is_ok());
+}
+
+#[tokio::test]
+async fn test_moved_records_fail_authentication() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.create_table("admins", None).await.unwrap();
+    let row = Row {
+        id: "alice".to_string(),
+        columns: vec![("name".to_string(), "Alice".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+
+    // A record copied under another row or table doesn't decrypt there
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    db.db.insert("users/bob", stored.clone()).unwrap();
+    db.db.insert("admins/alice", stored.clone()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "bob").await,
+        Err(VibraError::Decryption { layer: 9 })
+    ));
+    assert!(matches!(
+        db.get_row("admins", "alice").await,
+        Err(VibraError::Decryption { .. })
+    ));
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row));
+
+    // The context is authenticated as associated data too: even with the original record's keys,
+    // a chunk only decrypts for the key it was written under
+    let record = record::decode_record(&stored).unwrap();
+    let data_key = db.table_key("users/alice").unwrap();
+    let keys = data_key.layer_keys(record.key_material, "users/alice", 10);
+    let nonces = &record.nonces[..10 * 12];
+    let decrypt = |aad: &[u8]| {
+        VibraDB::decrypt_chunk(CipherSuite::Aes256Gcm, record.chunks[0], &keys, nonces, aad)
+    };
+    assert!(decrypt(b"users/alice").is_ok());
+    for aad in [&b"users/bob"[..], b"admins/alice", b""] {
+        assert!(matches!(
+            decrypt(aad),
+            Err(VibraError::Decryption { layer: 9 })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_configured_cipher_seals_new_records() {
+    let dir = tempdir().unwrap();
+    let config = |cipher: &str| {
+        let toml = dir.path().join("Vibra.toml");
+        let content = format!("encryption_layers = 3\ncipher = \"{}\"\n", cipher);
+        fs::write(&toml, content).unwrap();
+        VibraConfig::from_file(&toml)
+            .unwrap()
+            .with_path(dir.path().join("db"))
+            .with_master_key(MasterKey::from_bytes([7; 32]))
+    };
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    let cipher_of = |db: &VibraDB, key: &str| {
+        let stored = db.db.get(key).unwrap().unwrap();
+        record::decode_record(&stored).unwrap().header.cipher_suite()
+    };
+
+    let db = VibraDB::new(config("chacha20poly1305")).unwrap();
+    assert_eq!(db.cipher(), CipherSuite::ChaCha20Poly1305);
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row("alice")).await.unwrap();
+    assert_eq!(cipher_of(&db, "users/alice"), CipherSuite::ChaCha20Poly1305);
+    // Both ciphers add the same nonce and tag per layer
+    let json = row("alice").to_json().unwrap();
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    assert_eq!(stored.len(), json.len() + per_row_overhead(3));
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice")));
+    db.close().await.unwrap();
+
+    // Switching back only changes new writes: each record names the cipher it was sealed with
+    let db = VibraDB::new(config("aes256gcm")).unwrap();
+    assert_eq!(db.cipher(), CipherSuite::Aes256Gcm);
+    db.insert_row("users", row("bob")).await.unwrap();
+    assert_eq!(cipher_of(&db, "users/bob"), CipherSuite::Aes256Gcm);
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice")));
+    assert_eq!(cipher_of(&db, "users/alice"), CipherSuite::ChaCha20Poly1305);
+
+    // Rekeying moves the older records to the configured cipher
+    db.rekey(3, None).await.unwrap();
+    assert_eq!(cipher_of(&db, "users/alice"), CipherSuite::Aes256Gcm);
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice")));
+}
+
+#[tokio::test]
+async fn test_unencrypted_tables_store_rows_as_json() {
+    let dir = tempdir().unwrap();
+    let config = || {
+        VibraConfig {
+            path: Some(dir.path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        }
+        .with_table(
+            "countries",
+            TableConfig {
+                encrypted: Some(false),
+                ..Default::default()
+            },
+        )
+    };
+    let db = VibraDB::new(config()).unwrap();
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "string".to_string(),
+    }];
+    assert!(db.create_table("countries", Some(schema.clone())).await.unwrap());
+    assert!(db.create_table("users", Some(schema)).await.unwrap());
+    assert!(db.table_key("countries/de").is_none());
+    assert!(db.table_key("users/alice").is_some());
+    let row = |id: &str, name: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), name.into())],
+    };
+    db.insert_row("countries", row("de", "Germany")).await.unwrap();
+    db.insert_row("users", row("alice", "Alice")).await.unwrap();
+
+    // The unencrypted table's row is its JSON behind a header; the other's is nowhere to be seen
+    let stored = db.db.get("countries/de").unwrap().unwrap();
+    let json = row("de", "Germany").to_json().unwrap();
+    let (header, header_len) = RecordHeader::decode(&stored).unwrap();
+    assert!(header.is_plaintext());
+    assert_eq!(&stored[header_len..], json.as_bytes());
+    let parsed = Row::from_json(str::from_utf8(&stored[header_len..]).unwrap()).unwrap();
+    assert_eq!(parsed, row("de", "Germany"));
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    assert!(!RecordHeader::decode(&stored).unwrap().0.is_plaintext());
+    assert!(!stored.windows(5).any(|window| window == b"Alice"));
+    let schema_record = db.schema.get("countries").unwrap().unwrap();
+    assert!(RecordHeader::decode(&schema_record).unwrap().0.is_plaintext());
+
+    db.clear_cache();
+    assert_eq!(db.get_row("countries", "de").await.unwrap(), Some(row("de", "Germany")));
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice", "Alice")));
+    // The schema is enforced alike
+    let bad = Row {
+        id: "fr".to_string(),
+        columns: vec![("capital".to_string(), "Paris".into())],
+    };
+    assert!(matches!(
+        db.insert_row("countries", bad).await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+
+    // Only unencrypted tables read unencrypted records, so one can't pass for an encrypted row
+    db.db.insert("users/mallory", db.db.get("countries/de").unwrap().unwrap()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "mallory").await,
+        Err(VibraError::MalformedRecord(_))
+    ));
+    db.db.remove("users/mallory").unwrap();
+
+    // Rekeying and rotating leave the unencrypted table alone: only the other table's data key is
+    // re-wrapped, and both tables' rows and schemas are skipped
+    assert_eq!(db.rekey(5, None).await.unwrap(), 1);
+    let report = db.rotate_master_key(MasterKey::generate()).await.unwrap();
+    assert_eq!((report.tables, report.rotated, report.skipped), (1, 0, 4));
+
+    for table_name in ["countries", "users"] {
+        assert!(db.table_exists(table_name).await.unwrap());
+        db.truncate_table(table_name).await.unwrap();
+        assert!(db.table_exists(table_name).await.unwrap());
+        assert!(db.scan_table(table_name).await.unwrap().is_empty());
+    }
+    db.insert_row("countries", row("de", "Germany")).await.unwrap();
+    let master_key = db.master_key();
+    db.close().await.unwrap();
+
+    // The table stays unencrypted without the setting, and creating it again changes nothing
+    let config = VibraConfig {
+        tables: HashMap::new(),
+        master_key: Some((*master_key).clone()),
+        ..config()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert!(!db.create_table("countries", None).await.unwrap());
+    db.insert_row("countries", row("fr", "France")).await.unwrap();
+    let stored = db.db.get("countries/fr").unwrap().unwrap();
+    assert!(RecordHeader::decode(&stored).unwrap().0.is_plaintext());
+    assert_eq!(db.get_row("countries", "de").await.unwrap(), Some(row("de", "Germany")));
+
+    // Recreated without the setting, it is encrypted
+    db.delete_table("countries").await.unwrap();
+    db.create_table("countries", None).await.unwrap();
+    db.insert_row("countries", row("de", "Germany")).await.unwrap();
+    let stored = db.db.get("countries/de").unwrap().unwrap();
+    assert!(!RecordHeader::decode(&stored).unwrap().0.is_plaintext());
+    assert_eq!(db.get_row("countries", "de").await.unwrap(), Some(row("de", "Germany")));
+}
+
+// A config for a database in `dir` whose users table has the given sensitive columns, if any
+fn sensitive_config(dir: &Path, sensitive: Option<&[&str]>, key: Option<MasterKey>) -> VibraConfig {
+    VibraConfig {
+        path: Some(dir.to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(4),
+        master_key: key,
+        ..Default::default()
+    }
+    .with_table(
+        "users",
+        TableConfig {
+            sensitive: sensitive.map(|columns| columns.iter().map(|c| c.to_string()).collect()),
+            ..Default::default()
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_sensitive_columns_are_sealed_in_the_row() {
+    let dir = tempdir().unwrap();
+    let sensitive: &[&str] = &["email", "ssn"];
+    let db = VibraDB::new(sensitive_config(dir.path(), Some(sensitive), test_key())).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let alice = Row {
+        id: "alice".to_string(),
+        columns: vec![
+            ("email".to_string(), "alice@example.com".into()),
+            ("username".to_string(), "Alice Smith".into()),
+            ("ssn".to_string(), "078-05-1120".into()),
+            ("avatar".to_string(), vec![0xff, 0x00].into()),
+        ],
+    };
+    db.insert_row("users", alice.clone()).await.unwrap();
+
+    // Only the sensitive values are encrypted
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    let contains = |needle: &[u8]| stored.windows(needle.len()).any(|window| window == needle);
+    assert!(record::holds_sealed_columns(&stored));
+    assert!(contains(b"Alice Smith") && contains(b"[255,0]"));
+    assert!(!contains(b"alice@example.com") && !contains(b"078-05-1120"));
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(alice.clone()));
+    assert_eq!(db.scan_table("users").await.unwrap(), vec![alice.clone()]);
+    let public = Row {
+        id: "alice".to_string(),
+        columns: vec![("username".to_string(), "Alice Smith".into())],
+    };
+    assert_eq!(db.scan_columns("users", &["username"]).await.unwrap(), vec![public.clone()]);
+    let sealed = db.scan_columns("users", &["ssn", "missing"]).await.unwrap();
+    assert_eq!(sealed[0].columns, vec![("ssn".to_string(), "078-05-1120".into())]);
+
+    // Typed values aren't rows, so they are encrypted whole
+    db.insert_typed("users", "settings", &vec![1, 2, 3]).await.unwrap();
+    let stored_typed = db.db.get("users/settings").unwrap().unwrap();
+    assert!(!record::holds_sealed_columns(&stored_typed));
+    assert_eq!(db.get_typed::<Vec<i32>>("users", "settings").await.unwrap(), Some(vec![1, 2, 3]));
+    db.db.remove("users/settings").unwrap();
+
+    // The sealed record vouches for the clear columns, and belongs to its row only
+    let mut edited = stored.to_vec();
+    let at = edited.windows(5).position(|window| window == b"Alice").unwrap();
+    edited[at..at + 5].copy_from_slice(b"Mally");
+    db.db.insert("users/alice", edited).unwrap();
+    db.clear_cache();
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::IntegrityCheckFailed)
+    ));
+    db.db.insert("users/alice", stored.clone()).unwrap();
+    db.db.insert("users/mallory", stored.clone()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "mallory").await,
+        Err(VibraError::Decryption { .. })
+    ));
+    db.db.remove("users/mallory").unwrap();
+
+    // Rekeying seals them again, and a dump carries them to another table
+    assert_eq!(db.rekey(2, None).await.unwrap(), 1);
+    let stored = db.db.get("users/alice").unwrap().unwrap();
+    assert!(record::holds_sealed_columns(&stored));
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(alice.clone()));
+    let dump_path = dir.path().join("users.dump");
+    db.export_table("users", &dump_path).await.unwrap();
+    assert_eq!(db.import_table("users_copy", &dump_path).await.unwrap(), 1);
+    assert_eq!(db.get_row("users_copy", "alice").await.unwrap(), Some(alice.clone()));
+    let stored = db.db.get("users_copy/alice").unwrap().unwrap();
+    assert!(!record::holds_sealed_columns(&stored));
+    db.close().await.unwrap();
+
+    // Without the master key, the clear columns still read, but nothing sealed does
+    let other_key = Some(MasterKey::from_bytes([9; 32]));
+    let db = VibraDB::new(sensitive_config(dir.path(), Some(sensitive), other_key)).unwrap();
+    assert_eq!(db.scan_columns("users", &["username"]).await.unwrap(), vec![public]);
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::MissingTableKey(_))
+    ));
+    assert!(matches!(
+        db.scan_columns("users", &["email"]).await,
+        Err(VibraError::MissingTableKey(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_reclassified_columns_are_migrated() {
+    let dir = tempdir().unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![
+            ("username".to_string(), id.into()),
+            ("email".to_string(), format!("{}@example.com", id).into()),
+            ("phone".to_string(), "555-0100".into()),
+        ],
+    };
+    let sealed = |db: &VibraDB, id: &str| {
+        let stored = db.db.get(format!("users/{}", id)).unwrap().unwrap();
+        let phone_clear = stored.windows(8).any(|window| window == b"555-0100");
+        (record::holds_sealed_columns(&stored), phone_clear)
+    };
+    let path = dir.path().to_path_buf();
+    let reopen = |db: VibraDB, sensitive: Option<&'static [&'static str]>| {
+        let path = path.clone();
+        async move {
+            db.close().await.unwrap();
+            VibraDB::new(sensitive_config(&path, sensitive, test_key())).unwrap()
+        }
+    };
+
+    // Rows from before the table had sensitive columns are encrypted whole
+    let db = VibraDB::new(sensitive_config(dir.path(), None, test_key())).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row("alice")).await.unwrap();
+    assert_eq!(db.migrate_sensitive_columns("users").await.unwrap(), 0);
+    assert_eq!(sealed(&db, "alice"), (false, false));
+
+    // Listing some seals just those in new writes, and migrating moves the older rows over
+    let db = reopen(db, Some(&["email"])).await;
+    db.insert_row("users", row("bob")).await.unwrap();
+    assert_eq!(sealed(&db, "bob"), (true, true));
+    assert_eq!(sealed(&db, "alice"), (false, false));
+    assert_eq!(db.migrate_sensitive_columns("users").await.unwrap(), 1);
+    assert_eq!(sealed(&db, "alice"), (true, true));
+    assert_eq!(db.migrate_sensitive_columns("users").await.unwrap(), 0);
+
+    // A column reclassified as sensitive is sealed by the next write, or by migrating
+    let db = reopen(db, Some(&["email", "phone"])).await;
+    db.update_row("users", row("bob")).await.unwrap();
+    assert_eq!(sealed(&db, "bob"), (true, false));
+    assert_eq!(sealed(&db, "alice"), (true, true));
+    assert_eq!(db.migrate_sensitive_columns("users").await.unwrap(), 1);
+    assert_eq!(sealed(&db, "alice"), (true, false));
+    let before = db.get_row_meta("users", "alice").await.unwrap().unwrap();
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row("alice")));
+
+    // Without the setting, rows go back to being encrypted whole
+    let db = reopen(db, None).await;
+    assert_eq!(db.get_row("users", "bob").await.unwrap(), Some(row("bob")));
+    assert_eq!(db.migrate_sensitive_columns("users").await.unwrap(), 2);
+    assert_eq!(sealed(&db, "alice"), (false, false));
+    assert_eq!(sealed(&db, "bob"), (false, false));
+    // Migrating isn't a write as far as the row is concerned
+    let after = db.get_row_meta("users", "alice").await.unwrap().unwrap();
+    assert_eq!((after.created_at, after.updated_at), (before.created_at, before.updated_at));
+    assert_eq!(db.scan_table("users").await.unwrap(), vec![row("alice"), row("bob")]);
+    assert!(matches!(
+        db.migrate_sensitive_columns("nope").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_deterministic_columns_share_ciphertext() {
+    let dir = tempdir().unwrap();
+    let columns = |names: &[&str]| Some(names.iter().map(|c| c.to_string()).collect());
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(4),
+        master_key: test_key(),
+        ..Default::default()
+    }
+    .with_table(
+        "users",
+        TableConfig {
+            sensitive: columns(&["ssn"]),
+            deterministic: columns(&["country", "birthplace"]),
+            ..Default::default()
+        },
+    )
+    .with_table(
+        "orders",
+        TableConfig {
+            deterministic: columns(&["country"]),
+            ..Default::default()
+        },
+    );
+    let db = VibraDB::new(config).unwrap();
+    for table_name in ["users", "orders", "notes"] {
+        db.create_table(table_name, None).await.unwrap();
+    }
+    let user = |id: &str, country: &str| Row {
+        id: id.to_string(),
+        columns: vec![
+            ("name".to_string(), id.into()),
+            ("country".to_string(), country.into()),
+            ("birthplace".to_string(), "France".into()),
+            ("ssn".to_string(), "078-05-1120".into()),
+        ],
+    };
+    let rows = vec![user("alice", "France"), user("bob", "France"), user("carol", "Germany")];
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    let order = Row {
+        id: "o1".to_string(),
+        columns: vec![
+            ("country".to_string(), "France".into()),
+            ("total".to_string(), "12".into()),
+        ],
+    };
+    db.insert_row("orders", order.clone()).await.unwrap();
+
+    // A column of a stored row, as its JSON
+    let stored_column = |table_name: &str, id: &str, column: &str| {
+        let stored = db.db.get(format!("{}/{}", table_name, id)).unwrap().unwrap();
+        let (header, _) = RecordHeader::decode(&stored).unwrap();
+        assert!(header.has_deterministic_columns());
+        let split: serde_json::Value =
+            serde_json::from_slice(record::sealed_columns_value(&stored).unwrap().unwrap())
+                .unwrap();
+        let columns = split["columns"].as_array().unwrap().clone();
+        let found = columns.into_iter().find(|entry| entry[0] == column).unwrap();
+        found[1].clone()
+    };
+    // Equal values share ciphertext within a column, but not across columns or tables
+    let france = stored_column("users", "alice", "country");
+    assert!(france.is_array());
+    assert_eq!(stored_column("users", "bob", "country"), france);
+    assert_ne!(stored_column("users", "carol", "country"), france);
+    assert_ne!(stored_column("users", "alice", "birthplace"), france);
+    assert_ne!(stored_column("orders", "o1", "country"), france);
+    // Columns that aren't deterministic stay randomized: ssn is sealed, and without a sensitive
+    // list everything else in orders is too
+    let stored = |table_name: &str, id: &str| {
+        db.db.get(format!("{}/{}", table_name, id)).unwrap().unwrap().to_vec()
+    };
+    let contains = |stored: &[u8], needle: &[u8]| {
+        stored.windows(needle.len()).any(|window| window == needle)
+    };
+    for id in ["alice", "bob", "carol"] {
+        let stored = stored("users", id);
+        assert!(!contains(&stored, b"France") && !contains(&stored, b"078-05-1120"));
+        assert!(contains(&stored, id.as_bytes()));
+    }
+    assert_eq!(stored_column("orders", "o1", "total"), serde_json::Value::Null);
+    db.insert_row("notes", user("alice", "France")).await.unwrap();
+    db.insert_row("notes", user("bob", "France")).await.unwrap();
+    assert!(!record::holds_sealed_columns(&stored("notes", "alice")));
+
+    // Rows read back whole, and are matched by ciphertext
+    db.clear_cache();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert_eq!(db.get_row("orders", "o1").await.unwrap(), Some(order));
+    let country = db.scan_columns("users", &["country"]).await.unwrap();
+    assert_eq!(country[2].columns, vec![("country".to_string(), "Germany".into())]);
+    let found = db.find_by("users", "country", "France").await.unwrap();
+    assert_eq!(found, rows[..2].to_vec());
+    assert!(db.find_by("users", "country", "france").await.unwrap().is_empty());
+    assert!(matches!(
+        db.find_by("users", "name", "alice").await,
+        Err(VibraError::NotIndexed { .. })
+    ));
+
+    // Ciphertext copied from another row is caught by the sealed record
+    let alice = stored("users", "alice");
+    let carol = stored_column("users", "carol", "country");
+    let json = record::sealed_columns_value(&alice).unwrap().unwrap();
+    let mut split: serde_json::Value = serde_json::from_slice(json).unwrap();
+    for entry in split["columns"].as_array_mut().unwrap() {
+        if entry[0] == "country" {
+            entry[1] = carol.clone();
+        }
+    }
+    let forged = serde_json::to_vec(&split).unwrap();
+    db.db.insert("users/alice", record::encode_deterministic_columns(&forged, 0)).unwrap();
+    db.clear_cache();
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::IntegrityCheckFailed)
+    ));
+}
+
+#[tokio::test]
+async fn test_blind_index_finds_rows_by_value() {
+    let dir = tempdir().unwrap();
+    let user = |id: &str, email: &str| Row {
+        id: id.to_string(),
+        columns: vec![
+            ("username".to_string(), id.into()),
+            ("email".to_string(), email.into()),
+        ],
+    };
+    let alice = user("alice", "Alice@Example.com");
+    let bob = user("bob", "bob@example.com");
+    let carol = user("carol", " alice@example.com ");
+    let db = VibraDB::new(sensitive_config(dir.path(), Some(&["email"]), test_key())).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", alice.clone()).await.unwrap();
+    db.insert_row("users", bob.clone()).await.unwrap();
+    db.insert_typed("users", "settings", &vec![1, 2, 3]).await.unwrap();
+
+    // Rows stored before the index are indexed when it is created, and writes after it index
+    // themselves; text matches ignoring case and surrounding whitespace
+    assert_eq!(db.create_blind_index("users", "email").await.unwrap(), 2);
+    db.insert_many_rows("users", vec![carol.clone()]).await.unwrap();
+    let found = db.find_by("users", "email", "alice@example.com").await.unwrap();
+    assert_eq!(found, vec![alice.clone(), carol.clone()]);
+    assert_eq!(db.find_by("users", "email", "BOB@example.com").await.unwrap(), vec![bob]);
+    assert!(db.find_by("users", "email", "dave@example.com").await.unwrap().is_empty());
+    // Binary values never match text
+    let bytes: &[u8] = b"bob@example.com";
+    assert!(db.find_by("users", "email", bytes).await.unwrap().is_empty());
+
+    // No tree holds an indexed value in the clear, only MACs of it
+    for name in db.db.tree_names() {
+        for entry in db.db.open_tree(&name).unwrap().iter() {
+            let (k, v) = entry.unwrap();
+            for stored in [&k, &v] {
+                let text = String::from_utf8_lossy(stored).to_lowercase();
+                assert!(!text.contains("example.com"), "found an email in tree {:?}", name);
+            }
+        }
+    }
+    assert!(!db.index.is_empty());
+
+    assert!(matches!(
+        db.find_by("users", "username", "alice").await,
+        Err(VibraError::NotIndexed { .. })
+    ));
+    assert!(matches!(
+        db.find_by("nope", "email", "alice").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "string".to_string(),
+    }];
+    db.create_table("people", Some(schema)).await.unwrap();
+    assert!(matches!(
+        db.create_blind_index("people", "email").await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+
+    // The index is kept across reopening, and rebuilt for a new master key
+    db.close().await.unwrap();
+    let db = VibraDB::new(sensitive_config(dir.path(), Some(&["email"]), test_key())).unwrap();
+    let found = db.find_by("users", "email", "alice@example.com").await.unwrap();
+    assert_eq!(found, vec![alice.clone(), carol.clone()]);
+    db.rotate_master_key(MasterKey::generate()).await.unwrap();
+    let found = db.find_by("users", "email", "alice@example.com").await.unwrap();
+    assert_eq!(found, vec![alice, carol]);
+}
+
+#[tokio::test]
+async fn test_blind_index_follows_writes_and_deletes() {
+    let dir = tempdir().unwrap();
+    let user = |id: &str, email: &str| Row {
+        id: id.to_string(),
+        columns: vec![("email".to_string(), email.into())],
+    };
+    let db = VibraDB::new(sensitive_config(dir.path(), Some(&["email"]), test_key())).unwrap();
+    db.create_table("users", None).await.unwrap();
+    assert_eq!(db.create_blind_index("users", "email").await.unwrap(), 0);
+    // Keys of the index entries that belong to a row
+    let entries = |db: &VibraDB, id: &str| {
+        db.index
+            .iter()
+            .keys()
+            .map(|key| String::from_utf8(key.unwrap().to_vec()).unwrap())
+            .filter(|key| key.ends_with(&format!("/{}", id)))
+            .count()
+    };
+
+    db.insert_row("users", user("alice", "a@example.com")).await.unwrap();
+    assert_eq!(entries(&db, "alice"), 2);
+
+    // Updating a row moves its entry to the new value
+    db.update_row("users", user("alice", "b@example.com")).await.unwrap();
+    assert!(db.find_by("users", "email", "a@example.com").await.unwrap().is_empty());
+    let found = db.find_by("users", "email", "b@example.com").await.unwrap();
+    assert_eq!(found, vec![user("alice", "b@example.com")]);
+    assert_eq!(entries(&db, "alice"), 2);
+    db.append_to_column("users", "alice", "email", b".org").await.unwrap();
+    let found = db.find_by("users", "email", "b@example.com.org").await.unwrap();
+    assert_eq!(found, vec![user("alice", "b@example.com.org")]);
+    assert!(db.find_by("users", "email", "b@example.com").await.unwrap().is_empty());
+
+    // Transactions and atomic batches index what they commit
+    db.transaction(&["users"], move |tx| tx.insert("users", user("bob", "c@example.com")))
+        .await
+        .unwrap();
+    db.insert_rows_atomic("users", vec![user("carol", "c@example.com")]).await.unwrap();
+    assert_eq!(db.find_by("users", "email", "c@example.com").await.unwrap().len(), 2);
+
+    // Deleting a row removes its entries, and truncating the table removes all of them
+    assert!(db.delete_row("users", "alice").await.unwrap().is_some());
+    assert_eq!(entries(&db, "alice"), 0);
+    assert!(db.find_by("users", "email", "b@example.com.org").await.unwrap().is_empty());
+    db.insert_row_with_ttl("users", user("dave", "d@example.com"), Duration::from_millis(1))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    assert_eq!(db.sweep_expired("users").await.unwrap(), 1);
+    assert_eq!(entries(&db, "dave"), 0);
+    db.truncate_table("users").await.unwrap();
+    assert_eq!(db.index.len(), 0);
+    // Deleting the table drops its indexes with it
+    db.insert_row("users", user("erin", "e@example.com")).await.unwrap();
+    db.delete_table("users").await.unwrap();
+    assert_eq!(db.index.len(), 0);
+    db.create_table("users", None).await.unwrap();
+    assert!(matches!(
+        db.find_by("users", "email", "e@example.com").await,
+        Err(VibraError::NotIndexed { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_rekey_moves_inline_records_to_the_master_key() {
+    let dir = tempdir().unwrap();
+    let config = |master_key: Option<MasterKey>| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key,
+        ..Default::default()
+    };
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "text".to_string(),
+    }];
+    let rows: Vec<Row> = (0..10)
+        .map(|i| Row {
+            id: format!("user{}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    let key = MasterKey::generate();
+    let db = VibraDB::new(config(Some(key.clone()))).unwrap();
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+
+    // Rewrite everything the way versions before master keys did, with the keys inline
+    let inline = |tree: &sled::Tree, context: fn(&str) -> String| {
+        for entry in tree.iter() {
+            let (key, stored) = entry.unwrap();
+            let context = context(str::from_utf8(&key).unwrap());
+            let value = db.decrypt_bytes(&context, &stored).unwrap();
+            tree.insert(key, db.seal(&context, &value, 10, None).unwrap()).unwrap();
+        }
+    };
+    inline(&db.db, str::to_string);
+    inline(&db.schema, VibraDB::schema_context);
+    db.close().await.unwrap();
+
+    // Records with inline keys still read, and rekeying rewrites them, schema included, with
+    // keys derived from the table's data key
+    let db = VibraDB::new(config(Some(key.clone()))).unwrap();
+    for entry in db.db.iter().values() {
+        let (header, _) = RecordHeader::decode(&entry.unwrap()).unwrap();
+        assert_eq!(header.version, record::VERSION_INLINE_KEYS);
+    }
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert_eq!(db.rekey(10, None).await.unwrap(), 10);
+    for tree in [&**db.db, &db.schema] {
+        for entry in tree.iter().values() {
+            let (header, _) = RecordHeader::decode(&entry.unwrap()).unwrap();
+            assert_eq!(header.version, record::VERSION_BOUND);
+        }
+    }
+    db.close().await.unwrap();
+
+    let db = VibraDB::new(config(Some(key))).unwrap();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert_eq!(db.table_schema("users").unwrap(), Some(schema));
+    db.close().await.unwrap();
+    let other = VibraDB::new(config(Some(MasterKey::generate()))).unwrap();
+    assert!(matches!(other.scan_table("users").await, Err(VibraError::MissingTableKey(_))));
+}
+
+#[tokio::test]
+async fn test_dumps_are_re_encrypted_on_import() {
+    let open = |master_key: MasterKey| {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: Some(master_key),
+            ..Default::default()
+        };
+        VibraDB::new(config).unwrap()
+    };
+    let key = MasterKey::generate();
+    let db = open(key.clone());
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "text".to_string(),
+    }];
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    let dir = tempdir().unwrap();
+    let dump_path = dir.path().join("users.vdump");
+    db.export_table("users", &dump_path).await.unwrap();
+
+    // Rows are bound to their table, so importing under another name re-encrypts them
+    assert_eq!(db.import_table("people", &dump_path).await.unwrap(), 1);
+    assert_eq!(db.get_row("people", "user1").await.unwrap(), Some(row.clone()));
+
+    // The dump carries the table's wrapped data key, so it outlives the table
+    db.delete_table("users").await.unwrap();
+    assert_eq!(db.import_table("users", &dump_path).await.unwrap(), 1);
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row.clone()));
+    let other = open(key);
+    assert_eq!(other.import_table("people", &dump_path).await.unwrap(), 1);
+    assert_eq!(other.scan_table("people").await.unwrap(), vec![row]);
+    assert_eq!(other.table_schema("people").unwrap(), Some(schema));
+
+    // A database with another master key can't read the dump, and creates nothing
+    let stranger = open(MasterKey::generate());
+    assert!(matches!(
+        stranger.import_table("people", &dump_path).await,
+        Err(VibraError::Decryption { .. })
+    ));
+    assert!(!stranger.table_exists("people").await.unwrap());
+}
+
+#[test]
+fn test_generate_key_file() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("vibra.key");
+    VibraDB::generate_key_file(&path).unwrap();
+    let text = fs::read_to_string(&path).unwrap();
+    assert_eq!(text.len(), 65);
+    let key = MasterKey::parse(&text).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // An existing key file is never replaced
+    assert!(matches!(
+        VibraDB::generate_key_file(&path),
+        Err(VibraError::KeyFile { ref source, .. }) if source.kind() == io::ErrorKind::AlreadyExists
+    ));
+    let kept = MasterKey::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(kept.to_hex(), key.to_hex());
+    VibraDB::generate_key_file(dir.path().join("other.key")).unwrap();
+    let other = fs::read_to_string(dir.path().join("other.key")).unwrap();
+    assert_ne!(other, text);
+}
+
+#[tokio::test]
+async fn test_open_with_passphrase() {
+    let dir = tempdir().unwrap();
+    let config = || VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        ..Default::default()
+    };
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    let db = VibraDB::open_with_passphrase(config(), "correct horse").unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row.clone()).await.unwrap();
+    db.close().await.unwrap();
+
+    // A wrong passphrase is rejected up front, every time, rather than reading garbage
+    for _ in 0..2 {
+        assert!(matches!(
+            VibraDB::open_with_passphrase(config(), "battery staple"),
+            Err(VibraError::InvalidPassphrase)
+        ));
+    }
+    let db = VibraDB::open_with_passphrase(config(), "correct horse").unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row.clone()));
+
+    // Stronger parameters and a new passphrase only rewrite the lock, not the rows
+    let lock_path = dir.path().join(passphrase::LOCK_FILE);
+    let old_lock = fs::read_to_string(&lock_path).unwrap();
+    let stronger = PassphraseParams {
+        memory_kib: 32 * 1024,
+        iterations: 3,
+        parallelism: 2,
+    };
+    db.set_passphrase("battery staple", stronger).await.unwrap();
+    let lock = PassphraseLock::load(&lock_path).unwrap().unwrap();
+    assert_eq!(lock.params(), stronger);
+    assert!(!fs::read_to_string(&lock_path).unwrap().contains(&old_lock));
+    db.close().await.unwrap();
+
+    assert!(matches!(
+        VibraDB::open_with_passphrase(config(), "correct horse"),
+        Err(VibraError::InvalidPassphrase)
+    ));
+    let db = VibraDB::open_with_passphrase(config(), "battery staple").unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+    let weak = PassphraseParams {
+        memory_kib: 1,
+        ..stronger
+    };
+    assert!(matches!(
+        db.set_passphrase("battery staple", weak).await,
+        Err(VibraError::InvalidConfig(_))
+    ));
+}
+
+// A table of 300 rows, ordered by id, in a database with the given master key. The table is
+// written the way versions before table data keys did, so rotating has records to re-encrypt.
+async fn rotation_fixture(dir: &Path, master_key: MasterKey) -> (VibraDB, Vec<Row>) {
+    let config = || VibraConfig {
+        path: Some(dir.to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: Some(master_key.clone()),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config()).unwrap();
+    let rows: Vec<Row> = (0..300)
+        .map(|i| Row {
+            id: format!("user{:03}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    if db.table_exists("users").await.unwrap() {
+        return (db, rows);
+    }
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "text".to_string(),
+    }];
+    db.create_table("users", Some(schema)).await.unwrap();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    let legacy = RecordKey::Master(Arc::new(master_key.clone()));
+    let reseal = |tree: &sled::Tree, context: fn(&str) -> String| {
+        for entry in tree.iter() {
+            let (key, stored) = entry.unwrap();
+            let context = context(str::from_utf8(&key).unwrap());
+            let value = db.decrypt_bytes(&context, &stored).unwrap();
+            tree.insert(key, db.seal(&context, &value, 10, Some(&legacy)).unwrap()).unwrap();
+        }
+    };
+    reseal(&db.db, str::to_string);
+    reseal(&db.schema, VibraDB::schema_context);
+    let mut meta = TableMeta::decode(&db.tables.get("users").unwrap().unwrap()).unwrap();
+    meta.data_key = None;
+    db.tables.insert("users", meta.encode().unwrap()).unwrap();
+    db.close().await.unwrap();
+    (VibraDB::new(config()).unwrap(), rows)
+}
+
+#[tokio::test]
+async fn test_rotate_master_key_resumes_after_an_interruption() {
+    let dir = tempdir().unwrap();
+    let (old_key, new_key) = (MasterKey::generate(), MasterKey::generate());
+    let (db, rows) = rotation_fixture(dir.path(), old_key.clone()).await;
+
+    // Stop after the schema's batch and the first batch of rows, as if the process died
+    let partial = db.rotate_batches(new_key.clone(), Some(2)).await.unwrap();
+    let report = |tables, rotated| RotationReport {
+        tables,
+        rotated,
+        skipped: 0,
+        failed: 0,
+    };
+    assert_eq!(partial, report(0, 101));
+    db.clear_cache();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    db.close().await.unwrap();
+
+    // Rotated rows moved to the table's new data key, which is still wrapped by the old master
+    // key until the rotation finishes, so everything reads on the old key. No other rotation can
+    // start.
+    let (db, _) = rotation_fixture(dir.path(), old_key.clone()).await;
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert!(matches!(
+        db.rotate_master_key(MasterKey::generate()).await,
+        Err(VibraError::InvalidKey { .. })
+    ));
+
+    // Resuming rotates only the rest, then re-wraps the data key
+    assert_eq!(db.rotate_master_key(new_key.clone()).await.unwrap(), report(1, 200));
+    assert!(db.meta.get(rotation::ROTATION_KEY).unwrap().is_none());
+    db.close().await.unwrap();
+
+    // Every record now uses the table's data key, which only the new master key unwraps
+    let (db, _) = rotation_fixture(dir.path(), new_key.clone()).await;
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert!(db.table_schema("users").unwrap().is_some());
+    for tree in [&**db.db, &db.schema] {
+        for entry in tree.iter().values() {
+            let (header, _) = RecordHeader::decode(&entry.unwrap()).unwrap();
+            assert_eq!(header.version, record::VERSION_BOUND);
+        }
+    }
+    db.close().await.unwrap();
+    let (db, _) = rotation_fixture(dir.path(), old_key).await;
+    assert!(matches!(db.scan_table("users").await, Err(VibraError::MissingTableKey(_))));
+}
+
+#[tokio::test]
+async fn test_reads_and_writes_during_rotation() {
+    let dir = tempdir().unwrap();
+    let new_key = MasterKey::generate();
+    let (db, rows) = rotation_fixture(dir.path(), MasterKey::generate()).await;
+    let late = Row {
+        id: "user999".to_string(),
+        columns: vec![("name".to_string(), "Late".into())],
+    };
+
+    let reads = async {
+        for _ in 0..5 {
+            db.clear_cache();
+            assert_eq!(db.scan_table("users").await.unwrap()[..300], rows[..]);
+            tokio::task::yield_now().await;
+        }
+        db.insert_row("users", late.clone()).await.unwrap();
+    };
+    let (report, _) = tokio::join!(db.rotate_master_key(new_key.clone()), reads);
+
+    // The row written during the rotation already used the table's data key
+    let report = report.unwrap();
+    assert_eq!((report.tables, report.rotated, report.failed), (1, 301, 0));
+    assert!(report.skipped <= 1);
+    assert_eq!(
+        db.rotate_master_key(new_key.clone()).await.unwrap(),
+        RotationReport {
+            tables: 0,
+            rotated: 0,
+            skipped: 302,
+            failed: 0
+        }
+    );
+    db.close().await.unwrap();
+    let (db, mut rows) = rotation_fixture(dir.path(), new_key).await;
+    rows.push(late);
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+}
+
+#[tokio::test]
+async fn test_rotation_only_rewraps_table_keys() {
+    let dir = tempdir().unwrap();
+    let config = |master_key: &MasterKey| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: Some(master_key.clone()),
+        ..Default::default()
+    };
+    let (old_key, new_key) = (MasterKey::generate(), MasterKey::generate());
+    let db = VibraDB::new(config(&old_key)).unwrap();
+    let rows: Vec<Row> = (0..20)
+        .map(|i| Row {
+            id: format!("user{:02}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    for table_name in ["users", "admins"] {
+        db.create_table(table_name, None).await.unwrap();
+        db.insert_many_rows(table_name, rows.clone()).await.unwrap();
+    }
+    let records: Vec<_> = db.db.iter().collect::<Result<_, _>>().unwrap();
+    let wrapped = db.tables.get("users").unwrap().unwrap();
+
+    let report = db.rotate_master_key(new_key.clone()).await.unwrap();
+    assert_eq!(
+        report,
+        RotationReport {
+            tables: 2,
+            rotated: 0,
+            skipped: 40,
+            failed: 0
+        }
+    );
+    // The rows are untouched; only the table's wrapped key changed
+    assert_eq!(db.db.iter().collect::<Result<Vec<_>, _>>().unwrap(), records);
+    assert_ne!(db.tables.get("users").unwrap().unwrap(), wrapped);
+    db.close().await.unwrap();
+
+    let db = VibraDB::new(config(&new_key)).unwrap();
+    assert_eq!(db.scan_table("admins").await.unwrap(), rows);
+    db.close().await.unwrap();
+    let db = VibraDB::new(config(&old_key)).unwrap();
+    assert!(matches!(
+        db.get_row("users", "user00").await,
+        Err(VibraError::MissingTableKey(ref table)) if table == "users"
+    ));
+}
+
+#[tokio::test]
+async fn test_deleting_a_table_destroys_its_data_key() {
+    let dir = tempdir().unwrap();
+    let config = || VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config()).unwrap();
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "text".to_string(),
+    }];
+    let row = Row {
+        id: "alice".to_string(),
+        columns: vec![("name".to_string(), "Alice".into())],
+    };
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    db.insert_row("users", row.clone()).await.unwrap();
+    let stored_row = db.db.get("users/alice").unwrap().unwrap();
+    let stored_schema = db.schema.get("users").unwrap().unwrap();
+    assert_eq!(db.delete_table("users").await.unwrap(), 1);
+
+    // Restoring the records, and the table's entry without its key, doesn't bring the rows back
+    let restore = |db: &VibraDB| {
+        db.db.insert("users/alice", stored_row.clone()).unwrap();
+        db.schema.insert("users", stored_schema.clone()).unwrap();
+    };
+    restore(&db);
+    db.tables.insert("users", TableMeta::new().encode().unwrap()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::MissingTableKey(_))
+    ));
+    assert!(matches!(db.table_schema("users"), Err(VibraError::MissingTableKey(_))));
+    db.close().await.unwrap();
+
+    // Nor does a new table of the same name, whose data key is another
+    let db = VibraDB::new(config()).unwrap();
+    db.delete_table("users").await.unwrap();
+    db.create_table("users", Some(schema)).await.unwrap();
+    restore(&db);
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::Decryption { .. })
+    ));
+    db.close().await.unwrap();
+    let db = VibraDB::new(config()).unwrap();
+    assert!(matches!(
+        db.get_row("users", "alice").await,
+        Err(VibraError::Decryption { .. })
+    ));
+
+    // Tables from before data keys are given one by create_table, and keep their rows
+    db.delete_table("users").await.unwrap();
+    db.tables.insert("users", TableMeta::new().encode().unwrap()).unwrap();
+    let legacy = RecordKey::Master(db.master_key());
+    let json = row.to_json().unwrap();
+    let sealed = db.seal("users/alice", json.as_bytes(), 10, Some(&legacy)).unwrap();
+    db.db.insert("users/alice", sealed).unwrap();
+    assert!(!db.create_table("users", None).await.unwrap());
+    let meta = TableMeta::decode(&db.tables.get("users").unwrap().unwrap()).unwrap();
+    assert!(meta.data_key.is_some());
+    assert_eq!(db.get_row("users", "alice").await.unwrap(), Some(row));
+}
+
+#[test]
+fn test_master_key_debug_is_redacted() {
+    let key = MasterKey::from_bytes([0xab; 32]);
+    assert_eq!(format!("{:?}", key), "MasterKey(<redacted>)");
+    let config = VibraConfig::default().with_master_key(key);
+    assert!(config.master_key.is_some());
+    assert!(!format!("{:?}", config.master_key).contains("171"));
+
+    // Nor do data keys or the key material passed around while sealing
+    let data_key = DataKey::generate(&mut StdRng::seed_from_u64(1));
+    assert_eq!(format!("{:?}", data_key), "DataKey(<redacted>)");
+    let layer_keys = data_key.layer_keys(&[0; keys::SALT_LEN], "users/alice", 2);
+    assert_eq!(layer_keys.len(), 64);
+    assert_eq!(format!("{:?}", layer_keys), "SecretBytes(<redacted 64 bytes>)");
+    let generated = VibraDB::generate_key(&mut StdRng::seed_from_u64(1));
+    assert_eq!(format!("{:#?}", generated), "SecretBytes(<redacted 32 bytes>)");
+    assert_ne!(&*generated, &[0; 32]);
+}
+
+#[tokio::test]
+async fn test_seeded_rng_is_reproducible() {
+    let new_seeded_db = |seed| {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            seed: Some(seed),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        VibraDB::new(config).unwrap()
+    };
+
+    // Everything but the write timestamps in the header is reproducible
+    let encrypt = |seed| {
+        let mut stored = new_seeded_db(seed).encrypt_value("test/value", b"John Doe").unwrap();
+        stored[Timestamp::Created.range().start..Timestamp::Updated.range().end].fill(0);
+        stored
+    };
+    let first = encrypt(42);
+    let second = encrypt(42);
+    assert_eq!(first, second);
+
+    let other_seed = encrypt(7);
+    assert_ne!(first, other_seed);
+
+    // Unseeded databases never repeat key material
+    let db = VibraDB::new(VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        master_key: test_key(),
+        ..Default::default()
+    })
+    .unwrap();
+    let encrypt = || db.encrypt_value("test/value", b"John Doe").unwrap();
+    assert_ne!(encrypt(), encrypt());
+    assert_eq!(db.decrypt_value("test/value", &first).unwrap(), "John Doe");
+}
+
+#[tokio::test]
+async fn test_rekey_rotates_every_row() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().to_path_buf();
+    let config = VibraConfig {
+        path: Some(path.clone()),
+        cache_size: Some(1024),
+        encryption_layers: Some(5),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table(
+        "users",
+        Some(vec![Column {
+            name: "name".to_string(),
+            data_type: "string".to_string(),
+        }]),
+    )
+    .await
+    .unwrap();
+    let rows: Vec<Row> = (0..20)
+        .map(|i| Row {
+            id: format!("user{:02}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    let before = db.db.get("users/user00").unwrap().unwrap();
+
+    // A malformed master key is rejected before anything changes
+    assert!(matches!(
+        db.rekey(12, Some("secret".to_string())).await,
+        Err(VibraError::InvalidKey { .. })
+    ));
+    assert_eq!(db.encryption_layers(), 5);
+    assert_eq!(db.db.get("users/user00").unwrap().unwrap(), before);
+    assert_eq!(db.rekey(12, None).await.unwrap(), 20);
+    assert_eq!(db.encryption_layers(), 12);
+    let after = db.db.get("users/user00").unwrap().unwrap();
+    assert_ne!(before, after);
+    assert_eq!(RecordHeader::decode(&after).unwrap().0.layers, 12);
+    db.close().await.unwrap();
+
+    let config = VibraConfig {
+        path: Some(path),
+        cache_size: Some(1024),
+        encryption_layers: Some(12),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    // The schema was re-encrypted too
+    let bad = Row {
+        id: "bad".to_string(),
+        columns: vec![("age".to_string(), "42".into())],
+    };
+    assert!(db.insert_row("users", bad).await.is_err());
+}
+
+#[tokio::test]
+async fn test_rekey_with_a_new_master_key() {
+    let dir = tempdir().unwrap();
+    let config = |master_key: [u8; 32]| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: Some(MasterKey::from_bytes(master_key)),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config([7; 32])).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let rows: Vec<Row> = (0..5)
+        .map(|i| Row {
+            id: format!("user{}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+
+    assert_eq!(db.rekey(3, Some("ab".repeat(32))).await.unwrap(), 5);
+    assert_eq!(db.master_key().as_bytes(), &[0xab; 32]);
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    db.close().await.unwrap();
+
+    // The database now opens with the new key
+    let db = VibraDB::new(config([0xab; 32])).unwrap();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+}
+
+#[tokio::test]
+async fn test_rekey_settings_survive_a_reopen() {
+    let dir = tempdir().unwrap();
+    let config = |master_key: [u8; 32], cipher| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        cipher,
+        master_key: Some(MasterKey::from_bytes(master_key)),
+        ..Default::default()
+    };
+    let chacha = Some(CipherSuite::ChaCha20Poly1305);
+    let db = VibraDB::new(config([7; 32], chacha)).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    assert_eq!(db.rekey(4, None).await.unwrap(), 1);
+    db.close().await.unwrap();
+
+    // Without settings of its own, the config takes the layers and cipher of the rekey
+    let db = VibraDB::new(config([7; 32], None)).unwrap();
+    assert_eq!(db.encryption_layers(), 4);
+    assert_eq!(db.cipher(), CipherSuite::ChaCha20Poly1305);
+    // Including one that swapped the master key, and a truncate after it
+    assert_eq!(db.rekey(2, Some("ab".repeat(32))).await.unwrap(), 1);
+    db.truncate_db().await.unwrap();
+    db.close().await.unwrap();
+    let db = VibraDB::new(config([0xab; 32], None)).unwrap();
+    assert_eq!(db.encryption_layers(), 2);
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row).await.unwrap();
+    let stored = db.db.get("users/user1").unwrap().unwrap();
+    assert_eq!(RecordHeader::decode(&stored).unwrap().0.layers, 2);
+    db.close().await.unwrap();
+
+    // A config that sets them still wins
+    let db = VibraDB::new(config([0xab; 32], Some(CipherSuite::Aes256Gcm))).unwrap();
+    assert_eq!(db.encryption_layers(), 2);
+    assert_eq!(db.cipher(), CipherSuite::Aes256Gcm);
+}
+
+#[tokio::test]
+async fn test_insert_and_delete_return_prior_row() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+
+    let first = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    let second = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    assert_eq!(db.insert_row("users", first.clone()).await.unwrap(), None);
+    assert_eq!(db.insert_row("users", second.clone()).await.unwrap(), Some(first));
+    assert_eq!(db.delete_row("users", "user1").await.unwrap(), Some(second));
+    assert_eq!(db.delete_row("users", "user1").await.unwrap(), None);
+
+    // An expired row was never visible, so it isn't reported as replaced
+    let short_lived = Row {
+        id: "user2".to_string(),
+        columns: vec![("name".to_string(), "Temp".into())],
+    };
+    db.insert_row_with_ttl("users", short_lived.clone(), Duration::from_millis(1))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(db.insert_row("users", short_lived).await.unwrap(), None);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics_count_operations() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+
+    db.insert_row("users", row).await.unwrap();
+    db.get_row("users", "user1").await.unwrap(); // Cached by the insert
+    db.invalidate("users", "user1");
+    db.get_row("users", "user1").await.unwrap();
+    db.delete_row("users", "user1").await.unwrap();
+
+    let metrics = db.metrics_snapshot();
+    assert_eq!(metrics.inserts, 1);
+    assert_eq!(metrics.gets, 2);
+    assert_eq!(metrics.deletes, 1);
+    assert_eq!(metrics.cache_hits, 1);
+    assert_eq!(metrics.cache_misses, 1);
+    assert_eq!(metrics.get_latency.count, 2);
+    assert!(metrics.encryption_time.count >= 1);
+    assert!(metrics.to_prometheus().contains("vibra_inserts_total 1\n"));
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.health_check().await.unwrap();
+
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row).await.unwrap();
+    db.health_check().await.unwrap();
+    // The sentinel doesn't linger
+    assert!(db.db.get(HEALTH_KEY).unwrap().is_none());
+
+    // Stored data that no longer decrypts fails the check
+    let mut stored = db.db.get("users/user1").unwrap().unwrap().to_vec();
+    let last = stored.len() - 1;
+    stored[last] ^= 0xff;
+    db.db.insert("users/user1", stored).unwrap();
+    match db.health_check().await {
+        Err(VibraError::CorruptRow { key, .. }) => assert_eq!(key, "users/user1"),
+        other => panic!("expected a corrupt row error, got {:?}", other),
+    }
+    // As does a table whose data key doesn't unwrap, even without rows
+    db.create_table("orders", None).await.unwrap();
+    let mut meta: serde_json::Value =
+        serde_json::from_slice(&db.tables.get("orders").unwrap().unwrap()).unwrap();
+    meta["data_key"] = STANDARD.encode([0u8; 60]).into();
+    db.tables.insert("orders", serde_json::to_vec(&meta).unwrap()).unwrap();
+    assert!(matches!(
+        db.health_check().await,
+        Err(VibraError::MissingTableKey(table)) if table == "orders"
+    ));
+
+    // A broken config never gets as far as a usable database
+    let broken = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(0),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    assert!(VibraDB::new(broken).is_err());
+}
+
+#[tokio::test]
+async fn test_create_table_is_idempotent_under_concurrency() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "string".to_string(),
+    }];
+
+    let handles: Vec<_> = (0..32)
+        .map(|_| {
+            let db = db.clone();
+            let schema = schema.clone();
+            tokio::spawn(async move { db.create_table("users", Some(schema)).await.unwrap() })
+        })
+        .collect();
+    let mut created = 0;
+    for handle in handles {
+        if handle.await.unwrap() {
+            created += 1;
+        }
+    }
+    assert_eq!(created, 1);
+    assert_eq!(db.list_tables().await.unwrap(), vec!["users".to_string()]);
+
+    // Creating it again is a no-op that keeps the original schema
+    assert!(!db.create_table("users", None).await.unwrap());
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("age".to_string(), "42".into())],
+    };
+    assert!(db.insert_row("users", row).await.is_err());
+}
+
+#[tokio::test]
+async fn test_missing_table_is_not_a_missing_row() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+
+    match db.insert_row("users", row.clone()).await {
+        Err(VibraError::TableNotFound(table)) => assert_eq!(table, "users"),
+        other => panic!("expected a missing table error, got {:?}", other),
+    }
+    assert!(!db.table_exists("users").await.unwrap());
+    assert!(matches!(
+        db.get_row("users", "user1").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+    assert!(matches!(
+        db.delete_row("users", "user1").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+    assert!(matches!(
+        db.scan_table("users").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+
+    db.create_table("users", None).await.unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), None);
+    assert_eq!(db.delete_row("users", "user1").await.unwrap(), None);
+    assert!(db.scan_table("users").await.unwrap().is_empty());
+    assert_eq!(db.insert_row("users", row.clone()).await.unwrap(), None);
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+}
+
+#[tokio::test]
+async fn test_load_table() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let rows: Vec<Row> = (0..50)
+        .map(|i| Row {
+            id: format!("code{}", i),
+            columns: vec![("label".to_string(), format!("Label {}", i).into())],
+        })
+        .collect();
+    db.create_table("lookup", None).await.unwrap();
+    db.create_table("lookup_other", None).await.unwrap();
+    db.insert_many_rows("lookup", rows.clone()).await.unwrap();
+    db.insert_row("lookup_other", rows[0].clone()).await.unwrap();
+
+    let map = db.load_table("lookup").await.unwrap();
+    assert_eq!(map.len(), 50);
+    for row in rows {
+        assert_eq!(map.get(&row.id), Some(&row));
+    }
+    assert_eq!(db.load_table("lookup_other").await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_scan_table_lenient_skips_past_corrupt_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    for id in ["a", "c"] {
+        let row = Row {
+            id: id.to_string(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row("users", row).await.unwrap();
+    }
+    db.db.insert("users/b", b"not an encrypted record".to_vec()).unwrap();
+
+    assert!(db.scan_table("users").await.is_err());
+    let results = db.scan_table_lenient("users").await.unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 2);
+    assert_eq!(results[0].as_ref().unwrap().id, "a");
+    assert_eq!(results[2].as_ref().unwrap().id, "c");
+    match &results[1] {
+        Err(VibraError::CorruptRow { key, .. }) => assert_eq!(key, "users/b"),
+        other => panic!("expected a corrupt row error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_with_sled() {
+    let dir = tempdir().unwrap();
+    let sled_db = sled::Config::new()
+        .path(dir.path())
+        .cache_capacity(1024 * 1024)
+        .flush_every_ms(Some(50))
+        .mode(sled::Mode::LowSpace)
+        .open()
+        .unwrap();
+    let config = VibraConfig {
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::with_sled(sled_db.clone(), config).unwrap();
+    db.create_table("users", None).await.unwrap();
+
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+    // The handle is shared, so the caller's copy sees the same data
+    assert!(sled_db.get("users/user1").unwrap().is_some());
+
+    // Without a path there is no directory to delete
+    drop(sled_db);
+    assert!(matches!(db.delete_db().await, Err(VibraError::MissingConfig("path"))));
+}
+
+#[tokio::test]
+async fn test_row_meta_timestamps() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    assert_eq!(db.get_row_meta("users", "user1").await.unwrap(), None);
+
+    let before = SystemTime::now() - Duration::from_millis(1);
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row).await.unwrap();
+    let inserted = db.get_row_meta("users", "user1").await.unwrap().unwrap();
+    assert!(inserted.created_at >= before);
+    assert_eq!(inserted.created_at, inserted.updated_at);
+    assert_eq!(
+        inserted.size_bytes,
+        db.db.get("users/user1").unwrap().unwrap().len()
+    );
+
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.update_row("users", row).await.unwrap();
+    let updated = db.get_row_meta("users", "user1").await.unwrap().unwrap();
+    assert_eq!(updated.created_at, inserted.created_at);
+    assert!(updated.updated_at > inserted.updated_at);
+
+    // Counters are writes too, and rekeying is not
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    db.increment_column("users", "user1", "logins", 1).await.unwrap();
+    let incremented = db.get_row_meta("users", "user1").await.unwrap().unwrap();
+    assert_eq!(incremented.created_at, inserted.created_at);
+    assert!(incremented.updated_at > updated.updated_at);
+    db.rekey(12, None).await.unwrap();
+    let rekeyed = db.get_row_meta("users", "user1").await.unwrap().unwrap();
+    assert_eq!(rekeyed.created_at, incremented.created_at);
+    assert_eq!(rekeyed.updated_at, incremented.updated_at);
+}
+
+#[tokio::test]
+async fn test_truncate_tables_with_prefix() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let tables = [
+        "tenant42_users",
+        "tenant42_orders",
+        "tenant42_invoices",
+        "archive_tenant42_users",
+    ];
+    for table in tables {
+        db.create_table(table, None).await.unwrap();
+        let row = Row {
+            id: "row1".to_string(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row(table, row).await.unwrap();
+    }
+
+    assert_eq!(db.truncate_tables_with_prefix("tenant42_").await.unwrap(), 3);
+    for table in &tables[..3] {
+        assert!(db.table_exists(table).await.unwrap());
+        assert_eq!(db.get_row(table, "row1").await.unwrap(), None);
+        assert!(db.scan_table(table).await.unwrap().is_empty());
+    }
+    assert!(db.get_row("archive_tenant42_users", "row1").await.unwrap().is_some());
+    assert_eq!(db.truncate_tables_with_prefix("tenant7_").await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_duplicate_columns_are_rejected() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("name".to_string(), "Jane Doe".into()),
+        ],
+    };
+
+    match db.insert_row("users", row.clone()).await {
+        Err(VibraError::DuplicateColumn { table, column }) => {
+            assert_eq!(table, "users");
+            assert_eq!(column, "name");
+        }
+        other => panic!("expected a duplicate column error, got {:?}", other),
+    }
+    assert!(matches!(
+        db.insert_many_rows("users", vec![row]).await,
+        Err(VibraError::DuplicateColumn { .. })
+    ));
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_prefetch_warms_the_cache() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let rows: Vec<Row> = (0..5)
+        .map(|i| Row {
+            id: format!("user{}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    db.clear_cache();
+
+    db.prefetch("users", &["user0", "user1", "user2", "missing"])
+        .await
+        .unwrap();
+    let stats = db.cache_stats();
+    assert_eq!(stats.len, 3);
+    assert_eq!(stats.capacity, 1024);
+    assert_eq!((stats.hits, stats.misses), (0, 0));
+
+    for row in &rows[..3] {
+        assert_eq!(db.get_row("users", &row.id).await.unwrap().as_ref(), Some(row));
+    }
+    assert_eq!(db.cache_stats().hits, 3);
+    assert_eq!(db.get_row("users", "user3").await.unwrap().as_ref(), Some(&rows[3]));
+    assert_eq!(db.cache_stats().misses, 1);
+}
+
+#[tokio::test]
+async fn test_poisoned_cache_shard_is_cleared() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+
+    // Panic while the shard holding the row is locked
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        db.cache.pop_matching(|_| panic!("panic while holding a cache shard"))
+    }));
+    assert!(panicked.is_err());
+
+    // Reads fall back to sled and the shard works again
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row.clone()));
+    let stats = db.cache_stats();
+    assert_eq!((stats.poisoned, stats.misses), (1, 1));
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+    assert_eq!((db.cache_stats().poisoned, db.cache_stats().hits), (1, 1));
+}
+
+#[tokio::test]
+async fn test_cache_miss_caches_the_row_key() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    db.clear_cache();
+
+    assert_eq!(db.get_row("users", "user1").await.unwrap().as_ref(), Some(&row));
+    assert_eq!((db.cache_stats().hits, db.cache_stats().misses), (0, 1));
+    assert_eq!(db.get_row("users", "user1").await.unwrap().as_ref(), Some(&row));
+    assert_eq!((db.cache_stats().hits, db.cache_stats().misses), (1, 1));
+
+    // The miss cached exactly one entry, under the row's own key
+    assert_eq!(db.cache_stats().len, 1);
+    assert!(db.cache.get("users/user1").is_some());
+}
+
+#[tokio::test]
+async fn test_warm_reads_do_not_parse_json() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    let parses = || ROW_PARSES.with(|parses| parses.get());
+
+    // The insert cached the row itself, so reading it back parses nothing
+    let before = parses();
+    for _ in 0..10 {
+        assert_eq!(db.get_row("users", "user1").await.unwrap().as_ref(), Some(&row));
+    }
+    assert_eq!(parses(), before);
+
+    // A miss parses the row once, and the hits after it reuse the cached value
+    db.clear_cache();
+    for _ in 0..10 {
+        assert_eq!(db.get_row("users", "user1").await.unwrap().as_ref(), Some(&row));
+    }
+    assert_eq!(parses(), before + 1);
+    assert_eq!((db.cache_stats().hits, db.cache_stats().misses), (19, 1));
+    let cached = db.cache.get("users/user1").unwrap();
+    assert!(Arc::ptr_eq(&cached, &db.cache.get("users/user1").unwrap()));
+    assert_eq!(*cached, row);
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+struct Address {
+    city: String,
+    zip: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+struct Customer {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+    address: Address,
+}
+
+#[tokio::test]
+async fn test_typed_round_trip() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("customers", None).await.unwrap();
+    let customer = Customer {
+        name: "John Doe".to_string(),
+        age: 42,
+        tags: vec!["vip".to_string()],
+        address: Address {
+            city: "Springfield".to_string(),
+            zip: None,
+        },
+    };
+
+    db.insert_typed("customers", "c1", &customer).await.unwrap();
+    assert_eq!(
+        db.get_typed::<Customer>("customers", "c1").await.unwrap(),
+        Some(customer)
+    );
+    assert_eq!(db.get_typed::<Customer>("customers", "c2").await.unwrap(), None);
+    assert!(db.table_exists("customers").await.unwrap());
+    // A value of a different shape is an error, not a panic
+    assert!(db.get_typed::<Vec<u8>>("customers", "c1").await.is_err());
+    assert_eq!(db.cache_stats().len, 0);
+
+    // In a table with a schema, a value has to fit it like a row
+    let column = |name: &str, data_type: &str| Column {
+        name: name.to_string(),
+        data_type: data_type.to_string(),
+    };
+    let schema = vec![column("name", "string"), column("age", "integer")];
+    db.create_table("people", Some(schema)).await.unwrap();
+    #[derive(serde::Serialize)]
+    struct Person {
+        name: String,
+        age: u32,
+        nickname: Option<String>,
+    }
+    let person = |nickname: Option<&str>| Person {
+        name: "John Doe".to_string(),
+        age: 42,
+        nickname: nickname.map(str::to_string),
+    };
+    db.insert_typed("people", "p1", &person(None)).await.unwrap();
+    let err = db.insert_typed("people", "p2", &person(Some("JD"))).await.unwrap_err();
+    assert!(matches!(err, VibraError::SchemaViolation { column, .. } if column == "nickname"));
+    assert!(matches!(
+        db.insert_typed("people", "p3", &serde_json::json!({ "name": { "first": "John" } })).await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+    assert!(matches!(
+        db.insert_typed("people", "p4", &vec![1, 2, 3]).await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+    assert_eq!(db.get_typed::<serde_json::Value>("people", "p2").await.unwrap(), None);
+}
+
+#[test]
+fn test_row_cache_evicts_at_byte_budget() {
+    // A row of the given size: its id, one column name and the value's length
+    let row = |size: usize| {
+        Arc::new(Row {
+            id: "r".to_string(),
+            columns: vec![("v".to_string(), "x".repeat(size - 2).into())],
+        })
+    };
+    // Each entry is a 4-byte key plus a 96-byte row: 100 bytes
+    let entry = |i: usize| (format!("t/{:02}", i), row(96));
+    let mut cache = cache::RowCache::new(std::num::NonZero::new(1024).unwrap(), Some(300));
+    for i in 0..3 {
+        let (key, value) = entry(i);
+        cache.put(key, value);
+    }
+    assert_eq!((cache.len(), cache.bytes()), (3, 300));
+
+    // The fourth entry pushes the total past 300 bytes, so the least recently used one goes
+    cache.get("t/00");
+    let (key, value) = entry(3);
+    cache.put(key, value);
+    assert_eq!((cache.len(), cache.bytes()), (3, 300));
+    assert!(cache.contains("t/00"));
+    assert!(!cache.contains("t/01"));
+
+    // Replacing an entry accounts for the old value, and an oversized entry isn't kept
+    cache.put("t/00".to_string(), row(46));
+    assert_eq!((cache.len(), cache.bytes()), (3, 250));
+    cache.put("t/99".to_string(), row(400));
+    assert_eq!((cache.len(), cache.bytes()), (0, 0));
+
+    // Binary values count their length too
+    let blob = Arc::new(Row {
+        id: "r".to_string(),
+        columns: vec![("v".to_string(), Value::Bytes(vec![0; 94]))],
+    });
+    cache.put("t/00".to_string(), blob);
+    assert_eq!(cache.bytes(), 100);
+
+    // Whichever limit is hit first wins
+    let mut cache = cache::RowCache::new(std::num::NonZero::new(2).unwrap(), Some(1000));
+    for i in 0..3 {
+        let (key, value) = entry(i);
+        cache.put(key, value);
+    }
+    assert_eq!((cache.len(), cache.bytes()), (2, 200));
+    assert!(cache.pop("t/02").is_some());
+    assert_eq!(cache.bytes(), 100);
+}
+
+#[tokio::test]
+async fn test_cache_bytes_limits_cached_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        cache_bytes: Some(4400),
+        // One shard, so the whole budget applies to every row
+        cache_shards: Some(1),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("big", None).await.unwrap();
+    for i in 0..10 {
+        let row = Row {
+            id: format!("row{}", i),
+            columns: vec![("blob".to_string(), "x".repeat(1000).into())],
+        };
+        db.insert_row("big", row).await.unwrap();
+    }
+
+    let stats = db.cache_stats();
+    assert_eq!(stats.max_bytes, Some(4400));
+    assert!(stats.bytes <= 4400);
+    assert_eq!((stats.len, stats.shards), (4, 1));
+    // Evicted rows are still readable from sled
+    assert!(db.get_row("big", "row0").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_legacy_records_are_still_readable() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+
+    // Records written before the format was versioned lack the 4-byte prefix, and store their
+    // keys inline
+    let json = row.to_json().unwrap();
+    let stored = db.seal("users/user1", json.as_bytes(), 10, None).unwrap();
+    db.db.insert("users/user1", &stored[4..]).unwrap();
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row.clone()));
+    assert!(db.get_row_meta("users", "user1").await.unwrap().is_some());
+
+    // Rekeying rewrites it in the current format, with keys derived from the table's data key
+    db.rekey(10, None).await.unwrap();
+    let stored = db.db.get("users/user1").unwrap().unwrap();
+    let (header, _) = RecordHeader::decode(&stored).unwrap();
+    assert_eq!(header.version, record::VERSION_BOUND);
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+}
+
+#[test]
+fn test_sharded_cache_splits_its_budget() {
+    let entries = |n: usize| std::num::NonZero::new(n).unwrap();
+    let cache = cache::ShardedCache::new(entries(10), Some(1000), entries(4));
+    assert_eq!((cache.shard_count(), cache.cap()), (4, 10));
+    assert_eq!(cache.max_bytes(), Some(1000));
+
+    let row = Arc::new(Row {
+        id: "r".to_string(),
+        columns: vec![("v".to_string(), "x".repeat(8).into())],
+    });
+    for i in 0..100 {
+        cache.put(format!("t/{:02}", i), row.clone());
+    }
+    assert!(cache.len() <= 10);
+    assert!(cache.bytes() <= 1000);
+    let cached: Vec<String> = (0..100)
+        .map(|i| format!("t/{:02}", i))
+        .filter(|key| cache.contains(key))
+        .collect();
+    assert_eq!(cached.len(), cache.len());
+    assert_eq!(cache.pop_matching(|key| key.starts_with("t/")), cached.len());
+    assert_eq!((cache.len(), cache.bytes()), (0, 0));
+
+    // There are never more shards than entries
+    let cache = cache::ShardedCache::new(entries(3), None, entries(16));
+    assert_eq!((cache.shard_count(), cache.cap()), (3, 3));
+}
+
+// Read every row from 8 tasks in parallel, checking each read and that the cache served it
+async fn parallel_cached_gets(shards: usize) {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        cache_shards: Some(shards),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let rows: Vec<Row> = (0..64)
+        .map(|i| Row {
+            id: format!("user{:02}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    assert_eq!(db.cache_stats().shards, shards);
+
+    let tasks: Vec<_> = (0..8)
+        .map(|t| {
+            let db = db.clone();
+            let rows = rows.clone();
+            tokio::spawn(async move {
+                for round in 0..50 {
+                    for i in 0..rows.len() {
+                        let row = &rows[(i + t * 8 + round) % rows.len()];
+                        let got = db.get_row("users", &row.id).await.unwrap();
+                        assert_eq!(got.as_ref(), Some(row));
+                    }
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    // Every read was served by the cache
+    let stats = db.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (8 * 50 * 64, 0));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_parallel_gets_through_the_sharded_cache() {
+    parallel_cached_gets(1).await;
+    parallel_cached_gets(16).await;
+}
+
+#[tokio::test]
+async fn test_bad_records_are_errors_not_panics() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+
+    // Decrypts and passes its integrity check, but the JSON isn't a list of columns
+    let sealed = db.encrypt_value("users/user1", br#"{"name": "John Doe"}"#).unwrap();
+    db.db.insert("users/user1", sealed).unwrap();
+    assert!(matches!(
+        db.get_row("users", "user1").await,
+        Err(VibraError::Serialization(_))
+    ));
+    assert!(db.cache.get("users/user1").is_none());
+
+    // A bad cache entry is only a bad copy: it is dropped and the row read from sled instead
+    let row = Row {
+        id: "user2".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+    let someone_else = Arc::new(Row {
+        id: "someone else".to_string(),
+        columns: vec![],
+    });
+    db.cache.put("users/user2".to_string(), someone_else.clone());
+    assert_eq!(db.get_row("users", "user2").await.unwrap(), Some(row.clone()));
+    assert_eq!(db.cache.get("users/user2").as_deref(), Some(&row));
+    db.cache.put("users/missing".to_string(), someone_else);
+    assert_eq!(db.get_row("users", "missing").await.unwrap(), None);
+    assert!(db.cache.get("users/missing").is_none());
+
+    // Inserting over the bad record replaces it; the bad record isn't returned as the prior row
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    assert_eq!(db.insert_row("users", row.clone()).await.unwrap(), None);
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+}
+
+#[tokio::test]
+async fn test_unreadable_records_are_not_missing_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    assert_eq!(db.get_row("users", "absent").await.unwrap(), None);
+
+    // Garbage doesn't even parse as a record
+    db.db.insert("users/garbage", vec![0xb5; 512]).unwrap();
+    assert!(matches!(
+        db.get_row("users", "garbage").await,
+        Err(VibraError::MalformedRecord(_))
+    ));
+
+    // A well-formed record whose ciphertext was tampered with fails at the outermost layer
+    let json = br#"[["name",{"Text":"John Doe"}]]"#;
+    let mut sealed = db.encrypt_value("users/tampered", json).unwrap();
+    *sealed.last_mut().unwrap() ^= 0xff;
+    db.db.insert("users/tampered", sealed).unwrap();
+    assert!(matches!(
+        db.get_row("users", "tampered").await,
+        Err(VibraError::Decryption { layer: 9 })
+    ));
+    assert!(db.cache.get("users/tampered").is_none());
+}
+
+#[tokio::test]
+async fn test_decryption_stops_at_the_first_bad_layer() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let sealed = db.encrypt_value("users/user1", br#"{"id":"user1","columns":[]}"#).unwrap();
+    let (_, header_len) = RecordHeader::decode(&sealed).unwrap();
+    let nonces_start = header_len + 1 + keys::SALT_LEN;
+
+    // One flipped byte anywhere in the ciphertext fails the outermost layer's tag check
+    let mut flipped = sealed.clone();
+    flipped[nonces_start + 10 * 12 + 20] ^= 0x01;
+    db.db.insert("users/user1", flipped).unwrap();
+    assert!(matches!(
+        db.get_row("users", "user1").await,
+        Err(VibraError::Decryption { layer: 9 })
+    ));
+
+    // A corrupted nonce only fails its own layer, after the layers outside it decrypted fine
+    for layer in [0, 4, 9] {
+        let mut bad_nonce = sealed.clone();
+        bad_nonce[nonces_start + layer * 12] ^= 0x01;
+        db.db.insert("users/user1", bad_nonce).unwrap();
+        match db.get_row("users", "user1").await {
+            Err(VibraError::Decryption { layer: failed }) => assert_eq!(failed, layer),
+            other => panic!("expected a decryption error, got {:?}", other),
+        }
+    }
+    assert!(db.cache.get("users/user1").is_none());
+
+    db.db.insert("users/user1", sealed).unwrap();
+    assert!(db.get_row("users", "user1").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_rows_are_checked_against_their_key() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "alice".to_string(),
+        columns: vec![("name".to_string(), "Alice".into())],
+    };
+    db.insert_row("users", row).await.unwrap();
+
+    // A record with inline keys, which aren't bound to where it is stored, still names the row
+    // it was written for when copied under another id
+    let json = db.get_row("users", "alice").await.unwrap().unwrap().to_json().unwrap();
+    let stored = db.seal("users/alice", json.as_bytes(), 10, None).unwrap();
+    db.db.insert("users/mallory", stored).unwrap();
+    assert!(matches!(
+        db.get_row("users", "mallory").await,
+        Err(VibraError::RowIdMismatch { ref expected, ref stored })
+            if expected == "mallory" && stored == "alice"
+    ));
+
+    // Records from before the id was stored hold only the columns, and still read
+    let sealed = db.encrypt_value("users/bob", br#"[["name","Bob"]]"#).unwrap();
+    db.db.insert("users/bob", sealed).unwrap();
+    let bob = db.get_row("users", "bob").await.unwrap().unwrap();
+    assert_eq!((bob.id.as_str(), bob.get("name")), ("bob", Some(&"Bob".into())));
+}
+
+#[tokio::test]
+async fn test_short_entries_are_corrupt() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+
+    // Empty, like a table marker, and a record cut off partway through its header
+    let sealed = db.encrypt_value("users/torn", b"[]").unwrap();
+    for (id, stored) in [("empty", Vec::new()), ("torn", sealed[..20].to_vec())] {
+        db.db.insert(format!("users/{}", id), stored.clone()).unwrap();
+        match db.get_row("users", id).await {
+            Err(VibraError::CorruptEntry {
+                key,
+                expected_min,
+                actual,
+            }) => {
+                assert_eq!(key, format!("users/{}", id));
+                assert_eq!(actual, stored.len());
+                assert!(expected_min > actual);
+            }
+            other => panic!("expected CorruptEntry, got {:?}", other),
+        }
+    }
+    assert!(matches!(
+        db.scan_table("users").await,
+        Err(VibraError::CorruptEntry { .. })
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_readers_never_miss_a_row_being_updated() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let version = |i: usize| Row {
+        id: "user1".to_string(),
+        columns: vec![("version".to_string(), i.to_string().into())],
+    };
+    db.insert_row("users", version(0)).await.unwrap();
+
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|r| {
+            let (db, done) = (db.clone(), done.clone());
+            tokio::spawn(async move {
+                while !done.load(Ordering::Relaxed) {
+                    // Half the readers keep going to sled rather than the cache
+                    if r % 2 == 0 {
+                        db.invalidate("users", "user1");
+                    }
+                    assert!(db.get_row("users", "user1").await.unwrap().is_some());
+                }
+            })
+        })
+        .collect();
+    for i in 1..=200 {
+        db.update_row("users", version(i)).await.unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.await.unwrap();
+    }
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(version(200)));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_deleted_rows_stay_deleted_under_concurrent_reads() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+
+    for round in 0..100 {
+        let id = format!("user{}", round);
+        let row = Row {
+            id: id.clone(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row("users", row).await.unwrap();
+
+        let deleted = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..8)
+            .map(|r| {
+                let (db, deleted, id) = (db.clone(), deleted.clone(), id.clone());
+                tokio::spawn(async move {
+                    while !deleted.load(Ordering::Relaxed) {
+                        // Most readers go to sled, so their fills race the delete
+                        if r % 4 != 0 {
+                            db.invalidate("users", &id);
+                        }
+                        db.get_row("users", &id).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..round % 8 {
+            task::yield_now().await;
+        }
+        db.delete_row("users", &id).await.unwrap();
+        deleted.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.await.unwrap();
+        }
+
+        // Reads that were still in flight during the delete must not have cached the row again
+        assert!(!db.cache.contains(&format!("users/{}", id)), "round {}", round);
+        assert_eq!(db.get_row("users", &id).await.unwrap(), None, "round {}", round);
+    }
+}
+
+#[test]
+fn test_operations_time_out() {
+    // A single blocking thread, so a hung blocking task holds up every sled call behind it
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        let db = VibraDB::new(config).unwrap();
+        db.create_table("users", None).await.unwrap();
+        let row = Row {
+            id: "user1".to_string(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row("users", row.clone()).await.unwrap();
+        db.clear_cache();
+
+        let hung = task::spawn_blocking(|| std::thread::sleep(Duration::from_millis(300)));
+        let timeout = Duration::from_millis(20);
+        assert!(matches!(
+            db.get_row_with_timeout("users", "user1", timeout).await,
+            Err(VibraError::Timeout(t)) if t == timeout
+        ));
+        let renamed = Row {
+            id: "user1".to_string(),
+            columns: vec![("name".to_string(), "Jane Doe".into())],
+        };
+        assert!(matches!(
+            db.insert_row_with_timeout("users", renamed.clone(), timeout).await,
+            Err(VibraError::Timeout(_))
+        ));
+        assert_eq!(db.cache_stats().len, 0);
+
+        // Once the blocking thread frees up, the abandoned write lands and reads see it
+        hung.await.unwrap();
+        task::spawn_blocking(|| ()).await.unwrap();
+        assert_eq!(
+            db.get_row_with_timeout("users", "user1", Duration::from_secs(5)).await.unwrap(),
+            Some(renamed)
+        );
+    });
+}
+
+#[test]
+fn test_failed_writes_leave_no_phantom_rows() {
+    // A single blocking thread, so writes queue up behind a task we control
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        };
+        let db = VibraDB::new(config).unwrap();
+        db.create_table("users", None).await.unwrap();
+        let row = |id: &str, name: &str| Row {
+            id: id.to_string(),
+            columns: vec![("name".to_string(), name.into())],
+        };
+        db.insert_row("users", row("user1", "John Doe")).await.unwrap();
+
+        // Both writes pass validation, then fail in sled because the table vanished under them
+        let (release, hold) = std::sync::mpsc::channel::<()>();
+        let hung = task::spawn_blocking(move || hold.recv());
+        let update = tokio::spawn({
+            let db = db.clone();
+            async move { db.update_row("users", row("user1", "Jane Doe")).await }
+        });
+        let insert = tokio::spawn({
+            let db = db.clone();
+            async move { db.insert_row("users", row("user2", "Richard Roe")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let marker = db.tables.remove("users").unwrap().unwrap();
+        release.send(()).unwrap();
+        hung.await.unwrap().unwrap();
+        assert!(matches!(update.await.unwrap(), Err(VibraError::TableNotFound(_))));
+        assert!(matches!(insert.await.unwrap(), Err(VibraError::TableNotFound(_))));
+
+        // Neither the cache nor sled has the rows that were never written
+        db.tables.insert("users", marker).unwrap();
+        assert!(db.cache.get("users/user2").is_none());
+        for _ in 0..2 {
+            assert_eq!(
+                db.get_row("users", "user1").await.unwrap(),
+                Some(row("user1", "John Doe"))
+            );
+            assert_eq!(db.get_row("users", "user2").await.unwrap(), None);
+            db.clear_cache();
+        }
+    });
+}
+
+// Move `amount` between two accounts' balances, aborting if the source would go negative
+async fn transfer(db: &VibraDB, from: &str, to: &str, amount: i64) -> Result<(), VibraError> {
+    let (from, to) = (from.to_string(), to.to_string());
+    db.transaction(&["accounts"], move |tx| {
+        let balance = |id: &str| -> TransactionResult<i64> {
+            let row = tx.get("accounts", id)?.ok_or_else(|| VibraError::InvalidValue {
+                column: "balance".to_string(),
+                reason: format!("no account {}", id),
+            })?;
+            Ok(row.get("balance").and_then(Value::as_text).unwrap().parse().unwrap())
+        };
+        let set = |id: &str, balance: i64| -> TransactionResult<()> {
+            let row = Row {
+                id: id.to_string(),
+                columns: vec![("balance".to_string(), balance.to_string().into())],
+            };
+            tx.insert("accounts", row)?;
+            Ok(())
+        };
+        let to_balance = balance(&to)?;
+        set(&to, to_balance + amount)?;
+        // The credit above has been written; failing now must undo it too
+        let from_balance = balance(&from)?;
+        if from_balance < amount {
+            return Err(VibraError::InvalidValue {
+                column: "balance".to_string(),
+                reason: "insufficient funds".to_string(),
+            }
+            .into());
+        }
+        set(&from, from_balance - amount)?;
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_transaction_transfer() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("accounts", None).await.unwrap();
+    for (id, balance) in [("alice", "100"), ("bob", "50")] {
+        let row = Row {
+            id: id.to_string(),
+            columns: vec![("balance".to_string(), balance.into())],
+        };
+        db.insert_row("accounts", row).await.unwrap();
+    }
+    let balance = |db: VibraDB, id: &'static str| async move {
+        let row = db.get_row("accounts", id).await.unwrap().unwrap();
+        row.get("balance").and_then(Value::as_text).unwrap().to_string()
+    };
+
+    transfer(&db, "alice", "bob", 30).await.unwrap();
+    assert_eq!(balance(db.clone(), "alice").await, "70");
+    assert_eq!(balance(db.clone(), "bob").await, "80");
+
+    // Aborted after crediting bob: nothing changes, in sled or in the cache
+    assert!(matches!(
+        transfer(&db, "alice", "bob", 500).await,
+        Err(VibraError::InvalidValue { .. })
+    ));
+    assert_eq!(balance(db.clone(), "alice").await, "70");
+    assert_eq!(balance(db.clone(), "bob").await, "80");
+
+    // Concurrent transfers in both directions conflict and retry, but never lose money
+    let handles: Vec<_> = (0..20)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let (from, to) = if i % 2 == 0 { ("alice", "bob") } else { ("bob", "alice") };
+                transfer(&db, from, to, 5).await.unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(balance(db.clone(), "alice").await, "70");
+    assert_eq!(balance(db.clone(), "bob").await, "80");
+
+    assert!(matches!(
+        db.transaction(&["missing"], |_| Ok(())).await,
+        Err(VibraError::TableNotFound(_))
+    ));
+    assert!(matches!(
+        db.transaction(&["accounts"], |tx| tx.get("other", "alice")).await,
+        Err(VibraError::InvalidName { .. })
+    ));
+}
+
+// Records every log message, so tests can check what was logged
+struct CaptureLogger(Mutex<Vec<String>>);
+
+static CAPTURED_LOGS: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+// Messages logged so far, by any test, that mention `needle`
+fn logs_mentioning(needle: &str) -> Vec<String> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&CAPTURED_LOGS).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    let logs = CAPTURED_LOGS.0.lock().unwrap();
+    logs.iter().filter(|message| message.contains(needle)).cloned().collect()
+}
+
+#[tokio::test]
+async fn test_log_operations() {
+    logs_mentioning(""); // Install the logger before anything is logged
+    for log_operations in [false, true] {
+        let config = VibraConfig {
+            path: Some(tempdir().unwrap().path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            log_operations,
+            master_key: test_key(),
+            ..Default::default()
+        };
+        let db = VibraDB::new(config).unwrap();
+        let (table, id) = (format!("logged_{}", log_operations), format!("row_{}", log_operations));
+        db.create_table(&table, None).await.unwrap();
+        let row = Row {
+            id: id.clone(),
+            columns: vec![("name".to_string(), "Alice".into())],
+        };
+        db.insert_row(&table, row).await.unwrap();
+        assert!(db.get_row(&table, &id).await.unwrap().is_some());
+        db.delete_row(&table, &id).await.unwrap();
+
+        if log_operations {
+            let logs = logs_mentioning(&id);
+            assert!(logs.iter().any(|message| message.starts_with("Inserted row")));
+            assert!(logs.iter().any(|message| message.starts_with("Cache hit")));
+            assert!(logs.iter().any(|message| message.starts_with("Deleted row")));
+        } else {
+            assert_eq!(logs_mentioning(&table), Vec::<String>::new());
+            assert_eq!(logs_mentioning(&id), Vec::<String>::new());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_logs_never_contain_key_material_or_plaintext() {
+    logs_mentioning(""); // Install the logger before anything is logged
+    // Per-row logging is on, the most that is ever logged
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        log_operations: true,
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("secrets", None).await.unwrap();
+    let secret = "correct-horse-battery-staple";
+    let row = Row {
+        id: "row1".to_string(),
+        columns: vec![("password".to_string(), secret.into())],
+    };
+
+    // Insert, hit, miss and replace, then fail to parse a record whose JSON quotes the secret
+    db.insert_row("secrets", row.clone()).await.unwrap();
+    db.get_row("secrets", "row1").await.unwrap();
+    db.clear_cache();
+    db.get_row("secrets", "row1").await.unwrap();
+    let stored = db.db.get("secrets/row1").unwrap().unwrap();
+    db.insert_row("secrets", row).await.unwrap();
+    let bad = format!(r#"{{"id": "row2", "columns": "{}"}}"#, secret);
+    let sealed = db.encrypt_value("secrets/row2", bad.as_bytes()).unwrap();
+    db.db.insert("secrets/row2", sealed).unwrap();
+    assert!(db.get_row("secrets", "row2").await.is_err());
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let record = record::decode_record(&stored).unwrap();
+    let mut material: Vec<String> = record.key_material.chunks(32).map(hex).collect();
+    material.extend(record.nonces.chunks(12).map(hex));
+    assert!(!logs_mentioning("row1").is_empty());
+    assert_eq!(logs_mentioning(secret), Vec::<String>::new());
+    for bytes in material {
+        assert_eq!(logs_mentioning(&bytes), Vec::<String>::new());
+    }
+    // The parse failure is still logged, just without the value it failed on
+    let failures = logs_mentioning("secrets/row2");
+    assert!(failures.iter().any(|message| message.contains("Data error in row JSON")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_insert_row_if_absent() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = |writer: usize| Row {
+        id: "user1".to_string(),
+        columns: vec![("writer".to_string(), writer.to_string().into())],
+    };
+
+    // Racing writers for the same id: exactly one wins, and its row is the one stored
+    let writers: Vec<_> = (0..8)
+        .map(|writer| {
+            let db = db.clone();
+            tokio::spawn(async move { db.insert_row_if_absent("users", row(writer)).await })
+        })
+        .collect();
+    let mut winners = Vec::new();
+    for (writer, handle) in writers.into_iter().enumerate() {
+        if handle.await.unwrap().unwrap() {
+            winners.push(writer);
+        }
+    }
+    assert_eq!(winners.len(), 1);
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row(winners[0])));
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row(winners[0])));
+
+    // A losing call leaves the cache alone too
+    assert!(!db.insert_row_if_absent("users", row(99)).await.unwrap());
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row(winners[0])));
+
+    // An expired row is as good as absent
+    db.insert_row_with_ttl("users", row(100), Duration::from_millis(1)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(db.insert_row_if_absent("users", row(101)).await.unwrap());
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row(101)));
+
+    assert!(matches!(
+        db.insert_row_if_absent("missing", row(0)).await,
+        Err(VibraError::TableNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_table_markers_live_in_their_own_tree() {
+    let dir = tempdir().unwrap();
+    let open = || {
+        VibraDB::new(VibraConfig {
+            path: Some(dir.path().to_path_buf()),
+            cache_size: Some(1024),
+            encryption_layers: Some(10),
+            master_key: test_key(),
+            ..Default::default()
+        })
+        .unwrap()
+    };
+    let db = open();
+    let before = now_millis();
+    db.create_table("users", None).await.unwrap();
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    db.insert_row("users", row.clone()).await.unwrap();
+
+    // The row keyspace only holds rows; the table has a metadata record of its own
+    assert!(db.db.iter().keys().all(|key| key.unwrap().contains(&b'/')));
+    let meta = db.tables.get("users").unwrap().unwrap();
+    let meta: TableMeta = serde_json::from_slice(&meta).unwrap();
+    assert_eq!(meta.version, TABLE_META_VERSION);
+    assert!(meta.created_at >= before && meta.created_at <= now_millis());
+
+    // A database from before the tables tree has bare markers among its rows, and rows encrypted
+    // with the master key
+    db.tables.remove("users").unwrap();
+    db.db.insert("users", b"").unwrap();
+    let json = row.to_json().unwrap();
+    let legacy = RecordKey::Master(db.master_key());
+    let sealed = db.seal("users/user1", json.as_bytes(), 10, Some(&legacy)).unwrap();
+    db.db.insert("users/user1", sealed).unwrap();
+    db.close().await.unwrap();
+
+    let db = open();
+    assert!(!db.db.contains_key("users").unwrap());
+    assert!(db.table_exists("users").await.unwrap());
+    assert_eq!(db.list_tables().await.unwrap(), vec!["users".to_string()]);
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+}
+
+#[tokio::test]
+async fn test_reserved_table_names_are_rejected() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(10),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    for name in ["__vibra", "__vibra__", "__vibra_health", "__vibra_tables"] {
+        assert!(matches!(
+            db.create_table(name, None).await,
+            Err(VibraError::InvalidName { .. })
+        ));
+    }
+    // The health check's sentinel can't be read or clobbered as a row either
+    assert!(matches!(
+        db.get_row("__vibra_health", "sentinel").await,
+        Err(VibraError::InvalidName { .. })
+    ));
+    db.health_check().await.unwrap();
+
+    // Names that merely contain the prefix are fine
+    assert!(db.create_table("my__vibra", None).await.unwrap());
+    assert!(db.create_table("_vibra", None).await.unwrap());
+}
+
+fn names_config(dir: &Path, encrypt_names: bool) -> VibraConfig {
+    VibraConfig {
+        path: Some(dir.to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(4),
+        master_key: test_key(),
+        encrypt_names,
+        ..Default::default()
+    }
+    .with_table(
+        "customers",
+        TableConfig {
+            sensitive: Some(vec!["email".to_string()]),
+            ..Default::default()
+        },
+    )
+}
+
+// Every key of every tree, lowercased
+fn raw_keys(db: &VibraDB) -> Vec<String> {
+    let mut keys = vec![];
+    for name in db.db.tree_names() {
+        keys.push(String::from_utf8_lossy(&name).to_lowercase());
+        for key in db.db.open_tree(&name).unwrap().iter().keys() {
+            keys.push(String::from_utf8_lossy(&key.unwrap()).to_lowercase());
+        }
+    }
+    keys
+}
+
+#[tokio::test]
+async fn test_encrypted_names_keep_names_out_of_keys() {
+    let dir = tempdir().unwrap();
+    let customer = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![
+            ("name".to_string(), id.into()),
+            ("email".to_string(), format!("{}@example.com", id).into()),
+        ],
+    };
+    let ids = ["zoe", "alice", "mallory", "bob"];
+    let db = VibraDB::new(names_config(dir.path(), true)).unwrap();
+    let schema = vec![Column {
+        name: "sku".to_string(),
+        data_type: "string".to_string(),
+    }];
+    db.create_table("customers", None).await.unwrap();
+    db.create_table("customers_archive", None).await.unwrap();
+    db.create_table("orders", Some(schema)).await.unwrap();
+    for id in ids {
+        db.insert_row("customers", customer(id)).await.unwrap();
+    }
+    let ttl = Duration::from_secs(3600);
+    db.insert_row_with_ttl("customers_archive", customer("trent"), ttl).await.unwrap();
+    db.create_blind_index("customers", "email").await.unwrap();
+    let order = Row {
+        id: "order-1".to_string(),
+        columns: vec![("sku".to_string(), "widget".into())],
+    };
+    db.insert_row("orders", order.clone()).await.unwrap();
+
+    // No key of any tree holds a table name or row id
+    let names = ["customers", "archive", "orders", "zoe", "alice", "mallory", "bob", "trent"];
+    for key in raw_keys(&db) {
+        for name in names.iter().chain(&["order-1"]) {
+            assert!(!key.contains(name), "found {:?} in key {:?}", name, key);
+        }
+    }
+
+    // The public API behaves as it does with names in the clear
+    let mut sorted = ids.map(customer).to_vec();
+    sorted.sort_by(|a, b| a.id.cmp(&b.id));
+    assert_eq!(db.scan_table("customers").await.unwrap(), sorted);
+    assert_eq!(db.get_row("customers", "bob").await.unwrap(), Some(customer("bob")));
+    assert_eq!(db.get_row("customers", "carol").await.unwrap(), None);
+    assert!(db.table_exists("customers").await.unwrap());
+    assert!(!db.table_exists("suppliers").await.unwrap());
+    let tables = db.list_tables().await.unwrap();
+    assert_eq!(tables, ["customers", "customers_archive", "orders"]);
+    let (page, cursor) = db.scan_paginated("customers", None, 2).await.unwrap();
+    assert_eq!((page, cursor.as_deref()), (sorted[..2].to_vec(), Some("bob")));
+    let (page, cursor) = db.scan_paginated("customers", Some("bob"), 2).await.unwrap();
+    assert_eq!((page, cursor), (sorted[2..].to_vec(), None));
+    let found = db.find_by("customers", "email", "Mallory@example.com").await.unwrap();
+    assert_eq!(found, vec![customer("mallory")]);
+    let columns = db.scan_columns("customers", &["name"]).await.unwrap();
+    assert_eq!(columns.iter().map(|row| row.id.as_str()).collect::<Vec<_>>(), sorted_ids(&ids));
+    assert!(matches!(
+        db.insert_row("orders", customer("eve")).await,
+        Err(VibraError::SchemaViolation { .. })
+    ));
+
+    assert_eq!(db.delete_row("customers", "zoe").await.unwrap(), Some(customer("zoe")));
+    assert_eq!(db.get_row("customers", "zoe").await.unwrap(), None);
+    assert_eq!(db.truncate_tables_with_prefix("customers_").await.unwrap(), 1);
+    assert!(db.scan_table("customers_archive").await.unwrap().is_empty());
+    assert_eq!(db.scan_table("customers").await.unwrap().len(), 3);
+    db.truncate_table("customers").await.unwrap();
+    assert!(db.scan_table("customers").await.unwrap().is_empty());
+    assert!(db.find_by("customers", "email", "bob@example.com").await.unwrap().is_empty());
+    assert_eq!(db.delete_table("customers_archive").await.unwrap(), 0);
+    assert_eq!(db.list_tables().await.unwrap(), ["customers", "orders"]);
+
+    // The names key is kept with the database, which keeps its names encrypted when the config
+    // no longer asks for it
+    db.insert_row("customers", customer("alice")).await.unwrap();
+    db.close().await.unwrap();
+    let db = VibraDB::new(names_config(dir.path(), false)).unwrap();
+    assert_eq!(db.get_row("customers", "alice").await.unwrap(), Some(customer("alice")));
+    assert_eq!(db.get_row("orders", "order-1").await.unwrap(), Some(order));
+    assert_eq!(db.list_tables().await.unwrap(), ["customers", "orders"]);
+    assert!(raw_keys(&db).iter().all(|key| !key.contains("alice")));
+
+    // A database created without it keeps its names as they are
+    let plain_dir = tempdir().unwrap();
+    let plain = VibraDB::new(names_config(plain_dir.path(), false)).unwrap();
+    plain.create_table("customers", None).await.unwrap();
+    plain.close().await.unwrap();
+    let plain = VibraDB::new(names_config(plain_dir.path(), true)).unwrap();
+    plain.insert_row("customers", customer("alice")).await.unwrap();
+    assert!(plain.db.contains_key("customers/alice").unwrap());
+}
+
+fn sorted_ids<'a>(ids: &[&'a str]) -> Vec<&'a str> {
+    let mut ids = ids.to_vec();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn test_encrypted_names_survive_rotation_and_dumps() {
+    let dir = tempdir().unwrap();
+    let row = Row {
+        id: "alice".to_string(),
+        columns: vec![("email".to_string(), "alice@example.com".into())],
+    };
+    let db = VibraDB::new(names_config(dir.path(), true)).unwrap();
+    db.create_table("accounts", None).await.unwrap();
+    db.insert_row("accounts", row.clone()).await.unwrap();
+
+    // Dumps carry the names the rows are stored under, and import into a database that keeps
+    // its names in the clear. The table has no sensitive columns, which would keep its row ids
+    // in the clear part of each row.
+    let dump = dir.path().join("accounts.dump");
+    db.export_table("accounts", &dump).await.unwrap();
+    let dumped = String::from_utf8_lossy(&fs::read(&dump).unwrap()).to_lowercase();
+    assert!(!dumped.contains("accounts") && !dumped.contains("alice"));
+    let plain_dir = tempdir().unwrap();
+    let plain = VibraDB::new(names_config(plain_dir.path(), false)).unwrap();
+    assert_eq!(plain.import_table("clients", &dump).await.unwrap(), 1);
+    assert_eq!(plain.get_row("clients", "alice").await.unwrap(), Some(row.clone()));
+    assert!(plain.db.contains_key("clients/alice").unwrap());
+
+    // The names key is re-wrapped with the data keys, so the database opens with the new key
+    let new_key = MasterKey::from_bytes([9; 32]);
+    db.rotate_master_key(new_key.clone()).await.unwrap();
+    db.close().await.unwrap();
+    let config = VibraConfig {
+        master_key: Some(new_key),
+        ..names_config(dir.path(), true)
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert_eq!(db.get_row("accounts", "alice").await.unwrap(), Some(row.clone()));
+    db.close().await.unwrap();
+    assert!(matches!(
+        VibraDB::new(names_config(dir.path(), true)),
+        Err(VibraError::InvalidKey { .. })
+    ));
+
+    // Truncating the database keeps the key the tables created afterwards are named with
+    let config = VibraConfig {
+        master_key: Some(MasterKey::from_bytes([9; 32])),
+        ..names_config(dir.path(), true)
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.truncate_db().await.unwrap();
+    db.create_table("accounts", None).await.unwrap();
+    db.insert_row("accounts", row.clone()).await.unwrap();
+    db.close().await.unwrap();
+    let config = VibraConfig {
+        master_key: Some(MasterKey::from_bytes([9; 32])),
+        ..names_config(dir.path(), true)
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert_eq!(db.get_row("accounts", "alice").await.unwrap(), Some(row));
+    assert!(raw_keys(&db).iter().all(|key| !key.contains("alice")));
+}
+
+#[tokio::test]
+async fn test_migrate_legacy_rewrites_inline_records_and_resumes() {
+    let dir = tempdir().unwrap();
+    let config = || VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(3),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "text".to_string(),
+    }];
+    let rows: Vec<Row> = (0..250)
+        .map(|i| Row {
+            id: format!("user{:03}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+
+    // A directory as versions before master keys left it: tables without data keys, rows and
+    // schemas with their keys inline, half of them from before records were versioned
+    let db = VibraDB::new(config()).unwrap();
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    let legacy = |tree: &sled::Tree, context: fn(&str) -> String| {
+        for (i, entry) in tree.iter().enumerate() {
+            let (key, stored) = entry.unwrap();
+            let context = context(str::from_utf8(&key).unwrap());
+            let value = db.decrypt_bytes(&context, &stored).unwrap();
+            let sealed = db.seal(&context, &value, 3, None).unwrap();
+            let sealed = if i % 2 == 0 { &sealed[4..] } else { &sealed[..] };
+            tree.insert(key, sealed).unwrap();
+        }
+    };
+    legacy(&db.db, str::to_string);
+    legacy(&db.schema, VibraDB::schema_context);
+    let mut meta = TableMeta::decode(&db.tables.get("users").unwrap().unwrap()).unwrap();
+    meta.data_key = None;
+    db.tables.insert("users", meta.encode().unwrap()).unwrap();
+    db.close().await.unwrap();
+
+    // Legacy records read as they are
+    let db = VibraDB::new(config()).unwrap();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert_eq!(db.table_schema("users").unwrap(), Some(schema.clone()));
+
+    // Interrupted after the schemas and the first batch of rows, it picks up where it stopped
+    let report = db.migrate_batches(Some(2)).await.unwrap();
+    assert_eq!(
+        report,
+        MigrationReport {
+            migrated: 101,
+            skipped: 0,
+            failed: 0
+        }
+    );
+    assert!(db.meta.get(migration::MIGRATION_KEY).unwrap().is_some());
+    db.close().await.unwrap();
+    let db = VibraDB::new(config()).unwrap();
+    let report = db.migrate_legacy().await.unwrap();
+    assert_eq!(
+        report,
+        MigrationReport {
+            migrated: 150,
+            skipped: 0,
+            failed: 0
+        }
+    );
+    assert!(db.meta.get(migration::MIGRATION_KEY).unwrap().is_none());
+    for tree in [&**db.db, &db.schema] {
+        for entry in tree.iter().values() {
+            let entry = entry.unwrap();
+            let record = record::decode_record(&entry).unwrap();
+            assert_eq!(record.header.version, record::VERSION_BOUND);
+            assert_eq!(record.header.layers, 3);
+            assert_eq!(record.key_source, KeySource::Table);
+        }
+    }
+    // Nothing is left to migrate
+    let report = db.migrate_legacy().await.unwrap();
+    assert_eq!((report.migrated, report.skipped), (0, 251));
+    db.close().await.unwrap();
+
+    let db = VibraDB::new(config()).unwrap();
+    assert_eq!(db.scan_table("users").await.unwrap(), rows);
+    assert_eq!(db.table_schema("users").unwrap(), Some(schema));
+}
+
+#[tokio::test]
+async fn test_verify_integrity_flags_corrupt_rows() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    for table_name in ["orders", "users"] {
+        db.create_table(table_name, None).await.unwrap();
+        let rows = (0..300).map(|i| Row {
+            id: format!("r{:03}", i),
+            columns: vec![("n".to_string(), format!("{}", i).into())],
+        });
+        db.insert_many_rows(table_name, rows.collect()).await.unwrap();
+    }
+    // Typed values aren't rows, but are intact
+    db.insert_typed("users", "settings", &vec![1, 2, 3]).await.unwrap();
+    let report = db.verify_integrity().await.unwrap();
+    assert_eq!(report, IntegrityReport { ok: 601, corrupt: vec![] });
+
+    // A flipped bit, a truncated record, and a record that decrypts to something other than JSON
+    let mut flipped = db.db.get("orders/r007").unwrap().unwrap().to_vec();
+    let last = flipped.len() - 1;
+    flipped[last] ^= 1;
+    db.db.insert("orders/r007", flipped).unwrap();
+    let truncated = db.db.get("users/r250").unwrap().unwrap();
+    db.db.insert("users/r250", &truncated[..20]).unwrap();
+    db.db.insert("users/r001", db.encrypt_value("users/r001", b"{not json").unwrap()).unwrap();
+
+    let report = db.verify_integrity().await.unwrap();
+    assert_eq!(report.ok, 598);
+    let keys: Vec<&str> = report.corrupt.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["orders/r007", "users/r001", "users/r250"]);
+    assert!(report.corrupt[2].1.contains("at least"), "{}", report.corrupt[2].1);
+
+    let report = db.verify_table_integrity("orders").await.unwrap();
+    assert_eq!(report.ok, 299);
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].0, "orders/r007");
+    assert!(matches!(
+        db.verify_table_integrity("missing").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_upgrade_on_read_converges_to_current_settings() {
+    let dir = tempdir().unwrap();
+    let config = |layers, cipher, upgrade_on_read| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(layers),
+        cipher: Some(cipher),
+        upgrade_on_read,
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let rows: Vec<Row> = (0..20)
+        .map(|i| Row {
+            id: format!("user{:02}", i),
+            columns: vec![("name".to_string(), format!("User {}", i).into())],
+        })
+        .collect();
+
+    // Rows written with two layers of AES-GCM, a few of them with their keys inline
+    let db = VibraDB::new(config(2, CipherSuite::Aes256Gcm, false)).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.insert_many_rows("users", rows.clone()).await.unwrap();
+    for row in &rows[..5] {
+        let key = format!("users/{}", row.id);
+        let sealed = db.seal(&key, row.to_json().unwrap().as_bytes(), 2, None).unwrap();
+        db.db.insert(key, sealed).unwrap();
+    }
+    db.close().await.unwrap();
+
+    let db = VibraDB::new(config(3, CipherSuite::ChaCha20Poly1305, true)).unwrap();
+    let outdated = |db: &VibraDB| {
+        let entries = db.db.iter().map(Result::unwrap);
+        entries.filter(|(k, v)| db.is_outdated(str::from_utf8(k).unwrap(), v)).count()
+    };
+    assert_eq!(outdated(&db), 20);
+    for row in &rows {
+        assert_eq!(db.get_row("users", &row.id).await.unwrap().as_ref(), Some(row));
+    }
+    // Upgrades finish in the background
+    for _ in 0..500 {
+        if outdated(&db) == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(outdated(&db), 0);
+
+    // A second pass reads everything back, with nothing left to upgrade
+    db.clear_cache();
+    for row in &rows {
+        assert_eq!(db.get_row("users", &row.id).await.unwrap().as_ref(), Some(row));
+    }
+    for (_, stored) in db.db.iter().map(Result::unwrap) {
+        let record = record::decode_record(&stored).unwrap();
+        assert_eq!(record.header.layers, 3);
+        assert_eq!(record.header.cipher_suite(), CipherSuite::ChaCha20Poly1305);
+        assert_eq!(record.key_source, KeySource::Table);
+    }
+
+    // An upgrade of a record that was written since it was read leaves the write alone
+    let stale = db.db.get("users/user00").unwrap().unwrap();
+    let mut updated = rows[0].clone();
+    updated.set("name", "Renamed");
+    db.update_row("users", updated.clone()).await.unwrap();
+    db.upgrade_record("users/user00".to_string(), stale, rows[0].to_json().unwrap().into_bytes());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    db.clear_cache();
+    assert_eq!(db.get_row("users", "user00").await.unwrap(), Some(updated));
+}
+
+#[tokio::test]
+async fn test_nonces_never_repeat_across_restarts() {
+    let dir = tempdir().unwrap();
+    let config = |strict_nonces| VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(3),
+        strict_nonces,
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut prefix = None;
+    let mut collect_nonces = |db: &VibraDB| {
+        for stored in db.db.iter().values() {
+            let stored = stored.unwrap();
+            let record = record::decode_record(&stored).unwrap();
+            assert!(record.header.has_counter_nonces());
+            for nonce in record.nonces.chunks(12) {
+                assert_eq!(*prefix.get_or_insert(nonce[..4].to_vec()), nonce[..4]);
+                seen.insert(nonce.to_vec());
+            }
+        }
+    };
+    // Many concurrent writers, then a clean restart, then a restart after a crash that lost the
+    // latest high-water marks
+    let write_burst = |db: &VibraDB, round: usize| {
+        let tasks = (0..8).map(|task| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    let row = Row {
+                        id: format!("r{}-{}-{}", round, task, i),
+                        columns: vec![("v".to_string(), "x".into())],
+                    };
+                    db.insert_row("t", row).await.unwrap();
+                }
+            })
+        });
+        futures::future::join_all(tasks.collect::<Vec<_>>())
+    };
+    for (round, strict) in [false, true, false].into_iter().enumerate() {
+        let db = VibraDB::new(config(strict)).unwrap();
+        if round == 0 {
+            db.create_table("t", None).await.unwrap();
+        }
+        let mark_at_open = db.meta.get(nonces::NONCE_KEY).unwrap().unwrap();
+        for result in write_burst(&db, round).await {
+            result.unwrap();
+        }
+        if round == 1 {
+            // Forget the marks written since the database was opened, as a crash might
+            db.meta.insert(nonces::NONCE_KEY, mark_at_open).unwrap();
+        }
+        db.close().await.unwrap();
+    }
+    let db = VibraDB::new(config(false)).unwrap();
+    collect_nonces(&db);
+    // Every layer of every chunk of 1200 rows got a nonce of its own
+    assert_eq!(seen.len(), 1200 * 3);
+
+    // A sequence with no room left past its mark refuses to open rather than wrap around
+    let state: serde_json::Value =
+        serde_json::from_slice(&db.meta.get(nonces::NONCE_KEY).unwrap().unwrap()).unwrap();
+    let exhausted = serde_json::json!({ "prefix": state["prefix"], "reserved": u64::MAX - 1 });
+    db.meta.insert(nonces::NONCE_KEY, serde_json::to_vec(&exhausted).unwrap()).unwrap();
+    db.close().await.unwrap();
+    let err = VibraDB::new(config(false)).err().unwrap();
+    assert!(matches!(err, VibraError::NonceExhausted));
+    let reason = "the nonce sequence is exhausted: every nonce it can hand out has been used";
+    assert_eq!(err.to_string(), reason);
+}
+
+#[tokio::test]
+async fn test_encrypted_backup_restores_under_another_master_key() {
+    let dir = tempdir().unwrap();
+    let config = |name: &str, master_key| VibraConfig {
+        path: Some(dir.path().join(name)),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        encrypt_names: true,
+        master_key: Some(MasterKey::from_bytes(master_key)),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config("source", [1; 32])).unwrap();
+    let schema = vec![Column {
+        name: "country".to_string(),
+        data_type: "string".to_string(),
+    }];
+    db.create_table("users", Some(schema.clone())).await.unwrap();
+    db.create_table("orders", None).await.unwrap();
+    db.create_table("empty", None).await.unwrap();
+    db.create_table("settings", None).await.unwrap();
+    // More rows than are restored in one batch
+    let users = (0..600).map(|i| Row {
+        id: format!("user{:03}", i),
+        columns: vec![("country".to_string(), ["France", "Peru"][i % 2].into())],
+    });
+    db.insert_many_rows("users", users.collect()).await.unwrap();
+    db.create_blind_index("users", "country").await.unwrap();
+    let order = Row {
+        id: "o1".to_string(),
+        columns: vec![("total".to_string(), "42".into())],
+    };
+    db.insert_row_with_ttl("orders", order, Duration::from_secs(3600)).await.unwrap();
+    db.insert_typed("settings", "limits", &vec![1, 2, 3]).await.unwrap();
+    let backup_key = MasterKey::from_bytes([9; 32]);
+    let backup = dir.path().join("vibra.backup");
+    assert_eq!(db.backup_encrypted(&backup, &backup_key).await.unwrap(), 602);
+
+    // Nothing in the backup is readable as is, the row ids included
+    let bytes = fs::read(&backup).unwrap();
+    assert!(!bytes.windows(7).any(|window| window == b"user001" || window == b"France\""));
+
+    let restored = VibraDB::restore_encrypted(&backup, &backup_key, config("restored", [2; 32]))
+        .await
+        .unwrap();
+    assert_eq!(restored.list_tables().await.unwrap(), ["empty", "orders", "settings", "users"]);
+    for table_name in ["empty", "orders", "users"] {
+        let rows = db.scan_table(table_name).await.unwrap();
+        assert_eq!(restored.scan_table(table_name).await.unwrap(), rows);
+        for row in rows {
+            let meta = db.get_row_meta(table_name, &row.id).await.unwrap().unwrap();
+            let copy = restored.get_row_meta(table_name, &row.id).await.unwrap().unwrap();
+            assert_eq!((meta.created_at, meta.updated_at), (copy.created_at, copy.updated_at));
+        }
+    }
+    assert_eq!(restored.table_schema("users").unwrap(), Some(schema));
+    assert_eq!(restored.find_by("users", "country", "Peru").await.unwrap().len(), 300);
+    assert_eq!(restored.expiry.len(), 1);
+    let settings: Vec<i32> = restored.get_typed("settings", "limits").await.unwrap().unwrap();
+    assert_eq!(settings, [1, 2, 3]);
+    // The restored database is sealed with its own master key alone
+    restored.close().await.unwrap();
+    assert!(matches!(
+        VibraDB::new(config("restored", [1; 32])),
+        Err(VibraError::InvalidKey { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_encrypted_backup_rejects_wrong_keys_and_damage() {
+    let dir = tempdir().unwrap();
+    let config = |name: &str| VibraConfig {
+        path: Some(dir.path().join(name)),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config("source")).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let rows = (0..10).map(|i| Row {
+        id: format!("user{}", i),
+        columns: vec![("name".to_string(), format!("User {}", i).into())],
+    });
+    db.insert_many_rows("users", rows.collect()).await.unwrap();
+    let backup_key = MasterKey::from_bytes([9; 32]);
+    let backup = dir.path().join("vibra.backup");
+    db.backup_encrypted(&backup, &backup_key).await.unwrap();
+    let bytes = fs::read(&backup).unwrap();
+
+    // Each failure leaves no database behind
+    let restore = |name: &'static str, key: [u8; 32]| {
+        let config = config(name);
+        let backup = backup.clone();
+        async move { VibraDB::restore_encrypted(&backup, &MasterKey::from_bytes(key), config).await }
+    };
+    assert!(matches!(restore("wrong", [8; 32]).await, Err(VibraError::InvalidBackupKey)));
+    assert!(!dir.path().join("wrong").exists());
+
+    let mut damaged = bytes.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 1;
+    fs::write(&backup, &damaged).unwrap();
+    assert!(matches!(
+        restore("damaged", [9; 32]).await,
+        Err(VibraError::InvalidBackup(ref reason)) if reason.contains("authenticate")
+    ));
+    fs::write(&backup, &bytes[..bytes.len() - 10]).unwrap();
+    assert!(matches!(
+        restore("truncated", [9; 32]).await,
+        Err(VibraError::InvalidBackup(ref reason)) if reason.contains("end of file")
+    ));
+    fs::write(&backup, b"not a backup").unwrap();
+    assert!(matches!(restore("garbage", [9; 32]).await, Err(VibraError::InvalidBackup(_))));
+    for name in ["damaged", "truncated", "garbage"] {
+        assert!(!dir.path().join(name).exists());
+    }
+
+    // A database that already has tables isn't restored into
+    fs::write(&backup, &bytes).unwrap();
+    assert!(matches!(
+        VibraDB::restore_encrypted(&backup, &backup_key, config("source")).await,
+        Err(VibraError::InvalidConfig(_)) | Err(VibraError::Open { .. })
+    ));
+    let restored = restore("restored", [9; 32]).await.unwrap();
+    assert_eq!(restored.scan_table("users").await.unwrap(), db.scan_table("users").await.unwrap());
+}
+
+// A database keeping an audit log, with a few changes of each kind recorded in it
+async fn audited_db(dir: &Path) -> VibraDB {
+    let config = VibraConfig {
+        path: Some(dir.to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        audit_log: true,
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    db.create_table("orders", None).await.unwrap();
+    for id in ["user1", "user2"] {
+        let row = Row {
+            id: id.to_string(),
+            columns: vec![("name".to_string(), "John Doe".into())],
+        };
+        db.insert_row("users", row).await.unwrap();
+    }
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "Jane Doe".into())],
+    };
+    db.update_row("users", row).await.unwrap();
+    db.delete_row("users", "user2").await.unwrap();
+    // Deleting a row that isn't there changes nothing, so isn't recorded
+    db.delete_row("users", "user3").await.unwrap();
+    db.truncate_table("users").await.unwrap();
+    db.delete_table("orders").await.unwrap();
+    db
+}
+
+#[tokio::test]
+async fn test_audit_log_records_changes_in_a_chain() {
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    let intact = AuditReport {
+        verified: 6,
+        first_broken: None,
+    };
+    assert_eq!(db.verify_audit_log().await.unwrap(), intact);
+    let log = db.db.open_tree(audit::AUDIT_TREE).unwrap();
+    let entries: Vec<serde_json::Value> = log
+        .iter()
+        .values()
+        .map(|stored| serde_json::from_slice(&stored.unwrap()[32..]).unwrap())
+        .collect();
+    let ops: Vec<&str> = entries.iter().map(|entry| entry["op"].as_str().unwrap()).collect();
+    assert_eq!(ops, ["insert", "insert", "update", "delete", "truncate_table", "delete_table"]);
+    assert_eq!(entries[3]["table"], "users");
+    assert_eq!(entries[3]["row_id"], "user2");
+    assert!(entries[5].get("row_id").is_none());
+    assert_eq!(entries[0]["prev"], "0".repeat(64));
+
+    // Every other way of writing rows is recorded too, in the same transaction as the write
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    assert!(db.insert_row_if_absent("users", row("a")).await.unwrap());
+    assert!(!db.insert_row_if_absent("users", row("a")).await.unwrap());
+    db.increment_column("users", "a", "visits", 1).await.unwrap();
+    db.append_to_column("users", "b", "name", b"x").await.unwrap();
+    db.insert_many_rows("users", vec![row("a"), row("c")]).await.unwrap();
+    db.insert_rows_atomic("users", vec![row("d")]).await.unwrap();
+    db.transaction(&["users"], move |tx| {
+        tx.insert("users", row("e"))?;
+        tx.remove("users", "d")?;
+        Ok(())
+    })
+    .await
+    .unwrap();
+    let aborted = db.transaction(&["users"], move |tx| -> TransactionResult<()> {
+        tx.insert("users", row("g"))?;
+        Err(VibraError::InvalidConfig("abort".to_string()).into())
+    });
+    assert!(aborted.await.is_err());
+    db.insert_row_with_ttl("users", row("f"), Duration::ZERO).await.unwrap();
+    assert_eq!(db.sweep_expired("users").await.unwrap(), 1);
+    // A read of an expired row removes it, but isn't taken for a delete
+    db.insert_row_with_ttl("users", row("g"), Duration::ZERO).await.unwrap();
+    assert!(db.get_row("users", "g").await.unwrap().is_none());
+    db.truncate_db().await.unwrap();
+    let ops: Vec<String> = log
+        .iter()
+        .values()
+        .skip(6)
+        .map(|stored| {
+            let entry: serde_json::Value = serde_json::from_slice(&stored.unwrap()[32..]).unwrap();
+            format!("{} {}", entry["op"].as_str().unwrap(), entry["row_id"].as_str().unwrap_or(""))
+        })
+        .collect();
+    let expected = [
+        "insert a", "update a", "insert b", "update a", "insert c", "insert d", "insert e",
+        "delete d", "insert f", "expire f", "insert g", "expire g", "delete_table ",
+    ];
+    assert_eq!(ops, expected);
+    assert_eq!(db.verify_audit_log().await.unwrap().verified, 19);
+    drop(log);
+
+    // The chain carries on after a reopen, and survives a master key rotation
+    db.close().await.unwrap();
+    let db = audited_db(dir.path()).await;
+    assert_eq!(db.verify_audit_log().await.unwrap().verified, 25);
+    let new_key = MasterKey::from_bytes([7; 32]);
+    db.rotate_master_key(new_key.clone()).await.unwrap();
+    db.delete_table("users").await.unwrap();
+    assert_eq!(db.verify_audit_log().await.unwrap().verified, 26);
+    db.close().await.unwrap();
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        master_key: Some(new_key),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    assert_eq!(db.verify_audit_log().await.unwrap().verified, 26);
+
+    // Without audit_log, changes aren't recorded
+    db.create_table("users", None).await.unwrap();
+    db.truncate_table("users").await.unwrap();
+    assert_eq!(db.verify_audit_log().await.unwrap().verified, 26);
+}
+
+#[tokio::test]
+async fn test_verify_audit_log_pinpoints_tampering() {
+    let tampered = |db: &VibraDB| db.db.open_tree(audit::AUDIT_TREE).unwrap();
+    let first_broken = |report: AuditReport| report.first_broken.unwrap();
+
+    // An edited entry no longer matches its MAC
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    let log = tampered(&db);
+    let stored = log.get(2u64.to_be_bytes()).unwrap().unwrap();
+    let edited = String::from_utf8_lossy(&stored).replace("\"update\"", "\"insert\"");
+    log.insert(2u64.to_be_bytes(), edited.as_bytes()).unwrap();
+    let report = db.verify_audit_log().await.unwrap();
+    assert_eq!(report.verified, 2);
+    let (seq, reason) = first_broken(report);
+    assert_eq!(seq, 2);
+    assert!(reason.contains("MAC"), "{}", reason);
+
+    // An entry edited by someone with the key breaks the link to the one after it
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    let log = tampered(&db);
+    let stored = log.get(1u64.to_be_bytes()).unwrap().unwrap();
+    let body = String::from_utf8_lossy(&stored[32..]).replace("user2", "user9");
+    let mut mac = <hmac::Hmac<Sha256> as hmac::Mac>::new_from_slice(&test_key().unwrap().audit_key()).unwrap();
+    hmac::Mac::update(&mut mac, body.as_bytes());
+    let resealed = [hmac::Mac::finalize(mac).into_bytes().as_slice(), body.as_bytes()].concat();
+    log.insert(1u64.to_be_bytes(), resealed).unwrap();
+    let (seq, reason) = first_broken(db.verify_audit_log().await.unwrap());
+    assert_eq!(seq, 2);
+    assert!(reason.contains("chain to entry 1"), "{}", reason);
+
+    // So do removed entries, even the last one
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    let log = tampered(&db);
+    log.remove(3u64.to_be_bytes()).unwrap();
+    assert_eq!(first_broken(db.verify_audit_log().await.unwrap()), (3, "it is missing".to_string()));
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    tampered(&db).remove(5u64.to_be_bytes()).unwrap();
+    assert_eq!(first_broken(db.verify_audit_log().await.unwrap()).0, 5);
+
+    // As do entries swapped around
+    let dir = tempdir().unwrap();
+    let db = audited_db(dir.path()).await;
+    let log = tampered(&db);
+    let (first, second) = (log.get(0u64.to_be_bytes()).unwrap(), log.get(1u64.to_be_bytes()).unwrap());
+    log.insert(0u64.to_be_bytes(), second.unwrap()).unwrap();
+    log.insert(1u64.to_be_bytes(), first.unwrap()).unwrap();
+    assert_eq!(first_broken(db.verify_audit_log().await.unwrap()), (0, "it is numbered 1".to_string()));
+}
+
+#[tokio::test]
+async fn test_shred_row_removes_the_row_everywhere() {
+    let dir = tempdir().unwrap();
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("email".to_string(), format!("{}@example.com", id).into())],
+    };
+    db.insert_row_with_ttl("users", row("user1"), Duration::from_secs(3600)).await.unwrap();
+    db.insert_row("users", row("user2")).await.unwrap();
+    db.create_blind_index("users", "email").await.unwrap();
+    db.get_row("users", "user1").await.unwrap();
+    assert!(db.cache.contains("users/user1"));
+
+    assert!(db.shred_row("users", "user1").await.unwrap());
+    assert!(db.db.get("users/user1").unwrap().is_none());
+    assert!(db.expiry.get("users/user1").unwrap().is_none());
+    assert!(!db.cache.contains("users/user1"));
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), None);
+    assert!(db.find_by("users", "email", "user1@example.com").await.unwrap().is_empty());
+    assert_eq!(db.scan_table("users").await.unwrap(), [row("user2")]);
+
+    // A row that is already gone is no error, and a stale cache entry for it goes too
+    db.cache.put("users/user1".to_string(), Arc::new(row("user1")));
+    assert!(!db.shred_row("users", "user1").await.unwrap());
+    assert!(!db.cache.contains("users/user1"));
+    assert!(!db.shred_row("users", "user3").await.unwrap());
+    assert!(matches!(
+        db.shred_row("missing", "user1").await,
+        Err(VibraError::TableNotFound(_))
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shred_row_alongside_an_insert() {
+    let config = VibraConfig {
+        path: Some(tempdir().unwrap().path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(1),
+        master_key: test_key(),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let row = |id: &str, name: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), name.into())],
+    };
+
+    for round in 0..100 {
+        let id = format!("user{}", round);
+        db.insert_row("users", row(&id, "old")).await.unwrap();
+        // Reads meanwhile see one of the rows or nothing, never the noise
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = tokio::spawn({
+            let (db, id, done) = (db.clone(), id.clone(), done.clone());
+            async move {
+                while !done.load(Ordering::Relaxed) {
+                    db.cache.pop(&format!("users/{}", id));
+                    let read = db.get_row("users", &id).await.unwrap();
+                    let (old, new) = (row(&id, "old"), row(&id, "new"));
+                    assert!(read.is_none() || read == Some(old) || read == Some(new));
+                }
+            }
+        });
+        let shred = tokio::spawn({
+            let (db, id) = (db.clone(), id.clone());
+            async move { db.shred_row("users", &id).await.unwrap() }
+        });
+        db.insert_row("users", row(&id, "new")).await.unwrap();
+        assert!(shred.await.unwrap());
+        done.store(true, Ordering::Relaxed);
+        reader.await.unwrap();
+        // The new row is kept if it was written after the shred began, and is whole either way
+        let stored = db.get_row("users", &id).await.unwrap();
+        assert!(stored.is_none() || stored == Some(row(&id, "new")), "{:?}", stored);
+    }
+}
+
+#[tokio::test]
+async fn test_shred_table_destroys_its_data_key() {
+    let dir = tempdir().unwrap();
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        tables: HashMap::from([(
+            "countries".to_string(),
+            TableConfig {
+                encrypted: Some(false),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    for table_name in ["users", "countries"] {
+        db.create_table(table_name, None).await.unwrap();
+        let rows = (0..10).map(|i| Row {
+            id: format!("r{}", i),
+            columns: vec![("name".to_string(), format!("Name {}", i).into())],
+        });
+        db.insert_many_rows(table_name, rows.collect()).await.unwrap();
+        db.get_row(table_name, "r1").await.unwrap();
+    }
+    let copy = db.db.get("users/r1").unwrap().unwrap();
+
+    assert_eq!(db.shred_table("users").await.unwrap(), 10);
+    assert!(!db.table_exists("users").await.unwrap());
+    assert_eq!(db.db.scan_prefix("users/").count(), 0);
+    assert!(!db.cache.contains("users/r1"));
+    // A copy of a row left behind doesn't decrypt, even once the table is back
+    db.create_table("users", None).await.unwrap();
+    db.db.insert("users/r1", copy).unwrap();
+    assert!(db.get_row("users", "r1").await.is_err());
+
+    // A table without a data key is shredded all the same
+    assert_eq!(db.shred_table("countries").await.unwrap(), 10);
+    assert!(!db.table_exists("countries").await.unwrap());
+    assert_eq!(db.db.scan_prefix("countries/").count(), 0);
+    assert!(matches!(db.shred_table("countries").await, Err(VibraError::TableNotFound(_))));
+}
+
+#[tokio::test]
+async fn test_cache_plaintext_false_caches_records() {
+    let dir = tempdir().unwrap();
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        cache_plaintext: Some(false),
+        tables: HashMap::from([(
+            "countries".to_string(),
+            TableConfig {
+                cache_plaintext: Some(true),
+                ..Default::default()
+            },
+        )]),
+        ..Default::default()
+    };
+    let db = VibraDB::new(config).unwrap();
+    let row = |id: &str, name: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), name.into())],
+    };
+    db.create_table("users", None).await.unwrap();
+    db.create_table("countries", None).await.unwrap();
+    db.insert_row("users", row("user1", "John Doe")).await.unwrap();
+    db.insert_row("countries", row("fr", "France")).await.unwrap();
+
+    // A write only evicts the record, which the next read caches as stored
+    assert!(!db.cache.contains("users/user1"));
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row("user1", "John Doe")));
+    assert!(db.cache.get("users/user1").is_none());
+    let cached = db.cache.get_sealed("users/user1").unwrap();
+    assert_eq!(cached, db.db.get("users/user1").unwrap().unwrap());
+    assert!(!cached.windows(8).any(|window| window == b"John Doe"));
+    // Hits are decrypted again, without going to sled
+    let hits = db.cache_stats().hits;
+    db.db.remove("users/user1").unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row("user1", "John Doe")));
+    assert_eq!(db.cache_stats().hits, hits + 1);
+    db.insert_row("users", row("user1", "Jane Doe")).await.unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row("user1", "Jane Doe")));
+    db.delete_row("users", "user1").await.unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), None);
+
+    // Prefetching caches records too, and a table can keep caching rows
+    db.insert_many_rows("users", vec![row("user2", "Jane Roe")]).await.unwrap();
+    db.prefetch("users", &["user2"]).await.unwrap();
+    assert!(db.cache.get_sealed("users/user2").is_some());
+    assert_eq!(db.get_row("countries", "fr").await.unwrap(), Some(row("fr", "France")));
+    assert!(db.cache.get("countries/fr").is_some());
+    assert!(db.cache.get_sealed("countries/fr").is_none());
+}
+
+// A database in the given compliance mode, with the given layer count if set, that names rows in
+// its errors
+fn compliance_config(
+    path: &Path,
+    compliance_mode: ComplianceMode,
+    encryption_layers: Option<usize>,
+) -> VibraConfig {
+    VibraConfig {
+        path: Some(path.to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers,
+        compliance_mode: Some(compliance_mode),
+        master_key: test_key(),
+        log_operations: true,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_strict_compliance_mode_rejects_a_legacy_database_until_migrated() {
+    let dir = tempdir().unwrap();
+    let row = |id: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+    let standard = compliance_config(dir.path(), ComplianceMode::Standard, Some(2));
+    let db = VibraDB::new(standard).unwrap();
+    let schema = vec![Column {
+        name: "name".to_string(),
+        data_type: "string".to_string(),
+    }];
+    db.create_table("users", Some(schema)).await.unwrap();
+    db.insert_row("users", row("user1")).await.unwrap();
+    db.insert_row("users", row("user2")).await.unwrap();
+    db.close().await.unwrap();
+
+    // Opening it strict fails, naming the first record and pointing at the migration
+    let strict = || compliance_config(dir.path(), ComplianceMode::Strict, None);
+    let err = VibraDB::new(strict()).err().unwrap();
+    match &err {
+        VibraError::NonConformantDatabase { records, key, reason } => {
+            assert_eq!(*records, 3);
+            assert_eq!(key, "users");
+            assert_eq!(reason, "its schema: it has 2 encryption layers");
+        }
+        other => panic!("expected NonConformantDatabase, got {:?}", other),
+    }
+    assert!(err.to_string().contains("rekey(1, None)"));
+
+    // The scan lists everything that doesn't conform; rekeying to one layer fixes all of it
+    let db = VibraDB::new(compliance_config(dir.path(), ComplianceMode::Standard, Some(1))).unwrap();
+    let reason = "it has 2 encryption layers".to_string();
+    assert_eq!(
+        db.scan_nonconformant().await.unwrap(),
+        vec![
+            ("users".to_string(), format!("its schema: {}", reason)),
+            ("users/user1".to_string(), reason.clone()),
+            ("users/user2".to_string(), reason),
+        ]
+    );
+    assert_eq!(db.rekey(1, None).await.unwrap(), 2);
+    assert!(db.scan_nonconformant().await.unwrap().is_empty());
+    db.close().await.unwrap();
+
+    let db = VibraDB::new(strict()).unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row("user1")));
+}
+
+#[tokio::test]
+async fn test_strict_compliance_mode() {
+    let dir = tempdir().unwrap();
+    let strict = || compliance_config(dir.path(), ComplianceMode::Strict, None);
+    let db = VibraDB::new(strict()).unwrap();
+    assert_eq!(db.encryption_layers(), 1);
+    let row = |id: &str, name: &str| Row {
+        id: id.to_string(),
+        columns: vec![("name".to_string(), name.into())],
+    };
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row("user1", "John Doe")).await.unwrap();
+    db.update_row("users", row("user1", "Jane Doe")).await.unwrap();
+    db.insert_row("users", row("user2", "John Roe")).await.unwrap();
+    assert_eq!(db.delete_row("users", "user2").await.unwrap(), Some(row("user2", "John Roe")));
+    db.clear_cache();
+    assert_eq!(db.scan_table("users").await.unwrap(), vec![row("user1", "Jane Doe")]);
+    assert!(db.scan_nonconformant().await.unwrap().is_empty());
+
+    // Every record is one AES-256-GCM layer under derived keys, bound to its key
+    let stored = db.db.get("users/user1").unwrap().unwrap();
+    let (header, _) = RecordHeader::decode(&stored).unwrap();
+    assert_eq!(header.version, record::VERSION_BOUND);
+    assert_eq!(header.cipher, record::CIPHER_LAYERED_AES_GCM);
+    assert_eq!(header.layers, 1);
+
+    // Nothing else is written or read
+    assert!(matches!(db.rekey(2, None).await, Err(VibraError::InvalidConfig(_))));
+    assert!(matches!(
+        db.encrypt_with_layers("users/user3", b"{}", 2),
+        Err(VibraError::NonConformant { .. })
+    ));
+    let json = row("user3", "Jane Roe").to_json().unwrap();
+    let sealed = db.seal("users/user3", json.as_bytes(), 2, Some(&db.record_key("users/user3"))).unwrap();
+    db.db.insert("users/user3", sealed).unwrap();
+    match db.get_row("users", "user3").await {
+        Err(VibraError::NonConformant { reason, .. }) => {
+            assert_eq!(reason, "it has 2 encryption layers")
+        }
+        other => panic!("expected NonConformant, got {:?}", other),
+    }
+    db.db.remove("users/user3").unwrap();
+    db.close().await.unwrap();
+
+    // Its master key has to come from outside
+    let err = VibraDB::open_with_passphrase(strict(), "hunter2").err().unwrap();
+    assert!(matches!(err, VibraError::InvalidConfig(_)));
+}
+
+#[tokio::test]
+async fn test_lookups_match_values_exactly() {
+    let dir = tempdir().unwrap();
+    let user = |id: &str, email: &str, country: &str| Row {
+        id: id.to_string(),
+        columns: vec![
+            ("email".to_string(), email.into()),
+            ("country".to_string(), country.into()),
+        ],
+    };
+    let config = VibraConfig {
+        path: Some(dir.path().to_path_buf()),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        master_key: test_key(),
+        ..Default::default()
+    }
+    .with_table(
+        "users",
+        TableConfig {
+            deterministic: Some(vec!["country".to_string()]),
+            ..Default::default()
+        },
+    );
+    let db = VibraDB::new(config).unwrap();
+    db.create_table("users", None).await.unwrap();
+    let alice = user("alice", "alice@example.com", "France");
+    // Values differing from alice's in their last byte only
+    let mallory = user("mallory", "alice@example.con", "Francf");
+    db.insert_many_rows("users", vec![alice.clone(), mallory]).await.unwrap();
+    db.create_blind_index("users", "email").await.unwrap();
+
+    // An index entry pointing alice's MAC at mallory, as a stale or colliding one would, finds
+    // only the row whose value matches
+    let entry = db.index.iter().keys().map(Result::unwrap).find(|key| key.ends_with(b"/alice"));
+    let entry = String::from_utf8(entry.unwrap().to_vec()).unwrap();
+    let stale = format!("{}mallory", entry.strip_suffix("alice").unwrap());
+    db.index.insert(stale, &[]).unwrap();
+    let found = db.find_by("users", "email", "alice@example.com").await.unwrap();
+    assert_eq!(found, vec![alice.clone()]);
+    assert!(db.find_by("users", "email", "alice@example.co").await.unwrap().is_empty());
+
+    // Deterministic ciphertext matches the same way
+    assert_eq!(db.find_by("users", "country", "France").await.unwrap(), vec![alice]);
+    assert!(db.find_by("users", "country", "Franc").await.unwrap().is_empty());
+}
@@ -0,0 +1,303 @@
+use super::keys::DataKey;
+use super::names::Names;
+use super::{has_expired, record, NewTable, TableMeta, Timestamp, VibraDB};
+use crate::error::VibraError;
+use crate::models::Column;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tokio::task;
+
+// Table dumps start with these bytes, followed by the format version
+const DUMP_MAGIC: &[u8; 8] = b"VIBRADMP";
+const DUMP_VERSION: u8 = 4;
+
+// A row as it is stored: its id, its expiry (unix millis, if any) and its encrypted record
+type DumpedRow = (String, Option<u64>, Vec<u8>);
+
+// What a dump file holds
+struct Dump {
+    source: String,            // The exported table's name
+    data_key: Option<Vec<u8>>, // Its data key, wrapped by the master key, if it had one
+    names_key: Option<Vec<u8>>, // The names key, wrapped likewise, if the database had one
+    schema: Option<Vec<u8>>,   // Its encrypted schema, if any
+    rows: Vec<DumpedRow>,
+}
+
+impl VibraDB {
+    // Export a table's encrypted records to a portable dump file.
+    //
+    // Records are copied as stored, key material and headers included, along with the table's
+    // data key as wrapped by the master key, so the dump is as encrypted as the database, and
+    // importing it takes the master key it was written with. A table created without encryption
+    // has no data key, and its records are dumped unencrypted. The table name and row ids are
+    // those it is stored under, so a database that encrypts names dumps them encrypted, along
+    // with its wrapped names key. Layout, big endian:
+    // [magic: 8 bytes][version: u8][table name length: u32][table name]
+    // [wrapped data key length: u32][wrapped data key, if any]
+    // [wrapped names key length: u32][wrapped names key, if any]
+    // [schema record length: u32][schema record, if any]
+    // [row count: u64] then per row [id length: u32][id][expires at: u64, 0 for never]
+    // [record length: u32][record]
+    pub async fn export_table(&self, table_name: &str, dest: &Path) -> Result<(), VibraError> {
+        let entries = self.table_entries(table_name).await?;
+        let this = self.clone();
+        let table_name = self.stored_table(table_name).into_owned();
+        let dest = dest.to_path_buf();
+        task::spawn_blocking(move || this.write_dump(&table_name, &entries, &dest)).await.unwrap()
+    }
+
+    // Write a dump of a table's entries, given the name it is stored under, as export_table lays
+    // it out. Blocks on sled and the file.
+    fn write_dump(
+        &self,
+        table_name: &str,
+        entries: &[(sled::IVec, sled::IVec)],
+        dest: &Path,
+    ) -> Result<(), VibraError> {
+        let schema = self.schema.get(table_name.as_bytes())?;
+        let data_key = match self.tables.get(table_name.as_bytes())? {
+            Some(stored) => TableMeta::decode(&stored)?.wrapped_key(),
+            None => None,
+        };
+        let names_key = self.names.as_ref().map(|names| names.wrapped(&self.master_key()));
+        let prefix_len = table_name.len() + 1;
+
+        let mut out = BufWriter::new(File::create(dest)?);
+        out.write_all(DUMP_MAGIC)?;
+        out.write_all(&[DUMP_VERSION])?;
+        out.write_all(&(table_name.len() as u32).to_be_bytes())?;
+        out.write_all(table_name.as_bytes())?;
+        let data_key = data_key.unwrap_or_default();
+        out.write_all(&(data_key.len() as u32).to_be_bytes())?;
+        out.write_all(&data_key)?;
+        let names_key = names_key.unwrap_or_default();
+        out.write_all(&(names_key.len() as u32).to_be_bytes())?;
+        out.write_all(&names_key)?;
+        let schema = schema.as_deref().unwrap_or_default();
+        out.write_all(&(schema.len() as u32).to_be_bytes())?;
+        out.write_all(schema)?;
+        out.write_all(&(entries.len() as u64).to_be_bytes())?;
+        for (key, record) in entries {
+            let id = &key[prefix_len..];
+            let expires_at = self
+                .expiry
+                .get(key)?
+                .and_then(|ivec| <[u8; 8]>::try_from(ivec.as_ref()).ok())
+                .map_or(0, u64::from_be_bytes);
+            out.write_all(&(id.len() as u32).to_be_bytes())?;
+            out.write_all(id)?;
+            out.write_all(&expires_at.to_be_bytes())?;
+            out.write_all(&(record.len() as u32).to_be_bytes())?;
+            out.write_all(record)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    // Import a dump written by `export_table` into a table, returning how many rows were imported.
+    //
+    // The target may have a different name from the exported table. If it doesn't exist it is
+    // created with the dumped schema; an existing table keeps its own. Every record is decrypted,
+    // checked against the target's schema and re-encrypted for its new key before anything is
+    // written, keeping its timestamps, then the table, if it is new, and all rows are written in
+    // one transaction, replacing rows with the same ids. A dump that fails a check leaves the
+    // database as it was.
+    pub async fn import_table(&self, table_name: &str, src: &Path) -> Result<usize, VibraError> {
+        Self::validate_table_name(table_name)?;
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        let src = src.to_path_buf();
+        task::spawn_blocking(move || {
+            let dump = Self::read_dump(&src)?;
+            // A table created or deleted meanwhile changes what the rows are checked against and
+            // encrypted for, so they are prepared again
+            loop {
+                if let Some(imported) = this.import_dump(&table_name, &dump)? {
+                    return Ok(imported);
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    // Check and re-encrypt a dump's rows for a table, creating the table if it doesn't exist, and
+    // write them. None if the table was created or deleted before they were written. Blocks on
+    // sled and the rayon pool.
+    fn import_dump(&self, table_name: &str, dump: &Dump) -> Result<Option<usize>, VibraError> {
+        let source = &dump.source;
+        // The exported table's data key, which a database with another master key can't unwrap
+        let data_key = match &dump.data_key {
+            Some(wrapped) => {
+                let ring = self.key_ring();
+                let mut master_keys = std::iter::once(&ring.current).chain(&ring.previous);
+                let data_key = master_keys.find_map(|key| key.unwrap(source, wrapped));
+                Some(data_key.ok_or(VibraError::Decryption { layer: 0 })?)
+            }
+            None => None,
+        };
+        // Likewise the key the dumped row ids are encrypted with, if they are
+        let names = match &dump.names_key {
+            Some(wrapped) => {
+                let ring = self.key_ring();
+                let mut master_keys = std::iter::once(&ring.current).chain(&ring.previous);
+                let names = master_keys.find_map(|key| Names::unwrap(key, wrapped));
+                Some(names.ok_or(VibraError::Decryption { layer: 0 })?)
+            }
+            None => None,
+        };
+
+        let dumped_schema = match &dump.schema {
+            Some(sealed) => {
+                let context = Self::schema_context(source);
+                let json = self.dumped_value(&context, sealed, data_key.as_ref())?;
+                let json = String::from_utf8(json).map_err(|_| VibraError::InvalidUtf8)?;
+                Some(serde_json::from_str::<Vec<Column>>(&json)?)
+            }
+            None => None,
+        };
+        let stored_table = self.stored_table(table_name).into_owned();
+        let new_table = match self.tables.contains_key(stored_table.as_bytes())? {
+            true => None,
+            false => Some(self.new_table(table_name, dumped_schema)?),
+        };
+        let schema = match &new_table {
+            Some(table) => table.schema.clone(),
+            None => self.table_schema(table_name)?,
+        };
+        let layers = self.encryption_layers();
+        let resealed = dump
+            .rows
+            .par_iter()
+            .map(|(id, expires_at, record)| {
+                let context = format!("{}/{}", source, id);
+                let value = self.dumped_value(&context, record, data_key.as_ref())?;
+                let id = match &names {
+                    Some(names) => names.open_row_id(source, id)?,
+                    None => id.clone(),
+                };
+                let row = Self::parse_row(&id, &value)?;
+                let key = self.row_key(table_name, &id)?;
+                Self::check_unique_columns(table_name, &row)?;
+                if let Some(schema) = &schema {
+                    Self::check_row(table_name, schema, &row)?;
+                }
+                let mut sealed = match &new_table {
+                    Some(NewTable { meta, data_key, .. }) => {
+                        let data_key = Some(data_key.clone());
+                        self.encrypt_for(&key, &value, layers, meta.plaintext, data_key)?
+                    }
+                    None => self.encrypt_value(&key, &value)?,
+                };
+                Self::copy_record_time(&mut sealed, record, Timestamp::Created);
+                Self::copy_record_time(&mut sealed, record, Timestamp::Updated);
+                Ok((key, *expires_at, sealed))
+            })
+            .collect::<Result<Vec<_>, VibraError>>()?;
+
+        let written = self.commit_table_rows(|rows, expiry, tables, schemas, changes| {
+            let exists = tables.get(stored_table.as_bytes())?.is_some();
+            match &new_table {
+                Some(table) if !exists => table.store_in(tables, schemas)?,
+                None if exists => {}
+                _ => return Ok(false),
+            }
+            for (key, expires_at, sealed) in &resealed {
+                let prior_expiry = match expires_at {
+                    Some(expires_at) => expiry.insert(key.as_bytes(), &expires_at.to_be_bytes())?,
+                    None => expiry.remove(key.as_bytes())?,
+                };
+                let prior = rows.insert(key.as_bytes(), sealed.as_slice())?;
+                let replaced = prior.is_some() && !has_expired(prior_expiry.as_ref());
+                changes.push((Self::write_op(replaced), key.clone()));
+            }
+            Ok(true)
+        })?;
+        if !written {
+            return Ok(None);
+        }
+        // Rows read before the key is in place fail to decrypt, rather than read as something else
+        if let Some(table) = new_table {
+            self.load_new_table(table);
+        }
+        self.reindex_rows(resealed.iter().map(|(key, _, _)| key.as_str()))?;
+        let prefix = self.table_prefix(table_name);
+        self.cache.pop_matching(|key| key.starts_with(&prefix));
+        Ok(Some(dump.rows.len()))
+    }
+
+    // The value of a dumped record. Unencrypted ones, from tables created without encryption, are
+    // taken as they are: a dump isn't authenticated as a whole, so they let through nothing that a
+    // dump of such a table couldn't, and the target decides how the rows are stored. Rows with
+    // sealed columns are opened with the dump's data key, like encrypted records.
+    fn dumped_value(
+        &self,
+        context: &str,
+        stored: &[u8],
+        data_key: Option<&DataKey>,
+    ) -> Result<Vec<u8>, VibraError> {
+        if let Some(value) = record::sealed_columns_value(stored)? {
+            return self.open_sealed_columns(context, value, data_key);
+        }
+        match record::plaintext_value(stored)? {
+            Some(value) => Ok(value.to_vec()),
+            None => self.decrypt_for(context, stored, data_key),
+        }
+    }
+
+    // Read and check a dump file: its table name, its schema record, if any, and its rows
+    fn read_dump(src: &Path) -> Result<Dump, VibraError> {
+        let invalid = |reason: &str| VibraError::InvalidDump(reason.to_string());
+        let mut input = BufReader::new(File::open(src)?);
+        let mut read = |len: usize| -> Result<Vec<u8>, VibraError> {
+            let mut buf = Vec::new();
+            input.by_ref().take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() != len {
+                return Err(invalid("unexpected end of file"));
+            }
+            Ok(buf)
+        };
+
+        if read(DUMP_MAGIC.len()).map_err(|_| invalid("not a vibra table dump"))? != DUMP_MAGIC {
+            return Err(invalid("not a vibra table dump"));
+        }
+        let version = read(1)?[0];
+        if version != DUMP_VERSION {
+            return Err(invalid(&format!("unsupported dump version {}", version)));
+        }
+        let name_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+        let source =
+            String::from_utf8(read(name_len)?).map_err(|_| invalid("table name is not UTF-8"))?;
+        let key_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+        let data_key = Some(read(key_len)?).filter(|key| !key.is_empty());
+        let key_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+        let names_key = Some(read(key_len)?).filter(|key| !key.is_empty());
+        let schema_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+        let schema = read(schema_len)?;
+        let schema = (!schema.is_empty()).then_some(schema);
+
+        let count = u64::from_be_bytes(read(8)?.try_into().unwrap());
+        let mut rows = Vec::new();
+        for _ in 0..count {
+            let id_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+            let id =
+                String::from_utf8(read(id_len)?).map_err(|_| invalid("row id is not UTF-8"))?;
+            let expires_at = u64::from_be_bytes(read(8)?.try_into().unwrap());
+            let record_len = u32::from_be_bytes(read(4)?.try_into().unwrap()) as usize;
+            let record = read(record_len)?;
+            rows.push((id, (expires_at != 0).then_some(expires_at), record));
+        }
+        if read(1).is_ok() {
+            return Err(invalid("trailing data after the last row"));
+        }
+        Ok(Dump {
+            source,
+            data_key,
+            names_key,
+            schema,
+            rows,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use super::VibraDB;
+use crate::error::VibraError;
+use crate::models::IntegrityReport;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::de::IgnoredAny;
+use sled::IVec;
+use tokio::task;
+use zeroize::Zeroizing;
+
+const VERIFY_BATCH: usize = 256; // Records decrypted at a time, so few plaintexts are held at once
+
+impl VibraDB {
+    // Check that every row of every table decrypts, authenticates and holds well-formed JSON,
+    // without returning any of it
+    pub async fn verify_integrity(&self) -> Result<IntegrityReport, VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || {
+            let mut report = IntegrityReport::default();
+            for (stored_table, table_name) in this.table_names("")? {
+                this.verify_table(&stored_table, &table_name, &mut report)?;
+            }
+            this.finish_report(&mut report);
+            Ok(report)
+        })
+        .await
+        .unwrap()
+    }
+
+    // verify_integrity for the rows of one table
+    pub async fn verify_table_integrity(
+        &self,
+        table_name: &str,
+    ) -> Result<IntegrityReport, VibraError> {
+        self.require_table(table_name)?;
+        let this = self.clone();
+        let table_name = table_name.to_string();
+        task::spawn_blocking(move || {
+            let mut report = IntegrityReport::default();
+            let stored_table = this.stored_table(&table_name).into_owned();
+            this.verify_table(&stored_table, &table_name, &mut report)?;
+            this.finish_report(&mut report);
+            Ok(report)
+        })
+        .await
+        .unwrap()
+    }
+
+    // Verify a table's rows a batch at a time, decrypting each batch in parallel. Blocks on sled.
+    fn verify_table(
+        &self,
+        stored_table: &str,
+        table_name: &str,
+        report: &mut IntegrityReport,
+    ) -> Result<(), VibraError> {
+        let prefix = format!("{}/", stored_table);
+        let mut entries = self.db.scan_prefix(prefix.as_bytes());
+        loop {
+            let batch: Vec<(IVec, IVec)> =
+                entries.by_ref().take(VERIFY_BATCH).collect::<Result<_, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            let failures: Vec<(String, String)> = batch
+                .par_iter()
+                .filter_map(|(k, v)| {
+                    let key = String::from_utf8_lossy(k);
+                    let error = self.verify_entry(&key, v).err()?;
+                    // Rows whose id doesn't decrypt are reported under their stored id
+                    let stored_id = &key[prefix.len()..];
+                    let row_id = self.logical_id(stored_table, stored_id);
+                    let row_id = row_id.unwrap_or_else(|_| stored_id.to_string());
+                    Some((format!("{}/{}", table_name, row_id), error.to_string()))
+                })
+                .collect();
+            report.ok += batch.len() - failures.len();
+            report.corrupt.extend(failures);
+        }
+        Ok(())
+    }
+
+    // Decrypt a stored row and check that it parses, dropping the plaintext. Typed values are
+    // stored under row keys too, so anything else that is JSON passes as well.
+    fn verify_entry(&self, key: &str, stored: &[u8]) -> Result<(), VibraError> {
+        Self::check_record_len(key, stored)?;
+        let row_id = self.row_id_of(key)?;
+        let value = Zeroizing::new(self.decrypt_bytes(key, stored)?);
+        match Self::parse_row(&row_id, &value) {
+            Ok(_) => Ok(()),
+            Err(error @ VibraError::RowIdMismatch { .. }) => Err(error),
+            Err(_) => {
+                serde_json::from_slice::<IgnoredAny>(&value)?;
+                Ok(())
+            }
+        }
+    }
+
+    // Order the report's failures by key, and log how the check went
+    fn finish_report(&self, report: &mut IntegrityReport) {
+        report.corrupt.sort();
+        let total = report.ok + report.corrupt.len();
+        if report.corrupt.is_empty() {
+            info!("Verified {} rows: all intact", total);
+        } else {
+            warn!("Verified {} rows: {} corrupt", total, report.corrupt.len());
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use super::crypto::ct_eq;
+use super::keys::MasterKey;
+use super::VibraDB;
+use crate::config::{self, VibraConfig};
+use crate::error::VibraError;
+use keyring::{Entry, Error};
+use log::info;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+const DEFAULT_ACCOUNT: &str = "master-key"; // Unless keyring_account is set
+
+// The OS keychain entry a database's master key is kept in, as 64 hex digits like a key file
+pub(crate) struct KeyringEntry {
+    service: String,
+    account: String,
+    entry: Entry,
+}
+
+impl KeyringEntry {
+    // The entry named by a config's keyring_service and keyring_account
+    pub(crate) fn for_config(config: &VibraConfig) -> Result<KeyringEntry, VibraError> {
+        let service = config
+            .keyring_service
+            .clone()
+            .ok_or(VibraError::MissingConfig("keyring_service"))?;
+        let account = config
+            .keyring_account
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ACCOUNT.to_string());
+        let entry = Entry::new(&service, &account)
+            .map_err(|err| keyring_error(&service, &account, err))?;
+        Ok(KeyringEntry {
+            service,
+            account,
+            entry,
+        })
+    }
+
+    // The master key stored in the entry, or KeyringEntryMissing if it holds none
+    pub(crate) fn load(&self) -> Result<MasterKey, VibraError> {
+        // The key's text is wiped as soon as it is parsed
+        let text = self.entry.get_password().map(Zeroizing::new).map_err(|err| self.error(err))?;
+        MasterKey::parse(&text).map_err(|reason| VibraError::InvalidKey {
+            origin: format!("keychain entry {:?}/{:?}", self.service, self.account),
+            reason,
+        })
+    }
+
+    // Store a master key in the entry, replacing whatever it held
+    fn store(&self, key: &MasterKey) -> Result<(), VibraError> {
+        self.entry.set_password(&key.to_hex()).map_err(|err| self.error(err))
+    }
+
+    fn error(&self, err: Error) -> VibraError {
+        keyring_error(&self.service, &self.account, err)
+    }
+}
+
+// Describe a keychain failure by what the caller can do about it
+fn keyring_error(service: &str, account: &str, err: Error) -> VibraError {
+    let (service, account) = (service.to_string(), account.to_string());
+    match err {
+        Error::NoEntry => VibraError::KeyringEntryMissing { service, account },
+        Error::NoStorageAccess(source) => VibraError::KeyringLocked {
+            service,
+            account,
+            reason: source.to_string(),
+        },
+        other => VibraError::Keyring {
+            service,
+            account,
+            reason: other.to_string(),
+        },
+    }
+}
+
+impl VibraDB {
+    // Open a database whose keychain entry has no master key yet, with a new random one that is
+    // stored there once the database turns out to be new. A database that already has tables
+    // lost its key, so none is made up for it and the entry is left empty.
+    pub(super) fn new_with_keyring_key(config: VibraConfig) -> Result<VibraDB, VibraError> {
+        let entry = KeyringEntry::for_config(&config)?;
+        let db = Self::new(config.with_master_key(MasterKey::generate()))?;
+        if !db.tables.is_empty() {
+            return Err(entry.error(Error::NoEntry));
+        }
+        entry.store(&db.master_key())?;
+        info!("Stored a new master key in the keychain");
+        Ok(db)
+    }
+
+    // Write the master key kept in the keychain entry a config names to a new key file, as a
+    // backup that `key_file` or `import_keyring_key` can use. Like `generate_key_file`, an
+    // existing file is never overwritten, and on unix only its owner can read the new one.
+    pub fn export_keyring_key(
+        config: &VibraConfig,
+        path: impl AsRef<Path>,
+    ) -> Result<(), VibraError> {
+        let key = KeyringEntry::for_config(config)?.load()?;
+        Self::write_key_file(path.as_ref(), &key)?;
+        info!("Exported the master key from the keychain to {:?}", path.as_ref());
+        Ok(())
+    }
+
+    // Store the master key from a key file in the keychain entry a config names, e.g. to restore
+    // an exported key on another machine. An entry already holding another key is left alone, so
+    // a key that may still be needed is never replaced.
+    pub fn import_keyring_key(
+        config: &VibraConfig,
+        path: impl AsRef<Path>,
+    ) -> Result<(), VibraError> {
+        let key = config::read_key_file(path.as_ref())?;
+        let entry = KeyringEntry::for_config(config)?;
+        match entry.load() {
+            Ok(stored) if ct_eq(stored.as_bytes(), key.as_bytes()) => return Ok(()),
+            Ok(_) => {
+                return Err(VibraError::Keyring {
+                    service: entry.service,
+                    account: entry.account,
+                    reason: "the entry already holds another master key".to_string(),
+                })
+            }
+            Err(VibraError::KeyringEntryMissing { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        entry.store(&key)?;
+        info!("Imported the master key from {:?} into the keychain", path.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod keychain_tests;
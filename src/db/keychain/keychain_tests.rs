@@ -0,0 +1,162 @@
+use super::*;
+use crate::config::MasterKeySource;
+use crate::models::Row;
+use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+use keyring::mock::MockCredential;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex, OnceLock};
+use tempfile::tempdir;
+
+// Mock credentials by service and account. The mock backend's credentials live only as long as
+// the entry they were built for, so these are kept here for a database to find them again.
+type Credentials = Mutex<HashMap<(String, String), Arc<MockCredential>>>;
+static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+fn credential(service: &str, account: &str) -> Arc<MockCredential> {
+    let mut credentials = CREDENTIALS.get_or_init(Default::default).lock().unwrap();
+    let key = (service.to_string(), account.to_string());
+    credentials.entry(key).or_default().clone()
+}
+
+#[derive(Debug)]
+struct SharedCredential(Arc<MockCredential>);
+
+impl CredentialApi for SharedCredential {
+    fn set_secret(&self, secret: &[u8]) -> keyring::Result<()> {
+        self.0.set_secret(secret)
+    }
+
+    fn get_secret(&self) -> keyring::Result<Vec<u8>> {
+        self.0.get_secret()
+    }
+
+    fn delete_credential(&self) -> keyring::Result<()> {
+        self.0.delete_credential()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self.0.as_any()
+    }
+}
+
+struct SharedBuilder;
+
+impl CredentialBuilderApi for SharedBuilder {
+    fn build(
+        &self,
+        _target: Option<&str>,
+        service: &str,
+        account: &str,
+    ) -> keyring::Result<Box<Credential>> {
+        Ok(Box::new(SharedCredential(credential(service, account))))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+// A config keeping its master key in a keychain entry of its own
+fn keyring_config(dir: &Path, service: &str) -> VibraConfig {
+    keyring::set_default_credential_builder(Box::new(SharedBuilder));
+    VibraConfig {
+        path: Some(dir.join("db")),
+        cache_size: Some(1024),
+        encryption_layers: Some(2),
+        key_source: Some(MasterKeySource::Keyring),
+        keyring_service: Some(service.to_string()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_keyring_key_generated_on_first_open() {
+    let dir = tempdir().unwrap();
+    let config = || keyring_config(dir.path(), "first-open");
+    let stored = || credential("first-open", DEFAULT_ACCOUNT);
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![("name".to_string(), "John Doe".into())],
+    };
+
+    // The first open generates the key and stores it; later ones read it back
+    assert!(stored().get_password().is_err());
+    let db = VibraDB::new(config()).unwrap();
+    let key = stored().get_password().unwrap();
+    assert_eq!(MasterKey::parse(&key).unwrap().to_hex().as_str(), key);
+    db.create_table("users", None).await.unwrap();
+    db.insert_row("users", row.clone()).await.unwrap();
+    db.close().await.unwrap();
+    let db = VibraDB::new(config()).unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row.clone()));
+    db.close().await.unwrap();
+    assert_eq!(stored().get_password().unwrap(), key);
+
+    // A backup of the key restores a lost entry; a database with tables doesn't get a new one
+    let backup = dir.path().join("backup.key");
+    VibraDB::export_keyring_key(&config(), &backup).unwrap();
+    assert_eq!(fs::read_to_string(&backup).unwrap().trim(), key);
+    stored().delete_credential().unwrap();
+    assert!(matches!(
+        VibraDB::new(config()),
+        Err(VibraError::KeyringEntryMissing { ref service, ref account })
+            if service == "first-open" && account == DEFAULT_ACCOUNT
+    ));
+    assert!(stored().get_password().is_err());
+    assert!(matches!(
+        VibraDB::export_keyring_key(&config(), dir.path().join("none.key")),
+        Err(VibraError::KeyringEntryMissing { .. })
+    ));
+    VibraDB::import_keyring_key(&config(), &backup).unwrap();
+    let db = VibraDB::new(config()).unwrap();
+    assert_eq!(db.get_row("users", "user1").await.unwrap(), Some(row));
+    db.close().await.unwrap();
+
+    // Importing the same key again is a no-op, but another key never replaces it
+    VibraDB::import_keyring_key(&config(), &backup).unwrap();
+    let other = dir.path().join("other.key");
+    VibraDB::generate_key_file(&other).unwrap();
+    assert!(matches!(
+        VibraDB::import_keyring_key(&config(), &other),
+        Err(VibraError::Keyring { ref reason, .. }) if reason.contains("another master key")
+    ));
+    assert_eq!(stored().get_password().unwrap(), key);
+}
+
+#[tokio::test]
+async fn test_keyring_errors() {
+    let dir = tempdir().unwrap();
+    let config = keyring_config(dir.path(), "errors");
+    let db = VibraDB::new(config).unwrap();
+    db.close().await.unwrap();
+
+    // A locked keychain is an error, not a reason to generate another key
+    let locked = || keyring::Error::NoStorageAccess("the keychain is locked".into());
+    credential("errors", DEFAULT_ACCOUNT).set_error(locked());
+    let config = keyring_config(dir.path(), "errors");
+    assert!(matches!(
+        VibraDB::new(config),
+        Err(VibraError::KeyringLocked { ref reason, .. }) if reason.contains("locked")
+    ));
+    let config = keyring_config(dir.path(), "errors");
+    VibraDB::new(config).unwrap().close().await.unwrap();
+
+    // A key that doesn't parse names the entry it came from
+    credential("errors", DEFAULT_ACCOUNT).set_password("abcdef").unwrap();
+    assert!(matches!(
+        VibraDB::new(keyring_config(dir.path(), "errors")),
+        Err(VibraError::InvalidKey { ref origin, .. }) if origin.contains("\"errors\"")
+    ));
+
+    // The service must be named
+    let unnamed = VibraConfig {
+        keyring_service: None,
+        ..keyring_config(dir.path(), "errors")
+    };
+    assert!(matches!(
+        VibraDB::new(unnamed),
+        Err(VibraError::InvalidConfig(ref message)) if message.contains("keyring_service")
+    ));
+}
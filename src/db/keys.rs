@@ -0,0 +1,365 @@
+use crate::error::VibraError;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce as SivNonce};
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use zeroize::{Zeroize, Zeroizing};
+
+pub(crate) const SALT_LEN: usize = 32; // Random salt stored in each record instead of its keys
+const KEY_INFO: &[u8] = b"vibradb record key v1"; // Domain separation for derived record keys
+const CHECK_INFO: &[u8] = b"vibradb key check v1"; // Domain separation for key check values
+const WRAP_INFO: &[u8] = b"vibradb table key wrap v1"; // Domain separation for the wrapping key
+const INDEX_INFO: &[u8] = b"vibradb blind index v1"; // Domain separation for blind index keys
+// Domain separation for the keys of deterministically encrypted columns
+const DETERMINISTIC_INFO: &[u8] = b"vibradb deterministic column v1";
+// Domain separation for the keys table names and row ids are encrypted with
+const NAMES_INFO: &[u8] = b"vibradb names v1";
+const BACKUP_INFO: &[u8] = b"vibradb backup v1"; // Domain separation for the keys of backups
+const AUDIT_INFO: &[u8] = b"vibradb audit log v1"; // Domain separation for the audit log's MAC key
+pub(crate) const DETERMINISTIC_NONCE_LEN: usize = 12;
+const NONCE_LEN: usize = 12;
+
+/// The 256-bit secret that protects every record, directly or through the data key of its table.
+///
+/// Records store a random salt instead of their keys. The key of each layer is HKDF-SHA256 over
+/// the data key of the record's table (the master key, for records older than table data keys)
+/// and the salt, with the layer index and the record's context (its sled key, `table/id` for
+/// rows) as the info. Data keys are stored wrapped by the master key, so reading a database takes
+/// the master key it was written with and a record copied under another key doesn't decrypt.
+///
+/// Vibra never stores the master key itself, except encrypted under a passphrase for databases
+/// opened with `VibraDB::open_with_passphrase`: losing it loses the data. Its `Debug` output is
+/// redacted so it doesn't end up in logs, and its bytes are wiped from memory when it is dropped.
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    // Use existing key bytes, e.g. loaded from a secrets manager
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        MasterKey(bytes)
+    }
+
+    // Generate a new random key with the OS RNG, filled in place so no copy is left behind
+    pub fn generate() -> Self {
+        let mut key = MasterKey([0u8; 32]);
+        rand::rngs::OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    // Parse a key written as 64 hex digits or as base64, ignoring surrounding whitespace. The
+    // error says what was wrong with the text, without quoting it. Base64 of 32 bytes is never an
+    // even number of hex digits, so text that is one is read as hex whatever its length.
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("the key is empty".to_string());
+        }
+        let bytes = Zeroizing::new(
+            if text.len().is_multiple_of(2) && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+                (0..text.len() / 2)
+                    .map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).unwrap())
+                    .collect()
+            } else {
+                STANDARD_NO_PAD.decode(text.trim_end_matches('=')).map_err(|_| {
+                    "expected 32 bytes written as 64 hex digits or as base64".to_string()
+                })?
+            },
+        );
+        MasterKey::from_slice(&bytes).ok_or_else(|| {
+            format!("decodes to {} bytes, but a master key is 32 bytes", bytes.len())
+        })
+    }
+
+    // Copy a key out of a buffer holding exactly its 32 bytes
+    pub(crate) fn from_slice(bytes: &[u8]) -> Option<Self> {
+        let mut key = MasterKey([0u8; 32]);
+        key.0.copy_from_slice(bytes.get(..32).filter(|_| bytes.len() == 32)?);
+        Some(key)
+    }
+
+    // The key as 64 hex digits, as written to key files
+    pub(crate) fn to_hex(&self) -> Zeroizing<String> {
+        let mut hex = Zeroizing::new(String::with_capacity(64));
+        for byte in self.0 {
+            hex.push(char::from_digit(u32::from(byte >> 4), 16).unwrap());
+            hex.push(char::from_digit(u32::from(byte & 0xf), 16).unwrap());
+        }
+        hex
+    }
+
+    // Derive the keys of every layer of a record, concatenated in layer order
+    pub(crate) fn layer_keys(&self, salt: &[u8], context: &str, layers: usize) -> SecretBytes {
+        derive_layer_keys(&self.0, salt, context, layers)
+    }
+
+    // Encrypt a table's data key for storage, bound to the table's name: [nonce][ciphertext]
+    pub(crate) fn wrap(&self, table_name: &str, data_key: &DataKey) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: &data_key.0,
+            aad: table_name.as_bytes(),
+        };
+        let ciphertext = self
+            .wrapping_cipher()
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("AES-GCM encrypts a 32-byte key");
+        [&nonce[..], &ciphertext].concat()
+    }
+
+    // Decrypt a data key wrapped for `table_name`, or None if this key didn't wrap it
+    pub(crate) fn unwrap(&self, table_name: &str, wrapped: &[u8]) -> Option<DataKey> {
+        if wrapped.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: table_name.as_bytes(),
+        };
+        let key = self.wrapping_cipher().decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        DataKey::from_slice(&SecretBytes::new(key))
+    }
+
+    // The key data keys are wrapped with, kept apart from record keys by its HKDF info
+    fn wrapping_cipher(&self) -> Aes256Gcm {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(WRAP_INFO, key.as_mut_slice())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()))
+    }
+
+    // The cipher a backup made with this key as its backup key is sealed with, derived from the
+    // backup's own random salt so no two backups share a key
+    pub(crate) fn backup_cipher(&self, salt: &[u8]) -> Aes256Gcm {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(salt), &self.0)
+            .expand(BACKUP_INFO, key.as_mut_slice())
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()))
+    }
+
+    // The key the blind index of a table's column is computed with, kept apart from the data keys
+    // and from the other columns' by its HKDF info. The table name's length goes first, so no two
+    // table and column pairs share an info.
+    pub(crate) fn index_key(&self, table_name: &str, column: &str) -> SecretBytes {
+        let mut key = SecretBytes::zeroed(32);
+        let name_len = (table_name.len() as u32).to_be_bytes();
+        let info = [INDEX_INFO, &name_len, table_name.as_bytes(), column.as_bytes()];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand_multi_info(&info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    // The key the audit log's entries are authenticated with
+    pub(crate) fn audit_key(&self) -> SecretBytes {
+        let mut key = SecretBytes::zeroed(32);
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(AUDIT_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        key
+    }
+
+    // A value that identifies the key without revealing it, to tell whether two keys are the same
+    pub(crate) fn check_value(&self) -> String {
+        let mut check = [0u8; 32];
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand(CHECK_INFO, &mut check)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        check.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// A table's own 256-bit key, from which the keys of its rows and schema are derived. It is only
+// ever stored wrapped by the master key, in the table's metadata, so destroying that copy makes
+// the table's records unreadable, and changing the master key only means re-wrapping it.
+#[derive(Clone)]
+pub(crate) struct DataKey([u8; 32]);
+
+impl DataKey {
+    // Generate a new key with the database's RNG, so a seeded one makes table keys reproducible
+    pub(crate) fn generate(rng: &mut dyn RngCore) -> Self {
+        let mut key = DataKey([0u8; 32]);
+        rng.fill_bytes(&mut key.0);
+        key
+    }
+
+    // Copy a key out of a buffer holding exactly its 32 bytes, as MasterKey::from_slice does
+    fn from_slice(bytes: &[u8]) -> Option<Self> {
+        let mut key = DataKey([0u8; 32]);
+        key.0.copy_from_slice(bytes.get(..32).filter(|_| bytes.len() == 32)?);
+        Some(key)
+    }
+
+    // Derive the keys of every layer of a record, as MasterKey::layer_keys does
+    pub(crate) fn layer_keys(&self, salt: &[u8], context: &str, layers: usize) -> SecretBytes {
+        derive_layer_keys(&self.0, salt, context, layers)
+    }
+
+    // The keys a column of the table is encrypted deterministically with: the AES-GCM-SIV key,
+    // then the key its synthetic nonces are computed with. Each column of each table has its own,
+    // so equal values only have equal ciphertext within a column.
+    pub(crate) fn column_keys(&self, column: &str) -> SecretBytes {
+        let mut keys = SecretBytes::zeroed(64);
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand_multi_info(&[DETERMINISTIC_INFO, column.as_bytes()], &mut keys)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        keys
+    }
+
+    // The keys a names key pseudonymizes with, shaped like column_keys: those for table names
+    // with no table, and those for the row ids of the table stored under `stored_table`
+    pub(crate) fn name_keys(&self, stored_table: Option<&str>) -> SecretBytes {
+        let mut keys = SecretBytes::zeroed(64);
+        let scope = match stored_table {
+            Some(stored_table) => [&[1], stored_table.as_bytes()].concat(),
+            None => vec![0],
+        };
+        Hkdf::<Sha256>::new(None, &self.0)
+            .expand_multi_info(&[NAMES_INFO, &scope], &mut keys)
+            .expect("64 bytes is a valid HKDF-SHA256 output length");
+        keys
+    }
+}
+
+// Encrypt `plaintext` so that equal plaintexts have equal ciphertext under the same `keys` (see
+// DataKey::column_keys): AES-256-GCM-SIV with a nonce that is an HMAC of the plaintext. Returns
+// [synthetic nonce][ciphertext].
+pub(crate) fn seal_deterministic(
+    keys: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, VibraError> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&keys[32..]).expect("HMAC takes keys of any length");
+    mac.update(plaintext);
+    let nonce = mac.finalize().into_bytes();
+    let nonce = SivNonce::from_slice(&nonce[..DETERMINISTIC_NONCE_LEN]);
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let ciphertext = Aes256GcmSiv::new_from_slice(&keys[..32])
+        .expect("the deterministic key is 32 bytes")
+        .encrypt(nonce, payload)
+        .map_err(|_| VibraError::Encryption { layer: 0 })?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+// Decrypt what seal_deterministic returned
+pub(crate) fn open_deterministic(
+    keys: &[u8],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, VibraError> {
+    if sealed.len() < DETERMINISTIC_NONCE_LEN {
+        return Err(VibraError::Decryption { layer: 0 });
+    }
+    let (nonce, ciphertext) = sealed.split_at(DETERMINISTIC_NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad,
+    };
+    Aes256GcmSiv::new_from_slice(&keys[..32])
+        .expect("the deterministic key is 32 bytes")
+        .decrypt(SivNonce::from_slice(nonce), payload)
+        .map(Zeroizing::new)
+        .map_err(|_| VibraError::Decryption { layer: 0 })
+}
+
+/// Key material on its way between functions, such as a record's layer keys.
+///
+/// The bytes are wiped from memory when it is dropped. It derefs to them, but has no `Clone`
+/// and a redacted `Debug`, so it can't be copied or logged by accident.
+pub(crate) struct SecretBytes(Zeroizing<Vec<u8>>);
+
+impl SecretBytes {
+    // Take ownership of key material, wiping it when dropped
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        SecretBytes(Zeroizing::new(bytes))
+    }
+
+    // A zeroed buffer to fill in place, so no unwiped copy of the key material is made
+    pub(crate) fn zeroed(len: usize) -> Self {
+        SecretBytes::new(vec![0u8; len])
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+// HKDF-SHA256 over a key and a record's salt, with a layer index and the record's context as the
+// info of each layer's key
+fn derive_layer_keys(secret: &[u8; 32], salt: &[u8], context: &str, layers: usize) -> SecretBytes {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), secret);
+    let mut keys = SecretBytes::zeroed(layers * 32);
+    for (layer, key) in keys.chunks_mut(32).enumerate() {
+        let info = [KEY_INFO, &(layer as u16).to_be_bytes(), context.as_bytes()];
+        hkdf.expand_multi_info(&info, key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+    }
+    keys
+}
+
+// The master keys a database holds. New records are sealed with `current`. Records not rotated
+// yet, or read just before they were, decrypt with `previous`, the key the last rotation started
+// from.
+pub(crate) struct KeyRing {
+    pub(crate) current: Arc<MasterKey>,
+    pub(crate) previous: Option<Arc<MasterKey>>,
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(<redacted>)")
+    }
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(<redacted>)")
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes(<redacted {} bytes>)", self.0.len())
+    }
+}
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for DataKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
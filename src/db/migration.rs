@@ -0,0 +1,210 @@
+use super::record::{self, KeySource};
+use super::{Timestamp, VibraDB};
+use crate::error::VibraError;
+use crate::models::MigrationReport;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use sled::IVec;
+use std::ops::Bound;
+use tokio::task;
+
+pub(super) const MIGRATION_KEY: &str = "migration"; // MigrationProgress JSON in the meta tree
+const MIGRATION_BATCH: usize = 100; // Records rewritten per transaction
+
+// How far an interrupted migration got. Schemas are migrated before rows; `after` is the last key
+// migrated in the tree being worked on.
+#[derive(Serialize, Deserialize, Default)]
+struct MigrationProgress {
+    schemas_done: bool,
+    after: Option<String>,
+}
+
+// What migrating a single record comes to, before it is written
+enum Outcome {
+    Migrated(Vec<u8>),
+    Skipped,
+    Failed,
+}
+
+impl VibraDB {
+    // Rewrite every record stored in the legacy format, with its keys inline (unversioned records
+    // and version 1 ones alike), with keys derived from its table's data key, giving tables
+    // created before data keys one first, or from the master key outside tables. Values, layer
+    // counts and write times stay as they were. Reads accept both formats throughout, so a
+    // database can be migrated while in use, or not at all.
+    //
+    // Records are rewritten in batches, each in one transaction with the progress it makes, so
+    // a migration that is interrupted, by a crash or an error, resumes after the last batch
+    // written when it is called again.
+    pub async fn migrate_legacy(&self) -> Result<MigrationReport, VibraError> {
+        self.migrate_batches(None).await
+    }
+
+    // Migrate, stopping after `max_batches` batches as if interrupted, when given
+    pub(crate) async fn migrate_batches(
+        &self,
+        max_batches: Option<usize>,
+    ) -> Result<MigrationReport, VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || this.migrate_blocking(max_batches)).await.unwrap()
+    }
+
+    fn migrate_blocking(&self, max_batches: Option<usize>) -> Result<MigrationReport, VibraError> {
+        // Rotations rewrite the same records, so only one of them runs at a time
+        let _running = self.rotation.try_lock().map_err(|_| {
+            VibraError::InvalidConfig(
+                "a master key rotation or migration is already running".to_string(),
+            )
+        })?;
+        let mut progress = match self.meta.get(MIGRATION_KEY)? {
+            Some(stored) => serde_json::from_slice::<MigrationProgress>(&stored)?,
+            None => MigrationProgress::default(),
+        };
+        if progress.after.is_some() || progress.schemas_done {
+            info!("Resuming an interrupted migration of legacy records");
+        }
+        self.meta.insert(MIGRATION_KEY, serde_json::to_vec(&progress)?)?;
+        self.assign_table_keys()?;
+
+        let mut report = MigrationReport::default();
+        let mut batches = 0;
+        if !progress.schemas_done {
+            let done = self.migrate_tree(
+                &self.schema,
+                Self::schema_context,
+                &mut progress,
+                &mut report,
+                &mut batches,
+                max_batches,
+            )?;
+            if !done {
+                return Ok(report);
+            }
+            progress.schemas_done = true;
+            progress.after = None;
+        }
+        if !self.migrate_tree(
+            &self.db,
+            str::to_string,
+            &mut progress,
+            &mut report,
+            &mut batches,
+            max_batches,
+        )? {
+            return Ok(report);
+        }
+
+        self.meta.remove(MIGRATION_KEY)?;
+        info!(
+            "Migrated legacy records: {} migrated, {} skipped, {} failed",
+            report.migrated, report.skipped, report.failed
+        );
+        if report.failed > 0 {
+            warn!("{} legacy records could not be decrypted and keep their format", report.failed);
+        }
+        Ok(report)
+    }
+
+    // Migrate the records of one tree after `progress.after`, returning false if it stopped
+    // because `max_batches` were written
+    fn migrate_tree(
+        &self,
+        tree: &sled::Tree,
+        context: fn(&str) -> String,
+        progress: &mut MigrationProgress,
+        report: &mut MigrationReport,
+        batches: &mut usize,
+        max_batches: Option<usize>,
+    ) -> Result<bool, VibraError> {
+        let start = match &progress.after {
+            Some(after) => Bound::Excluded(IVec::from(after.as_bytes())),
+            None => Bound::Unbounded,
+        };
+        let mut entries = tree.range::<IVec, _>((start, Bound::Unbounded));
+        loop {
+            if max_batches.is_some_and(|max| *batches >= max) {
+                return Ok(false);
+            }
+            let batch: Vec<(IVec, IVec)> =
+                entries.by_ref().take(MIGRATION_BATCH).collect::<Result<_, _>>()?;
+            let Some((last, _)) = batch.last() else {
+                return Ok(true);
+            };
+            progress.after = Some(String::from_utf8_lossy(last).into_owned());
+            let outcomes: Vec<Outcome> = batch
+                .par_iter()
+                .map(|(key, stored)| {
+                    self.migrate_record(&context(&String::from_utf8_lossy(key)), stored)
+                })
+                .collect();
+            let progress_json = serde_json::to_vec(progress)?;
+
+            let written = (tree, &self.meta).transaction(|(rows, meta)| {
+                let mut written = 0;
+                for ((key, stored), outcome) in batch.iter().zip(&outcomes) {
+                    // A record replaced or removed since it was read is in the current format,
+                    // or is gone
+                    if let Outcome::Migrated(sealed) = outcome {
+                        if rows.get(key)?.as_ref() == Some(stored) {
+                            rows.insert(key, sealed.as_slice())?;
+                            written += 1;
+                        }
+                    }
+                }
+                meta.insert(MIGRATION_KEY, progress_json.as_slice())?;
+                Ok::<_, ConflictableTransactionError<VibraError>>(written)
+            })?;
+
+            for ((key, _), outcome) in batch.iter().zip(&outcomes) {
+                match outcome {
+                    Outcome::Migrated(_) => {}
+                    Outcome::Skipped => report.skipped += 1,
+                    Outcome::Failed => {
+                        warn!(
+                            "Failed to migrate record {}: it doesn't decrypt",
+                            self.loggable(&String::from_utf8_lossy(key))
+                        );
+                        report.failed += 1;
+                    }
+                }
+            }
+            let migrated = outcomes.iter().filter(|o| matches!(o, Outcome::Migrated(_))).count();
+            report.migrated += written;
+            report.skipped += migrated - written;
+            *batches += 1;
+            info!(
+                "Migrating legacy records: {} migrated, {} skipped, {} failed so far",
+                report.migrated, report.skipped, report.failed
+            );
+        }
+    }
+
+    // Re-encrypt a record with its keys inline in the current format, keeping its layer count
+    // and write times. Records in the current format are left alone.
+    fn migrate_record(&self, context: &str, stored: &[u8]) -> Outcome {
+        let Ok(record) = record::decode_record(stored) else {
+            return Outcome::Failed;
+        };
+        let header = record.header;
+        if record.key_source != KeySource::Inline || header.is_plaintext() {
+            return Outcome::Skipped;
+        }
+        // Inline keys don't depend on the master key, and legacy records aren't bound to their
+        // context
+        let value = match Self::decrypt_with(&self.master_key(), None, context, stored) {
+            Ok(value) => value,
+            Err(_) => return Outcome::Failed,
+        };
+        let key = self.record_key(context);
+        match self.seal(context, &value, header.layers as usize, Some(&key)) {
+            Ok(mut sealed) => {
+                Self::copy_record_time(&mut sealed, stored, Timestamp::Created);
+                Self::copy_record_time(&mut sealed, stored, Timestamp::Updated);
+                Outcome::Migrated(sealed)
+            }
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use super::keys::{self, DataKey, MasterKey};
+use super::{KeyRng, TableMeta, VibraDB};
+use crate::config::VibraConfig;
+use crate::error::VibraError;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::warn;
+use sha2::Sha256;
+use sled::Db;
+use std::borrow::Cow;
+use std::str;
+
+pub(super) const NAMES_KEY: &str = "names_key"; // The names key in the meta tree, wrapped
+pub(super) const NAMES_CONTEXT: &str = "__vibra_names"; // What the names key is wrapped for
+const PSEUDONYM_LEN: usize = 16; // Bytes of HMAC a table is stored under, as hex digits
+
+// Databases created with `encrypt_names` store no table name or row id in the clear. A table is
+// stored under a pseudonym, an HMAC of its name in hex, and its entry in the tables tree holds
+// its name encrypted, so list_tables can still name it. Row ids are encrypted deterministically
+// with AES-256-GCM-SIV, so a row's key can be computed from its id and its id recovered from its
+// key, in URL-safe base64, which never contains '/'. Both are keyed by a random names key, stored
+// wrapped by the master key in the meta tree.
+//
+// Keys keep the `table/id` shape, with the stored table name and id in place of the real ones,
+// so the records, cache entries and expiry and index entries of a row are all under its stored
+// key, and the per-table maps are keyed by stored table name. Everything that builds or parses
+// keys goes through stored_table, row_key, table_prefix and row_id_of, which leave names as they
+// are in other databases.
+pub(super) struct Names {
+    key: DataKey,
+}
+
+impl Names {
+    // The pseudonym a table is stored under
+    fn table(&self, table_name: &str) -> String {
+        let keys = self.key.name_keys(None);
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&keys[32..])
+            .expect("HMAC takes keys of any length");
+        mac.update(table_name.as_bytes());
+        let mac = mac.finalize().into_bytes();
+        mac[..PSEUDONYM_LEN].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // The id a row of the table stored under `stored_table` is stored under
+    fn seal_row_id(&self, stored_table: &str, row_id: &str) -> Result<String, VibraError> {
+        let keys = self.key.name_keys(Some(stored_table));
+        let sealed = keys::seal_deterministic(&keys, stored_table.as_bytes(), row_id.as_bytes())?;
+        Ok(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    // The id a row was stored under `stored_id` for
+    pub(super) fn open_row_id(
+        &self,
+        stored_table: &str,
+        stored_id: &str,
+    ) -> Result<String, VibraError> {
+        let sealed =
+            URL_SAFE_NO_PAD.decode(stored_id).map_err(|_| VibraError::Decryption { layer: 0 })?;
+        let keys = self.key.name_keys(Some(stored_table));
+        let row_id = keys::open_deterministic(&keys, stored_table.as_bytes(), &sealed)?;
+        String::from_utf8(row_id.to_vec()).map_err(|_| VibraError::InvalidUtf8)
+    }
+
+    // A table's name as kept in its entry, encrypted so only the names key reveals it
+    fn seal_table_name(&self, table_name: &str) -> Result<String, VibraError> {
+        let keys = self.key.name_keys(None);
+        Ok(STANDARD.encode(keys::seal_deterministic(&keys, b"", table_name.as_bytes())?))
+    }
+
+    // The name kept in a table's entry
+    fn open_table_name(&self, sealed: &str) -> Result<String, VibraError> {
+        let sealed = STANDARD.decode(sealed).map_err(|_| VibraError::Decryption { layer: 0 })?;
+        let keys = self.key.name_keys(None);
+        let table_name = keys::open_deterministic(&keys, b"", &sealed)?;
+        String::from_utf8(table_name.to_vec()).map_err(|_| VibraError::InvalidUtf8)
+    }
+
+    // The names key wrapped by `master_key`, as the meta tree keeps it
+    pub(super) fn wrapped(&self, master_key: &MasterKey) -> Vec<u8> {
+        master_key.wrap(NAMES_CONTEXT, &self.key)
+    }
+
+    // Unwrap a names key, if `master_key` wrapped it
+    pub(super) fn unwrap(master_key: &MasterKey, wrapped: &[u8]) -> Option<Names> {
+        Some(Names {
+            key: master_key.unwrap(NAMES_CONTEXT, wrapped)?,
+        })
+    }
+}
+
+// The name a table is stored under, given the names key of a database that encrypts them
+pub(super) fn stored_table<'a>(names: Option<&Names>, table_name: &'a str) -> Cow<'a, str> {
+    match names {
+        Some(names) => Cow::Owned(names.table(table_name)),
+        None => Cow::Borrowed(table_name),
+    }
+}
+
+// The name of the table whose entry is stored under `stored_name`
+pub(super) fn table_name_of(
+    names: Option<&Names>,
+    stored_name: &[u8],
+    meta: &TableMeta,
+) -> Result<String, VibraError> {
+    match (names, &meta.name) {
+        (Some(names), Some(sealed)) => names.open_table_name(sealed),
+        _ => Ok(String::from_utf8_lossy(stored_name).into_owned()),
+    }
+}
+
+impl VibraDB {
+    // Unwrap the names key of a database created with encrypt_names, or create one for a new
+    // database the config sets it for. A database keeps the setting it was created with.
+    pub(super) fn load_names(
+        db: &Db,
+        tables: &sled::Tree,
+        meta: &sled::Tree,
+        master_key: &MasterKey,
+        config: &VibraConfig,
+        rng: &KeyRng,
+    ) -> Result<Option<Names>, VibraError> {
+        if let Some(wrapped) = meta.get(NAMES_KEY)? {
+            let names = Names::unwrap(master_key, &wrapped);
+            let names = names.ok_or_else(|| VibraError::InvalidKey {
+                origin: "the config".to_string(),
+                reason: "it doesn't unwrap the key the database's names are encrypted with"
+                    .to_string(),
+            })?;
+            if !config.encrypt_names {
+                warn!("The database was created with encrypt_names, so its names stay encrypted");
+            }
+            return Ok(Some(names));
+        }
+        if !config.encrypt_names {
+            return Ok(None);
+        }
+        if !tables.is_empty() || db.iter().next().is_some() {
+            warn!(
+                "encrypt_names only applies to new databases; this one already has tables, so \
+                 their names are stored as they are"
+            );
+            return Ok(None);
+        }
+        let names = Names {
+            key: rng.with(DataKey::generate),
+        };
+        meta.insert(NAMES_KEY, names.wrapped(master_key))?;
+        Ok(Some(names))
+    }
+
+    // The name a table's entry, schema and rows are stored under
+    pub(super) fn stored_table<'a>(&self, table_name: &'a str) -> Cow<'a, str> {
+        stored_table(self.names.as_deref(), table_name)
+    }
+
+    // The id a row of the table stored under `stored_table` is stored under
+    pub(super) fn stored_id<'a>(
+        &self,
+        stored_table: &str,
+        row_id: &'a str,
+    ) -> Result<Cow<'a, str>, VibraError> {
+        match &self.names {
+            Some(names) => Ok(Cow::Owned(names.seal_row_id(stored_table, row_id)?)),
+            None => Ok(Cow::Borrowed(row_id)),
+        }
+    }
+
+    // The prefix every key of a table's rows starts with
+    pub(super) fn table_prefix(&self, table_name: &str) -> String {
+        format!("{}/", self.stored_table(table_name))
+    }
+
+    // The id of the row stored under `stored_id` in the table stored under `stored_table`
+    pub(super) fn logical_id(
+        &self,
+        stored_table: &str,
+        stored_id: &str,
+    ) -> Result<String, VibraError> {
+        match &self.names {
+            Some(names) => names.open_row_id(stored_table, stored_id),
+            None => Ok(stored_id.to_string()),
+        }
+    }
+
+    // The id of the row stored under a row key
+    pub(super) fn row_id_of(&self, key: &str) -> Result<String, VibraError> {
+        let (stored_table, stored_id) = key.split_once('/').ok_or_else(|| {
+            VibraError::MalformedRecord(format!("{} is not a row key", self.loggable(key)))
+        })?;
+        self.logical_id(stored_table, stored_id)
+    }
+
+    // Order a table's entries by row id, which is how sled orders them unless ids are encrypted
+    pub(super) fn sort_by_row_id(&self, entries: &mut [(sled::IVec, sled::IVec)]) {
+        if self.names.is_some() {
+            entries.sort_by_cached_key(|(k, _)| {
+                str::from_utf8(k).ok().and_then(|key| self.row_id_of(key).ok())
+            });
+        }
+    }
+
+    // Record a new table's name in its entry, encrypted, if the database encrypts names
+    pub(super) fn name_table(
+        &self,
+        meta: &mut TableMeta,
+        table_name: &str,
+    ) -> Result<(), VibraError> {
+        if let Some(names) = &self.names {
+            meta.name = Some(names.seal_table_name(table_name)?);
+        }
+        Ok(())
+    }
+
+    // The stored and real names of the tables whose names start with `prefix`, in name order.
+    // Blocks on sled.
+    pub(super) fn table_names(&self, prefix: &str) -> Result<Vec<(String, String)>, VibraError> {
+        let mut table_names = vec![];
+        let Some(names) = self.names.as_deref() else {
+            for key in self.tables.scan_prefix(prefix.as_bytes()).keys() {
+                if let Ok(table_name) = String::from_utf8(key?.to_vec()) {
+                    table_names.push((table_name.clone(), table_name));
+                }
+            }
+            return Ok(table_names);
+        };
+        for entry in self.tables.iter() {
+            let (stored_name, stored) = entry?;
+            let meta = TableMeta::decode(&stored)?;
+            let table_name = table_name_of(Some(names), &stored_name, &meta)?;
+            if table_name.starts_with(prefix) {
+                let stored_name = String::from_utf8_lossy(&stored_name).into_owned();
+                table_names.push((stored_name, table_name));
+            }
+        }
+        table_names.sort_by(|(_, a), (_, b)| a.cmp(b));
+        Ok(table_names)
+    }
+}
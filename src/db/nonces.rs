@@ -0,0 +1,109 @@
+use super::KeyRng;
+use crate::error::VibraError;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+pub(super) const NONCE_KEY: &str = "nonces"; // NonceState JSON in the meta tree
+const NONCE_BLOCK: u64 = 1 << 16; // Counter values reserved each time the mark is written
+const NONCE_SAFETY_MARGIN: u64 = 1 << 32; // Counter values skipped whenever the database opens
+
+// Every nonce a database seals records with is its 4-byte prefix, drawn at random when the
+// sequence is created, followed by a 64-bit big endian counter, so no two are ever equal. The
+// counter is handed out from blocks reserved in advance: the high-water mark in the meta tree is
+// raised past a block before any of it is used, and only flushed to disk first in strict mode.
+// Otherwise a crash can lose the latest marks while records using them survive elsewhere, e.g.
+// in a dump, so a sequence reopened starts a safety margin past the last mark it finds, far more
+// than it hands out between two of sled's flushes.
+#[derive(Serialize, Deserialize)]
+struct NonceState {
+    prefix: [u8; 4],
+    reserved: u64, // Every counter value below it may have been used
+}
+
+struct Counter {
+    next: u64,
+    reserved: u64,
+}
+
+pub(super) struct NonceSequence {
+    meta: sled::Tree,
+    prefix: [u8; 4],
+    strict: bool, // Flush each new high-water mark before using it
+    counter: Mutex<Counter>,
+}
+
+impl NonceSequence {
+    // Resume the database's sequence past a safety margin, or start one for a new database
+    pub(super) fn open(
+        meta: &sled::Tree,
+        rng: &KeyRng,
+        strict: bool,
+    ) -> Result<NonceSequence, VibraError> {
+        let (prefix, start) = match meta.get(NONCE_KEY)? {
+            Some(stored) => {
+                let state: NonceState = serde_json::from_slice(&stored)?;
+                let start = state.reserved.checked_add(NONCE_SAFETY_MARGIN);
+                (state.prefix, start.ok_or_else(exhausted)?)
+            }
+            None => {
+                let mut prefix = [0u8; 4];
+                rng.with(|rng| rng.fill_bytes(&mut prefix));
+                (prefix, 0)
+            }
+        };
+        let sequence = NonceSequence {
+            meta: meta.clone(),
+            prefix,
+            strict,
+            counter: Mutex::new(Counter {
+                next: start,
+                reserved: start,
+            }),
+        };
+        sequence.save(start)?;
+        Ok(sequence)
+    }
+
+    // The next `count` nonces, 12 bytes each
+    pub(super) fn take(&self, count: usize) -> Result<Vec<u8>, VibraError> {
+        // Only ever updated after the mark is saved, so a panic can't leave it ahead of the mark
+        let mut counter = self.counter.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let end = counter.next.checked_add(count as u64).ok_or_else(exhausted)?;
+        if end > counter.reserved {
+            let reserved = end.checked_add(NONCE_BLOCK).ok_or_else(exhausted)?;
+            self.save(reserved)?;
+            counter.reserved = reserved;
+        }
+        let mut nonces = Vec::with_capacity(count * 12);
+        for n in counter.next..end {
+            nonces.extend_from_slice(&self.prefix);
+            nonces.extend_from_slice(&n.to_be_bytes());
+        }
+        counter.next = end;
+        Ok(nonces)
+    }
+
+    // Write the sequence's state again, after the meta tree was cleared
+    pub(super) fn restore(&self) -> Result<(), VibraError> {
+        let counter = self.counter.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.save(counter.reserved)
+    }
+
+    // Record the prefix and a new high-water mark, durably in strict mode
+    fn save(&self, reserved: u64) -> Result<(), VibraError> {
+        let state = NonceState {
+            prefix: self.prefix,
+            reserved,
+        };
+        self.meta.insert(NONCE_KEY, serde_json::to_vec(&state)?)?;
+        if self.strict {
+            self.meta.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// The counter is 64 bits wide, so it takes reopening a database billions of times to get here
+fn exhausted() -> VibraError {
+    VibraError::NonceExhausted
+}
@@ -0,0 +1,171 @@
+use super::keys::MasterKey;
+use crate::error::VibraError;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+pub(crate) const LOCK_FILE: &str = "passphrase.json"; // Kept in the database directory
+const LOCK_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+
+/// The Argon2id cost parameters a passphrase is stretched with.
+///
+/// They are stored next to the database, so raising them later with `VibraDB::set_passphrase`
+/// doesn't change how the database is opened. The defaults are the OWASP recommendation for
+/// Argon2id; desktop apps can usually afford more memory.
+///
+/// # Fields
+///
+/// * `memory_kib` - Memory used per derivation, in KiB. At least 8 per lane.
+/// * `iterations` - Passes over the memory. At least 1.
+/// * `parallelism` - Lanes computed in parallel. At least 1.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PassphraseParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PassphraseParams {
+    fn default() -> Self {
+        PassphraseParams {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+/// What `LOCK_FILE` holds: the master key, encrypted with AES-256-GCM under a key derived from
+/// the passphrase with Argon2id, along with the salt and parameters that derivation takes.
+///
+/// The GCM tag doubles as the check value for the passphrase: a wrong one fails to decrypt the
+/// master key, so it is rejected before any row is read. Binary fields are base64.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PassphraseLock {
+    version: u32,
+    params: PassphraseParams,
+    salt: String,
+    nonce: String,
+    wrapped_key: String,
+}
+
+impl PassphraseLock {
+    // Encrypt a master key under a passphrase, with a fresh salt and nonce
+    pub(crate) fn seal(
+        master_key: &MasterKey,
+        passphrase: &str,
+        params: PassphraseParams,
+    ) -> Result<Self, VibraError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; 12];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let cipher = Self::cipher(passphrase, &salt, params)?;
+        let wrapped_key = cipher
+            .encrypt(Nonce::from_slice(&nonce), master_key.as_bytes().as_slice())
+            .map_err(|_| VibraError::Encryption { layer: 0 })?;
+        Ok(PassphraseLock {
+            version: LOCK_VERSION,
+            params,
+            salt: STANDARD.encode(salt),
+            nonce: STANDARD.encode(nonce),
+            wrapped_key: STANDARD.encode(wrapped_key),
+        })
+    }
+
+    // Decrypt the master key, failing with InvalidPassphrase if the passphrase is wrong
+    pub(crate) fn unlock(&self, passphrase: &str, path: &Path) -> Result<MasterKey, VibraError> {
+        let malformed = |reason: &str| VibraError::InvalidKey {
+            origin: format!("passphrase file {}", path.display()),
+            reason: reason.to_string(),
+        };
+        if self.version != LOCK_VERSION {
+            return Err(malformed(&format!("unsupported version {}", self.version)));
+        }
+        let decode = |field: &str| STANDARD.decode(field).map_err(|_| malformed("bad base64"));
+        let (salt, nonce) = (decode(&self.salt)?, decode(&self.nonce)?);
+        if nonce.len() != 12 {
+            return Err(malformed("the nonce is not 12 bytes"));
+        }
+        // A wrong passphrase costs the same Argon2id run and AES-GCM tag check as the right one,
+        // whose tag is compared in constant time, so the two can't be told apart by timing
+        let cipher = Self::cipher(passphrase, &salt, self.params)?;
+        let key = cipher
+            .decrypt(Nonce::from_slice(&nonce), decode(&self.wrapped_key)?.as_slice())
+            .map_err(|_| VibraError::InvalidPassphrase)?;
+        let key = Zeroizing::new(key);
+        MasterKey::from_slice(&key).ok_or_else(|| malformed("the key is not 32 bytes"))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn params(&self) -> PassphraseParams {
+        self.params
+    }
+
+    // Stretch the passphrase into the key the master key is encrypted with
+    fn cipher(
+        passphrase: &str,
+        salt: &[u8],
+        params: PassphraseParams,
+    ) -> Result<Aes256Gcm, VibraError> {
+        let argon2_params = Params::new(
+            params.memory_kib,
+            params.iterations,
+            params.parallelism,
+            Some(32),
+        )
+        .map_err(|e| VibraError::InvalidConfig(format!("invalid passphrase params: {}", e)))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params)
+            .hash_password_into(passphrase.as_bytes(), salt, key.as_mut_slice())
+            .map_err(|e| VibraError::InvalidConfig(format!("invalid passphrase salt: {}", e)))?;
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice())))
+    }
+
+    // Read the lock from a database directory, or None if the database has none yet
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, VibraError> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(VibraError::KeyFile {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|e| VibraError::InvalidKey {
+                origin: format!("passphrase file {}", path.display()),
+                reason: format!("not a passphrase file: {}", e),
+            })
+    }
+
+    // Replace the lock atomically, through a temporary file renamed over it, so a crash never
+    // leaves the database without a readable one
+    pub(crate) fn save(&self, path: &Path) -> Result<(), VibraError> {
+        let key_file_error = |source| VibraError::KeyFile {
+            path: path.to_path_buf(),
+            source,
+        };
+        let temp = path.with_extension("json.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp).map_err(key_file_error)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?).map_err(key_file_error)?;
+        file.sync_all().map_err(key_file_error)?;
+        fs::rename(&temp, path).map_err(key_file_error)
+    }
+}
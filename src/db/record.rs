@@ -0,0 +1,430 @@
+use super::keys::SALT_LEN;
+use crate::config::CipherSuite;
+use crate::error::VibraError;
+use std::ops::Range;
+
+// Every versioned record starts with this byte. Legacy records start with the high byte of their
+// big-endian value length instead, which is zero for any value that fits in memory, so the two
+// can't be confused.
+const MAGIC: u8 = 0xb5;
+pub(crate) const VERSION_INLINE_KEYS: u8 = 1; // The layer keys are stored in the record
+pub(crate) const VERSION_DERIVED_KEYS: u8 = 2; // A salt is stored; keys come from the master key
+pub(crate) const VERSION_TABLE_KEYS: u8 = 3; // A salt is stored; keys come from the table's key
+pub(crate) const VERSION_BOUND: u8 = 4; // The key source is stored too; the context is the AAD
+pub(crate) const FORMAT_VERSION: u8 = VERSION_BOUND; // The newest version
+pub(crate) const CIPHER_LAYERED_AES_GCM: u8 = 0; // Every layer is AES-256-GCM
+pub(crate) const CIPHER_LAYERED_CHACHA20_POLY1305: u8 = 1; // Every layer is ChaCha20-Poly1305
+pub(crate) const FLAG_PLAINTEXT: u8 = 0x02; // The value is stored as is, without any layers
+// With FLAG_PLAINTEXT: the value is a row whose sensitive columns are sealed inside it
+pub(crate) const FLAG_SEALED_COLUMNS: u8 = 0x04;
+// With FLAG_SEALED_COLUMNS: some of the row's columns are encrypted deterministically in place
+pub(crate) const FLAG_DETERMINISTIC_COLUMNS: u8 = 0x08;
+// Without FLAG_PLAINTEXT: the nonces come from the database's NonceSequence
+pub(crate) const FLAG_COUNTER_NONCES: u8 = 0x10;
+
+// Bytes before the header fields that legacy records lack: magic, version, cipher id and flags
+const PREFIX_LEN: usize = 4;
+// Fixed part of the header, up to and including the chunk count
+pub(crate) const FIXED_HEADER_LEN: usize = PREFIX_LEN + LEGACY_FIXED_HEADER_LEN;
+const LEGACY_FIXED_HEADER_LEN: usize = 30;
+
+// What the layer keys of a record come from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum KeySource {
+    Inline, // Stored in the record
+    Master, // Derived from a salt and the master key
+    Table,  // Derived from a salt and the data key of the record's table
+}
+
+impl KeySource {
+    // The byte naming the key source at the front of the key material, from version 4
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            KeySource::Inline => 0,
+            KeySource::Master => 1,
+            KeySource::Table => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        [KeySource::Master, KeySource::Table]
+            .into_iter()
+            .find(|source| source.tag() == tag)
+    }
+}
+
+impl CipherSuite {
+    // The cipher id records sealed with this suite carry in their header
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => CIPHER_LAYERED_AES_GCM,
+            CipherSuite::ChaCha20Poly1305 => CIPHER_LAYERED_CHACHA20_POLY1305,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+            .into_iter()
+            .find(|suite| suite.id() == id)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Timestamp {
+    Created,
+    Updated,
+}
+
+impl Timestamp {
+    // Where the timestamp sits in a current-version record
+    pub(crate) fn range(self) -> Range<usize> {
+        match self {
+            Timestamp::Created => PREFIX_LEN + 10..PREFIX_LEN + 18,
+            Timestamp::Updated => PREFIX_LEN + 18..PREFIX_LEN + 26,
+        }
+    }
+}
+
+/// The plaintext header at the front of every stored record.
+///
+/// Serialized big endian, in field order, after a magic byte:
+/// `[magic: u8][version: u8][cipher: u8][flags: u8][value length: u64][layer count: u16]`
+/// `[created at: u64][updated at: u64][chunk count: u32][ciphertext length per chunk: u32 each]`.
+/// The key material, nonces and ciphertext chunks follow it. Up to version 1 the key material
+/// is the key of every layer; from version 2 it is a salt, and the keys are derived from it and
+/// the database's `MasterKey`, or in version 3 the data key of the record's table. From version
+/// 4 the salt is preceded by a byte naming which of the two keys it is used with, and every layer
+/// authenticates the record's context (its sled key, `table/id` for rows) as associated data, so
+/// a record moved under another key fails to decrypt. The value's integrity hash is not part of
+/// the header; it is encrypted along with the value so it reveals nothing about the plaintext.
+///
+/// Legacy records have the same layout without the magic, version, cipher and flags bytes, and
+/// decode as version 0, with their keys inline.
+///
+/// # Fields
+///
+/// * `version` - The record format version.
+/// * `cipher` - Which cipher sealed the chunks, as `CipherSuite::id`. Legacy records are AES-GCM.
+/// * `flags` - Per-record options. Bit 0 marks a compressed value, which isn't written yet, so
+///   records carrying it are rejected. Bit 1 marks a record of a table created without encryption:
+///   it has no layers, key material or nonces, and its single chunk is the value itself, with the
+///   cipher byte unused. Bit 2, only ever set along with bit 1, marks a row of a table with
+///   sensitive columns, whose value holds the other columns as is and those sealed in a record
+///   of their own. Bit 3, only ever set along with bit 2, marks such a row holding columns
+///   encrypted deterministically with AES-256-GCM-SIV among its clear ones, whose ciphertext is
+///   equal for equal values. Bit 4, only ever set on records with layers, marks nonces taken
+///   from the database's nonce sequence, a prefix random to the database followed by a counter,
+///   rather than drawn at random.
+/// * `value_len` - Length of the plaintext value.
+/// * `layers` - Number of encryption layers.
+/// * `created_at`, `updated_at` - Write times in unix milliseconds.
+/// * `chunk_lens` - Ciphertext length of each chunk.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct RecordHeader {
+    pub(crate) version: u8,
+    pub(crate) cipher: u8,
+    pub(crate) flags: u8,
+    pub(crate) value_len: u64,
+    pub(crate) layers: u16,
+    pub(crate) created_at: u64,
+    pub(crate) updated_at: u64,
+    pub(crate) chunk_lens: Vec<u32>,
+}
+
+// A decoded record, borrowing its key material and ciphertext from the stored bytes
+pub(crate) struct Record<'a> {
+    pub(crate) header: RecordHeader,
+    pub(crate) key_source: KeySource,
+    pub(crate) key_material: &'a [u8], // The layer keys, or the salt to derive them from
+    pub(crate) nonces: &'a [u8],
+    pub(crate) chunks: Vec<&'a [u8]>,
+}
+
+impl RecordHeader {
+    pub(crate) fn time(&self, field: Timestamp) -> u64 {
+        match field {
+            Timestamp::Created => self.created_at,
+            Timestamp::Updated => self.updated_at,
+        }
+    }
+
+    // The cipher the chunks were sealed with. Decoding rejects ids that name none.
+    pub(crate) fn cipher_suite(&self) -> CipherSuite {
+        CipherSuite::from_id(self.cipher).unwrap_or_default()
+    }
+
+    // Whether the value is stored unencrypted
+    pub(crate) fn is_plaintext(&self) -> bool {
+        self.flags & FLAG_PLAINTEXT != 0
+    }
+
+    // Whether the value is a row with its sensitive columns sealed inside it
+    pub(crate) fn has_sealed_columns(&self) -> bool {
+        self.flags & FLAG_SEALED_COLUMNS != 0
+    }
+
+    // Whether the record's nonces come from a nonce sequence, so none repeats within the database
+    pub(crate) fn has_counter_nonces(&self) -> bool {
+        self.flags & FLAG_COUNTER_NONCES != 0
+    }
+
+    // Whether the value is a row holding deterministically encrypted columns
+    pub(crate) fn has_deterministic_columns(&self) -> bool {
+        self.flags & FLAG_DETERMINISTIC_COLUMNS != 0
+    }
+
+    // Whether the layer keys are derived from a salt rather than stored
+    pub(crate) fn derives_keys(&self) -> bool {
+        self.version >= VERSION_DERIVED_KEYS
+    }
+
+    // Whether the chunks authenticate the record's context as associated data
+    pub(crate) fn binds_context(&self) -> bool {
+        self.version >= VERSION_BOUND
+    }
+
+    // Length of the key material that follows the header, key source byte included
+    fn key_material_len(&self) -> usize {
+        if self.is_plaintext() {
+            0
+        } else if self.binds_context() {
+            1 + SALT_LEN
+        } else if self.derives_keys() {
+            SALT_LEN
+        } else {
+            self.layers as usize * 32
+        }
+    }
+
+    // Length of the header once encoded
+    fn encoded_len(&self) -> usize {
+        FIXED_HEADER_LEN + self.chunk_lens.len() * 4
+    }
+
+    // Append the header. Records are encoded as version 1 or later; legacy ones are never written.
+    fn encode(&self, out: &mut Vec<u8>) {
+        let version = self.version.max(VERSION_INLINE_KEYS);
+        out.extend_from_slice(&[MAGIC, version, self.cipher, self.flags]);
+        out.extend_from_slice(&self.value_len.to_be_bytes());
+        out.extend_from_slice(&self.layers.to_be_bytes());
+        out.extend_from_slice(&self.created_at.to_be_bytes());
+        out.extend_from_slice(&self.updated_at.to_be_bytes());
+        out.extend_from_slice(&(self.chunk_lens.len() as u32).to_be_bytes());
+        for len in &self.chunk_lens {
+            out.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    // Parse the header at the front of a stored record, returning it and its encoded length
+    pub(crate) fn decode(stored: &[u8]) -> Result<(RecordHeader, usize), VibraError> {
+        let (version, cipher, flags, fields) = match stored.first() {
+            Some(&MAGIC) => {
+                let prefix = stored.get(..PREFIX_LEN).ok_or_else(|| malformed("truncated header"))?;
+                (prefix[1], prefix[2], prefix[3], &stored[PREFIX_LEN..])
+            }
+            // Anything else is a legacy record, whose first byte belongs to the value length
+            Some(_) => (0, CIPHER_LAYERED_AES_GCM, 0, stored),
+            None => return Err(malformed("truncated header")),
+        };
+        if version > FORMAT_VERSION {
+            return Err(malformed(&format!("unsupported format version {}", version)));
+        }
+        if CipherSuite::from_id(cipher).is_none() {
+            return Err(malformed(&format!("unsupported cipher {}", cipher)));
+        }
+        let sealed_columns = FLAG_PLAINTEXT | FLAG_SEALED_COLUMNS;
+        let known = match flags & sealed_columns {
+            FLAG_PLAINTEXT => sealed_columns,
+            both if both == sealed_columns => sealed_columns | FLAG_DETERMINISTIC_COLUMNS,
+            0 => FLAG_COUNTER_NONCES,
+            _ => 0,
+        };
+        if flags & !known != 0 {
+            return Err(malformed(&format!("unsupported flags {:#04x}", flags)));
+        }
+
+        let field = |range: Range<usize>| -> Result<&[u8], VibraError> {
+            fields.get(range).ok_or_else(|| malformed("truncated header"))
+        };
+        let u64_at = |at: usize| -> Result<u64, VibraError> {
+            Ok(u64::from_be_bytes(field(at..at + 8)?.try_into().unwrap()))
+        };
+        let u32_at = |at: usize| -> Result<u32, VibraError> {
+            Ok(u32::from_be_bytes(field(at..at + 4)?.try_into().unwrap()))
+        };
+        let chunk_count = u32_at(26)? as usize;
+        // Bound the count by the bytes present before trusting it with an allocation
+        if fields.len() < LEGACY_FIXED_HEADER_LEN + chunk_count * 4 {
+            return Err(malformed("truncated header"));
+        }
+        let header = RecordHeader {
+            version,
+            cipher,
+            flags,
+            value_len: u64_at(0)?,
+            layers: u16::from_be_bytes(field(8..10)?.try_into().unwrap()),
+            created_at: u64_at(10)?,
+            updated_at: u64_at(18)?,
+            chunk_lens: (0..chunk_count)
+                .map(|c| u32_at(LEGACY_FIXED_HEADER_LEN + c * 4))
+                .collect::<Result<_, _>>()?,
+        };
+        let header_len = stored.len() - fields.len() + LEGACY_FIXED_HEADER_LEN + chunk_count * 4;
+        Ok((header, header_len))
+    }
+}
+
+// Serialize a record in the version its header names
+pub(crate) fn encode_record(
+    header: &RecordHeader,
+    key_material: &[u8],
+    nonces: &[u8],
+    chunks: &[Vec<u8>],
+) -> Vec<u8> {
+    let body_len: usize = chunks.iter().map(|c| c.len()).sum();
+    let capacity = header.encoded_len() + key_material.len() + nonces.len() + body_len;
+    let mut stored = Vec::with_capacity(capacity);
+    header.encode(&mut stored);
+    stored.extend_from_slice(key_material);
+    stored.extend_from_slice(nonces);
+    for chunk in chunks {
+        stored.extend_from_slice(chunk);
+    }
+    stored
+}
+
+// Split a stored record into its header, key material and ciphertext chunks
+pub(crate) fn decode_record(stored: &[u8]) -> Result<Record<'_>, VibraError> {
+    let (header, header_len) = RecordHeader::decode(stored)?;
+    let layers = header.layers as usize;
+    let chunk_count = header.chunk_lens.len();
+    let keys_end = header_len + header.key_material_len();
+    let nonces_end = keys_end + chunk_count * layers * 12;
+    if header.is_plaintext() {
+        if layers != 0 || chunk_count != 1 {
+            return Err(malformed("plaintext records hold a single chunk and no layers"));
+        }
+    } else if layers == 0 || chunk_count == 0 || stored.len() < nonces_end {
+        return Err(malformed("truncated header"));
+    }
+    let mut chunks = Vec::with_capacity(chunk_count);
+    let mut offset = nonces_end;
+    for &len in &header.chunk_lens {
+        let end = offset + len as usize;
+        chunks.push(stored.get(offset..end).unwrap_or_default());
+        offset = end;
+    }
+    if offset != stored.len() {
+        return Err(malformed("chunk lengths do not match the stored size"));
+    }
+    let mut key_material = &stored[header_len..keys_end];
+    let key_source = match header.version {
+        // A plaintext record's zero layers have no keys at all
+        _ if header.is_plaintext() => KeySource::Inline,
+        0 | VERSION_INLINE_KEYS => KeySource::Inline,
+        VERSION_DERIVED_KEYS => KeySource::Master,
+        VERSION_TABLE_KEYS => KeySource::Table,
+        _ => {
+            let source = KeySource::from_tag(key_material[0]);
+            key_material = &key_material[1..];
+            source.ok_or_else(|| malformed(&format!("unknown key source {}", stored[header_len])))?
+        }
+    };
+    Ok(Record {
+        key_source,
+        key_material,
+        nonces: &stored[keys_end..nonces_end],
+        chunks,
+        header,
+    })
+}
+
+// Serialize a value unencrypted, as a record of a table created without encryption
+pub(crate) fn encode_plaintext(value: &[u8], now: u64) -> Vec<u8> {
+    encode_unencrypted(value, FLAG_PLAINTEXT, now)
+}
+
+// Serialize a row whose sensitive columns are already sealed inside it
+pub(crate) fn encode_sealed_columns(value: &[u8], now: u64) -> Vec<u8> {
+    encode_unencrypted(value, FLAG_PLAINTEXT | FLAG_SEALED_COLUMNS, now)
+}
+
+// Serialize a row whose sensitive columns are sealed inside it and some others are encrypted
+// deterministically in place
+pub(crate) fn encode_deterministic_columns(value: &[u8], now: u64) -> Vec<u8> {
+    let flags = FLAG_PLAINTEXT | FLAG_SEALED_COLUMNS | FLAG_DETERMINISTIC_COLUMNS;
+    encode_unencrypted(value, flags, now)
+}
+
+fn encode_unencrypted(value: &[u8], flags: u8, now: u64) -> Vec<u8> {
+    let header = RecordHeader {
+        version: FORMAT_VERSION,
+        cipher: CIPHER_LAYERED_AES_GCM,
+        flags,
+        value_len: value.len() as u64,
+        layers: 0,
+        created_at: now,
+        updated_at: now,
+        chunk_lens: vec![value.len() as u32],
+    };
+    let mut stored = Vec::with_capacity(header.encoded_len() + value.len());
+    header.encode(&mut stored);
+    stored.extend_from_slice(value);
+    stored
+}
+
+// The value of a plaintext record, or None for an encrypted one or a row with sealed columns
+pub(crate) fn plaintext_value(stored: &[u8]) -> Result<Option<&[u8]>, VibraError> {
+    unencrypted_value(stored, false)
+}
+
+// The value of a row with sealed columns, or None for any other record
+pub(crate) fn sealed_columns_value(stored: &[u8]) -> Result<Option<&[u8]>, VibraError> {
+    unencrypted_value(stored, true)
+}
+
+fn unencrypted_value(stored: &[u8], sealed_columns: bool) -> Result<Option<&[u8]>, VibraError> {
+    let record = decode_record(stored)?;
+    if !record.header.is_plaintext() || record.header.has_sealed_columns() != sealed_columns {
+        return Ok(None);
+    }
+    let value = record.chunks[0];
+    if value.len() as u64 != record.header.value_len {
+        return Err(malformed("value length does not match the stored size"));
+    }
+    Ok(Some(value))
+}
+
+// The shortest a record can be: its header with a single chunk length, one layer's key (or a
+// salt, which is as long) and nonce, and a chunk holding just the checksum and a GCM tag. Legacy
+// records lack the prefix bytes, and plaintext records can be just a header.
+pub(crate) fn min_record_len(stored: &[u8]) -> usize {
+    let min = FIXED_HEADER_LEN + 4 + 32 + 12 + 32 + 16;
+    match stored.first() {
+        Some(&MAGIC) if stored.get(3).is_some_and(|flags| flags & FLAG_PLAINTEXT != 0) => {
+            FIXED_HEADER_LEN + 4
+        }
+        Some(&MAGIC) => min,
+        _ => min - PREFIX_LEN,
+    }
+}
+
+// Whether a stored record says it is a row with sealed columns, without decoding it
+pub(crate) fn holds_sealed_columns(stored: &[u8]) -> bool {
+    stored.first() == Some(&MAGIC)
+        && stored.get(3).is_some_and(|flags| flags & FLAG_SEALED_COLUMNS != 0)
+}
+
+// Overwrite a timestamp in a record freshly encoded in version 1 or later
+pub(crate) fn set_time(stored: &mut [u8], field: Timestamp, millis: u64) {
+    if let Some(dest) = stored.get_mut(field.range()) {
+        dest.copy_from_slice(&millis.to_be_bytes());
+    }
+}
+
+fn malformed(reason: &str) -> VibraError {
+    VibraError::MalformedRecord(reason.to_string())
+}
+
+#[cfg(test)]
+mod record_tests;
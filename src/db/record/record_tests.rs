@@ -0,0 +1,264 @@
+use super::*;
+
+fn header() -> RecordHeader {
+    RecordHeader {
+        version: VERSION_INLINE_KEYS,
+        cipher: CIPHER_LAYERED_AES_GCM,
+        flags: 0,
+        value_len: 5,
+        layers: 2,
+        created_at: 1_700_000_000_000,
+        updated_at: 1_700_000_000_500,
+        chunk_lens: vec![3, 4],
+    }
+}
+
+// A record with the given header and recognizable key material and chunks
+fn encode(header: &RecordHeader) -> Vec<u8> {
+    let keys = vec![1; header.key_material_len()];
+    let nonces = vec![2; header.chunk_lens.len() * header.layers as usize * 12];
+    let chunks: Vec<Vec<u8>> = header
+        .chunk_lens
+        .iter()
+        .map(|&len| vec![3; len as usize])
+        .collect();
+    encode_record(header, &keys, &nonces, &chunks)
+}
+
+#[test]
+fn test_header_round_trip() {
+    let header = header();
+    let stored = encode(&header);
+    assert_eq!(stored[0], MAGIC);
+
+    let (decoded, header_len) = RecordHeader::decode(&stored).unwrap();
+    assert_eq!(decoded, header);
+    assert_eq!(header_len, FIXED_HEADER_LEN + 8);
+
+    let record = decode_record(&stored).unwrap();
+    assert_eq!(record.key_material, &[1; 64][..]);
+    assert_eq!(record.nonces, &[2; 48][..]);
+    assert_eq!(record.chunks, vec![&[3; 3][..], &[3; 4][..]]);
+
+    let mut stored = stored;
+    set_time(&mut stored, Timestamp::Created, 42);
+    let (decoded, _) = RecordHeader::decode(&stored).unwrap();
+    assert_eq!(decoded.created_at, 42);
+    assert_eq!(decoded.updated_at, header.updated_at);
+}
+
+#[test]
+fn test_unknown_version_is_rejected() {
+    let mut stored = encode(&header());
+    stored[1] = FORMAT_VERSION + 1;
+    match decode_record(&stored) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("version")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+
+    let mut stored = encode(&header());
+    stored[3] = 0x01;
+    assert!(matches!(
+        RecordHeader::decode(&stored),
+        Err(VibraError::MalformedRecord(_))
+    ));
+}
+
+#[test]
+fn test_legacy_record_is_detected() {
+    // Legacy records are the current layout without the magic, version, cipher and flags bytes
+    let header = header();
+    let stored = encode(&header);
+    let legacy = &stored[PREFIX_LEN..];
+
+    let record = decode_record(legacy).unwrap();
+    assert_eq!(record.header, RecordHeader { version: 0, ..header });
+    assert_eq!(record.chunks, vec![&[3; 3][..], &[3; 4][..]]);
+}
+
+#[test]
+fn test_truncated_records_are_rejected() {
+    let stored = encode(&header());
+    for len in [0, 1, PREFIX_LEN, FIXED_HEADER_LEN, stored.len() - 1] {
+        assert!(matches!(
+            decode_record(&stored[..len]),
+            Err(VibraError::MalformedRecord(_))
+        ));
+    }
+}
+
+#[test]
+fn test_derived_key_records_store_a_salt() {
+    let derived = RecordHeader {
+        version: VERSION_DERIVED_KEYS,
+        layers: 10,
+        ..header()
+    };
+    let stored = encode(&derived);
+    assert_eq!(stored[1], VERSION_DERIVED_KEYS);
+
+    // Only the salt is stored, however many layers there are
+    let record = decode_record(&stored).unwrap();
+    assert!(record.header.derives_keys());
+    assert_eq!(record.key_source, KeySource::Master);
+    assert_eq!(record.key_material, &[1; SALT_LEN][..]);
+    assert_eq!(record.nonces, &[2; 2 * 10 * 12][..]);
+    assert!(!decode_record(&encode(&header())).unwrap().header.derives_keys());
+
+    // Records keyed by their table's data key store a salt the same way
+    let table_keyed = RecordHeader {
+        version: VERSION_TABLE_KEYS,
+        ..derived
+    };
+    let stored = encode(&table_keyed);
+    let record = decode_record(&stored).unwrap();
+    assert_eq!(record.key_source, KeySource::Table);
+    assert_eq!(record.key_material, &[1; SALT_LEN][..]);
+}
+
+#[test]
+fn test_bound_records_name_their_key_source() {
+    let bound = RecordHeader {
+        version: VERSION_BOUND,
+        layers: 10,
+        ..header()
+    };
+    let mut stored = encode(&bound);
+    let (decoded, header_len) = RecordHeader::decode(&stored).unwrap();
+    assert!(decoded.derives_keys() && decoded.binds_context());
+    assert!(!header().binds_context());
+
+    // The key source byte precedes the salt, and isn't part of it
+    for source in [KeySource::Master, KeySource::Table] {
+        stored[header_len] = source.tag();
+        let record = decode_record(&stored).unwrap();
+        assert_eq!(record.key_source, source);
+        assert_eq!(record.key_material, &[1; SALT_LEN][..]);
+    }
+    stored[header_len] = 7;
+    match decode_record(&stored) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("key source")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_cipher_ids() {
+    for suite in [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+        let stored = encode(&RecordHeader {
+            cipher: suite.id(),
+            ..header()
+        });
+        let (decoded, _) = RecordHeader::decode(&stored).unwrap();
+        assert_eq!(decoded.cipher_suite(), suite);
+    }
+    // Legacy records predate the cipher id and are all AES-GCM
+    let stored = encode(&header());
+    let (legacy, _) = RecordHeader::decode(&stored[PREFIX_LEN..]).unwrap();
+    assert_eq!(legacy.cipher_suite(), CipherSuite::Aes256Gcm);
+
+    let mut stored = stored;
+    stored[2] = 2;
+    match decode_record(&stored) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("cipher 2")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_plaintext_records() {
+    let stored = encode_plaintext(b"{\"id\":\"de\"}", 42);
+    assert_eq!(stored.len(), FIXED_HEADER_LEN + 4 + 11);
+    assert!(stored.len() >= min_record_len(&stored));
+    let record = decode_record(&stored).unwrap();
+    assert!(record.header.is_plaintext());
+    assert_eq!((record.header.layers, record.header.created_at), (0, 42));
+    assert!(record.key_material.is_empty() && record.nonces.is_empty());
+    assert_eq!(plaintext_value(&stored).unwrap(), Some(&b"{\"id\":\"de\"}"[..]));
+    assert_eq!(plaintext_value(&encode(&header())).unwrap(), None);
+
+    // An empty value is still a record
+    let empty = encode_plaintext(b"", 0);
+    assert_eq!(empty.len(), min_record_len(&empty));
+    assert_eq!(plaintext_value(&empty).unwrap(), Some(&b""[..]));
+
+    // Plaintext records have no layers, and encrypted ones some
+    let layered = encode(&RecordHeader {
+        flags: FLAG_PLAINTEXT,
+        ..header()
+    });
+    assert!(matches!(
+        decode_record(&layered),
+        Err(VibraError::MalformedRecord(_))
+    ));
+    let mut wrong_len = stored.clone();
+    wrong_len.push(b' ');
+    assert!(matches!(
+        plaintext_value(&wrong_len),
+        Err(VibraError::MalformedRecord(_))
+    ));
+}
+
+#[test]
+fn test_sealed_columns_records() {
+    let value = b"{\"id\":\"alice\"}";
+    let stored = encode_sealed_columns(value, 42);
+    let (decoded, _) = RecordHeader::decode(&stored).unwrap();
+    assert!(decoded.is_plaintext() && decoded.has_sealed_columns());
+    assert!(holds_sealed_columns(&stored) && !holds_sealed_columns(&encode_plaintext(value, 0)));
+    assert!(stored.len() >= min_record_len(&stored));
+    assert_eq!(sealed_columns_value(&stored).unwrap(), Some(&value[..]));
+    // Neither kind of unencrypted record passes for the other
+    assert_eq!(plaintext_value(&stored).unwrap(), None);
+    assert_eq!(sealed_columns_value(&encode_plaintext(value, 42)).unwrap(), None);
+    assert_eq!(sealed_columns_value(&encode(&header())).unwrap(), None);
+
+    // The flag means nothing on an encrypted record
+    let layered = encode(&RecordHeader {
+        flags: FLAG_SEALED_COLUMNS,
+        ..header()
+    });
+    match decode_record(&layered) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("flags 0x04")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_deterministic_columns_records() {
+    let value = b"{\"id\":\"alice\"}";
+    let stored = encode_deterministic_columns(value, 42);
+    let (decoded, _) = RecordHeader::decode(&stored).unwrap();
+    assert!(decoded.has_sealed_columns() && decoded.has_deterministic_columns());
+    let (sealed, _) = RecordHeader::decode(&encode_sealed_columns(value, 42)).unwrap();
+    assert!(!sealed.has_deterministic_columns());
+    assert!(holds_sealed_columns(&stored));
+    assert_eq!(sealed_columns_value(&stored).unwrap(), Some(&value[..]));
+
+    // The flag only means something on a row with sealed columns
+    let mut bare = encode_plaintext(value, 42);
+    bare[3] |= FLAG_DETERMINISTIC_COLUMNS;
+    match decode_record(&bare) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("flags 0x0a")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn test_counter_nonces_flag() {
+    let stored = encode(&RecordHeader {
+        flags: FLAG_COUNTER_NONCES,
+        ..header()
+    });
+    let record = decode_record(&stored).unwrap();
+    assert!(record.header.has_counter_nonces());
+    assert!(!decode_record(&encode(&header())).unwrap().header.has_counter_nonces());
+
+    // Unencrypted records have no nonces for the flag to describe
+    let mut bare = encode_plaintext(b"{}", 42);
+    bare[3] |= FLAG_COUNTER_NONCES;
+    match decode_record(&bare) {
+        Err(VibraError::MalformedRecord(reason)) => assert!(reason.contains("flags 0x12")),
+        other => panic!("expected a malformed record, got {:?}", other.err()),
+    }
+}
@@ -0,0 +1,324 @@
+use super::crypto::ct_eq;
+use super::keys::MasterKey;
+use super::record::{self, KeySource};
+use super::names::NAMES_KEY;
+use super::{passphrase, RecordKey, TableMeta, Timestamp, VibraDB, ENCRYPTION_KEY};
+use crate::error::VibraError;
+use crate::models::RotationReport;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use sled::IVec;
+use std::ops::Bound;
+use std::sync::Arc;
+use tokio::task;
+
+pub(super) const ROTATION_KEY: &str = "rotation"; // RotationProgress JSON in the meta tree
+const ROTATION_BATCH: usize = 100; // Records rewritten per transaction
+
+// How far an interrupted rotation got. Schemas are rotated before rows; `after` is the last key
+// rotated in the tree being worked on.
+#[derive(Serialize, Deserialize)]
+struct RotationProgress {
+    key_check: String, // MasterKey::check_value of the key being rotated to
+    schemas_done: bool,
+    after: Option<String>,
+}
+
+// What rotating a single record comes to, before it is written
+enum Outcome {
+    Rotated(Vec<u8>),
+    Skipped,
+    Failed,
+}
+
+impl VibraDB {
+    // Switch the database to `new_key`, which new writes use from the start. Records encrypted
+    // with their table's data key stay as they are; the rest, written before tables had data keys,
+    // are re-encrypted with their table's key (given one first if the table has none), or with
+    // `new_key` outside tables. Last, every table's data key is re-wrapped with `new_key`. Reads
+    // keep working throughout: records the rotation hasn't reached yet are read with the old key,
+    // which this handle keeps until it is closed.
+    //
+    // Records are rewritten in batches, each in one transaction with the progress it makes, and
+    // the data keys are re-wrapped in one transaction that also finishes the rotation, so a
+    // rotation that is interrupted, by a crash or an error, resumes after the last batch written
+    // when it is called again with the same key on a database opened with the old one.
+    pub async fn rotate_master_key(
+        &self,
+        new_key: MasterKey,
+    ) -> Result<RotationReport, VibraError> {
+        self.rotate_batches(new_key, None).await
+    }
+
+    // Rotate, stopping after `max_batches` batches as if interrupted, when given
+    pub(crate) async fn rotate_batches(
+        &self,
+        new_key: MasterKey,
+        max_batches: Option<usize>,
+    ) -> Result<RotationReport, VibraError> {
+        let this = self.clone();
+        task::spawn_blocking(move || this.rotate_blocking(Arc::new(new_key), max_batches, None))
+            .await
+            .unwrap()
+    }
+
+    // Rotate on this thread. `settings`, the EncryptionSettings JSON of a rekey, are stored along
+    // with the key swap.
+    pub(super) fn rotate_blocking(
+        &self,
+        new_key: Arc<MasterKey>,
+        max_batches: Option<usize>,
+        settings: Option<&[u8]>,
+    ) -> Result<RotationReport, VibraError> {
+        let _running = self.rotation.try_lock().map_err(|_| {
+            VibraError::InvalidConfig("a master key rotation is already running".to_string())
+        })?;
+        let key_check = new_key.check_value();
+        let mut progress = match self.meta.get(ROTATION_KEY)? {
+            Some(stored) => serde_json::from_slice::<RotationProgress>(&stored)?,
+            None => RotationProgress {
+                key_check: key_check.clone(),
+                schemas_done: false,
+                after: None,
+            },
+        };
+        if !ct_eq(progress.key_check.as_bytes(), key_check.as_bytes()) {
+            return Err(VibraError::InvalidKey {
+                origin: "rotate_master_key".to_string(),
+                reason: "an interrupted rotation to another master key has to be finished first"
+                    .to_string(),
+            });
+        }
+        if progress.after.is_some() || progress.schemas_done {
+            info!("Resuming an interrupted master key rotation");
+        }
+        self.meta.insert(ROTATION_KEY, serde_json::to_vec(&progress)?)?;
+        self.assign_table_keys()?;
+        {
+            let mut ring = self.master_keys.write().unwrap_or_else(|p| p.into_inner());
+            // Already switched when resuming a rotation this handle started
+            if !ct_eq(ring.current.check_value().as_bytes(), key_check.as_bytes()) {
+                let old = std::mem::replace(&mut ring.current, new_key.clone());
+                ring.previous = Some(old);
+            }
+        }
+
+        let mut report = RotationReport::default();
+        let mut batches = 0;
+        if !progress.schemas_done {
+            let done = self.rotate_tree(
+                &self.schema,
+                Self::schema_context,
+                &mut progress,
+                &mut report,
+                &mut batches,
+                max_batches,
+            )?;
+            if !done {
+                return Ok(report);
+            }
+            progress.schemas_done = true;
+            progress.after = None;
+        }
+        if !self.rotate_tree(
+            &self.db,
+            str::to_string,
+            &mut progress,
+            &mut report,
+            &mut batches,
+            max_batches,
+        )? {
+            return Ok(report);
+        }
+
+        // The old key stays in the ring: reads that fetched a record before it was rotated may
+        // still be decrypting it
+        report.tables = self.rewrap_table_keys(settings)?;
+        // Blind indexes are keyed by the master key, so lookups only find rows again once rebuilt
+        self.rebuild_blind_indexes()?;
+        // As is the audit log's MAC
+        self.reseal_audit_log()?;
+        info!(
+            "Rotated the master key: {} table keys re-wrapped, {} records rotated, {} skipped, \
+             {} failed",
+            report.tables, report.rotated, report.skipped, report.failed
+        );
+        if report.failed > 0 {
+            warn!("{} records could not be decrypted and keep their old keys", report.failed);
+        }
+        let lock = self.path.as_ref().map(|path| path.join(passphrase::LOCK_FILE));
+        if lock.is_some_and(|lock| lock.exists()) {
+            warn!("The passphrase still unlocks the old master key; call set_passphrase");
+        }
+        Ok(report)
+    }
+
+    // Rotate the records of one tree after `progress.after`, returning false if it stopped
+    // because `max_batches` were written
+    fn rotate_tree(
+        &self,
+        tree: &sled::Tree,
+        context: fn(&str) -> String,
+        progress: &mut RotationProgress,
+        report: &mut RotationReport,
+        batches: &mut usize,
+        max_batches: Option<usize>,
+    ) -> Result<bool, VibraError> {
+        let start = match &progress.after {
+            Some(after) => Bound::Excluded(IVec::from(after.as_bytes())),
+            None => Bound::Unbounded,
+        };
+        let mut entries = tree.range::<IVec, _>((start, Bound::Unbounded));
+        loop {
+            if max_batches.is_some_and(|max| *batches >= max) {
+                return Ok(false);
+            }
+            let batch: Vec<(IVec, IVec)> =
+                entries.by_ref().take(ROTATION_BATCH).collect::<Result<_, _>>()?;
+            let Some((last, _)) = batch.last() else {
+                return Ok(true);
+            };
+            progress.after = Some(String::from_utf8_lossy(last).into_owned());
+            let outcomes: Vec<Outcome> = batch
+                .par_iter()
+                .map(|(key, stored)| {
+                    self.rotate_record(&context(&String::from_utf8_lossy(key)), stored)
+                })
+                .collect();
+            let progress_json = serde_json::to_vec(progress)?;
+
+            let written = (tree, &self.meta).transaction(|(rows, meta)| {
+                let mut written = 0;
+                for ((key, stored), outcome) in batch.iter().zip(&outcomes) {
+                    // A record replaced or removed since it was read was written with the new key,
+                    // or is gone
+                    if let Outcome::Rotated(sealed) = outcome {
+                        if rows.get(key)?.as_ref() == Some(stored) {
+                            rows.insert(key, sealed.as_slice())?;
+                            written += 1;
+                        }
+                    }
+                }
+                meta.insert(ROTATION_KEY, progress_json.as_slice())?;
+                Ok::<_, ConflictableTransactionError<VibraError>>(written)
+            })?;
+
+            for ((key, _), outcome) in batch.iter().zip(&outcomes) {
+                match outcome {
+                    Outcome::Rotated(_) => {}
+                    Outcome::Skipped => report.skipped += 1,
+                    Outcome::Failed => {
+                        warn!(
+                            "Failed to rotate record {}: neither key decrypts it",
+                            self.loggable(&String::from_utf8_lossy(key))
+                        );
+                        report.failed += 1;
+                    }
+                }
+            }
+            let rotated = outcomes.iter().filter(|o| matches!(o, Outcome::Rotated(_))).count();
+            report.rotated += written;
+            report.skipped += rotated - written;
+            *batches += 1;
+        }
+    }
+
+    // Re-wrap the data key of every table with the current master key and finish the rotation,
+    // storing a rekey's `settings` too, in one transaction, returning how many keys were
+    // re-wrapped
+    fn rewrap_table_keys(&self, settings: Option<&[u8]>) -> Result<usize, VibraError> {
+        let (current, previous) = {
+            let ring = self.key_ring();
+            (ring.current.clone(), ring.previous.clone())
+        };
+        let mut rewrapped = Vec::new();
+        for entry in self.tables.iter() {
+            let (name, stored) = entry?;
+            let mut meta = TableMeta::decode(&stored)?;
+            let table_name = String::from_utf8_lossy(&name).into_owned();
+            // Tables created since the rotation started already use the new key
+            if meta.data_key.is_none() || meta.data_key(&current, &table_name).is_some() {
+                continue;
+            }
+            let Some(data_key) = previous.as_ref().and_then(|old| meta.data_key(old, &table_name))
+            else {
+                warn!(
+                    "Failed to re-wrap the data key of table {}: neither key unwraps it",
+                    self.loggable(&table_name)
+                );
+                continue;
+            };
+            meta.set_data_key(&current, &table_name, &data_key);
+            rewrapped.push((name, stored, meta.encode()?));
+        }
+
+        // The names key, if the database has one, is re-wrapped along with them
+        let names_key = self.names.as_ref().map(|names| names.wrapped(&current));
+        let count = (&self.tables, &self.meta).transaction(|(tables, meta)| {
+            let mut count = 0;
+            for (name, stored, updated) in &rewrapped {
+                // A table deleted or recreated since it was read is gone, or already uses the
+                // new key
+                if tables.get(name)?.as_ref() == Some(stored) {
+                    tables.insert(name, updated.as_slice())?;
+                    count += 1;
+                }
+            }
+            if let Some(names_key) = &names_key {
+                meta.insert(NAMES_KEY, names_key.as_slice())?;
+            }
+            if let Some(settings) = settings {
+                meta.insert(ENCRYPTION_KEY, settings)?;
+            }
+            meta.remove(ROTATION_KEY)?;
+            Ok::<_, ConflictableTransactionError<VibraError>>(count)
+        })?;
+        Ok(count)
+    }
+
+    // Re-encrypt a record with its table's data key, or the current master key outside tables,
+    // keeping its layer count and write times, unless it already uses that key
+    fn rotate_record(&self, context: &str, stored: &[u8]) -> Outcome {
+        let (current, previous) = {
+            let ring = self.key_ring();
+            (ring.current.clone(), ring.previous.clone())
+        };
+        let Ok(record) = record::decode_record(stored) else {
+            return Outcome::Failed;
+        };
+        let header = record.header;
+        // Data keys are re-wrapped rather than records re-encrypted, and unencrypted records have
+        // no keys at all
+        if record.key_source == KeySource::Table || header.is_plaintext() {
+            return Outcome::Skipped;
+        }
+        let key = self.record_key(context);
+        let on_current = if header.derives_keys() {
+            Self::decrypt_with(&current, None, context, stored).ok()
+        } else {
+            None
+        };
+        if on_current.is_some() && matches!(key, RecordKey::Master(_)) {
+            return Outcome::Skipped;
+        }
+        // Records with inline keys decrypt with either key, and move to derived ones
+        let old = previous.unwrap_or_else(|| current.clone());
+        let value = match on_current {
+            Some(value) => value,
+            None => match Self::decrypt_with(&old, None, context, stored) {
+                Ok(value) => value,
+                Err(_) => return Outcome::Failed,
+            },
+        };
+        match self.seal(context, &value, header.layers as usize, Some(&key)) {
+            Ok(mut sealed) => {
+                Self::copy_record_time(&mut sealed, stored, Timestamp::Created);
+                Self::copy_record_time(&mut sealed, stored, Timestamp::Updated);
+                Outcome::Rotated(sealed)
+            }
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use super::audit::AuditOp;
+use super::{TableMeta, VibraDB};
+use crate::error::VibraError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::Level;
+use tokio::task;
+
+// Deleting a row only removes its key from sled, which is log-structured: the record it held
+// stays in the database's files until sled reuses the segment it was written to. Shredding writes
+// random bytes over the record first and flushes them, so the latest copy on disk is noise, then
+// removes it. Copies sled wrote before, while the row was rewritten or its segment was moved, can
+// outlive both, as can copies in the filesystem's and the disk's own remapped blocks, so shredding
+// is best effort. What keeps those copies unreadable is encryption: a row's keys are derived from
+// its table's data key, so shredding the table destroys that key, leaving every copy of the rows
+// it sealed undecryptable.
+impl VibraDB {
+    // Overwrite a row with random bytes of the same length and flush, then delete it along with
+    // its expiry, cache entry and index entries, and flush again. Returns whether there was a
+    // row; shredding one that is already gone still evicts whatever the cache holds for it.
+    //
+    // The row is only removed if it still holds the noise, in one transaction with its audit
+    // entry, so a row written again while the noise was flushed is kept: its record replaced the
+    // noise, and the old one is shredded all the same. Reads meanwhile see the row or nothing.
+    pub async fn shred_row(&self, table_name: &str, row_id: &str) -> Result<bool, VibraError> {
+        let key = self.row_key(table_name, row_id)?;
+        self.require_table(table_name)?;
+        let this = self.clone();
+        let (shredded, removed) = task::spawn_blocking(move || {
+            this.shredding.lock().unwrap_or_else(|p| p.into_inner()).insert(key.clone());
+            let shredded = this.shred_record(&key);
+            this.shredding.lock().unwrap_or_else(|p| p.into_inner()).remove(&key);
+            let (shredded, removed) = shredded?;
+            this.cache.pop(&key);
+            this.reindex_row(&key)?;
+            this.db.flush()?;
+            Ok::<_, VibraError>((shredded, removed))
+        })
+        .await
+        .unwrap()?;
+        if shredded && !removed {
+            self.log_op(
+                Level::Debug,
+                format_args!("Row written again while shredded, kept: {}/{}", table_name, row_id),
+            );
+        }
+        match shredded {
+            true => self.log_op(
+                Level::Debug,
+                format_args!("Shredded row from table {}: {}", table_name, row_id),
+            ),
+            false => self.log_op(
+                Level::Debug,
+                format_args!("No row to shred in table {}: {}", table_name, row_id),
+            ),
+        }
+        Ok(shredded)
+    }
+
+    // Delete a table as `delete_table` does, after overwriting its rows and schema like
+    // `shred_row` does and destroying its data key: the wrapped key in the table's entry is
+    // overwritten with random bytes, and all of it flushed, before anything is removed. Returns
+    // how many rows the table had.
+    //
+    // Destroying the key is what covers the copies overwriting misses, so tables without one,
+    // being unencrypted or older than data keys, only get the overwriting, as do records from
+    // before data keys, which carry their own keys. Dumps written by `export_table` hold their
+    // own copy of the wrapped key, and backups their rows, so neither is affected. Copies of the
+    // entry sled wrote before can hold the wrapped key too, until rotating the master key leaves
+    // nothing that unwraps them.
+    pub async fn shred_table(&self, table_name: &str) -> Result<usize, VibraError> {
+        Self::validate_table_name(table_name)?;
+        self.require_table(table_name)?;
+        let this = self.clone();
+        let stored_table = self.stored_table(table_name).into_owned();
+        task::spawn_blocking(move || {
+            let prefix = format!("{}/", stored_table);
+            for key in this.db.scan_prefix(prefix.as_bytes()).keys() {
+                this.overwrite(&this.db, &key?)?;
+            }
+            this.overwrite(&this.schema, stored_table.as_bytes())?;
+            let stored = this.tables.get(stored_table.as_bytes())?;
+            if let Some(stored) = stored {
+                let mut meta = TableMeta::decode(&stored)?;
+                if let Some(wrapped) = meta.wrapped_key() {
+                    let mut noise = vec![0u8; wrapped.len()];
+                    this.with_rng(|rng| rng.fill_bytes(&mut noise));
+                    meta.data_key = Some(STANDARD.encode(noise));
+                    // Only replaced if the table wasn't deleted or recreated meanwhile
+                    let _ = this.tables.compare_and_swap(
+                        stored_table.as_bytes(),
+                        Some(stored),
+                        Some(meta.encode()?),
+                    )?;
+                }
+            }
+            this.db.flush()?;
+            Ok::<_, VibraError>(())
+        })
+        .await
+        .unwrap()?;
+        let removed = self.delete_table(table_name).await?;
+        self.db.flush_async().await?;
+        self.log_op(Level::Debug, format_args!("Shredded table: {}", table_name));
+        Ok(removed)
+    }
+
+    // Overwrite the row stored under `key` with noise and flush, then remove it and its expiry if
+    // it still holds the noise. Returns whether there was a row, and whether it was removed.
+    // Blocks on sled.
+    fn shred_record(&self, key: &str) -> Result<(bool, bool), VibraError> {
+        let Some(noise) = self.overwrite(&self.db, key.as_bytes())? else {
+            return Ok((false, false));
+        };
+        self.db.flush()?;
+        let removed = self.commit_rows(|rows, expiry, _, changes| {
+            if rows.get(key.as_bytes())?.as_deref() != Some(noise.as_slice()) {
+                return Ok(false);
+            }
+            rows.remove(key.as_bytes())?;
+            expiry.remove(key.as_bytes())?;
+            changes.push((AuditOp::Delete, key.to_string()));
+            Ok(true)
+        })?;
+        Ok((true, removed))
+    }
+
+    // Write random bytes of the same length over the value stored under `key`, returning the
+    // bytes written, if there was a value. A value replaced meanwhile is overwritten in turn.
+    // Blocks on sled.
+    fn overwrite(&self, tree: &sled::Tree, key: &[u8]) -> Result<Option<Vec<u8>>, VibraError> {
+        let mut current = tree.get(key)?;
+        while let Some(stored) = current {
+            let mut noise = vec![0u8; stored.len()];
+            self.with_rng(|rng| rng.fill_bytes(&mut noise));
+            match tree.compare_and_swap(key, Some(stored), Some(noise.as_slice()))? {
+                Ok(()) => return Ok(Some(noise)),
+                Err(conflict) => current = conflict.current,
+            }
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,53 @@
+use super::VibraDB;
+use crate::error::VibraError;
+use crate::models::Row;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+/// A frozen, read-only view of a `VibraDB` taken by `VibraDB::snapshot`.
+///
+/// Writes made after the snapshot was taken are never visible through it, and writes that change
+/// several rows at once are either wholly visible or not at all. The snapshot holds a
+/// copy of every live row's stored (encrypted) record; sled values are reference counted, so the
+/// copy shares their bytes with the database. Rows are decrypted on read.
+///
+/// # Methods
+///
+/// - `get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError>`
+///   - Retrieves a row as it was when the snapshot was taken.
+///
+/// - `scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError>`
+///   - Retrieves every row of a table as it was when the snapshot was taken, ordered by row id.
+pub struct VibraSnapshot {
+    db: VibraDB,
+    entries: BTreeMap<sled::IVec, sled::IVec>,
+}
+
+impl VibraSnapshot {
+    pub(super) fn new(db: VibraDB, entries: BTreeMap<sled::IVec, sled::IVec>) -> Self {
+        VibraSnapshot { db, entries }
+    }
+
+    // Retrieve a row as of the snapshot
+    pub fn get_row(&self, table_name: &str, row_id: &str) -> Result<Option<Row>, VibraError> {
+        let key = self.db.row_key(table_name, row_id)?;
+        match self.entries.get(key.as_bytes()) {
+            Some(stored) => Ok(Some(self.db.decode_row(table_name, row_id, stored)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Retrieve every row of a table as of the snapshot
+    pub fn scan_table(&self, table_name: &str) -> Result<Vec<Row>, VibraError> {
+        VibraDB::validate_table_name(table_name)?;
+        let prefix = self.db.table_prefix(table_name);
+        let mut entries: Vec<_> = self
+            .entries
+            .range::<[u8], _>((Bound::Included(prefix.as_bytes()), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix.as_bytes()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        self.db.sort_by_row_id(&mut entries);
+        self.db.decode_rows(&entries)
+    }
+}
@@ -0,0 +1,168 @@
+use super::audit::{AuditOp, Changes};
+use super::{has_expired, Timestamp, VibraDB};
+use crate::error::VibraError;
+use crate::models::{Column, Row};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use tokio::task;
+
+/// What a `VibraDB::transaction` closure and the `VibraTransaction` accessors return.
+///
+/// A `VibraError` converts into the error with `?` or `.into()`, aborting the transaction. Sled
+/// conflicts are also reported through it; propagate them with `?` so the transaction is retried.
+pub type TransactionResult<T> = Result<T, ConflictableTransactionError<VibraError>>;
+
+/// A transactional view of the database, passed to the closure given to `VibraDB::transaction`.
+///
+/// Reads see the transaction's own writes. All writes commit together when the closure returns
+/// `Ok`, or not at all if it returns an error. Values are decrypted on `get` and encrypted on
+/// `insert`, inside the transaction, so only already-encrypted records are committed.
+///
+/// # Methods
+///
+/// - `get(&self, table_name: &str, row_id: &str) -> TransactionResult<Option<Row>>`
+///   - Retrieves a row, as of this transaction.
+///
+/// - `insert(&self, table_name: &str, row: Row) -> TransactionResult<Option<Row>>`
+///   - Inserts a row, validating it against the table's schema, and returns the row it replaced.
+///
+/// - `remove(&self, table_name: &str, row_id: &str) -> TransactionResult<Option<Row>>`
+///   - Deletes a row, returning the row that was removed, if any.
+pub struct VibraTransaction<'a> {
+    db: &'a VibraDB,
+    rows: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    schemas: &'a HashMap<String, Option<Vec<Column>>>, // Declared tables and their schemas
+    written: &'a RefCell<HashSet<String>>, // Keys to drop from the cache once committed
+    changes: &'a RefCell<Changes>,         // What this attempt changed, for the audit log
+}
+
+impl VibraTransaction<'_> {
+    // Build a row's key, checking the table was declared when the transaction started
+    fn key(&self, table_name: &str, row_id: &str) -> Result<String, VibraError> {
+        let key = self.db.row_key(table_name, row_id)?;
+        if !self.schemas.contains_key(table_name) {
+            return Err(VibraError::InvalidName {
+                name: table_name.to_string(),
+                reason: "the table was not declared for this transaction".to_string(),
+            });
+        }
+        Ok(key)
+    }
+
+    // Decode a stored record, unless it has expired
+    fn live_row(
+        &self,
+        table_name: &str,
+        row_id: &str,
+        stored: Option<sled::IVec>,
+        expires_at: Option<sled::IVec>,
+    ) -> Result<Option<Row>, VibraError> {
+        match stored {
+            Some(stored) if !has_expired(expires_at.as_ref()) => {
+                Ok(Some(self.db.decode_row(table_name, row_id, &stored)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn get(&self, table_name: &str, row_id: &str) -> TransactionResult<Option<Row>> {
+        let key = self.key(table_name, row_id)?;
+        let stored = self.rows.get(key.as_bytes())?;
+        let expires_at = self.expiry.get(key.as_bytes())?;
+        Ok(self.live_row(table_name, row_id, stored, expires_at)?)
+    }
+
+    pub fn insert(&self, table_name: &str, row: Row) -> TransactionResult<Option<Row>> {
+        let key = self.key(table_name, &row.id)?;
+        VibraDB::check_unique_columns(table_name, &row)?;
+        if let Some(schema) = &self.schemas[table_name] {
+            VibraDB::check_row(table_name, schema, &row)?;
+        }
+        let data = row.to_json().map_err(VibraError::from)?;
+        let mut sealed = self.db.encrypt_value(&key, data.as_bytes())?;
+
+        let prior = self.rows.get(key.as_bytes())?;
+        let prior_expiry = self.expiry.remove(key.as_bytes())?;
+        if let Some(stored) = prior.as_ref().filter(|_| !has_expired(prior_expiry.as_ref())) {
+            VibraDB::copy_record_time(&mut sealed, stored, Timestamp::Created);
+        }
+        self.rows.insert(key.as_bytes(), sealed)?;
+        let replaced = self.live_row(table_name, &row.id, prior, prior_expiry)?;
+        self.changes.borrow_mut().push((VibraDB::write_op(replaced.is_some()), key.clone()));
+        self.written.borrow_mut().insert(key);
+        Ok(replaced)
+    }
+
+    pub fn remove(&self, table_name: &str, row_id: &str) -> TransactionResult<Option<Row>> {
+        let key = self.key(table_name, row_id)?;
+        let prior = self.rows.remove(key.as_bytes())?;
+        let prior_expiry = self.expiry.remove(key.as_bytes())?;
+        if prior.is_some() {
+            self.changes.borrow_mut().push((AuditOp::Delete, key.clone()));
+        }
+        self.written.borrow_mut().insert(key);
+        Ok(self.live_row(table_name, row_id, prior, prior_expiry)?)
+    }
+}
+
+impl VibraDB {
+    // Run `f` as one transaction over rows of the given tables, returning what it returns.
+    //
+    // Everything `f` writes through its `VibraTransaction` commits atomically, or nothing does
+    // if it returns an error. Sled retries `f` when it conflicts with a concurrent writer, so it
+    // may run more than once and must not have side effects outside the transaction.
+    pub async fn transaction<F, T>(&self, tables: &[&str], f: F) -> Result<T, VibraError>
+    where
+        F: Fn(&VibraTransaction<'_>) -> TransactionResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        // Schemas are loaded up front: reading a tree outside the transaction while it runs
+        // would deadlock on sled's transaction lock
+        let mut schemas = HashMap::new();
+        for table_name in tables {
+            Self::validate_table_name(table_name)?;
+            schemas.insert(table_name.to_string(), self.table_schema(table_name)?);
+        }
+        let this = self.clone();
+        let (result, written, reindexed) = task::spawn_blocking(move || {
+            let written = RefCell::new(HashSet::<String>::new());
+            let result = this.commit_rows(|rows, expiry, tables, changes| {
+                // Checked inside the transaction, so a table can't be dropped halfway through
+                for table_name in schemas.keys() {
+                    this.check_table_in(tables, table_name)?;
+                }
+                let attempt = RefCell::new(Changes::new());
+                let value = f(&VibraTransaction {
+                    db: &this,
+                    rows,
+                    expiry,
+                    schemas: &schemas,
+                    written: &written,
+                    changes: &attempt,
+                })?;
+                changes.append(&mut attempt.into_inner());
+                Ok(value)
+            });
+            let written = written.into_inner();
+            // Reindexing reads what is stored, so keys the transaction didn't end up writing are
+            // left as they were
+            let reindexed = match &result {
+                Ok(_) => this.reindex_rows(written.iter().map(String::as_str)),
+                Err(_) => Ok(()),
+            };
+            (result, written, reindexed)
+        })
+        .await
+        .unwrap();
+
+        // Attempts that were retried may have touched more keys than the one that committed;
+        // dropping a few extra cache entries is harmless
+        for key in written {
+            self.cache.pop(&key);
+        }
+        reindexed?;
+        result
+    }
+}
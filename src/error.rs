@@ -0,0 +1,229 @@
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+/// Errors returned by `VibraDB` operations.
+///
+/// # Variants
+///
+/// * `SchemaViolation` - A row does not conform to the schema declared for its table.
+/// * `Storage` - The underlying sled database returned an error.
+/// * `Serialization` - A value could not be serialized or deserialized.
+/// * `Encryption` - A value could not be encrypted at the given layer.
+/// * `NonceExhausted` - The database's nonce sequence has no values left, so nothing more can be
+///   encrypted with it.
+/// * `Decryption` - A stored value could not be decrypted at the given layer.
+/// * `InvalidUtf8` - A decrypted value was not valid UTF-8.
+/// * `MalformedRecord` - A stored value's header does not describe a valid record.
+/// * `RowIdMismatch` - A row's stored payload belongs to a different row id than it is stored under.
+/// * `CorruptEntry` - The value stored under `key` is shorter than any record can be.
+/// * `IntegrityCheckFailed` - A value decrypted, but does not match the checksum stored with it.
+/// * `InvalidValue` - A column's value can't be used for the requested operation.
+/// * `InvalidName` - A table name or row id can't be used as part of a key.
+/// * `MissingConfig` - A required configuration value was not set.
+/// * `MissingKey` - No master key was configured: not in the config, `VIBRA_MASTER_KEY` or a key
+///   file.
+/// * `InvalidKey` - The master key from `origin` can't be used; `reason` says how to fix it.
+/// * `KeyFile` - The key file at `path` could not be read or written.
+/// * `KeyringEntryMissing` - The OS keychain has no master key under `service` and `account`.
+/// * `KeyringLocked` - The OS keychain is locked or denied access to the entry; `reason` is the
+///   platform's error.
+/// * `Keyring` - The OS keychain entry could not be read or written otherwise; `reason` says why.
+/// * `InvalidPassphrase` - The passphrase does not unlock the database's master key.
+/// * `InvalidConfig` - A configuration value is out of range.
+/// * `Open` - The database could not be opened at the configured path.
+/// * `Io` - A filesystem operation failed.
+/// * `Csv` - A CSV file could not be read or written.
+/// * `InUse` - The database can't be closed while other handles to it are still alive.
+/// * `CorruptRow` - The row stored under `key` could not be decoded; `source` says why.
+/// * `DuplicateColumn` - A row uses the same column name more than once.
+/// * `DuplicateRow` - A batch that must be written atomically contains the same row id twice.
+/// * `BatchTooLarge` - A batch has more rows than can be written in one transaction.
+/// * `TableNotFound` - The named table has not been created.
+/// * `MissingTableKey` - The named table's data key is gone, destroyed along with the table, or
+///   doesn't unwrap with the master key the database was opened with.
+/// * `NotIndexed` - `find_by` was given a column with neither a blind index nor deterministic
+///   encryption.
+/// * `InvalidDump` - A file given to `import_table` is not a table dump this version can read.
+/// * `InvalidBackup` - A file given to `restore_encrypted` is not a backup this version can read,
+///   or is damaged.
+/// * `InvalidBackupKey` - The backup key doesn't decrypt the backup given to `restore_encrypted`.
+/// * `Timeout` - An operation given a timeout didn't finish within it.
+/// * `NonConformant` - The record stored under `key` doesn't conform to `compliance_mode =
+///   "strict"`; `reason` says how.
+/// * `NonConformantDatabase` - A database opened with `compliance_mode = "strict"` holds `records`
+///   records or tables that don't conform, the first of them `key`.
+pub enum VibraError {
+    #[error("schema violation in table {table}, column {column}: {reason}")]
+    SchemaViolation {
+        table: String,
+        column: String,
+        reason: String,
+    },
+    #[error("storage error: {0}")]
+    Storage(#[from] sled::Error),
+    #[error("serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("encryption failed at layer {layer}")]
+    Encryption { layer: usize },
+    #[error("the nonce sequence is exhausted: every nonce it can hand out has been used")]
+    NonceExhausted,
+    #[error("decryption failed at layer {layer}")]
+    Decryption { layer: usize },
+    #[error("decrypted value is not valid UTF-8")]
+    InvalidUtf8,
+    #[error("stored record is malformed: {0}")]
+    MalformedRecord(String),
+    #[error("row {expected} holds the payload of row {stored}")]
+    RowIdMismatch { expected: String, stored: String },
+    #[error("entry {key} is corrupt: {actual} bytes stored, a record takes at least {expected_min}")]
+    CorruptEntry {
+        key: String,
+        expected_min: usize,
+        actual: usize,
+    },
+    #[error("stored value failed its integrity check")]
+    IntegrityCheckFailed,
+    #[error("invalid value in column {column}: {reason}")]
+    InvalidValue { column: String, reason: String },
+    #[error("invalid name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+    #[error("missing configuration value: {0}")]
+    MissingConfig(&'static str),
+    #[error(
+        "no master key configured: set key_file in Vibra.toml, the VIBRA_MASTER_KEY environment \
+         variable or VibraConfig::master_key, or create a key with VibraDB::generate_key_file"
+    )]
+    MissingKey,
+    #[error("invalid master key in {origin}: {reason}")]
+    InvalidKey { origin: String, reason: String },
+    #[error("key file {}: {source}", path.display())]
+    KeyFile { path: PathBuf, source: io::Error },
+    #[error("no master key in the keychain under service {service:?}, account {account:?}")]
+    KeyringEntryMissing { service: String, account: String },
+    #[error(
+        "keychain entry {service:?}/{account:?} can't be accessed, the keychain may be locked: \
+         {reason}"
+    )]
+    KeyringLocked {
+        service: String,
+        account: String,
+        reason: String,
+    },
+    #[error("keychain entry {service:?}/{account:?}: {reason}")]
+    Keyring {
+        service: String,
+        account: String,
+        reason: String,
+    },
+    #[error("wrong passphrase")]
+    InvalidPassphrase,
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("failed to open database at {}: {source}", path.display())]
+    Open { path: PathBuf, source: sled::Error },
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("csv error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("the database is still open through other handles")]
+    InUse,
+    #[error("row {key} could not be read: {source}")]
+    CorruptRow {
+        key: String,
+        #[source]
+        source: Box<VibraError>,
+    },
+    #[error("duplicate column {column} in a row of table {table}")]
+    DuplicateColumn { table: String, column: String },
+    #[error("row {id} appears more than once in a batch for table {table}")]
+    DuplicateRow { table: String, id: String },
+    #[error("batch of {rows} rows exceeds the limit of {max} rows per transaction")]
+    BatchTooLarge { rows: usize, max: usize },
+    #[error("table {0} does not exist")]
+    TableNotFound(String),
+    #[error(
+        "no data key for table {0}: it was deleted with the table, or the master key doesn't \
+         unwrap it"
+    )]
+    MissingTableKey(String),
+    #[error("column {column} of table {table} has no blind index and isn't deterministic")]
+    NotIndexed { table: String, column: String },
+    #[error("invalid table dump: {0}")]
+    InvalidDump(String),
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("the backup key doesn't decrypt this backup")]
+    InvalidBackupKey,
+    #[error("operation timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("{key} doesn't conform to strict compliance mode: {reason}")]
+    NonConformant { key: String, reason: String },
+    #[error(
+        "{records} records don't conform to strict compliance mode, starting with {key}: \
+         {reason}; migrate the database by opening it without compliance_mode, with \
+         encryption_layers = 1 and cipher = \"aes256gcm\", and running rekey(1, None) \
+         (scan_nonconformant lists what is left)"
+    )]
+    NonConformantDatabase {
+        records: usize,
+        key: String,
+        reason: String,
+    },
+}
+
+/// Problems with the contents of a `Vibra.toml`.
+///
+/// `VibraConfig::init` and `VibraConfig::from_file` fail with an `io::Error` of kind
+/// `InvalidData` that carries one of these; get it back with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<ConfigError>())`. Lines are 1-based.
+///
+/// # Variants
+///
+/// * `UnknownKey` - The file sets a key vibra doesn't know, most likely a typo of `suggestion`.
+/// * `Invalid` - The file isn't valid TOML, or `key` has a value of the wrong type.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("unknown key `{key}`{}{}", at_line(line), did_you_mean(suggestion))]
+    UnknownKey {
+        key: String,
+        line: Option<usize>,
+        suggestion: Option<String>,
+    },
+    #[error("invalid {}{}: {message}", key.as_deref().unwrap_or("Vibra.toml"), at_line(line))]
+    Invalid {
+        key: Option<String>,
+        line: Option<usize>,
+        message: String,
+    },
+}
+
+fn at_line(line: &Option<usize>) -> String {
+    line.map(|line| format!(" on line {}", line)).unwrap_or_default()
+}
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    suggestion
+        .as_ref()
+        .map(|key| format!(", did you mean `{}`?", key))
+        .unwrap_or_default()
+}
+
+impl From<TransactionError<VibraError>> for VibraError {
+    fn from(err: TransactionError<VibraError>) -> Self {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => VibraError::Storage(err),
+        }
+    }
+}
+
+// Lets `?` abort a transaction closure with a VibraError
+impl From<VibraError> for ConflictableTransactionError<VibraError> {
+    fn from(err: VibraError) -> Self {
+        ConflictableTransactionError::Abort(err)
+    }
+}
@@ -1,7 +1,20 @@
 pub mod config;
 pub mod db;
+pub mod error;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod models;
 
-pub use crate::config::VibraConfig;
-pub use crate::db::VibraDB;
-pub use crate::models::Row;
+pub use crate::config::{CipherSuite, ComplianceMode, MasterKeySource, TableConfig, VibraConfig};
+#[cfg(feature = "blocking")]
+pub use crate::db::VibraDbBlocking;
+pub use crate::db::{
+    MasterKey, PassphraseParams, TransactionResult, VibraDB, VibraSnapshot, VibraTransaction,
+};
+pub use crate::error::{ConfigError, VibraError};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::{HistogramSnapshot, MetricsSnapshot};
+pub use crate::models::{
+    AuditReport, CacheStats, Column, IntegrityReport, MigrationReport, RotationReport, Row, RowMeta,
+    Value,
+};
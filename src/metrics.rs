@@ -0,0 +1,180 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+// A latency histogram with fixed buckets, updated lock-free
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()], // Per-bucket counts; anything slower only hits `count`
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(&bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+            buckets,
+        }
+    }
+}
+
+// Counters and histograms shared by every clone of a `VibraDB`
+#[derive(Default)]
+pub(crate) struct Metrics {
+    inserts: AtomicU64,
+    gets: AtomicU64,
+    deletes: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    insert_latency: Histogram,
+    get_latency: Histogram,
+    delete_latency: Histogram,
+    encryption_time: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn record_insert(&self, elapsed: Duration) {
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.insert_latency.observe(elapsed);
+    }
+
+    pub(crate) fn record_get(&self, elapsed: Duration) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.get_latency.observe(elapsed);
+    }
+
+    pub(crate) fn record_delete(&self, elapsed: Duration) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+        self.delete_latency.observe(elapsed);
+    }
+
+    pub(crate) fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_encryption(&self, elapsed: Duration) {
+        self.encryption_time.observe(elapsed);
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            inserts: self.inserts.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            insert_latency: self.insert_latency.snapshot(),
+            get_latency: self.get_latency.snapshot(),
+            delete_latency: self.delete_latency.snapshot(),
+            encryption_time: self.encryption_time.snapshot(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+/// A point-in-time copy of a latency histogram.
+///
+/// # Fields
+///
+/// * `count` - The number of observations.
+/// * `sum` - The total of all observed durations.
+/// * `buckets` - `(upper bound in seconds, observations at or below it)` pairs, cumulative like
+///   Prometheus buckets. Observations slower than the last bound are only counted in `count`.
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: Duration,
+    pub buckets: Vec<(f64, u64)>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+/// A point-in-time copy of a database's operation metrics, returned by `VibraDB::metrics_snapshot`.
+///
+/// # Fields
+///
+/// * `inserts` - Rows written by `insert_row`.
+/// * `gets` - Lookups made by `get_row`.
+/// * `deletes` - Rows deleted by `delete_row`.
+/// * `cache_hits` - `get_row` lookups answered from the cache.
+/// * `cache_misses` - `get_row` lookups that had to read and decrypt the stored row.
+/// * `insert_latency`, `get_latency`, `delete_latency` - How long each operation took.
+/// * `encryption_time` - How long encrypting each value took.
+pub struct MetricsSnapshot {
+    pub inserts: u64,
+    pub gets: u64,
+    pub deletes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub insert_latency: HistogramSnapshot,
+    pub get_latency: HistogramSnapshot,
+    pub delete_latency: HistogramSnapshot,
+    pub encryption_time: HistogramSnapshot,
+}
+
+impl MetricsSnapshot {
+    // Render the metrics in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("vibra_inserts_total", "Rows inserted.", self.inserts),
+            ("vibra_gets_total", "Rows looked up.", self.gets),
+            ("vibra_deletes_total", "Rows deleted.", self.deletes),
+            ("vibra_cache_hits_total", "Lookups served from the cache.", self.cache_hits),
+            ("vibra_cache_misses_total", "Lookups that missed the cache.", self.cache_misses),
+        ];
+        for (name, help, value) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        let histograms = [
+            ("vibra_insert_seconds", "Time taken to insert a row.", &self.insert_latency),
+            ("vibra_get_seconds", "Time taken to look up a row.", &self.get_latency),
+            ("vibra_delete_seconds", "Time taken to delete a row.", &self.delete_latency),
+            ("vibra_encryption_seconds", "Time taken to encrypt a value.", &self.encryption_time),
+        ];
+        for (name, help, histogram) in histograms {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            for (bound, count) in &histogram.buckets {
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count).unwrap();
+            writeln!(out, "{}_sum {}", name, histogram.sum.as_secs_f64()).unwrap();
+            writeln!(out, "{}_count {}", name, histogram.count).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests;
@@ -0,0 +1,31 @@
+use super::*;
+
+#[test]
+fn test_histogram_buckets_are_cumulative() {
+    let histogram = Histogram::default();
+    histogram.observe(Duration::from_micros(50));
+    histogram.observe(Duration::from_millis(3));
+    histogram.observe(Duration::from_secs(2));
+
+    let snapshot = histogram.snapshot();
+    assert_eq!(snapshot.count, 3);
+    assert_eq!(snapshot.buckets.len(), BUCKETS.len());
+    assert_eq!(snapshot.buckets[0], (0.0001, 1));
+    assert_eq!(snapshot.buckets[5], (0.005, 2));
+    // Two seconds is past the last bucket, so it only shows up in the count
+    assert_eq!(snapshot.buckets.last().unwrap().1, 2);
+}
+
+#[test]
+fn test_prometheus_exposition() {
+    let metrics = Metrics::default();
+    metrics.record_insert(Duration::from_micros(200));
+    metrics.record_cache_hit();
+
+    let text = metrics.snapshot().to_prometheus();
+    assert!(text.contains("# TYPE vibra_inserts_total counter\nvibra_inserts_total 1\n"));
+    assert!(text.contains("vibra_cache_hits_total 1\n"));
+    assert!(text.contains("vibra_insert_seconds_bucket{le=\"0.00025\"} 1\n"));
+    assert!(text.contains("vibra_insert_seconds_bucket{le=\"+Inf\"} 1\n"));
+    assert!(text.contains("vibra_get_seconds_count 0\n"));
+}
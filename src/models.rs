@@ -1,20 +1,273 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
-#[derive(Clone, PartialEq, Debug, Serialize)]
-#[allow(dead_code)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+/// Describes a column in a table schema.
+///
+/// # Fields
+///
+/// * `name` - The name of the column.
+/// * `data_type` - The type of the column's values: `string`/`text`, `integer`/`int`,
+///   `float`/`real`, `boolean`/`bool` or `bytes`/`blob`.
 pub struct Column {
     pub name: String,
     pub data_type: String,
 }
 
-#[derive(Clone, PartialEq, Debug, Serialize)]
+impl Column {
+    // Check whether the column's data type is one Vibra knows how to validate
+    pub fn is_known_type(&self) -> bool {
+        matches!(
+            self.data_type.as_str(),
+            "string"
+                | "text"
+                | "integer"
+                | "int"
+                | "float"
+                | "real"
+                | "boolean"
+                | "bool"
+                | "bytes"
+                | "blob"
+        )
+    }
+
+    // Check whether a value conforms to the column's data type
+    pub fn accepts(&self, value: &Value) -> bool {
+        match (self.data_type.as_str(), value) {
+            ("bytes" | "blob", Value::Bytes(_)) => true,
+            ("string" | "text", Value::Text(_)) => true,
+            ("integer" | "int", Value::Text(text)) => text.parse::<i64>().is_ok(),
+            ("float" | "real", Value::Text(text)) => text.parse::<f64>().is_ok(),
+            ("boolean" | "bool", Value::Text(text)) => text.parse::<bool>().is_ok(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+/// A column value: either UTF-8 text or raw bytes.
+///
+/// # Variants
+///
+/// * `Text` - A UTF-8 string.
+/// * `Bytes` - Arbitrary binary data, which never has to be valid UTF-8.
+pub enum Value {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    // Get the value as text, if it is text
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            Value::Bytes(_) => None,
+        }
+    }
+
+    // Get the value as bytes, if it is binary
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Text(_) => None,
+            Value::Bytes(bytes) => Some(bytes),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Value::Text(text.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Value::Bytes(bytes.to_vec())
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 /// Represents a row in a table with an identifier and a collection of columns.
 ///
+/// Column names must be unique within a row: `VibraDB` rejects rows that repeat one, and `set`
+/// overwrites an existing column instead of adding a second one.
+///
+/// Rows serialize as `{"id": ..., "columns": [[name, value], ...]}`, which is also how they are
+/// stored; `to_json` and `from_json` convert to and from that form.
+///
 /// # Fields
 ///
 /// * `id` - A unique identifier for the row.
 /// * `columns` - A vector of tuples where each tuple contains a column name and its corresponding value.
 pub struct Row {
     pub id: String,
-    pub columns: Vec<(String, String)>, // (column_name, value)
+    pub columns: Vec<(String, Value)>, // (column_name, value)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+/// Metadata about a stored row, returned by `VibraDB::get_row_meta`.
+///
+/// # Fields
+///
+/// * `created_at` - When the row was first inserted. Overwriting a row keeps its creation time.
+/// * `updated_at` - When the row was last written.
+/// * `size_bytes` - The size of the stored, encrypted record.
+pub struct RowMeta {
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+    pub size_bytes: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+/// Statistics about the row cache, returned by `VibraDB::cache_stats`.
+///
+/// # Fields
+///
+/// * `hits` - `get_row` calls answered from the cache.
+/// * `misses` - `get_row` calls that had to read and decrypt the stored row.
+/// * `len` - The number of rows currently cached.
+/// * `capacity` - The maximum number of rows the cache holds (`cache_size`).
+/// * `bytes` - The size of the cached rows: their keys, ids, column names and values.
+/// * `max_bytes` - The byte budget of the cache (`cache_bytes`), if any.
+/// * `shards` - How many independently locked shards the cache is split into (`cache_shards`).
+/// * `poisoned` - How many times a shard was cleared because a panic poisoned its lock.
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+    pub capacity: usize,
+    pub bytes: usize,
+    pub max_bytes: Option<usize>,
+    pub shards: usize,
+    pub poisoned: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+/// What `VibraDB::rotate_master_key` did with the table data keys and stored records.
+///
+/// Records encrypted with their table's data key only need the key re-wrapped; the others, rows
+/// and table schemas alike, are re-encrypted.
+///
+/// # Fields
+///
+/// * `tables` - Table data keys re-wrapped with the new master key.
+/// * `rotated` - Records re-encrypted, with their table's data key if it has one, otherwise with
+///   the new master key.
+/// * `skipped` - Records left alone because they use their table's data key or already used the
+///   new master key, e.g. because they were written during the rotation or rotated before it was
+///   interrupted, or because they were replaced or removed while it ran.
+/// * `failed` - Records neither master key decrypts. They are left as they are, and logged.
+pub struct RotationReport {
+    pub tables: usize,
+    pub rotated: usize,
+    pub skipped: usize,
+    pub failed: usize,
 }
+
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+/// What `VibraDB::verify_integrity` found checking the stored rows.
+///
+/// # Fields
+///
+/// * `ok` - Rows that decrypted, passed their integrity checks and parsed.
+/// * `corrupt` - The `table/id` key of every row that didn't, with why, ordered by key. Rows
+///   whose id is encrypted and doesn't decrypt are listed under the id they are stored under.
+pub struct IntegrityReport {
+    pub ok: usize,
+    pub corrupt: Vec<(String, String)>,
+}
+
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+/// What `VibraDB::verify_audit_log` found walking the audit log.
+///
+/// # Fields
+///
+/// * `verified` - Entries that are authenticated and chain to the one before, up to the first
+///   broken link.
+/// * `first_broken` - The number of the first entry that isn't, counting from 0, with why; `None`
+///   if the whole log is intact. An entry that was removed breaks the chain at its number.
+pub struct AuditReport {
+    pub verified: usize,
+    pub first_broken: Option<(u64, String)>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+/// What `VibraDB::migrate_legacy` did with the stored records.
+///
+/// # Fields
+///
+/// * `migrated` - Records rewritten from the legacy format, with their keys inline, to the current
+///   one.
+/// * `skipped` - Records already in the current format, including those migrated before an
+///   interrupted migration stopped, or replaced or removed while it ran.
+/// * `failed` - Legacy records that don't decrypt. They are left as they are, and logged.
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Row {
+    // Get a column's value by name
+    pub fn get(&self, column: &str) -> Option<&Value> {
+        self.columns
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, value)| value)
+    }
+
+    // Set a column's value, overwriting it in place or appending it if the row doesn't have it
+    pub fn set(&mut self, column: impl Into<String>, value: impl Into<Value>) {
+        let column = column.into();
+        let value = value.into();
+        match self.columns.iter_mut().find(|(name, _)| *name == column) {
+            Some((_, existing)) => *existing = value,
+            None => self.columns.push((column, value)),
+        }
+    }
+
+    // Find a column name that appears more than once, if any
+    pub(crate) fn duplicate_column(&self) -> Option<&str> {
+        let mut seen = HashSet::new();
+        self.columns
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .find(|name| !seen.insert(*name))
+    }
+
+    // Serialize the row as JSON
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    // Parse a row from the JSON `to_json` produces
+    pub fn from_json(json: &str) -> Result<Row, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    // Map column names to values for lookups; the row itself keeps its column order
+    pub fn columns_map(&self) -> HashMap<&str, &Value> {
+        self.columns
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod models_tests;
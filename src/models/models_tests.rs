@@ -0,0 +1,90 @@
+use super::*;
+
+fn user() -> Row {
+    Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("age".to_string(), "42".into()),
+        ],
+    }
+}
+
+#[test]
+fn test_get_column() {
+    let row = user();
+    assert_eq!(row.get("name"), Some(&Value::Text("John Doe".to_string())));
+    assert_eq!(row.get("email"), None);
+}
+
+#[test]
+fn test_set_overwrites_in_place() {
+    let mut row = user();
+    row.set("name", "Jane Doe");
+    assert_eq!(row.get("name").and_then(Value::as_text), Some("Jane Doe"));
+    assert_eq!(row.columns.len(), 2);
+    assert_eq!(row.columns[0].0, "name");
+}
+
+#[test]
+fn test_set_appends_missing_column() {
+    let mut row = user();
+    row.set("avatar", vec![1u8, 2, 3]);
+    assert_eq!(row.columns.len(), 3);
+    assert_eq!(row.columns[2].0, "avatar");
+    assert_eq!(row.get("avatar").and_then(Value::as_bytes), Some(&[1u8, 2, 3][..]));
+}
+
+#[test]
+fn test_columns_map() {
+    let row = user();
+    let map = row.columns_map();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map["age"].as_text(), Some("42"));
+    assert!(!map.contains_key("email"));
+}
+
+#[test]
+fn test_duplicate_column() {
+    let mut row = user();
+    assert_eq!(row.duplicate_column(), None);
+    row.set("name", "Jane Doe");
+    assert_eq!(row.duplicate_column(), None);
+    row.columns.push(("age".to_string(), "43".into()));
+    assert_eq!(row.duplicate_column(), Some("age"));
+}
+
+#[test]
+fn test_row_json_round_trip() {
+    let rows = [
+        user(),
+        Row {
+            id: "ünïcødé 🦀".to_string(),
+            columns: vec![
+                ("名前".to_string(), "山田太郎".into()),
+                ("Ω".to_string(), vec![0u8, 159, 255].into()),
+            ],
+        },
+        Row {
+            id: "empty".to_string(),
+            columns: vec![],
+        },
+    ];
+    for row in rows {
+        let json = row.to_json().unwrap();
+        assert_eq!(Row::from_json(&json).unwrap(), row);
+    }
+    assert!(Row::from_json(r#"{"columns": []}"#).is_err());
+}
+
+#[test]
+fn test_column_round_trip() {
+    let column = Column {
+        name: "größe".to_string(),
+        data_type: "integer".to_string(),
+    };
+    let json = serde_json::to_string(&column).unwrap();
+    assert_eq!(serde_json::from_str::<Column>(&json).unwrap(), column);
+    // Columns and rows can be used as set members
+    assert_eq!(HashSet::from([column.clone(), column]).len(), 1);
+}
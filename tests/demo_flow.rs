@@ -0,0 +1,52 @@
+// Runs the flow of examples/demo.rs (and the README) against a temporary database, using only the
+// public API.
+
+use std::fs;
+use tempfile::tempdir;
+use vibradb::{Row, VibraConfig, VibraDB};
+
+#[tokio::test]
+async fn test_demo_flow() {
+    let dir = tempdir().unwrap();
+    let db_path = dir.path().join("vibra_db");
+    let key_path = dir.path().join("vibra.key");
+    VibraDB::generate_key_file(&key_path).unwrap();
+    let config_path = dir.path().join("Vibra.toml");
+    fs::write(
+        &config_path,
+        format!(
+            "path = {:?}\ncache_size = 100\nencryption_layers = 10\nkey_file = {:?}\n",
+            db_path.to_str().unwrap(),
+            key_path.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+
+    let config = VibraConfig::from_file(&config_path).unwrap();
+    let vibra_db = VibraDB::new(config).unwrap();
+    vibra_db.create_table("users", None).await.unwrap();
+
+    let row = Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe".into()),
+            ("email".to_string(), "john.doe@example.com".into()),
+        ],
+    };
+    vibra_db.insert_row("users", row.clone()).await.unwrap();
+    assert_eq!(vibra_db.get_row("users", "user1").await.unwrap(), Some(row));
+
+    let updated_row = Row {
+        id: "user1".to_string(),
+        columns: vec![
+            ("name".to_string(), "John Doe Updated".into()),
+            ("email".to_string(), "john.doe.updated@example.com".into()),
+        ],
+    };
+    vibra_db.update_row("users", updated_row.clone()).await.unwrap();
+    vibra_db.clear_cache();
+    assert_eq!(vibra_db.get_row("users", "user1").await.unwrap(), Some(updated_row));
+
+    vibra_db.delete_db().await.unwrap();
+    assert!(!db_path.exists());
+}